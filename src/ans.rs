@@ -0,0 +1,585 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::coder::EntropyCoder;
+use crate::error::{self, CorruptionKind, DecodeError};
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// rANS (range asymmetric numeral systems) entropy coder
+// ---------------------------------------------------------------------------
+//
+// Byte-oriented rANS with a 32-bit state, in the style of Fabian Giesen's
+// `rans_byte.h`.  Frequencies are normalised so they sum to `PROB_SCALE`,
+// which lets a single symbol cost a small fraction of a bit when the input
+// is heavily skewed -- something Huffman cannot do.
+//
+// rANS encodes in reverse, so the input is coded in independent chunks of
+// `CHUNK_SIZE` bytes.  Each chunk is laid out as:
+//
+//   [final encoder state: u32 LE] [renormalisation bytes in decode order]
+//
+// The decoder consumes exactly the bytes the encoder emitted for a chunk,
+// so chunks are concatenated without any length prefix.
+
+/// Number of bits of probability precision.
+pub const PROB_BITS: u32 = 12;
+
+/// Sum of all normalised frequencies.
+pub const PROB_SCALE: u32 = 1 << PROB_BITS;
+
+/// Lower bound of the normalised state interval.
+const RANS_L: u32 = 1 << 23;
+
+/// Number of input bytes coded per independent rANS chunk.
+pub const CHUNK_SIZE: usize = 1 << 16;
+
+/// Normalised frequency table shared by the rANS encoder and decoder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrequencyTable {
+    /// Normalised frequency for each byte.  0 means the byte never appears.
+    pub freq: [u32; 256],
+    /// Cumulative frequency: `cum[b]` is the sum of `freq[..b]`.
+    pub cum: [u32; 257],
+}
+
+impl FrequencyTable {
+    /// Scales raw byte counts so they sum to [`PROB_SCALE`], keeping every
+    /// present byte at a frequency of at least 1.
+    ///
+    /// An all-zero input yields an empty table.
+    pub fn normalize(counts: &[u64; 256]) -> Self {
+        let total: u64 = counts.iter().sum();
+        let mut freq = [0u32; 256];
+
+        if total > 0 {
+            let mut sum = 0u32;
+            for (b, &count) in counts.iter().enumerate() {
+                if count > 0 {
+                    let scaled = (count as u128 * PROB_SCALE as u128 / total as u128) as u32;
+                    freq[b] = scaled.max(1);
+                    sum += freq[b];
+                }
+            }
+
+            // Rounding leaves the sum slightly off; fix it up using the most
+            // frequent symbols, which absorb the error at the lowest cost.
+            while sum < PROB_SCALE {
+                let b = argmax(&freq);
+                freq[b] += 1;
+                sum += 1;
+            }
+            while sum > PROB_SCALE {
+                let b = argmax(&freq);
+                freq[b] -= 1;
+                sum -= 1;
+            }
+        }
+
+        Self::from_freqs(freq)
+    }
+
+    /// Builds the cumulative table from already-normalised frequencies.
+    fn from_freqs(freq: [u32; 256]) -> Self {
+        let mut cum = [0u32; 257];
+        for b in 0..256 {
+            cum[b + 1] = cum[b] + freq[b];
+        }
+        FrequencyTable { freq, cum }
+    }
+
+    /// Number of distinct symbols with a non-zero frequency.
+    pub fn symbol_count(&self) -> u16 {
+        self.freq.iter().filter(|&&f| f > 0).count() as u16
+    }
+
+    /// Serialises the table for the file header.
+    ///
+    /// Layout: `u16` symbol count, then one `(u8 symbol, u16 freq)` entry
+    /// per present byte, sorted by byte value.  All integers little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let count = self.symbol_count();
+        let mut out = Vec::with_capacity(2 + count as usize * 3);
+        out.extend_from_slice(&count.to_le_bytes());
+        for (b, &f) in self.freq.iter().enumerate() {
+            if f > 0 {
+                out.push(b as u8);
+                out.extend_from_slice(&(f as u16).to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Parses and validates a table written by [`to_bytes`](Self::to_bytes).
+    ///
+    /// Returns an `InvalidData` error if the table is truncated, has
+    /// unsorted or duplicate symbols, zero frequencies, or does not sum to
+    /// [`PROB_SCALE`].
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+        if bytes.len() < 2 {
+            return Err(invalid("ANS frequency table is truncated".to_string()));
+        }
+        let count = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if count > 256 {
+            return Err(invalid(format!("Invalid symbol count {count}")));
+        }
+        if bytes.len() != 2 + count * 3 {
+            return Err(invalid(format!(
+                "ANS frequency table length {} does not match symbol count {count}",
+                bytes.len()
+            )));
+        }
+
+        let mut freq = [0u32; 256];
+        let mut sum = 0u32;
+        let mut prev: Option<u8> = None;
+        for entry in bytes[2..].chunks_exact(3) {
+            let symbol = entry[0];
+            let f = u16::from_le_bytes([entry[1], entry[2]]) as u32;
+            if prev.is_some_and(|p| p >= symbol) {
+                return Err(invalid(format!(
+                    "ANS frequency table entry 0x{symbol:02X} is out of order"
+                )));
+            }
+            if f == 0 {
+                return Err(invalid(format!("Byte 0x{symbol:02X} has zero frequency")));
+            }
+            freq[symbol as usize] = f;
+            sum += f;
+            prev = Some(symbol);
+        }
+
+        if count > 0 && sum != PROB_SCALE {
+            return Err(invalid(format!(
+                "ANS frequencies sum to {sum}, expected {PROB_SCALE}"
+            )));
+        }
+
+        Ok(Self::from_freqs(freq))
+    }
+}
+
+/// Index of the largest entry (first one on ties).
+fn argmax(freq: &[u32; 256]) -> usize {
+    let mut best = 0;
+    for b in 1..256 {
+        if freq[b] > freq[best] {
+            best = b;
+        }
+    }
+    best
+}
+
+// ---------------------------------------------------------------------------
+// Encoder
+// ---------------------------------------------------------------------------
+
+/// Encodes everything from `input` with `table` and writes the chunked rANS
+/// payload to `output`.
+///
+/// Every byte read from `input` must have a non-zero frequency in `table`.
+/// Returns the number of payload bytes written.
+pub fn encode<R: Read + ?Sized, W: Write + ?Sized>(
+    table: &FrequencyTable,
+    input: &mut R,
+    output: &mut W,
+) -> std::io::Result<u64> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut emitted: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+    let mut written = 0u64;
+
+    loop {
+        let n = read_full(input, &mut chunk)?;
+        if n == 0 {
+            break;
+        }
+
+        emitted.clear();
+        let mut x = RANS_L;
+        for &byte in chunk[..n].iter().rev() {
+            let freq = table.freq[byte as usize];
+            if freq == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Byte 0x{byte:02X} is missing from the frequency table"),
+                ));
+            }
+            let start = table.cum[byte as usize];
+
+            let x_max = ((RANS_L >> PROB_BITS) << 8) * freq;
+            while x >= x_max {
+                emitted.push(x as u8);
+                x >>= 8;
+            }
+            x = ((x / freq) << PROB_BITS) + (x % freq) + start;
+        }
+
+        // The decoder reads the state first, then the renormalisation bytes
+        // in the reverse of the order they were emitted.
+        output.write_all(&x.to_le_bytes())?;
+        emitted.reverse();
+        output.write_all(&emitted)?;
+        written += 4 + emitted.len() as u64;
+
+        if n < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok(written)
+}
+
+/// Reads until `buf` is full or the reader reaches EOF.
+fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// ---------------------------------------------------------------------------
+// Decoder
+// ---------------------------------------------------------------------------
+
+/// rANS decoder with a slot-to-symbol lookup table.
+pub struct AnsDecoder {
+    table: FrequencyTable,
+    /// Maps each of the `PROB_SCALE` state slots to its symbol.
+    slot_symbol: Vec<u8>,
+}
+
+impl AnsDecoder {
+    /// Builds a decoder from a normalised frequency table.
+    pub fn new(table: &FrequencyTable) -> Self {
+        let mut slot_symbol = vec![0u8; PROB_SCALE as usize];
+        for b in 0..256 {
+            let start = table.cum[b] as usize;
+            let end = table.cum[b + 1] as usize;
+            slot_symbol[start..end].fill(b as u8);
+        }
+        AnsDecoder {
+            table: table.clone(),
+            slot_symbol,
+        }
+    }
+
+    /// Decodes `len` bytes from the chunked payload in `reader` into `writer`.
+    ///
+    /// Each chunk must return the state to its initial value; anything else
+    /// means the payload is corrupt.  Returns the number of payload bytes
+    /// consumed.
+    pub fn decode<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        len: u64,
+    ) -> std::io::Result<u64> {
+        let mut out = vec![0u8; CHUNK_SIZE];
+        let mut remaining = len;
+        let mut consumed = 0u64;
+        let mask = PROB_SCALE - 1;
+
+        if remaining > 0 && self.table.cum[256] == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "ANS frequency table is empty but data is expected",
+            ));
+        }
+
+        while remaining > 0 {
+            let n = remaining.min(CHUNK_SIZE as u64) as usize;
+            let produced = len - remaining;
+
+            let mut state = [0u8; 4];
+            read_payload(reader, &mut state)
+                .map_err(|e| error::locate(e, consumed * 8, produced))?;
+            let mut x = u32::from_le_bytes(state);
+            if !(RANS_L..RANS_L << 8).contains(&x) {
+                let msg = format!("Invalid ANS chunk state {x:#010x}");
+                return Err(DecodeError::new(CorruptionKind::InvalidState, msg)
+                    .at_bit(consumed * 8)
+                    .after_output(produced)
+                    .into());
+            }
+            consumed += 4;
+
+            for (i, slot_out) in out[..n].iter_mut().enumerate() {
+                let slot = x & mask;
+                let symbol = self.slot_symbol[slot as usize];
+                let freq = self.table.freq[symbol as usize];
+                let start = self.table.cum[symbol as usize];
+                x = freq * (x >> PROB_BITS) + slot - start;
+                while x < RANS_L {
+                    let mut byte = [0u8; 1];
+                    read_payload(reader, &mut byte)
+                        .map_err(|e| locate_cold(e, consumed * 8, produced + i as u64))?;
+                    x = (x << 8) | u32::from(byte[0]);
+                    consumed += 1;
+                }
+                *slot_out = symbol;
+            }
+
+            if x != RANS_L {
+                let msg = format!("ANS chunk ended in state {x:#010x}, expected {RANS_L:#010x}");
+                return Err(DecodeError::new(CorruptionKind::InvalidState, msg)
+                    .at_bit(consumed * 8)
+                    .after_output(produced + n as u64)
+                    .into());
+            }
+
+            writer.write_all(&out[..n])?;
+            remaining -= n as u64;
+        }
+
+        writer.flush()?;
+        Ok(consumed)
+    }
+}
+
+/// [`error::locate`] kept out of the decode loop.
+#[cold]
+#[inline(never)]
+fn locate_cold(err: std::io::Error, bit_offset: u64, output_bytes: u64) -> std::io::Error {
+    error::locate(err, bit_offset, output_bytes)
+}
+
+/// `read_exact` that reports a truncated payload the same way the Huffman
+/// decoder does.
+fn read_payload<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            DecodeError::new(
+                CorruptionKind::Truncated,
+                "Unexpected end of compressed bitstream",
+            )
+            .into()
+        } else {
+            e
+        }
+    })
+}
+
+// ---------------------------------------------------------------------------
+// EntropyCoder implementation
+// ---------------------------------------------------------------------------
+
+/// rANS coding as an [`EntropyCoder`].
+///
+/// The header table is the serialised [`FrequencyTable`]; the payload is the
+/// chunked rANS stream and never needs padding.
+pub struct AnsCoder {
+    table: FrequencyTable,
+}
+
+impl AnsCoder {
+    /// The normalised frequency table.
+    pub fn frequency_table(&self) -> &FrequencyTable {
+        &self.table
+    }
+}
+
+impl EntropyCoder for AnsCoder {
+    const METHOD: u8 = format::METHOD_ANS;
+    const NAME: &'static str = "ans";
+
+    fn from_frequencies(freqs: &[u64; 256]) -> std::io::Result<Self> {
+        Ok(AnsCoder {
+            table: FrequencyTable::normalize(freqs),
+        })
+    }
+
+    fn write_table(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.table.to_bytes());
+    }
+
+    fn read_table(table: &[u8]) -> std::io::Result<Self> {
+        Ok(AnsCoder {
+            table: FrequencyTable::from_bytes(table)?,
+        })
+    }
+
+    fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8> {
+        let mut writer = BufWriter::new(output);
+        encode(&self.table, input, &mut writer)?;
+        writer.flush()?;
+        Ok(0)
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<()> {
+        self.decode_counted(input, output, len).map(|_| ())
+    }
+
+    fn decode_counted<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        let mut reader = BufReader::new(input);
+        let consumed = AnsDecoder::new(&self.table).decode(&mut reader, output, len)?;
+        Ok(Some(consumed * 8))
+    }
+
+    fn estimated_payload_len(&self, freqs: &[u64; 256]) -> Option<u64> {
+        let mut bits = 0.0f64;
+        let mut total = 0u64;
+        for (&count, &freq) in freqs.iter().zip(self.table.freq.iter()) {
+            if count > 0 {
+                let p = freq as f64 / PROB_SCALE as f64;
+                bits -= count as f64 * p.log2();
+                total += count;
+            }
+        }
+        let chunks = total.div_ceil(CHUNK_SIZE as u64);
+        Some((bits / 8.0).ceil() as u64 + chunks * 4)
+    }
+
+    fn min_payload_len(&self, len: u64) -> u64 {
+        // Every chunk starts with its 32-bit final state.
+        len.div_ceil(CHUNK_SIZE as u64) * 4
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frequency::count_frequencies;
+
+    /// Helper: normalise, encode and decode `data` entirely in memory.
+    fn round_trip(data: &[u8]) -> (Vec<u8>, usize) {
+        let counts = count_frequencies(&mut &data[..]).unwrap();
+        let table = FrequencyTable::normalize(&counts);
+
+        let mut payload = Vec::new();
+        let written = encode(&table, &mut &data[..], &mut payload).unwrap();
+        assert_eq!(written, payload.len() as u64);
+
+        let decoder = AnsDecoder::new(&table);
+        let mut out = Vec::new();
+        decoder
+            .decode(&mut &payload[..], &mut out, data.len() as u64)
+            .unwrap();
+        (out, payload.len())
+    }
+
+    #[test]
+    fn normalize_sums_to_scale() {
+        let mut counts = [0u64; 256];
+        for (b, c) in counts.iter_mut().enumerate() {
+            *c = (b as u64 * 7919) % 1000 + 1;
+        }
+        let table = FrequencyTable::normalize(&counts);
+        assert_eq!(table.cum[256], PROB_SCALE);
+        assert!(table.freq.iter().all(|&f| f >= 1));
+    }
+
+    #[test]
+    fn normalize_keeps_rare_symbols() {
+        let mut counts = [0u64; 256];
+        counts[0x00] = 1_000_000_000;
+        counts[0x01] = 1;
+        let table = FrequencyTable::normalize(&counts);
+        assert_eq!(table.freq[0x01], 1);
+        assert_eq!(table.freq[0x00], PROB_SCALE - 1);
+    }
+
+    #[test]
+    fn normalize_empty() {
+        let table = FrequencyTable::normalize(&[0u64; 256]);
+        assert_eq!(table.symbol_count(), 0);
+        assert_eq!(table.to_bytes(), vec![0, 0]);
+    }
+
+    #[test]
+    fn table_bytes_round_trip() {
+        let data = b"abracadabra";
+        let counts = count_frequencies(&mut &data[..]).unwrap();
+        let table = FrequencyTable::normalize(&counts);
+        let bytes = table.to_bytes();
+        assert_eq!(bytes.len(), 2 + 5 * 3);
+        assert_eq!(FrequencyTable::from_bytes(&bytes).unwrap(), table);
+    }
+
+    #[test]
+    fn reject_table_bad_sum() {
+        let mut bytes = 1u16.to_le_bytes().to_vec();
+        bytes.extend(&[b'A', 0x00, 0x01]); // freq 256, not PROB_SCALE
+        let err = FrequencyTable::from_bytes(&bytes).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("sum"));
+    }
+
+    #[test]
+    fn reject_table_unsorted() {
+        let mut bytes = 2u16.to_le_bytes().to_vec();
+        bytes.extend(&[b'B', 0x00, 0x08]);
+        bytes.extend(&[b'A', 0x00, 0x08]);
+        let err = FrequencyTable::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("out of order"));
+    }
+
+    #[test]
+    fn round_trip_text() {
+        let data = b"The quick brown fox jumps over the lazy dog.";
+        let (out, _) = round_trip(data);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn round_trip_multiple_chunks() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 123)
+            .map(|i| (i % 251) as u8 ^ (i / 7) as u8)
+            .collect();
+        let (out, _) = round_trip(&data);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn single_symbol_costs_nothing() {
+        let data = vec![0x55u8; 10_000];
+        let (out, payload_len) = round_trip(&data);
+        assert_eq!(out, data);
+        // Only the 4-byte chunk state is stored.
+        assert_eq!(payload_len, 4);
+    }
+
+    #[test]
+    fn skewed_input_beats_one_bit_per_byte() {
+        // 99% one byte: Huffman needs at least 1 bit per byte.
+        let data: Vec<u8> = (0..100_000u32)
+            .map(|i| if i % 100 == 0 { (i / 100) as u8 } else { 0 })
+            .collect();
+        let (out, payload_len) = round_trip(&data);
+        assert_eq!(out, data);
+        assert!(payload_len < data.len() / 8);
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        let data = b"abracadabra abracadabra";
+        let counts = count_frequencies(&mut &data[..]).unwrap();
+        let table = FrequencyTable::normalize(&counts);
+        let mut payload = Vec::new();
+        encode(&table, &mut &data[..], &mut payload).unwrap();
+        payload.truncate(3);
+
+        let err = AnsDecoder::new(&table)
+            .decode(&mut &payload[..], &mut Vec::new(), data.len() as u64)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,627 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compress::{self, EncoderOptions};
+use crate::crypto::TempFile;
+use crate::decompress::{self, DecoderOptions};
+use crate::error;
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// Archives
+// ---------------------------------------------------------------------------
+//
+// `-a` packs a file or directory tree into a `.cmpra` archive.  Every regular
+// file becomes a member: a complete `.cmpr` file compressed on its own, so
+// any member can be extracted or tested without decoding the others.  A
+// central directory after the members lists them, and a fixed trailer at the
+// end of the archive points to it:
+//
+//   8        magic "CMPA", archive version 0x01, three zero bytes
+//   ...      members, back to back
+//   ...      central directory, one record per member:
+//              2   name length, little-endian u16
+//              N   name: UTF-8 relative path with `/` separators
+//              8   original size, little-endian u64
+//              4   Unix mode bits, little-endian u32
+//              8   modification time in seconds since the Unix epoch,
+//                  little-endian i64
+//              8   offset of the member, little-endian u64
+//              8   length of the member, little-endian u64
+//   20       trailer: u64 directory offset, u32 member count, u32 CRC-32
+//            of the directory, magic "CDIR"
+//
+// Symbolic links, special files and empty directories are not archived.
+
+/// Magic bytes at the start of an archive.
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"CMPA";
+
+/// Current archive version.
+pub const ARCHIVE_VERSION: u8 = 0x01;
+
+/// Size of the archive header.
+pub const ARCHIVE_HEADER_SIZE: u64 = 8;
+
+/// Size of the fixed trailer at the end of an archive.
+pub const ARCHIVE_TRAILER_SIZE: u64 = 20;
+
+/// Magic bytes at the end of the archive trailer.
+const DIRECTORY_MAGIC: [u8; 4] = *b"CDIR";
+
+/// Size of a directory record without its name.
+const RECORD_SIZE: usize = 2 + 8 + 4 + 8 + 8 + 8;
+
+/// A member of an archive, as listed in the central directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative path with `/` separators.
+    pub name: String,
+    /// Original size in bytes.
+    pub size: u64,
+    /// Unix mode bits (permissions).
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Offset of the member's `.cmpr` file in the archive.
+    pub offset: u64,
+    /// Length of the member's `.cmpr` file.
+    pub len: u64,
+}
+
+/// Whether `path` starts with the archive magic.
+pub fn is_archive(path: &Path) -> std::io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == ARCHIVE_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Archives `input_path`, a file or a directory tree, to `output_path`,
+/// compressing each file with `options`.  Member names start with the last
+/// component of `input_path`.  Returns the directory that was written.
+///
+/// The archive is written to a temporary file next to `output_path` and
+/// renamed over it once complete.
+pub fn create(
+    input_path: &Path,
+    output_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<Vec<Entry>> {
+    let root = match input_path.file_name() {
+        Some(name) => name.to_owned(),
+        None => input_path
+            .canonicalize()?
+            .file_name()
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Cannot archive `{}`", input_path.display()),
+                )
+            })?,
+    };
+    let skip = output_path.canonicalize().ok();
+    let mut files = Vec::new();
+    collect(input_path, utf8_name(root)?, skip.as_deref(), &mut files)?;
+
+    decompress::write_atomically(output_path, |temp_path| {
+        let mut output = BufWriter::new(File::create(temp_path)?);
+        output.write_all(&ARCHIVE_MAGIC)?;
+        output.write_all(&[ARCHIVE_VERSION, 0, 0, 0])?;
+        let mut offset = ARCHIVE_HEADER_SIZE;
+        let mut entries = Vec::with_capacity(files.len());
+        for (path, name) in files {
+            let metadata = fs::metadata(&path)?;
+            let (member, _) = TempFile::create()?;
+            let (size, len) = compress::compress_with_options(&path, member.path(), options)?;
+            std::io::copy(&mut File::open(member.path())?, &mut output)?;
+            entries.push(Entry {
+                name,
+                size,
+                mode: mode_of(&metadata),
+                mtime: mtime_of(&metadata),
+                offset,
+                len,
+            });
+            offset += len;
+        }
+        write_directory(&mut output, &entries, offset)?;
+        output.flush()?;
+        Ok(entries)
+    })
+}
+
+/// Appends the regular files under `path`, named from `name`, to `files`
+/// in name order, leaving out `skip`.
+fn collect(
+    path: &Path,
+    name: String,
+    skip: Option<&Path>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut children = fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let child_name = format!("{name}/{}", utf8_name(child.file_name())?);
+            collect(&child.path(), child_name, skip, files)?;
+        }
+    } else if metadata.is_file() && skip != path.canonicalize().ok().as_deref() {
+        files.push((path.to_path_buf(), name));
+    }
+    Ok(())
+}
+
+fn utf8_name(name: std::ffi::OsString) -> std::io::Result<String> {
+    name.into_string().map_err(|name| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("File name {name:?} is not valid UTF-8"),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+fn mtime_of(metadata: &fs::Metadata) -> i64 {
+    match metadata.modified() {
+        Ok(time) => match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Writes the central directory of `entries`, which starts at
+/// `directory_offset`, and the archive trailer.
+fn write_directory<W: Write>(
+    writer: &mut W,
+    entries: &[Entry],
+    directory_offset: u64,
+) -> std::io::Result<()> {
+    let count = u32::try_from(entries.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Too many files for one archive",
+        )
+    })?;
+    let mut directory = Vec::new();
+    for entry in entries {
+        let name_len = u16::try_from(entry.name.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("File name `{}` is too long", entry.name),
+            )
+        })?;
+        directory.extend_from_slice(&name_len.to_le_bytes());
+        directory.extend_from_slice(entry.name.as_bytes());
+        directory.extend_from_slice(&entry.size.to_le_bytes());
+        directory.extend_from_slice(&entry.mode.to_le_bytes());
+        directory.extend_from_slice(&entry.mtime.to_le_bytes());
+        directory.extend_from_slice(&entry.offset.to_le_bytes());
+        directory.extend_from_slice(&entry.len.to_le_bytes());
+    }
+    writer.write_all(&directory)?;
+    writer.write_all(&directory_offset.to_le_bytes())?;
+    writer.write_all(&count.to_le_bytes())?;
+    writer.write_all(&format::crc32(&directory).to_le_bytes())?;
+    writer.write_all(&DIRECTORY_MAGIC)
+}
+
+/// Reads the central directory of an archive.
+///
+/// Returns an `InvalidData` error if the archive header or trailer is
+/// damaged, the directory fails its CRC-32, a member lies outside the
+/// archive, or a name is not a safe relative path (see [`member_path`]).
+pub fn read_directory<R: Read + Seek>(reader: &mut R) -> std::io::Result<Vec<Entry>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < ARCHIVE_HEADER_SIZE + ARCHIVE_TRAILER_SIZE {
+        return Err(invalid("File too short for an archive"));
+    }
+    let mut header = [0u8; ARCHIVE_HEADER_SIZE as usize];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    if header[..4] != ARCHIVE_MAGIC {
+        return Err(invalid("Not a .cmpra archive"));
+    }
+    if header[4] != ARCHIVE_VERSION {
+        return Err(invalid(&format!(
+            "Unsupported archive version {:#04x}",
+            header[4]
+        )));
+    }
+
+    let trailer_pos = file_len - ARCHIVE_TRAILER_SIZE;
+    let mut trailer = [0u8; ARCHIVE_TRAILER_SIZE as usize];
+    reader.seek(SeekFrom::Start(trailer_pos))?;
+    reader.read_exact(&mut trailer)?;
+    if trailer[16..] != DIRECTORY_MAGIC {
+        return Err(invalid("Archive does not end in a central directory"));
+    }
+    let directory_offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let count = u32::from_le_bytes(trailer[8..12].try_into().unwrap()) as usize;
+    let stored_crc = u32::from_le_bytes(trailer[12..16].try_into().unwrap());
+    if !(ARCHIVE_HEADER_SIZE..=trailer_pos).contains(&directory_offset) {
+        return Err(invalid("Archive directory offset is out of range"));
+    }
+
+    let mut directory = vec![0u8; (trailer_pos - directory_offset) as usize];
+    reader.seek(SeekFrom::Start(directory_offset))?;
+    reader.read_exact(&mut directory)?;
+    if format::crc32(&directory) != stored_crc {
+        return Err(invalid("Archive directory CRC-32 mismatch"));
+    }
+
+    let mut entries = Vec::with_capacity(count.min(directory.len() / RECORD_SIZE));
+    let mut rest = &directory[..];
+    for _ in 0..count {
+        let name_len = match rest {
+            [a, b, ..] => u16::from_le_bytes([*a, *b]) as usize,
+            _ => return Err(invalid("Archive directory is shorter than its count")),
+        };
+        if rest.len() < RECORD_SIZE + name_len {
+            return Err(invalid("Archive directory is shorter than its count"));
+        }
+        let (record, tail) = rest.split_at(RECORD_SIZE + name_len);
+        rest = tail;
+        let name = std::str::from_utf8(&record[2..2 + name_len])
+            .map_err(|_| invalid("Archive member name is not valid UTF-8"))?;
+        member_path(Path::new(""), name)?;
+        let fields = &record[2 + name_len..];
+        let entry = Entry {
+            name: name.to_string(),
+            size: u64::from_le_bytes(fields[..8].try_into().unwrap()),
+            mode: u32::from_le_bytes(fields[8..12].try_into().unwrap()),
+            mtime: i64::from_le_bytes(fields[12..20].try_into().unwrap()),
+            offset: u64::from_le_bytes(fields[20..28].try_into().unwrap()),
+            len: u64::from_le_bytes(fields[28..36].try_into().unwrap()),
+        };
+        let end = entry.offset.checked_add(entry.len);
+        if entry.offset < ARCHIVE_HEADER_SIZE || end.is_none_or(|end| end > directory_offset) {
+            return Err(invalid(&format!(
+                "Archive member `{}` lies outside the archive",
+                entry.name
+            )));
+        }
+        entries.push(entry);
+    }
+    if !rest.is_empty() {
+        return Err(invalid("Archive directory is longer than its count"));
+    }
+    Ok(entries)
+}
+
+/// Where member `name` is extracted under `dest`.
+///
+/// Returns an `InvalidData` error unless `name` is a relative path of
+/// ordinary components, so that no member can be written outside `dest`.
+pub fn member_path(dest: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let mut path = dest.to_path_buf();
+    for part in name.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(component)), None) if component == part => path.push(part),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unsafe archive member name `{name}`"),
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Copies `entry` out of `archive_path` into a temporary `.cmpr` file and
+/// runs `f` on its path.  No other member is read.
+fn with_member<T>(
+    archive_path: &Path,
+    entry: &Entry,
+    f: impl FnOnce(&Path) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let member = TempFile::copy_of(archive_path, entry.offset, entry.len).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            std::io::Error::new(
+                e.kind(),
+                format!("Archive member `{}` is truncated", entry.name),
+            )
+        } else {
+            e
+        }
+    })?;
+    f(member.path())
+}
+
+/// Decompresses `entry` of `archive_path` to `output_path` and restores its
+/// modification time and mode bits.  Decoding and verification work as in
+/// [`decompress_with_options`](decompress::decompress_with_options).
+pub fn extract_member(
+    archive_path: &Path,
+    entry: &Entry,
+    output_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    with_member(archive_path, entry, |member| {
+        decompress::decompress_with_options(member, output_path, options)
+    })?;
+    restore_metadata(output_path, entry)
+}
+
+/// Decompresses `entry` of `archive_path` to stdout.
+pub fn extract_member_to_stdout(
+    archive_path: &Path,
+    entry: &Entry,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    with_member(archive_path, entry, |member| {
+        decompress::decompress_to_stdout_with_options(member, options)
+    })
+}
+
+/// Checks `entry` of `archive_path` without writing anything, as
+/// [`decompress::verify`] does for a `.cmpr` file.
+pub fn verify_member(
+    archive_path: &Path,
+    entry: &Entry,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    with_member(archive_path, entry, |member| {
+        decompress::verify(member, options)
+    })
+}
+
+/// Extracts the members of `archive_path` named in `members`, or all of them
+/// if it is empty, under `dest`, creating directories as needed.  Calls
+/// `on_existing` with each output path that already exists before
+/// overwriting it.  Returns the extracted entries.
+///
+/// Errors from a member name it; a [`DecodeError`](error::DecodeError)
+/// inside keeps its kind and location.
+pub fn extract(
+    archive_path: &Path,
+    dest: &Path,
+    members: &[String],
+    options: &DecoderOptions,
+    mut on_existing: impl FnMut(&Path),
+) -> std::io::Result<Vec<Entry>> {
+    let entries = select(archive_path, members)?;
+    for entry in &entries {
+        member_path(dest, &entry.name)
+            .and_then(|path| {
+                if path.exists() {
+                    on_existing(&path);
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                extract_member(archive_path, entry, &path, options)
+            })
+            .map_err(|e| error::in_member(e, &entry.name))?;
+    }
+    Ok(entries)
+}
+
+/// Decompresses the members of `archive_path` named in `members`, or all of
+/// them if it is empty, to stdout one after another.  Returns their entries.
+pub fn extract_to_stdout(
+    archive_path: &Path,
+    members: &[String],
+    options: &DecoderOptions,
+) -> std::io::Result<Vec<Entry>> {
+    let entries = select(archive_path, members)?;
+    for entry in &entries {
+        extract_member_to_stdout(archive_path, entry, options)
+            .map_err(|e| error::in_member(e, &entry.name))?;
+    }
+    Ok(entries)
+}
+
+/// The entries of `archive_path` named in `members`, in that order, or all
+/// of them if it is empty.
+fn select(archive_path: &Path, members: &[String]) -> std::io::Result<Vec<Entry>> {
+    let entries = read_directory(&mut File::open(archive_path)?)?;
+    if members.is_empty() {
+        return Ok(entries);
+    }
+    members
+        .iter()
+        .map(|name| {
+            entries
+                .iter()
+                .find(|entry| &entry.name == name)
+                .cloned()
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("No member `{name}` in `{}`", archive_path.display()),
+                    )
+                })
+        })
+        .collect()
+}
+
+fn restore_metadata(path: &Path, entry: &Entry) -> std::io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified(entry))?;
+    #[cfg(unix)]
+    fs::set_permissions(
+        path,
+        std::os::unix::fs::PermissionsExt::from_mode(entry.mode & 0o7777),
+    )?;
+    Ok(())
+}
+
+fn modified(entry: &Entry) -> SystemTime {
+    let seconds = Duration::from_secs(entry.mtime.unsigned_abs());
+    if entry.mtime >= 0 {
+        UNIX_EPOCH + seconds
+    } else {
+        UNIX_EPOCH - seconds
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::io::Cursor;
+
+    /// Writes a small tree under `dir/tree` and returns its files.
+    fn write_tree(dir: &Path) -> Vec<(&'static str, Vec<u8>)> {
+        let files = vec![
+            ("tree/a.txt", b"hello, archive\n".repeat(40)),
+            ("tree/empty", Vec::new()),
+            (
+                "tree/sub/b.bin",
+                (0..5000u32).map(|i| (i * 7 % 251) as u8).collect(),
+            ),
+        ];
+        for (name, data) in &files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, data).unwrap();
+        }
+        files
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let dir = TempDir::new("archive");
+        let files = write_tree(&dir);
+        let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(dir.join("tree/a.txt"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(
+            dir.join("tree/a.txt"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .unwrap();
+
+        let archive = dir.join("tree.cmpra");
+        let written = create(&dir.join("tree"), &archive, &EncoderOptions::default()).unwrap();
+        assert!(is_archive(&archive).unwrap());
+        let entries = read_directory(&mut File::open(&archive).unwrap()).unwrap();
+        assert_eq!(entries, written);
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["tree/a.txt", "tree/empty", "tree/sub/b.bin"]);
+        assert_eq!(entries[0].mtime, 1_000_000_000);
+
+        let out = dir.join("out");
+        extract(&archive, &out, &[], &DecoderOptions::default(), |_| {}).unwrap();
+        for (name, data) in &files {
+            assert_eq!(&fs::read(out.join(name)).unwrap(), data, "{name}");
+        }
+        let metadata = fs::metadata(out.join("tree/a.txt")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), old);
+        #[cfg(unix)]
+        assert_eq!(mode_of(&metadata), 0o600);
+    }
+
+    #[test]
+    fn member_extracts_without_the_others() {
+        let dir = TempDir::new("archive");
+        let files = write_tree(&dir);
+        let archive = dir.join("tree.cmpra");
+        let entries = create(&dir.join("tree"), &archive, &EncoderOptions::default()).unwrap();
+
+        // Wreck the first member: the last one still extracts.
+        let mut bytes = fs::read(&archive).unwrap();
+        let first = &entries[0];
+        bytes[first.offset as usize..(first.offset + first.len) as usize].fill(0);
+        fs::write(&archive, &bytes).unwrap();
+        assert!(verify_member(&archive, first, &DecoderOptions::default()).is_err());
+
+        let out = dir.join("b.bin");
+        extract_member(&archive, &entries[2], &out, &DecoderOptions::default()).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), files[2].1);
+
+        // Selecting members by name skips the wrecked one; naming it fails
+        // with the member in the message.
+        let out = dir.join("out");
+        let names = [entries[2].name.clone()];
+        let extracted =
+            extract(&archive, &out, &names, &DecoderOptions::default(), |_| {}).unwrap();
+        assert_eq!(extracted, [entries[2].clone()]);
+        assert!(!out.join(&first.name).exists());
+        let names = [first.name.clone()];
+        let err = extract(&archive, &out, &names, &DecoderOptions::default(), |_| {}).unwrap_err();
+        assert!(err.to_string().starts_with(&first.name), "{err}");
+        let names = ["missing".to_string()];
+        let err = extract(&archive, &out, &names, &DecoderOptions::default(), |_| {}).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn unsafe_names_are_refused() {
+        for name in ["../evil", "/etc/passwd", "a//b", "a/./b", "", "a/.."] {
+            assert!(member_path(Path::new("out"), name).is_err(), "{name}");
+
+            let entry = Entry {
+                name: name.to_string(),
+                size: 0,
+                mode: 0o644,
+                mtime: 0,
+                offset: ARCHIVE_HEADER_SIZE,
+                len: 0,
+            };
+            let mut bytes = ARCHIVE_MAGIC.to_vec();
+            bytes.extend_from_slice(&[ARCHIVE_VERSION, 0, 0, 0]);
+            write_directory(&mut bytes, &[entry], ARCHIVE_HEADER_SIZE).unwrap();
+            let err = read_directory(&mut Cursor::new(&bytes)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        assert_eq!(
+            member_path(Path::new("out"), "a/b.txt").unwrap(),
+            Path::new("out").join("a").join("b.txt")
+        );
+    }
+
+    #[test]
+    fn damaged_directory_is_rejected() {
+        let dir = TempDir::new("archive");
+        write_tree(&dir);
+        let archive = dir.join("tree.cmpra");
+        let entries = create(&dir.join("tree"), &archive, &EncoderOptions::default()).unwrap();
+        let bytes = fs::read(&archive).unwrap();
+        let directory_offset = entries.last().map(|e| e.offset + e.len).unwrap() as usize;
+
+        let mut damaged = bytes.clone();
+        damaged[directory_offset + 3] ^= 1;
+        let err = read_directory(&mut Cursor::new(&damaged)).unwrap_err();
+        assert!(err.to_string().contains("CRC-32"));
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(read_directory(&mut Cursor::new(truncated)).is_err());
+    }
+}
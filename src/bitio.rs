@@ -1,18 +1,21 @@
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 
 // ---------------------------------------------------------------------------
-// BitWriter -- accumulate bits, flush entire bytes to the underlying writer
+// BitWriter -- accumulate bits in a u64, flush whole words to the writer
 // ---------------------------------------------------------------------------
 
 /// Wraps a `BufWriter<W>` and provides bit-level write operations.
 ///
-/// Bits are written MSB-first within each byte.  Call [`flush`](BitWriter::flush)
-/// after the final write to flush any remaining partial byte (padded with zeros)
-/// and obtain the padding count for the file header.
+/// Bits are written MSB-first within each byte.  They are accumulated in a
+/// 64-bit word and emitted eight bytes at a time, so a write costs a shift
+/// and an OR regardless of alignment.  Call [`flush`](BitWriter::flush)
+/// after the final write to flush any remaining partial word (padded with
+/// zeros) and obtain the padding count for the file header.
 pub struct BitWriter<W: Write> {
     writer: BufWriter<W>,
-    buffer: u8,          // Partial byte being accumulated.
-    bits_in_buffer: u8,  // 0..8 bits currently held in `buffer`.
+    acc: u64,   // Pending bits, left-aligned (the next bit out is bit 63).
+    bits: u8,   // 0..64 bits currently held in `acc`.
+    words: u64, // Whole words emitted so far.
 }
 
 impl<W: Write> BitWriter<W> {
@@ -20,75 +23,68 @@ impl<W: Write> BitWriter<W> {
     pub fn new(inner: W) -> Self {
         BitWriter {
             writer: BufWriter::new(inner),
-            buffer: 0,
-            bits_in_buffer: 0,
+            acc: 0,
+            bits: 0,
+            words: 0,
         }
     }
 
+    /// Number of bits written since the writer was created.
+    pub fn bit_position(&self) -> u64 {
+        self.words * 64 + u64::from(self.bits)
+    }
+
     /// Writes a single bit (true = 1, false = 0).
     pub fn write_bit(&mut self, bit: bool) -> std::io::Result<()> {
-        if bit {
-            self.buffer |= 1 << (7 - self.bits_in_buffer);
-        }
-        self.bits_in_buffer += 1;
-
-        if self.bits_in_buffer == 8 {
-            self.writer.write_all(&[self.buffer])?;
-            self.buffer = 0;
-            self.bits_in_buffer = 0;
-        }
-
-        Ok(())
+        self.write_bits(u64::from(bit), 1)
     }
 
-    /// Writes `n_bits` from `value`, MSB first.
+    /// Writes `n_bits` (at most 64) from `value`, MSB first.
     ///
     /// Only the bottom `n_bits` of `value` are meaningful; higher bits are ignored.
-    ///
-    /// When the internal buffer is aligned and `n_bits >= 8`, full bytes are
-    /// written directly to the underlying writer without per-bit branching.
-    pub fn write_bits(&mut self, value: u64, mut n_bits: u8) -> std::io::Result<()> {
-        // Fast path: aligned full-byte writes
-        if n_bits >= 8 && self.bits_in_buffer == 0 {
-            while n_bits >= 8 {
-                let shift = n_bits - 8;
-                let byte = (value >> shift) as u8;
-                self.writer.write_all(&[byte])?;
-                n_bits -= 8;
-            }
-            if n_bits > 0 {
-                // Remaining bits (< 8) go into the buffer, positioned at the
-                // top of the byte (MSB-first).
-                self.buffer = (value as u8) << (8 - n_bits);
-                self.bits_in_buffer = n_bits;
-            }
+    #[inline(always)]
+    pub fn write_bits(&mut self, value: u64, n_bits: u8) -> std::io::Result<()> {
+        debug_assert!(n_bits <= 64);
+        if n_bits == 0 {
             return Ok(());
         }
+        let value = if n_bits == 64 {
+            value
+        } else {
+            value & ((1u64 << n_bits) - 1)
+        };
 
-        // Slow path: mixed alignment or small writes
-        for i in (0..n_bits).rev() {
-            let bit = ((value >> i) & 1) != 0;
-            self.write_bit(bit)?;
+        let free = 64 - self.bits;
+        if n_bits < free {
+            self.acc |= value << (free - n_bits);
+            self.bits += n_bits;
+            return Ok(());
         }
+
+        // Top up the accumulator, emit it as a whole word, and keep the
+        // bits that did not fit.
+        let rest = n_bits - free;
+        self.acc |= value >> rest;
+        self.writer.write_all(&self.acc.to_be_bytes())?;
+        self.words += 1;
+        self.acc = if rest == 0 { 0 } else { value << (64 - rest) };
+        self.bits = rest;
         Ok(())
     }
 
-    /// Flushes any remaining partial byte (padded with zero bits) and flushes
+    /// Flushes any remaining partial word (padded with zero bits) and flushes
     /// the underlying `BufWriter`.
     ///
     /// Returns the number of padding bits added (0..7).  This value must be
     /// stored in the `.cmpr` header so the decompressor can ignore trailing
     /// padding.
     pub fn flush(&mut self) -> std::io::Result<u8> {
-        let padding = if self.bits_in_buffer > 0 {
-            let pad = 8 - self.bits_in_buffer;
-            self.writer.write_all(&[self.buffer])?;
-            self.buffer = 0;
-            self.bits_in_buffer = 0;
-            pad
-        } else {
-            0
-        };
+        let bytes = self.bits.div_ceil(8);
+        let padding = bytes * 8 - self.bits;
+        self.writer
+            .write_all(&self.acc.to_be_bytes()[..bytes as usize])?;
+        self.acc = 0;
+        self.bits = 0;
 
         self.writer.flush()?;
         Ok(padding)
@@ -105,171 +101,171 @@ impl<W: Write> BitWriter<W> {
 }
 
 // ---------------------------------------------------------------------------
-// BitReader -- read bits from an underlying buffered reader
+// BitReader -- read bits from a buffered byte source, 8 bytes at a time
 // ---------------------------------------------------------------------------
 
-/// Wraps a `BufReader<R>` and provides bit-level read operations.
+/// Most bits [`BitReader::peek_bits`] can return in one call.  A refill
+/// always leaves at least this many bits buffered unless the input ends.
+pub const MAX_PEEK_BITS: u8 = 57;
+
+/// Provides bit-level read operations over a `BufRead` source.
 ///
 /// Bits are read MSB-first from each byte, matching the write order of
 /// `BitWriter`.
 ///
-/// The internal buffer holds up to 64 bits, enabling efficient peek-ahead
-/// without consuming from the underlying reader.
-pub struct BitReader<R: Read> {
-    reader: BufReader<R>,
-    buffer: u64,           // Accumulated bits, left-aligned.
-    bits_remaining: u8,    // 0..64 unconsumed bits in `buffer`.
+/// Bits are held in a 64-bit word that is refilled eight bytes at a time
+/// straight from the source's buffer, so [`peek_bits`](Self::peek_bits) and
+/// [`consume_bits`](Self::consume_bits) are a shift each in the common case.
+/// Use [`new`](BitReader::new) for any reader (buffered internally) or
+/// [`from_slice`](BitReader::from_slice) to decode in-memory data without
+/// copying it.
+pub struct BitReader<R: BufRead> {
+    source: R,
+    buffer: u64,  // Unconsumed bits, left-aligned (the next bit is bit 63).
+    bits: u8,     // 0..64 valid bits in `buffer`.
+    loaded: u64,  // Bytes pulled from `source` so far.
 }
 
-impl<R: Read> BitReader<R> {
-    /// Creates a new `BitReader` wrapping the given reader.
+impl<R: Read> BitReader<BufReader<R>> {
+    /// Creates a new `BitReader` wrapping the given reader in a `BufReader`.
     pub fn new(inner: R) -> Self {
+        Self::from_buf_read(BufReader::new(inner))
+    }
+}
+
+impl<'a> BitReader<&'a [u8]> {
+    /// Creates a `BitReader` that reads directly from `data`.
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::from_buf_read(data)
+    }
+}
+
+impl<R: BufRead> BitReader<R> {
+    /// Creates a `BitReader` over an already-buffered source.
+    pub fn from_buf_read(source: R) -> Self {
         BitReader {
-            reader: BufReader::new(inner),
+            source,
             buffer: 0,
-            bits_remaining: 0,
+            bits: 0,
+            loaded: 0,
         }
     }
 
-    /// Fills the internal buffer with at least `n` bits by reading from the
-    /// underlying reader.
-    ///
-    /// Returns `true` if enough bits were buffered, `false` on EOF before
-    /// filling the request.
-    fn fill_bits(&mut self, n: u8) -> std::io::Result<bool> {
-        while self.bits_remaining < n {
-            let mut byte = [0u8; 1];
-            match self.reader.read(&mut byte)? {
-                0 => return Ok(self.bits_remaining >= n),
-                _ => {
-                    self.buffer = (self.buffer << 8) | u64::from(byte[0]);
-                    self.bits_remaining += 8;
-                }
-            }
+    /// Number of bits consumed so far.
+    pub fn bit_position(&self) -> u64 {
+        self.loaded * 8 - self.bits as u64
+    }
+
+    /// Tops the bit buffer up to at least [`MAX_PEEK_BITS`] bits, or as many
+    /// as the source has left.
+    #[inline(always)]
+    fn refill(&mut self) -> std::io::Result<()> {
+        let chunk = self.source.fill_buf()?;
+        if chunk.len() >= 8 {
+            // Fast path: load a whole word and keep as many bytes as fit.
+            let word = u64::from_be_bytes(chunk[..8].try_into().unwrap());
+            let take = (63 - self.bits) >> 3;
+            self.buffer |= word >> self.bits;
+            self.bits += take * 8;
+            // Clear the partial byte shifted in beyond the bits taken.
+            self.buffer &= !(u64::MAX >> self.bits);
+            self.source.consume(take as usize);
+            self.loaded += take as u64;
+            return Ok(());
+        }
+
+        // Slow path: near the end of the source's buffer, one byte at a time.
+        while self.bits <= 56 {
+            let chunk = self.source.fill_buf()?;
+            let Some(&byte) = chunk.first() else {
+                break;
+            };
+            self.buffer |= u64::from(byte) << (56 - self.bits);
+            self.bits += 8;
+            self.source.consume(1);
+            self.loaded += 1;
         }
-        Ok(true)
+        Ok(())
     }
 
-    /// Reads a single bit.  Returns `Ok(Some(true))` for 1, `Ok(Some(false))` for 0,
-    /// or `Ok(None)` when the end of the input is reached.
+    /// Reads a single bit.  Returns `None` at EOF.
     pub fn read_bit(&mut self) -> std::io::Result<Option<bool>> {
-        if !self.fill_bits(1)? {
-            return Ok(None);
-        }
-        let bit = (self.buffer >> (self.bits_remaining - 1)) & 1;
-        self.bits_remaining -= 1;
-        Ok(Some(bit != 0))
+        Ok(self.read_bits(1)?.map(|bit| bit != 0))
     }
 
-    /// Reads `n` bits and returns them as a right-aligned `u64`, or `None` at EOF.
-    ///
-    /// Bits are read MSB-first and packed into the low bits of the result
-    /// (the first bit read becomes the MSB of the returned value).
-    #[allow(dead_code)]
+    /// Reads `n` bits (at most 64) as a right-aligned `u64`.  Returns `None`
+    /// if fewer than `n` bits remain, without consuming any.
     pub fn read_bits(&mut self, n: u8) -> std::io::Result<Option<u64>> {
-        if n == 0 {
-            return Ok(Some(0));
+        debug_assert!(n <= 64);
+        if n > MAX_PEEK_BITS {
+            // Split wide reads; only the low half can hit EOF after the
+            // high half succeeded.
+            let low = n - 32;
+            let Some(high) = self.read_bits(32)? else {
+                return Ok(None);
+            };
+            return match self.read_bits(low)? {
+                Some(value) => Ok(Some(high << low | value)),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Unexpected EOF during multi-byte read",
+                )),
+            };
         }
 
-        // Fast path: aligned full-byte reads from the underlying reader.
-        if n >= 8 && self.bits_remaining == 0 {
-            let mut value = 0u64;
-            let mut remaining = n;
-            while remaining >= 8 {
-                let mut byte = [0u8; 1];
-                match self.reader.read(&mut byte)? {
-                    0 if remaining == n => return Ok(None),
-                    0 => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::UnexpectedEof,
-                            "Unexpected EOF during multi-byte read",
-                        ));
-                    }
-                    _ => {
-                        value = (value << 8) | u64::from(byte[0]);
-                        remaining -= 8;
-                    }
-                }
-            }
-            if remaining > 0 {
-                // Read the partial byte for remaining bits
-                let mut byte = [0u8; 1];
-                match self.reader.read(&mut byte)? {
-                    0 => {} // no more data, return what we have
-                    _ => {
-                        self.buffer = u64::from(byte[0]);
-                        self.bits_remaining = 8;
-                        value = (value << remaining) | (self.buffer >> (8 - remaining));
-                        self.bits_remaining -= remaining;
-                    }
-                }
+        if self.bits < n {
+            self.refill()?;
+            if self.bits < n {
+                return Ok(None);
             }
-            return Ok(Some(value));
         }
-
-        // General path: use the buffer.
-        if !self.fill_bits(n)? {
-            return Ok(None);
-        }
-        // Mask to the unconsumed window, then extract the top n bits.
-        let mask = if self.bits_remaining >= 64 {
-            !0u64
-        } else {
-            (1u64 << self.bits_remaining) - 1
-        };
-        let window = self.buffer & mask;
-        let shift = self.bits_remaining - n;
-        let value = window >> shift;
-        self.bits_remaining -= n;
+        let value = self.peek_buffered(n);
+        self.consume_bits(n);
         Ok(Some(value))
     }
 
-    /// Peeks up to `n` bits from the bit stream without consuming them.
-    ///
-    /// Returns the peeked bits left-aligned within `n` bits (i.e., the first
-    /// bit read occupies the most significant position of the returned value,
-    /// and any unavailable trailing bits are zero).  Returns `None` only if
-    /// no bits at all are available.
-    ///
-    /// The stream position is unchanged; call
-    /// [`consume_bits`](BitReader::consume_bits) to advance past the peeked
-    /// bits.
+    /// Returns the next `n` bits (at most [`MAX_PEEK_BITS`]) without
+    /// consuming them, zero-padded if the input ends first.  Returns `None`
+    /// only if no bits at all remain.
+    #[inline(always)]
     pub fn peek_bits(&mut self, n: u8) -> std::io::Result<Option<u64>> {
-        if n == 0 {
-            return Ok(Some(0));
-        }
-        // Fill what we can; if nothing at all, return None.
-        self.fill_bits(n)?;
-        if self.bits_remaining == 0 {
-            return Ok(None);
+        debug_assert!(n <= MAX_PEEK_BITS);
+        if self.bits < n {
+            self.refill()?;
+            if self.bits == 0 && n > 0 {
+                return Ok(None);
+            }
         }
-        let mask = if self.bits_remaining >= 64 {
-            !0u64
+        Ok(Some(self.peek_buffered(n)))
+    }
+
+    /// The top `n` buffered bits; bits past `self.bits` read as zero.
+    #[inline(always)]
+    fn peek_buffered(&self, n: u8) -> u64 {
+        if n == 0 {
+            0
         } else {
-            (1u64 << self.bits_remaining) - 1
-        };
-        let window = self.buffer & mask;
-        let take = self.bits_remaining.min(n);
-        let shift = self.bits_remaining - take;
-        let value = window >> shift;
-        // Left-align the value within n bits.
-        Ok(Some(value << (n - take)))
+            self.buffer >> (64 - n)
+        }
     }
 
-    /// Consumes `n` bits from the buffer after a prior [`peek_bits`](BitReader::peek_bits)
-    /// or other read operation.
+    /// Discards `n` bits previously returned by [`peek_bits`](Self::peek_bits).
     ///
-    /// Silently caps at the number of remaining bits.
+    /// Consuming more bits than are buffered discards only the buffered ones.
+    #[inline(always)]
     pub fn consume_bits(&mut self, n: u8) {
-        self.bits_remaining = self.bits_remaining.saturating_sub(n);
+        let n = n.min(self.bits);
+        self.buffer = if n == 64 { 0 } else { self.buffer << n };
+        self.bits -= n;
     }
 
-    /// Consumes the reader and returns the inner `BufReader<R>`.
+    /// Consumes the reader and returns the underlying source.
     ///
-    /// Any remaining buffered bits are discarded.
+    /// **Important**: up to eight bytes already pulled into the bit buffer
+    /// are not returned to the source.
     #[allow(dead_code)]
-    pub fn into_inner(self) -> BufReader<R> {
-        self.reader
+    pub fn into_inner(self) -> R {
+        self.source
     }
 }
 
@@ -382,6 +378,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn write_bits_batched_plus_remainder() {
         let mut buf = Vec::new();
         {
@@ -425,6 +422,57 @@ mod tests {
         assert_eq!(buf, vec![0xB5, 0x79, 0xA0]);
     }
 
+    #[test]
+    fn write_bits_across_word_boundary() {
+        let mut buf = Vec::new();
+        let padding = {
+            let mut bw = BitWriter::new(&mut buf);
+            bw.write_bits(0x1FFF_FFFF_FFFF_FFFF, 61).unwrap(); // 61 ones
+            bw.write_bits(0b0101_0110, 8).unwrap(); // straddles bit 64
+            bw.flush().unwrap()
+        };
+        assert_eq!(padding, 3);
+        assert_eq!(buf.len(), 9);
+        assert_eq!(&buf[..7], &[0xFF; 7]);
+        assert_eq!(buf[7], 0b1111_1010);
+        assert_eq!(buf[8], 0b1011_0000);
+    }
+
+    #[test]
+    fn write_bits_full_word_and_high_bits_ignored() {
+        let mut buf = Vec::new();
+        {
+            let mut bw = BitWriter::new(&mut buf);
+            bw.write_bits(0xFFFF_FFFF_FFFF_FF0F, 4).unwrap(); // only 0xF
+            bw.write_bits(0x0123_4567_89AB_CDEF, 64).unwrap();
+            bw.write_bits(0, 4).unwrap();
+            bw.flush().unwrap();
+        }
+        assert_eq!(
+            buf,
+            vec![0xF0, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]
+        );
+    }
+
+    #[test]
+    fn write_many_unaligned_codes_round_trip() {
+        let codes: Vec<(u64, u8)> = (0..1000u64)
+            .map(|i| (i * 2654435761, (i % 32 + 1) as u8))
+            .collect();
+        let mut buf = Vec::new();
+        {
+            let mut bw = BitWriter::new(&mut buf);
+            for &(v, n) in &codes {
+                bw.write_bits(v, n).unwrap();
+            }
+            bw.flush().unwrap();
+        }
+        let mut br = BitReader::new(Cursor::new(buf));
+        for &(v, n) in &codes {
+            assert_eq!(br.read_bits(n).unwrap(), Some(v & ((1 << n) - 1)));
+        }
+    }
+
     // ---- BitReader tests ----
 
     #[test]
@@ -595,4 +643,66 @@ mod tests {
         assert_eq!(br.read_bits(24).unwrap(), Some(0x123456));
         assert_eq!(br.read_bits(8).unwrap(), Some(0x78));
     }
+
+    // ---- BitReader refill tests ----
+
+    #[test]
+    fn from_slice_reads_in_place() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x0F];
+        let mut br = BitReader::from_slice(&data);
+        assert_eq!(br.read_bits(4).unwrap(), Some(0x1));
+        assert_eq!(br.read_bits(64).unwrap(), Some(0x2345_6789_ABCD_EF00));
+        assert_eq!(br.read_bits(4).unwrap(), Some(0xF));
+        assert_eq!(br.read_bit().unwrap(), None);
+        assert!(br.into_inner().is_empty());
+    }
+
+    #[test]
+    fn peek_max_bits() {
+        let data = [0xFF; 16];
+        let mut br = BitReader::from_slice(&data);
+        assert_eq!(br.read_bits(3).unwrap(), Some(0b111));
+        assert_eq!(
+            br.peek_bits(MAX_PEEK_BITS).unwrap(),
+            Some((1 << MAX_PEEK_BITS) - 1)
+        );
+    }
+
+    #[test]
+    fn refill_across_source_buffer_boundaries() {
+        // A 3-byte BufReader forces the byte-at-a-time refill path.
+        let data: Vec<u8> = (0..=255).collect();
+        let source = BufReader::with_capacity(3, Cursor::new(data.clone()));
+        let mut br = BitReader::from_buf_read(source);
+        for &expected in &data {
+            assert_eq!(
+                br.peek_bits(12).unwrap().map(|v| v >> 4),
+                Some(u64::from(expected))
+            );
+            assert_eq!(br.read_bits(8).unwrap(), Some(u64::from(expected)));
+        }
+        assert_eq!(br.peek_bits(8).unwrap(), None);
+    }
+
+    #[test]
+    fn wide_read_past_eof_is_an_error() {
+        let mut br = BitReader::from_slice(&[0xAB; 6]);
+        let err = br.read_bits(64).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bit_position_counts_consumed_bits() {
+        let data = [0x5A; 20];
+        let source = BufReader::with_capacity(3, Cursor::new(data));
+        let mut br = BitReader::from_buf_read(source);
+        assert_eq!(br.bit_position(), 0);
+        br.read_bits(3).unwrap();
+        assert_eq!(br.bit_position(), 3);
+        br.peek_bits(MAX_PEEK_BITS).unwrap();
+        assert_eq!(br.bit_position(), 3);
+        br.read_bits(64).unwrap();
+        br.consume_bits(10);
+        assert_eq!(br.bit_position(), 77);
+    }
 }
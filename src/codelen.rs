@@ -0,0 +1,271 @@
+use crate::bitio::{BitReader, BitWriter};
+use crate::huffman::{self, HuffmanDecoder, MAX_CODE_LENGTH};
+
+// ---------------------------------------------------------------------------
+// Compact code-length table encoding (DEFLATE-style)
+// ---------------------------------------------------------------------------
+//
+// The 256-entry code-length vector is run-length encoded with the alphabet
+// below, and the resulting symbols are themselves Huffman-coded with a small
+// "code-length code" whose lengths (0..7) are sent first:
+//
+//   6 bits        HCLEN: number of code-length-code lengths that follow
+//   HCLEN x 3     code-length-code lengths, in `CL_ORDER`
+//   ...           Huffman-coded symbols (plus extra bits) until 256 lengths
+//                 have been produced, padded with zeros to a byte boundary
+
+/// Repeat the previous length 3..=6 times (2 extra bits).
+const REPEAT_PREV: u8 = MAX_CODE_LENGTH + 1;
+
+/// Repeat a zero length 3..=10 times (3 extra bits).
+const REPEAT_ZERO_SHORT: u8 = MAX_CODE_LENGTH + 2;
+
+/// Repeat a zero length 11..=138 times (7 extra bits).
+const REPEAT_ZERO_LONG: u8 = MAX_CODE_LENGTH + 3;
+
+/// Size of the code-length alphabet: literal lengths 0..=32 plus the three
+/// repeat codes.
+const CL_ALPHABET: usize = REPEAT_ZERO_LONG as usize + 1;
+
+/// Longest code allowed in the code-length code (fits in 3 bits).
+const CL_MAX_LEN: u8 = 7;
+
+/// Order in which code-length-code lengths are stored.  Symbols likely to be
+/// unused come last so trailing zeros can be trimmed via HCLEN.
+const CL_ORDER: [u8; CL_ALPHABET] = [
+    REPEAT_ZERO_SHORT, REPEAT_ZERO_LONG, REPEAT_PREV, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2,
+    14, 1, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+];
+
+/// Encodes a 256-entry code-length vector in the compact form.
+pub fn encode(lengths: &[u8; 256]) -> Vec<u8> {
+    let symbols = run_length_encode(lengths);
+
+    let mut freqs = [0u64; 256];
+    for &(symbol, _) in &symbols {
+        freqs[symbol as usize] += 1;
+    }
+    let cl_lengths = limited_lengths(&freqs);
+    let cl_table = huffman::CodeTable::from_lengths(&cl_lengths)
+        .expect("code-length code lengths are at most CL_MAX_LEN");
+
+    let hclen = CL_ORDER
+        .iter()
+        .rposition(|&s| cl_lengths[s as usize] > 0)
+        .map_or(0, |i| i + 1);
+
+    let mut out = Vec::new();
+    {
+        let mut writer = BitWriter::new(&mut out);
+        // Writing to a Vec cannot fail.
+        writer.write_bits(hclen as u64, 6).unwrap();
+        for &s in &CL_ORDER[..hclen] {
+            writer.write_bits(cl_lengths[s as usize] as u64, 3).unwrap();
+        }
+        for &(symbol, extra) in &symbols {
+            let s = symbol as usize;
+            writer.write_bits(cl_table.code[s], cl_table.code_len[s]).unwrap();
+            match symbol {
+                REPEAT_PREV => writer.write_bits(extra as u64 - 3, 2).unwrap(),
+                REPEAT_ZERO_SHORT => writer.write_bits(extra as u64 - 3, 3).unwrap(),
+                REPEAT_ZERO_LONG => writer.write_bits(extra as u64 - 11, 7).unwrap(),
+                _ => {}
+            }
+        }
+        writer.flush().unwrap();
+    }
+    out
+}
+
+/// Decodes a compact code-length table produced by [`encode`].
+///
+/// Returns an `InvalidData` error if the table is truncated, uses a repeat
+/// code with no previous length, or describes more than 256 lengths.
+pub fn decode(bytes: &[u8]) -> std::io::Result<[u8; 256]> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let truncated = || invalid("Compact code-length table is truncated");
+
+    let mut reader = BitReader::from_slice(bytes);
+
+    let hclen = reader.read_bits(6)?.ok_or_else(truncated)? as usize;
+    if hclen > CL_ALPHABET {
+        return Err(invalid("Invalid code-length code size"));
+    }
+    let mut cl_table = Vec::with_capacity(hclen);
+    for &s in &CL_ORDER[..hclen] {
+        let len = reader.read_bits(3)?.ok_or_else(truncated)? as u8;
+        if len > 0 {
+            cl_table.push((s, len));
+        }
+    }
+    cl_table.sort_unstable();
+    if cl_table.is_empty() {
+        return Err(invalid("Code-length code has no symbols"));
+    }
+    let kraft: u32 = cl_table.iter().map(|&(_, len)| 1 << (CL_MAX_LEN - len)).sum();
+    if kraft > 1 << CL_MAX_LEN {
+        return Err(invalid("Code-length code is oversubscribed"));
+    }
+    let decoder = HuffmanDecoder::new(&cl_table)?;
+
+    let mut lengths = [0u8; 256];
+    let mut pos = 0usize;
+    while pos < 256 {
+        let symbol = decoder.decode_byte(&mut reader)?.ok_or_else(truncated)?;
+        let (value, count) = match symbol {
+            REPEAT_PREV => {
+                if pos == 0 {
+                    return Err(invalid("Repeat code with no previous length"));
+                }
+                let extra = reader.read_bits(2)?.ok_or_else(truncated)?;
+                (lengths[pos - 1], 3 + extra as usize)
+            }
+            REPEAT_ZERO_SHORT => (0, 3 + reader.read_bits(3)?.ok_or_else(truncated)? as usize),
+            REPEAT_ZERO_LONG => (0, 11 + reader.read_bits(7)?.ok_or_else(truncated)? as usize),
+            len if len <= MAX_CODE_LENGTH => (len, 1),
+            _ => return Err(invalid("Invalid code-length symbol")),
+        };
+        if pos + count > 256 {
+            return Err(invalid("Compact code-length table overflows 256 entries"));
+        }
+        lengths[pos..pos + count].fill(value);
+        pos += count;
+    }
+
+    Ok(lengths)
+}
+
+/// Turns the length vector into `(symbol, run_length)` pairs using the
+/// repeat codes wherever they save space.
+fn run_length_encode(lengths: &[u8; 256]) -> Vec<(u8, u8)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < 256 {
+        let value = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == value).count();
+        i += run;
+
+        if value == 0 {
+            while run >= 11 {
+                let n = run.min(138);
+                symbols.push((REPEAT_ZERO_LONG, n as u8));
+                run -= n;
+            }
+            if run >= 3 {
+                symbols.push((REPEAT_ZERO_SHORT, run as u8));
+                run = 0;
+            }
+        } else {
+            symbols.push((value, 1));
+            run -= 1;
+            while run >= 3 {
+                let n = run.min(6);
+                symbols.push((REPEAT_PREV, n as u8));
+                run -= n;
+            }
+        }
+        symbols.extend(std::iter::repeat_n((value, 1), run));
+    }
+    symbols
+}
+
+/// Huffman code lengths for the code-length alphabet, limited to
+/// [`CL_MAX_LEN`] bits by repeatedly flattening the frequencies.
+fn limited_lengths(freqs: &[u64; 256]) -> [u8; 256] {
+    let mut freqs = *freqs;
+    loop {
+        let table = huffman::build_codes(&freqs).expect("at most 36 symbols");
+        if table.code_len.iter().all(|&l| l <= CL_MAX_LEN) {
+            return table.code_len;
+        }
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = f.div_ceil(2);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(lengths: &[u8; 256]) -> usize {
+        let bytes = encode(lengths);
+        assert_eq!(&decode(&bytes).unwrap(), lengths);
+        bytes.len()
+    }
+
+    #[test]
+    fn all_zero() {
+        assert!(round_trip(&[0u8; 256]) <= 4);
+    }
+
+    #[test]
+    fn all_eight_bits_is_small() {
+        // The raw pair layout needs 2 + 256 * 2 = 514 bytes.
+        assert!(round_trip(&[8u8; 256]) < 32);
+    }
+
+    #[test]
+    fn text_like_lengths() {
+        let mut lengths = [0u8; 256];
+        for (b, len) in lengths.iter_mut().enumerate().take(127).skip(32) {
+            *len = 5 + (b % 7) as u8;
+        }
+        lengths[b'\n' as usize] = 6;
+        round_trip(&lengths);
+    }
+
+    #[test]
+    fn every_length_value() {
+        let mut lengths = [0u8; 256];
+        for (b, len) in lengths.iter_mut().enumerate() {
+            *len = (b % 33) as u8;
+        }
+        round_trip(&lengths);
+    }
+
+    #[test]
+    fn skewed_alphabet_is_length_limited() {
+        // Many distinct run shapes produce a skewed code-length alphabet.
+        let mut lengths = [0u8; 256];
+        for (b, len) in lengths.iter_mut().enumerate() {
+            *len = match b % 40 {
+                0..=20 => 9,
+                21 => 0,
+                n => n as u8 - 10,
+            };
+        }
+        round_trip(&lengths);
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let bytes = encode(&[8u8; 256]);
+        let err = decode(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn reject_repeat_without_previous() {
+        // HCLEN = 3 covers REPEAT_ZERO_SHORT, REPEAT_ZERO_LONG, REPEAT_PREV;
+        // give only REPEAT_PREV a (1-bit) code, then use it first.
+        let mut out = Vec::new();
+        {
+            let mut w = BitWriter::new(&mut out);
+            w.write_bits(3, 6).unwrap();
+            w.write_bits(0, 3).unwrap();
+            w.write_bits(0, 3).unwrap();
+            w.write_bits(1, 3).unwrap();
+            w.write_bits(0, 1).unwrap(); // REPEAT_PREV
+            w.write_bits(0, 2).unwrap();
+            w.flush().unwrap();
+        }
+        let err = decode(&out).unwrap_err();
+        assert!(err.to_string().contains("no previous length"));
+    }
+}
@@ -0,0 +1,401 @@
+use std::io::{Read, Write};
+
+use crate::error::{CorruptionKind, DecodeError};
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// Pluggable entropy coders
+// ---------------------------------------------------------------------------
+
+/// An entropy coding backend for the `.cmpr` container.
+///
+/// The container handles everything around the payload -- magic, version,
+/// original size, padding byte, CRC-32 and the CLI -- and delegates the rest
+/// to the coder:
+///
+/// 1. [`from_frequencies`](EntropyCoder::from_frequencies) builds a model
+///    from the byte counts of the input.
+/// 2. [`write_table`](EntropyCoder::write_table) serialises the model into
+///    the header; [`read_table`](EntropyCoder::read_table) parses it back.
+/// 3. [`encode`](EntropyCoder::encode) and [`decode`](EntropyCoder::decode)
+///    turn the symbol stream into the payload and back.
+///
+/// Built-in coders use method bytes below [`CUSTOM_METHOD_MIN`]; coders
+/// defined outside this crate should pick a method byte at or above it.  Use
+/// [`compress_with`](crate::compress::compress_with) and
+/// [`decompress_with`](crate::decompress::decompress_with) to run a coder
+/// that the CLI does not know about.
+pub trait EntropyCoder: Sized {
+    /// Method byte recorded in the header.
+    const METHOD: u8;
+
+    /// Short human-readable name, e.g. for `--coder`.
+    const NAME: &'static str;
+
+    /// Builds a model from the byte frequencies of the input.
+    fn from_frequencies(freqs: &[u64; 256]) -> std::io::Result<Self>;
+
+    /// Appends the serialised model to `out`.  At most `u16::MAX` bytes.
+    fn write_table(&self, out: &mut Vec<u8>);
+
+    /// Parses a model written by [`write_table`](EntropyCoder::write_table).
+    ///
+    /// Implementations should reject malformed tables with `InvalidData`.
+    fn read_table(table: &[u8]) -> std::io::Result<Self>;
+
+    /// Encodes every byte of `input` into `output`.
+    ///
+    /// Returns the number of padding bits (0..7) in the final payload byte,
+    /// which the container stores in the header.
+    fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8>;
+
+    /// Decodes exactly `len` bytes from `input` into `output`.
+    ///
+    /// `input` starts at the first payload byte and continues past the end
+    /// of the payload (into the CRC trailer), so implementations must stop
+    /// on their own once `len` bytes are produced.
+    fn decode<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<()>;
+
+    /// Like [`decode`](EntropyCoder::decode), but also returns the number
+    /// of payload bits consumed, so the container can check that the
+    /// bitstream ends exactly where the header says.
+    ///
+    /// The default decodes with `decode` and returns `None`, which skips
+    /// those checks.
+    fn decode_counted<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        self.decode(input, output, len).map(|()| None)
+    }
+
+    /// Predicts the payload size in bytes for an input with byte counts
+    /// `freqs`, if the coder can do so cheaply.
+    ///
+    /// When the table plus this estimate exceeds the raw input size, the
+    /// container stores the input uncompressed instead.  The default
+    /// returns `None`, which disables that fallback.
+    fn estimated_payload_len(&self, _freqs: &[u64; 256]) -> Option<u64> {
+        None
+    }
+
+    /// The smallest payload, in bytes, that could decode to `len` bytes.
+    ///
+    /// The container refuses to decode a file whose payload is shorter, so
+    /// a forged original size cannot make the decoder write far more than
+    /// the file could hold.  The default of 0 disables the check.
+    fn min_payload_len(&self, _len: u64) -> u64 {
+        0
+    }
+
+    /// Whether the coder implements [`encode_indexed`](EntropyCoder::encode_indexed)
+    /// and [`decode_at`](EntropyCoder::decode_at).  Compressing with a seek
+    /// index is refused up front, before any output is written, for coders
+    /// that do not.
+    const SUPPORTS_INDEX: bool = false;
+
+    /// Like [`encode`](EntropyCoder::encode), but also returns the payload
+    /// bit offset reached after every `interval` input bytes, excluding the
+    /// end of the input.  These become seek index checkpoints that
+    /// [`decode_at`](EntropyCoder::decode_at) can start from.
+    ///
+    /// The default returns an `Unsupported` error, for coders whose payload
+    /// cannot be entered mid-stream.
+    fn encode_indexed<R: Read, W: Write>(
+        &self,
+        _input: &mut R,
+        _output: &mut W,
+        _interval: u64,
+    ) -> std::io::Result<(u8, Vec<u64>)> {
+        Err(unsupported_index::<Self>())
+    }
+
+    /// Decodes exactly `len` bytes starting at a checkpoint returned by
+    /// [`encode_indexed`](EntropyCoder::encode_indexed).
+    ///
+    /// `input` starts at the payload byte containing the checkpoint, whose
+    /// first `skip_bits` (0..7) bits precede it.  Returns the number of bits
+    /// of `input` consumed, including the skipped ones.
+    fn decode_at<R: Read, W: Write>(
+        &self,
+        _input: &mut R,
+        _output: &mut W,
+        _skip_bits: u8,
+        _len: u64,
+    ) -> std::io::Result<u64> {
+        Err(unsupported_index::<Self>())
+    }
+
+    /// Decodes a `len`-byte payload from `input` and returns the
+    /// checkpoints [`encode_indexed`](EntropyCoder::encode_indexed) would
+    /// have recorded, so existing files can be given a seek index.
+    ///
+    /// The default returns an `Unsupported` error.
+    fn scan_index<R: Read>(
+        &self,
+        _input: &mut R,
+        _len: u64,
+        _interval: u64,
+    ) -> std::io::Result<Vec<u64>> {
+        Err(unsupported_index::<Self>())
+    }
+}
+
+pub(crate) fn unsupported_index<C: EntropyCoder>() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("Coder `{}` does not support a seek index", C::NAME),
+    )
+}
+
+/// First method byte available to coders defined outside this crate.
+pub const CUSTOM_METHOD_MIN: u8 = 0x80;
+
+// ---------------------------------------------------------------------------
+// Stored (uncompressed) payloads
+// ---------------------------------------------------------------------------
+
+/// Pass-through "coder" used when entropy coding would expand the input.
+///
+/// The table is empty and the payload is the original bytes.
+pub struct StoredCoder;
+
+impl EntropyCoder for StoredCoder {
+    const METHOD: u8 = format::METHOD_STORED;
+    const NAME: &'static str = "stored";
+    const SUPPORTS_INDEX: bool = true;
+
+    fn from_frequencies(_freqs: &[u64; 256]) -> std::io::Result<Self> {
+        Ok(StoredCoder)
+    }
+
+    fn write_table(&self, _out: &mut Vec<u8>) {}
+
+    fn read_table(table: &[u8]) -> std::io::Result<Self> {
+        if !table.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Stored payload must not have a coder table",
+            ));
+        }
+        Ok(StoredCoder)
+    }
+
+    fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8> {
+        std::io::copy(input, output)?;
+        Ok(0)
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let copied = std::io::copy(&mut input.take(len), output)?;
+        if copied < len {
+            let err = DecodeError::new(
+                CorruptionKind::Truncated,
+                "Unexpected end of compressed bitstream",
+            );
+            return Err(err.at_bit(copied * 8).after_output(copied).into());
+        }
+        output.flush()
+    }
+
+    fn decode_counted<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        self.decode(input, output, len)?;
+        Ok(Some(len * 8))
+    }
+
+    fn estimated_payload_len(&self, freqs: &[u64; 256]) -> Option<u64> {
+        Some(freqs.iter().sum())
+    }
+
+    fn min_payload_len(&self, len: u64) -> u64 {
+        len
+    }
+
+    fn encode_indexed<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        interval: u64,
+    ) -> std::io::Result<(u8, Vec<u64>)> {
+        let len = std::io::copy(input, output)?;
+        Ok((0, stored_checkpoints(len, interval)))
+    }
+
+    fn decode_at<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        skip_bits: u8,
+        len: u64,
+    ) -> std::io::Result<u64> {
+        debug_assert_eq!(skip_bits, 0, "stored checkpoints are byte-aligned");
+        self.decode(input, output, len)?;
+        Ok(len * 8)
+    }
+
+    fn scan_index<R: Read>(
+        &self,
+        _input: &mut R,
+        len: u64,
+        interval: u64,
+    ) -> std::io::Result<Vec<u64>> {
+        Ok(stored_checkpoints(len, interval))
+    }
+}
+
+/// Checkpoints of a stored payload: byte `k * interval` is at bit
+/// `k * interval * 8`.
+fn stored_checkpoints(len: u64, interval: u64) -> Vec<u64> {
+    (1..len.div_ceil(interval))
+        .map(|k| k * interval * 8)
+        .collect()
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::compress_with;
+    use crate::decompress::{decompress, decompress_with};
+    use crate::test_util::TempDir;
+    use std::fs::File;
+
+    /// A toy "model" that XORs every byte with a key stored in the table --
+    /// enough to exercise the container plumbing the way an out-of-crate
+    /// coder would.
+    struct XorCoder {
+        key: u8,
+    }
+
+    impl EntropyCoder for XorCoder {
+        const METHOD: u8 = CUSTOM_METHOD_MIN;
+        const NAME: &'static str = "xor";
+
+        fn from_frequencies(freqs: &[u64; 256]) -> std::io::Result<Self> {
+            let key = (0..=255u8).max_by_key(|&b| freqs[b as usize]).unwrap();
+            Ok(XorCoder { key })
+        }
+
+        fn write_table(&self, out: &mut Vec<u8>) {
+            out.push(self.key);
+        }
+
+        fn read_table(table: &[u8]) -> std::io::Result<Self> {
+            match table {
+                [key] => Ok(XorCoder { key: *key }),
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "bad xor table",
+                )),
+            }
+        }
+
+        fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8> {
+            let mut data = Vec::new();
+            input.read_to_end(&mut data)?;
+            data.iter_mut().for_each(|b| *b ^= self.key);
+            output.write_all(&data)?;
+            Ok(0)
+        }
+
+        fn decode<R: Read, W: Write>(
+            &self,
+            input: &mut R,
+            output: &mut W,
+            len: u64,
+        ) -> std::io::Result<()> {
+            let mut data = vec![0u8; len as usize];
+            input.read_exact(&mut data)?;
+            data.iter_mut().for_each(|b| *b ^= self.key);
+            output.write_all(&data)
+        }
+    }
+
+    #[test]
+    fn custom_coder_round_trip() {
+        let dir = TempDir::new("coder");
+        let input_path = dir.join("input.bin");
+        let cmpr_path = dir.join("data.cmpr");
+        let output_path = dir.join("output.bin");
+
+        let data = b"custom coders share the container, CRC and CLI plumbing";
+        std::fs::write(&input_path, data).unwrap();
+
+        compress_with::<XorCoder>(&input_path, &cmpr_path).unwrap();
+
+        let header = format::read_header(&mut File::open(&cmpr_path).unwrap()).unwrap();
+        assert_eq!(header.method, XorCoder::METHOD);
+        assert_eq!(header.table, vec![b' ']);
+
+        decompress_with::<XorCoder>(&cmpr_path, &output_path).unwrap();
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+
+        // The built-in dispatcher does not know this method.
+        let err = decompress(&cmpr_path, &output_path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("method"));
+    }
+
+    #[test]
+    fn stored_round_trip() {
+        let data = b"stored payloads are copied verbatim";
+        let mut payload = Vec::new();
+        let padding = StoredCoder.encode(&mut &data[..], &mut payload).unwrap();
+        assert_eq!(padding, 0);
+        assert_eq!(payload, data);
+
+        // Trailing bytes (e.g. the CRC) are left alone.
+        payload.extend_from_slice(&[0xAA; 4]);
+        let mut out = Vec::new();
+        StoredCoder
+            .decode(&mut &payload[..], &mut out, data.len() as u64)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn stored_truncated_payload() {
+        let err = StoredCoder
+            .decode(&mut &b"short"[..], &mut Vec::new(), 10)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn custom_coder_crc_still_checked() {
+        let dir = TempDir::new("coder");
+        let input_path = dir.join("input.bin");
+        let cmpr_path = dir.join("data.cmpr");
+
+        std::fs::write(&input_path, b"abcabcabc").unwrap();
+        compress_with::<XorCoder>(&input_path, &cmpr_path).unwrap();
+
+        let mut bytes = std::fs::read(&cmpr_path).unwrap();
+        let header = format::read_header(&mut &bytes[..]).unwrap();
+        bytes[header.header_len() as usize] ^= 0x01;
+        std::fs::write(&cmpr_path, &bytes).unwrap();
+
+        let err = decompress_with::<XorCoder>(&cmpr_path, &dir.join("out.bin")).unwrap_err();
+        assert!(err.to_string().contains("CRC-32 mismatch"));
+    }
+}
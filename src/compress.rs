@@ -1,18 +1,144 @@
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::str::FromStr;
 
-use crate::bitio::BitWriter;
+use crate::ans::AnsCoder;
+use crate::coder::{self, EntropyCoder, StoredCoder};
+use crate::crypto::{self, MacKey, Secret, TempFile};
+use crate::decompress;
 use crate::format;
 use crate::frequency;
-use crate::huffman;
+use crate::huffman::{Huffman4Coder, HuffmanCoder};
+use crate::index::SeekIndex;
+use crate::parity;
+
+/// Built-in entropy coder used for the compressed payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coder {
+    /// Canonical Huffman coding (the default).
+    #[default]
+    Huffman,
+    /// Canonical Huffman coding split into four interleaved streams per
+    /// block.  Slightly larger output, markedly faster to decode.
+    Huffman4,
+    /// rANS coding with a normalised frequency table.  Better than Huffman
+    /// on heavily skewed inputs, at some cost in speed.
+    Ans,
+}
+
+impl FromStr for Coder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            HuffmanCoder::NAME => Ok(Coder::Huffman),
+            Huffman4Coder::NAME => Ok(Coder::Huffman4),
+            AnsCoder::NAME => Ok(Coder::Ans),
+            _ => Err(format!(
+                "unknown coder `{s}` (expected `huffman`, `huffman4` or `ans`)"
+            )),
+        }
+    }
+}
+
+/// Options controlling how a file is compressed.
+#[derive(Debug, Clone, Default)]
+pub struct EncoderOptions {
+    /// Entropy coder for the payload.
+    pub coder: Coder,
+    /// Record a seek index checkpoint every this many uncompressed bytes,
+    /// enabling [`decompress_range`](crate::decompress::decompress_range)
+    /// to start near the requested range.  Must be non-zero.  Not supported
+    /// by the `huffman4` and `ans` coders.
+    pub index_interval: Option<u64>,
+    /// Append a Reed–Solomon parity section (see [`crate::parity`]) so that
+    /// damage to the file can be detected and repaired.
+    pub parity: bool,
+    /// Seal the compressed file in an encrypted container locked with this
+    /// secret (see [`crate::crypto`]).
+    pub encrypt: Option<Secret>,
+    /// Append an HMAC-SHA-256 tag keyed with this key (see
+    /// [`crypto::MAC_SIZE`]), so that changes to the file can be detected.
+    /// When encrypting, the tag goes on the sealed file.
+    pub mac_key: Option<MacKey>,
+}
 
 /// Compresses `input_path` and writes the `.cmpr` output to `output_path`.
 ///
 /// Returns `(original_size, compressed_file_size)` on success for statistics.
 pub fn compress(input_path: &Path, output_path: &Path) -> std::io::Result<(u64, u64)> {
-    let (original_size, symbol_table, table) = build_codes(input_path)?;
+    compress_with_options(input_path, output_path, &EncoderOptions::default())
+}
+
+/// Like [`compress`], but with explicit [`EncoderOptions`].
+pub fn compress_with_options(
+    input_path: &Path,
+    output_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
+    if let Some(secret) = &options.encrypt {
+        // Sealed files are written in place, so only replace an existing
+        // output once the whole file is there.
+        return decompress::write_atomically(output_path, |temp_path| {
+            let mut output = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(temp_path)?;
+            compress_sealed(input_path, &mut output, options, secret)
+        });
+    }
+    match options.coder {
+        Coder::Huffman => compress_file::<HuffmanCoder>(input_path, output_path, options),
+        Coder::Huffman4 => compress_file::<Huffman4Coder>(input_path, output_path, options),
+        Coder::Ans => compress_file::<AnsCoder>(input_path, output_path, options),
+    }
+}
+
+/// Like [`compress`], but encodes the payload with an arbitrary
+/// [`EntropyCoder`], including ones defined outside this crate.
+///
+/// Falls back to a stored payload when the coder predicts that it would
+/// expand the input (see [`EntropyCoder::estimated_payload_len`]).
+pub fn compress_with<C: EntropyCoder>(
+    input_path: &Path,
+    output_path: &Path,
+) -> std::io::Result<(u64, u64)> {
+    compress_file::<C>(input_path, output_path, &EncoderOptions::default())
+}
+
+/// [`compress_with`] honouring the coder-independent parts of `options`.
+fn compress_file<C: EntropyCoder>(
+    input_path: &Path,
+    output_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
+    check_options::<C>(options)?;
+    let (original_size, freqs) = count_input(input_path)?;
+    let coder = C::from_frequencies(&freqs)?;
+    if should_store(&coder, &freqs, original_size) {
+        write_file(
+            input_path,
+            output_path,
+            original_size,
+            &StoredCoder,
+            options,
+        )
+    } else {
+        write_file(input_path, output_path, original_size, &coder, options)
+    }
+}
 
+/// Writes the complete `.cmpr` file for `input_path` with `coder`.
+fn write_file<C: EntropyCoder>(
+    input_path: &Path,
+    output_path: &Path,
+    original_size: u64,
+    coder: &C,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
     let output_file = OpenOptions::new()
         .read(true)
         .write(true)
@@ -20,11 +146,18 @@ pub fn compress(input_path: &Path, output_path: &Path) -> std::io::Result<(u64,
         .truncate(true)
         .open(output_path)?;
     let mut writer = BufWriter::new(output_file);
-    format::write_header(&mut writer, original_size, &symbol_table, 0)?;
+    write_header(&mut writer, original_size, coder)?;
     writer.flush()?;
 
     let mut file = writer.into_inner()?;
-    encode_and_finalize(&mut file, &mut File::open(input_path)?, &table)?;
+    let header_size = file.stream_position()?;
+    encode_and_finalize(
+        &mut file,
+        &mut File::open(input_path)?,
+        coder,
+        header_size,
+        options,
+    )?;
 
     let compressed_size = file.metadata()?.len();
     Ok((original_size, compressed_size))
@@ -34,14 +167,71 @@ pub fn compress(input_path: &Path, output_path: &Path) -> std::io::Result<(u64,
 ///
 /// Returns `(original_size, compressed_size)` on success for statistics.
 pub fn compress_to_stdout(input_path: &Path) -> std::io::Result<(u64, u64)> {
-    let (original_size, symbol_table, table) = build_codes(input_path)?;
+    compress_to_stdout_with_options(input_path, &EncoderOptions::default())
+}
 
+/// Like [`compress_to_stdout`], but with explicit [`EncoderOptions`].
+pub fn compress_to_stdout_with_options(
+    input_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
+    if let Some(secret) = &options.encrypt {
+        let mut buf = Cursor::new(Vec::new());
+        let sizes = compress_sealed(input_path, &mut buf, options, secret)?;
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(buf.get_ref())?;
+        out.flush()?;
+        return Ok(sizes);
+    }
+    match options.coder {
+        Coder::Huffman => compress_stdout::<HuffmanCoder>(input_path, options),
+        Coder::Huffman4 => compress_stdout::<Huffman4Coder>(input_path, options),
+        Coder::Ans => compress_stdout::<AnsCoder>(input_path, options),
+    }
+}
+
+/// Like [`compress_to_stdout`], but with an arbitrary [`EntropyCoder`].
+pub fn compress_to_stdout_with<C: EntropyCoder>(input_path: &Path) -> std::io::Result<(u64, u64)> {
+    compress_stdout::<C>(input_path, &EncoderOptions::default())
+}
+
+/// [`compress_to_stdout_with`] honouring the coder-independent parts of
+/// `options`.
+fn compress_stdout<C: EntropyCoder>(
+    input_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
+    check_options::<C>(options)?;
+    let (original_size, freqs) = count_input(input_path)?;
+    let coder = C::from_frequencies(&freqs)?;
+    if should_store(&coder, &freqs, original_size) {
+        write_stdout(input_path, original_size, &StoredCoder, options)
+    } else {
+        write_stdout(input_path, original_size, &coder, options)
+    }
+}
+
+/// Writes the complete `.cmpr` stream for `input_path` to stdout.
+fn write_stdout<C: EntropyCoder>(
+    input_path: &Path,
+    original_size: u64,
+    coder: &C,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64)> {
     // Buffer everything in memory since we need to seek back for padding.
     let mut buf = Vec::new();
     let mut cursor = Cursor::new(&mut buf);
-    format::write_header(&mut cursor, original_size, &symbol_table, 0)?;
+    write_header(&mut cursor, original_size, coder)?;
+    let header_size = cursor.stream_position()?;
 
-    let compressed_end = encode_and_finalize(&mut cursor, &mut File::open(input_path)?, &table)?;
+    let compressed_end = encode_and_finalize(
+        &mut cursor,
+        &mut File::open(input_path)?,
+        coder,
+        header_size,
+        options,
+    )?;
 
     let compressed_size = compressed_end;
     let stdout = std::io::stdout();
@@ -51,74 +241,136 @@ pub fn compress_to_stdout(input_path: &Path) -> std::io::Result<(u64, u64)> {
     Ok((original_size, compressed_size))
 }
 
+/// Compresses `input_path` into a temporary file with `options`, and seals
+/// that in an encrypted container written to `output`.  The parity section,
+/// if requested, protects the container rather than the sealed file.
+fn compress_sealed<F: Read + Write + Seek>(
+    input_path: &Path,
+    output: &mut F,
+    options: &EncoderOptions,
+    secret: &Secret,
+) -> std::io::Result<(u64, u64)> {
+    let inner_options = EncoderOptions {
+        parity: false,
+        encrypt: None,
+        ..options.clone()
+    };
+    let (inner, _) = TempFile::create()?;
+    let (original_size, inner_len) =
+        compress_with_options(input_path, inner.path(), &inner_options)?;
+    let mut reader = BufReader::new(File::open(inner.path())?);
+    let sealed_len = crypto::seal(&mut reader, inner_len, output, secret, options.parity)?;
+    Ok((original_size, sealed_len))
+}
+
 // ---------------------------------------------------------------------------
 // Shared internal helpers
 // ---------------------------------------------------------------------------
 
-/// Counts frequencies and builds the Huffman code table.
-fn build_codes(input_path: &Path) -> std::io::Result<(u64, Vec<(u8, u8)>, huffman::CodeTable)> {
+/// Rejects option values that no coder, or not coder `C`, can honour, before
+/// any output is written.
+fn check_options<C: EntropyCoder>(options: &EncoderOptions) -> std::io::Result<()> {
+    if options.index_interval == Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Seek index interval must be non-zero",
+        ));
+    }
+    if options.index_interval.is_some() && !C::SUPPORTS_INDEX {
+        return Err(coder::unsupported_index::<C>());
+    }
+    Ok(())
+}
+
+/// Returns the input size and its byte frequencies.
+fn count_input(input_path: &Path) -> std::io::Result<(u64, [u64; 256])> {
     let original_size = input_path.metadata()?.len();
     let mut input_file = File::open(input_path)?;
     let freqs = frequency::count_frequencies(&mut input_file)?;
+    Ok((original_size, freqs))
+}
 
-    let table = huffman::build_codes(&freqs).map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-    })?;
-
-    let mut symbol_table: Vec<(u8, u8)> =
-        Vec::with_capacity(table.symbol_count as usize);
-    for b in 0..=255u16 {
-        let len = table.code_len[b as usize];
-        if len > 0 {
-            symbol_table.push((b as u8, len));
+/// Whether `coder`'s table plus estimated payload would exceed the raw
+/// input, in which case the input is stored instead.
+fn should_store<C: EntropyCoder>(coder: &C, freqs: &[u64; 256], original_size: u64) -> bool {
+    if C::METHOD == StoredCoder::METHOD {
+        return false;
+    }
+    match coder.estimated_payload_len(freqs) {
+        Some(payload_len) => {
+            let mut table = Vec::new();
+            coder.write_table(&mut table);
+            table.len() as u64 + payload_len > original_size
         }
+        None => false,
     }
+}
 
-    Ok((original_size, symbol_table, table))
+/// Writes the `.cmpr` header (with a placeholder padding byte).
+fn write_header<C: EntropyCoder, W: Write>(
+    writer: &mut W,
+    original_size: u64,
+    coder: &C,
+) -> std::io::Result<()> {
+    let mut table = Vec::new();
+    coder.write_table(&mut table);
+    format::write_method_header(writer, original_size, C::METHOD, &table, 0)
 }
 
-/// Encodes input data through the bit writer, flushes, writes the real padding
-/// byte, and appends the CRC-32 trailer.  `file` must support seeking (regular
-/// File or Cursor).
-fn encode_and_finalize<W: Write + Read + Seek>(
+/// Encodes input data with `coder`, appends the seek index if requested,
+/// writes the real padding and flags bytes and the header CRC, appends the
+/// CRC-32 trailer, and then the HMAC-SHA-256 tag and parity section if
+/// requested.
+/// The header occupies the first `header_size` bytes of `file`, which must
+/// support seeking (regular File or Cursor).
+fn encode_and_finalize<C: EntropyCoder, W: Write + Read + Seek>(
     file: &mut W,
     input: &mut File,
-    table: &huffman::CodeTable,
+    coder: &C,
+    header_size: u64,
+    options: &EncoderOptions,
 ) -> std::io::Result<u64> {
-    // Encode bitstream
+    // Encode payload
     input.seek(SeekFrom::Start(0))?;
     let mut reader = BufReader::new(input);
-    let mut buf = [0u8; 8192];
-
-    let padding = {
-        let mut bit_writer = BitWriter::new(&mut *file);
-
-        loop {
-            let n = reader.read(&mut buf)?;
-            if n == 0 {
-                break;
-            }
-            for &byte in &buf[..n] {
-                let len = table.code_len[byte as usize];
-                if len > 0 {
-                    bit_writer.write_bits(table.code[byte as usize], len)?;
-                }
-            }
+    let padding = match options.index_interval {
+        Some(interval) => {
+            let (padding, bit_offsets) = coder.encode_indexed(&mut reader, &mut *file, interval)?;
+            SeekIndex::from_interval(interval, &bit_offsets)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Index interval is too large",
+                    )
+                })?
+                .write_section(&mut *file, interval)?;
+            padding
         }
-
-        bit_writer.flush()?
+        None => coder.encode(&mut reader, &mut *file)?,
     };
 
     // Record compressed data end offset
     let compressed_end = file.stream_position()?;
 
-    // Seek back and write the actual padding byte (the header placeholder
-    // was 0).  We need the file position for this, which is header-local.
-    let symbol_count = table.symbol_count;
-    let header_size = format::FIXED_HEADER_SIZE as u64 + symbol_count as u64 * 2;
+    // Seek back and write the actual padding and flags bytes (the header
+    // placeholders were 0).  We need the file position for this, which is
+    // header-local.
     let padding_offset = format::PADDING_OFFSET;
     file.seek(SeekFrom::Start(padding_offset))?;
     file.write_all(&[padding])?;
+    let mut flags = 0;
+    if options.index_interval.is_some() {
+        flags |= format::FLAG_INDEX;
+    }
+    if options.parity {
+        flags |= format::FLAG_PARITY;
+    }
+    if options.mac_key.is_some() {
+        flags |= format::FLAG_HMAC;
+    }
+    file.seek(SeekFrom::Start(format::FLAGS_OFFSET))?;
+    file.write_all(&[flags])?;
+    format::update_header_crc(file, header_size)?;
 
     // Compute and append CRC-32 over the compressed data bytes
     file.seek(SeekFrom::Start(header_size))?;
@@ -131,7 +383,15 @@ fn encode_and_finalize<W: Write + Read + Seek>(
     file.write_all(&crc.to_le_bytes())?;
     file.flush()?;
 
-    Ok(compressed_end + format::CRC_SIZE)
+    let mut container_len = compressed_end + format::CRC_SIZE;
+    if let Some(key) = &options.mac_key {
+        container_len += crypto::write_mac(file, container_len, key)?;
+    }
+    if options.parity {
+        let section_len = parity::write_section(file, container_len)?;
+        return Ok(container_len + section_len);
+    }
+    Ok(container_len)
 }
 
 // ---------------------------------------------------------------------------
@@ -221,8 +481,8 @@ mod tests {
 
     #[test]
     fn compress_reproduces_correct_header() {
-        let data = b"The quick brown fox jumps over the lazy dog.";
-        let (_input, output) = compress_to_temp(data);
+        let data = b"The quick brown fox jumps over the lazy dog.".repeat(10);
+        let (_input, output) = compress_to_temp(&data);
         let mut f = File::open(&output).unwrap();
         let header = format::read_header(&mut f).unwrap();
         assert_eq!(header.original_size, data.len() as u64);
@@ -231,4 +491,54 @@ mod tests {
         assert!(header.padding <= 7);
         let _ = std::fs::remove_file(&output);
     }
+
+    #[test]
+    fn compress_ans_writes_method_header() {
+        let (input, _) = compress_to_temp(&b"abracadabra".repeat(20));
+        let output = input.with_extension("ans.cmpr");
+        let options = EncoderOptions {
+            coder: Coder::Ans,
+            ..Default::default()
+        };
+        compress_with_options(&input, &output, &options).unwrap();
+
+        let mut f = File::open(&output).unwrap();
+        let header = format::read_header(&mut f).unwrap();
+        assert_eq!(header.method, format::METHOD_ANS);
+        assert_eq!(header.original_size, 220);
+        let coder = AnsCoder::read_table(&header.table).unwrap();
+        assert_eq!(coder.frequency_table().symbol_count(), 5);
+        let _ = std::fs::remove_file(&output);
+    }
+
+    #[test]
+    fn incompressible_input_is_stored() {
+        // Every byte value once: Huffman needs 8 bits each plus a table.
+        let data: Vec<u8> = (0..=255).collect();
+        let (_input, output) = compress_to_temp(&data);
+        let mut f = File::open(&output).unwrap();
+        let header = format::read_header(&mut f).unwrap();
+        assert_eq!(header.method, format::METHOD_STORED);
+        assert!(header.table.is_empty());
+        let file_len = std::fs::metadata(&output).unwrap().len();
+        assert_eq!(file_len, header.header_len() + 256 + format::CRC_SIZE);
+        let _ = std::fs::remove_file(&output);
+    }
+
+    #[test]
+    fn compressible_input_is_not_stored() {
+        let (_input, output) = compress_to_temp(&[b'a'; 1000]);
+        let mut f = File::open(&output).unwrap();
+        let header = format::read_header(&mut f).unwrap();
+        assert_eq!(header.method, format::METHOD_HUFFMAN);
+        let _ = std::fs::remove_file(&output);
+    }
+
+    #[test]
+    fn coder_from_str() {
+        assert_eq!("huffman".parse::<Coder>(), Ok(Coder::Huffman));
+        assert_eq!("huffman4".parse::<Coder>(), Ok(Coder::Huffman4));
+        assert_eq!("ans".parse::<Coder>(), Ok(Coder::Ans));
+        assert!("lz77".parse::<Coder>().is_err());
+    }
 }
@@ -0,0 +1,1224 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::decompress::{self, DecoderOptions};
+use crate::error::{CorruptionKind, DecodeError};
+use crate::format::{self, Header, HmacSha256};
+use crate::parity;
+
+// ---------------------------------------------------------------------------
+// Encrypted containers
+// ---------------------------------------------------------------------------
+//
+// `--encrypt` seals a complete `.cmpr` file -- header, payload, seek index
+// and CRC-32 -- inside a container with the `encrypted` method, so that
+// neither the data nor the coder tables and original size are readable
+// without the key:
+//
+//   header    original size = length of the sealed file, coder table =
+//             the encryption parameters below
+//   payload   the sealed file encrypted with ChaCha20, then its 16-byte
+//             Poly1305 tag (the AEAD of RFC 8439, with the whole header,
+//             salt and nonce included, as associated data)
+//   CRC-32    of the payload, as in every container
+//   parity    optional, protecting the encrypted container
+//
+// Encryption parameters (34 bytes):
+//
+//   1   cipher, 0x01 = ChaCha20-Poly1305
+//   1   key derivation, 0x01 = PBKDF2-HMAC-SHA-256
+//   4   PBKDF2 iterations, little-endian u32
+//   16  salt
+//   12  nonce
+//
+// The CRC-32 is checked before the tag, so accidental damage is reported as
+// a CRC mismatch and only a wrong key or deliberate tampering as an
+// authentication failure.
+
+/// Cipher byte for ChaCha20-Poly1305.
+pub const CIPHER_CHACHA20_POLY1305: u8 = 0x01;
+
+/// Key derivation byte for PBKDF2-HMAC-SHA-256.
+pub const KDF_PBKDF2_SHA256: u8 = 0x01;
+
+/// PBKDF2 iterations used to stretch a passphrase.
+pub const PASSPHRASE_ITERATIONS: u32 = 600_000;
+
+/// Most PBKDF2 iterations a file may ask for, so that a forged header
+/// cannot keep the decoder busy for hours.
+pub const MAX_ITERATIONS: u32 = 10_000_000;
+
+/// Salt size in bytes.
+pub const SALT_SIZE: usize = 16;
+
+/// Nonce size in bytes.
+pub const NONCE_SIZE: usize = 12;
+
+/// Authentication tag size in bytes.
+pub const TAG_SIZE: u64 = 16;
+
+/// Size of the encryption parameters stored as the coder table.
+pub const PARAMS_SIZE: usize = 2 + 4 + SALT_SIZE + NONCE_SIZE;
+
+/// Largest file ChaCha20 can encrypt under one nonce with its 32-bit block
+/// counter (block 0 keys Poly1305).
+const MAX_MESSAGE_LEN: u64 = (u32::MAX as u64) * 64;
+
+/// Bytes encrypted or decrypted per read.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// What an encrypted file is locked with.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Secret {
+    /// A passphrase, stretched with [`PASSPHRASE_ITERATIONS`] rounds of
+    /// PBKDF2.
+    Passphrase(Vec<u8>),
+    /// The contents of a key file.  They should be random, at least 32
+    /// bytes, and are not stretched.
+    KeyFile(Vec<u8>),
+}
+
+impl Secret {
+    /// Reads a key file.
+    pub fn from_key_file(path: &Path) -> std::io::Result<Self> {
+        let key = std::fs::read(path)?;
+        if key.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Key file `{}` is empty", path.display()),
+            ));
+        }
+        Ok(Secret::KeyFile(key))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Secret::Passphrase(bytes) | Secret::KeyFile(bytes) => bytes,
+        }
+    }
+
+    fn iterations(&self) -> u32 {
+        match self {
+            Secret::Passphrase(_) => PASSPHRASE_ITERATIONS,
+            Secret::KeyFile(_) => 1,
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Passphrase(_) => f.write_str("Passphrase(..)"),
+            Secret::KeyFile(_) => f.write_str("KeyFile(..)"),
+        }
+    }
+}
+
+/// The encryption parameters of an encrypted container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncryptionParams {
+    /// PBKDF2 iterations.
+    pub iterations: u32,
+    /// PBKDF2 salt.
+    pub salt: [u8; SALT_SIZE],
+    /// ChaCha20 nonce.
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+impl EncryptionParams {
+    /// Parses the coder table of an encrypted container.
+    pub fn parse(table: &[u8]) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        if table.len() != PARAMS_SIZE {
+            return Err(invalid(format!(
+                "Encryption parameters are {} bytes, expected {PARAMS_SIZE}",
+                table.len()
+            )));
+        }
+        if table[0] != CIPHER_CHACHA20_POLY1305 {
+            return Err(invalid(format!("Unsupported cipher {}", table[0])));
+        }
+        if table[1] != KDF_PBKDF2_SHA256 {
+            return Err(invalid(format!("Unsupported key derivation {}", table[1])));
+        }
+        let iterations = u32::from_le_bytes(table[2..6].try_into().unwrap());
+        if !(1..=MAX_ITERATIONS).contains(&iterations) {
+            return Err(invalid(format!(
+                "Invalid PBKDF2 iteration count {iterations}"
+            )));
+        }
+        Ok(EncryptionParams {
+            iterations,
+            salt: table[6..6 + SALT_SIZE].try_into().unwrap(),
+            nonce: table[6 + SALT_SIZE..].try_into().unwrap(),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut table = vec![CIPHER_CHACHA20_POLY1305, KDF_PBKDF2_SHA256];
+        table.extend_from_slice(&self.iterations.to_le_bytes());
+        table.extend_from_slice(&self.salt);
+        table.extend_from_slice(&self.nonce);
+        table
+    }
+
+    fn derive_key(&self, secret: &Secret) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        pbkdf2_hmac_sha256(secret.bytes(), &self.salt, self.iterations, &mut key);
+        key
+    }
+}
+
+/// Writes `inner`, a complete `.cmpr` file of `inner_len` bytes, to
+/// `output` as an encrypted container locked with `secret`, followed by a
+/// parity section if `parity` is set.  Returns the size of what was written.
+pub fn seal<R: Read, F: Read + Write + Seek>(
+    inner: &mut R,
+    inner_len: u64,
+    output: &mut F,
+    secret: &Secret,
+    parity: bool,
+) -> std::io::Result<u64> {
+    if inner_len > MAX_MESSAGE_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot encrypt more than {MAX_MESSAGE_LEN} bytes"),
+        ));
+    }
+    let mut params = EncryptionParams {
+        iterations: secret.iterations(),
+        salt: [0; SALT_SIZE],
+        nonce: [0; NONCE_SIZE],
+    };
+    random_bytes(&mut params.salt)?;
+    random_bytes(&mut params.nonce)?;
+    let key = params.derive_key(secret);
+
+    let mut header = Vec::new();
+    format::write_method_header(
+        &mut header,
+        inner_len,
+        format::METHOD_ENCRYPTED,
+        &params.to_bytes(),
+        0,
+    )?;
+    if parity {
+        let header_len = header.len() as u64;
+        header[format::FLAGS_OFFSET as usize] = format::FLAG_PARITY;
+        format::update_header_crc(&mut Cursor::new(&mut header), header_len)?;
+    }
+    output.write_all(&header)?;
+
+    let mut aead = Aead::new(&key, &params.nonce, &header);
+    let mut crc = 0;
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut remaining = inner_len;
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(BUF_SIZE as u64) as usize];
+        inner.read_exact(chunk)?;
+        aead.encrypt(chunk);
+        crc = format::crc32_update(crc, chunk);
+        output.write_all(chunk)?;
+        remaining -= chunk.len() as u64;
+    }
+    let tag = aead.tag();
+    crc = format::crc32_update(crc, &tag);
+    output.write_all(&tag)?;
+    output.write_all(&crc.to_le_bytes())?;
+    output.flush()?;
+
+    let container_len = header.len() as u64 + inner_len + TAG_SIZE + format::CRC_SIZE;
+    if parity {
+        return Ok(container_len + parity::write_section(output, container_len)?);
+    }
+    Ok(container_len)
+}
+
+/// Decrypts the encrypted container `input`, whose header is `header`,
+/// writing the sealed `.cmpr` file to `writer`.
+///
+/// The CRC-32 and the authentication tag are only checked at the end, so
+/// on error everything written must be discarded.
+pub fn open<W: Write>(
+    input: &mut File,
+    header: &Header,
+    secret: &Secret,
+    writer: &mut W,
+) -> std::io::Result<()> {
+    let params = EncryptionParams::parse(&header.table)?;
+    let header_len = header.header_len();
+    let payload_len = header.original_size + TAG_SIZE;
+    let container_len = decompress::container_len(header, input)?;
+    if container_len != header_len + payload_len + format::CRC_SIZE {
+        let kind = if container_len < header_len + payload_len + format::CRC_SIZE {
+            CorruptionKind::Truncated
+        } else {
+            CorruptionKind::TrailingBytes
+        };
+        let msg = format!(
+            "Encrypted payload is {} bytes, expected {payload_len}",
+            container_len.saturating_sub(header_len + format::CRC_SIZE)
+        );
+        return Err(DecodeError::new(kind, msg).into());
+    }
+
+    let mut aad = vec![0u8; header_len as usize];
+    input.seek(SeekFrom::Start(0))?;
+    input.read_exact(&mut aad)?;
+    let key = params.derive_key(secret);
+    let mut aead = Aead::new(&key, &params.nonce, &aad);
+
+    let mut crc = 0;
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut remaining = header.original_size;
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(BUF_SIZE as u64) as usize];
+        input.read_exact(chunk)?;
+        crc = format::crc32_update(crc, chunk);
+        aead.decrypt(chunk);
+        writer.write_all(chunk)?;
+        remaining -= chunk.len() as u64;
+    }
+    let mut trailer = [0u8; TAG_SIZE as usize + format::CRC_SIZE as usize];
+    input.read_exact(&mut trailer)?;
+    let (stored_tag, stored_crc) = trailer.split_at(TAG_SIZE as usize);
+    crc = format::crc32_update(crc, stored_tag);
+    let stored_crc = u32::from_le_bytes(stored_crc.try_into().unwrap());
+
+    let tag_offset = header_len + header.original_size;
+    if crc != stored_crc {
+        let msg = format!("CRC-32 mismatch: expected {stored_crc:#010x}, got {crc:#010x}");
+        let err = DecodeError::new(CorruptionKind::CrcMismatch, msg)
+            .at_file_offset(tag_offset + TAG_SIZE);
+        return Err(err.into());
+    }
+    if !tags_equal(&aead.tag(), stored_tag) {
+        let msg = "Authentication failed: wrong passphrase or key file, or the file was altered";
+        let err =
+            DecodeError::new(CorruptionKind::AuthenticationFailed, msg).at_file_offset(tag_offset);
+        return Err(err.into());
+    }
+    writer.flush()
+}
+
+/// Whether `header` belongs to an encrypted container.
+pub fn is_encrypted(header: &Header) -> bool {
+    header.method == format::METHOD_ENCRYPTED
+}
+
+/// If `input_path` is an encrypted container, checks it against the limits
+/// of `options` and decrypts it with their secret into a temporary file
+/// holding the sealed `.cmpr` file.
+pub(crate) fn open_if_encrypted(
+    input_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<Option<TempFile>> {
+    let mut input = File::open(input_path)?;
+    let header = format::read_header(&mut input)?;
+    if !is_encrypted(&header) {
+        return Ok(None);
+    }
+    decompress::check_limits(&header, &input, options)?;
+    let secret = options
+        .secret
+        .as_ref()
+        .ok_or_else(|| decompress::unsupported_method(header.method))?;
+    let (temp, file) = TempFile::create()?;
+    open(&mut input, &header, secret, &mut BufWriter::new(file))?;
+    Ok(Some(temp))
+}
+
+/// A file in the temporary directory, readable only by its owner, that is
+/// removed when dropped.
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates an empty temporary file, opened for reading and writing.
+    pub(crate) fn create() -> std::io::Result<(Self, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "cmprsr-{}-{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok((TempFile { path }, file))
+    }
+
+    /// Creates a temporary file holding the `len` bytes of `source` that
+    /// start at `offset`.  Fails with `UnexpectedEof` if `source` is
+    /// shorter.
+    pub(crate) fn copy_of(source: &Path, offset: u64, len: u64) -> std::io::Result<Self> {
+        let mut input = File::open(source)?;
+        input.seek(SeekFrom::Start(offset))?;
+        let (temp, file) = Self::create()?;
+        let mut writer = BufWriter::new(file);
+        let copied = std::io::copy(&mut input.take(len), &mut writer)?;
+        if copied != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("`{}` ends before byte {}", source.display(), offset + len),
+            ));
+        }
+        writer.flush()?;
+        Ok(temp)
+    }
+
+    /// Where the file is.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Compares two tags in time independent of where they differ.
+pub(crate) fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Fills `buf` from the operating system's random number generator.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> std::io::Result<()> {
+    getrandom::getrandom(buf)
+        .map_err(|e| std::io::Error::other(format!("Cannot read random bytes: {e}")))
+}
+
+// ---------------------------------------------------------------------------
+// HMAC-SHA-256 tags
+// ---------------------------------------------------------------------------
+//
+// `--hmac` appends a 32-byte HMAC-SHA-256 tag of everything before it --
+// header, payload, seek index and CRC-32 -- right after the CRC-32 trailer,
+// and sets `FLAG_HMAC`.  A parity section, if any, follows the tag and
+// protects it as well.  Anyone can recompute a CRC-32 after changing a file;
+// the tag cannot be recomputed without the key, so it catches deliberate
+// changes too.  For the same reason a decoder given a key insists on a tag:
+// otherwise a forger could clear the flag and drop it.
+
+/// Size of the HMAC-SHA-256 tag in bytes.
+pub const MAC_SIZE: u64 = 32;
+
+/// Key for HMAC-SHA-256 tags, used as is.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MacKey(Vec<u8>);
+
+impl MacKey {
+    /// Wraps `key`, which must not be empty.
+    pub fn new(key: Vec<u8>) -> std::io::Result<Self> {
+        if key.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "HMAC key is empty",
+            ));
+        }
+        Ok(MacKey(key))
+    }
+
+    /// Reads a key file.
+    pub fn from_key_file(path: &Path) -> std::io::Result<Self> {
+        let key = std::fs::read(path)?;
+        if key.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Key file `{}` is empty", path.display()),
+            ));
+        }
+        Ok(MacKey(key))
+    }
+
+    /// An HMAC-SHA-256 under this key, of no bytes yet.
+    pub(crate) fn hmac(&self) -> HmacSha256 {
+        HmacSha256::new(&self.0)
+    }
+}
+
+impl fmt::Debug for MacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MacKey(..)")
+    }
+}
+
+/// HMAC-SHA-256 of the first `len` bytes of `reader` under `key`.
+pub fn mac_of<R: Read + Seek>(
+    reader: &mut R,
+    len: u64,
+    key: &MacKey,
+) -> std::io::Result<[u8; MAC_SIZE as usize]> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut mac = key.hmac();
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(BUF_SIZE as u64) as usize];
+        reader.read_exact(chunk)?;
+        mac.update(chunk);
+        remaining -= chunk.len() as u64;
+    }
+    Ok(mac.finalize())
+}
+
+/// Appends the tag of the first `len` bytes of `file`, a container whose
+/// header has [`FLAG_HMAC`](format::FLAG_HMAC), at offset `len`.  Returns
+/// the size of what was written.
+pub fn write_mac<F: Read + Write + Seek>(
+    file: &mut F,
+    len: u64,
+    key: &MacKey,
+) -> std::io::Result<u64> {
+    let tag = mac_of(file, len, key)?;
+    file.seek(SeekFrom::Start(len))?;
+    file.write_all(&tag)?;
+    file.flush()?;
+    Ok(MAC_SIZE)
+}
+
+/// Checks `input`, whose header is `header`, against `key`: a file with a
+/// tag needs a key, and a key needs a file with a matching tag.  Files
+/// without a tag pass when there is no key.
+pub fn check_mac(input: &mut File, header: &Header, key: Option<&MacKey>) -> std::io::Result<()> {
+    let has_tag = header.flags & format::FLAG_HMAC != 0;
+    let key = match (has_tag, key) {
+        (false, None) => return Ok(()),
+        (true, Some(key)) => key,
+        (true, None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "File has an HMAC-SHA-256 tag; a key is needed to verify it",
+            ))
+        }
+        (false, Some(_)) => {
+            let msg = "File has no HMAC-SHA-256 tag to verify";
+            return Err(DecodeError::new(CorruptionKind::AuthenticationFailed, msg).into());
+        }
+    };
+    let tag_offset = decompress::container_len(header, input)?;
+    let tag = mac_of(input, tag_offset, key)?;
+    let mut stored = [0u8; MAC_SIZE as usize];
+    input.read_exact(&mut stored)?;
+    if !tags_equal(&tag, &stored) {
+        let msg = "HMAC-SHA-256 mismatch: wrong key, or the file was altered";
+        let err =
+            DecodeError::new(CorruptionKind::AuthenticationFailed, msg).at_file_offset(tag_offset);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// [`check_mac`] on `input_path` with the key in `options`.
+pub(crate) fn verify_mac(input_path: &Path, options: &DecoderOptions) -> std::io::Result<()> {
+    let mut input = File::open(input_path)?;
+    let header = format::read_header(&mut input)?;
+    check_mac(&mut input, &header, options.mac_key.as_ref())
+}
+
+// ---------------------------------------------------------------------------
+// PBKDF2-HMAC-SHA-256 (RFC 8018)
+// ---------------------------------------------------------------------------
+
+/// Derives `out.len()` bytes from `password` and `salt` with PBKDF2, using
+/// HMAC-SHA-256 and `iterations` rounds.
+pub fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let prf = HmacSha256::new(password);
+    for (i, block) in out.chunks_mut(32).enumerate() {
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u = mac.finalize();
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize();
+            for (t, u) in t.iter_mut().zip(u) {
+                *t ^= u;
+            }
+        }
+        block.copy_from_slice(&t[..block.len()]);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ChaCha20-Poly1305 (RFC 8439)
+// ---------------------------------------------------------------------------
+
+/// ChaCha20 keystream generator.
+struct ChaCha20 {
+    state: [u32; 16],
+    keystream: [u8; 64],
+    used: usize,
+}
+
+impl ChaCha20 {
+    fn new(key: &[u8; 32], nonce: &[u8; NONCE_SIZE], counter: u32) -> Self {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+        for (word, bytes) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        state[12] = counter;
+        for (word, bytes) in state[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        ChaCha20 {
+            state,
+            keystream: [0; 64],
+            used: 64,
+        }
+    }
+
+    /// The keystream block for the current counter.
+    fn block(&self) -> [u8; 64] {
+        fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+            x[a] = x[a].wrapping_add(x[b]);
+            x[d] = (x[d] ^ x[a]).rotate_left(16);
+            x[c] = x[c].wrapping_add(x[d]);
+            x[b] = (x[b] ^ x[c]).rotate_left(12);
+            x[a] = x[a].wrapping_add(x[b]);
+            x[d] = (x[d] ^ x[a]).rotate_left(8);
+            x[c] = x[c].wrapping_add(x[d]);
+            x[b] = (x[b] ^ x[c]).rotate_left(7);
+        }
+        let mut x = self.state;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        let mut out = [0u8; 64];
+        for ((bytes, word), initial) in out.chunks_exact_mut(4).zip(x).zip(self.state) {
+            bytes.copy_from_slice(&word.wrapping_add(initial).to_le_bytes());
+        }
+        out
+    }
+
+    /// XORs `data` with the next bytes of keystream.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 64 {
+                self.keystream = self.block();
+                self.state[12] = self.state[12].wrapping_add(1);
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// Incremental Poly1305 with 26-bit limbs.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        let le = |i: usize| u32::from_le_bytes(key[i..i + 4].try_into().unwrap());
+        Poly1305 {
+            r: [
+                le(0) & 0x3ff_ffff,
+                (le(3) >> 2) & 0x3ff_ff03,
+                (le(6) >> 4) & 0x3ff_c0ff,
+                (le(9) >> 6) & 0x3f0_3fff,
+                (le(12) >> 8) & 0x00f_ffff,
+            ],
+            h: [0; 5],
+            pad: [le(16), le(20), le(24), le(28)],
+            buffer: [0; 16],
+            buffered: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let take = data.len().min(16 - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.block(&block, 1 << 24);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(16);
+        for block in &mut blocks {
+            self.block(block.try_into().unwrap(), 1 << 24);
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Adds a 16-byte block (with `hibit` as its 129th bit) to the
+    /// accumulator and multiplies by `r` modulo 2^130 - 5.
+    fn block(&mut self, m: &[u8; 16], hibit: u32) {
+        const MASK: u32 = 0x3ff_ffff;
+        let le = |i: usize| u32::from_le_bytes(m[i..i + 4].try_into().unwrap());
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let [s1, s2, s3, s4] = [r1 * 5, r2 * 5, r3 * 5, r4 * 5];
+        let h = &mut self.h;
+        h[0] += le(0) & MASK;
+        h[1] += (le(3) >> 2) & MASK;
+        h[2] += (le(6) >> 4) & MASK;
+        h[3] += (le(9) >> 6) & MASK;
+        h[4] += (le(12) >> 8) | hibit;
+        let [h0, h1, h2, h3, h4] = h.map(u64::from);
+
+        let d0 = h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3;
+        let mut d3 = h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4;
+        let mut d4 = h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0;
+
+        d1 += d0 >> 26;
+        h[0] = d0 as u32 & MASK;
+        d2 += d1 >> 26;
+        h[1] = d1 as u32 & MASK;
+        d3 += d2 >> 26;
+        h[2] = d2 as u32 & MASK;
+        d4 += d3 >> 26;
+        h[3] = d3 as u32 & MASK;
+        let carry = (d4 >> 26) as u32;
+        h[4] = d4 as u32 & MASK;
+        h[0] += carry * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= MASK;
+    }
+
+    fn finalize(mut self) -> [u8; 16] {
+        const MASK: u32 = 0x3ff_ffff;
+        if self.buffered > 0 {
+            let mut block = [0u8; 16];
+            block[..self.buffered].copy_from_slice(&self.buffer[..self.buffered]);
+            block[self.buffered] = 1;
+            self.block(&block, 0);
+        }
+
+        // Fully carry h, then compute h - p and keep it if it did not
+        // underflow.
+        let mut h = self.h;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= MASK;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= MASK;
+        h[1] += h[0] >> 26;
+        h[0] &= MASK;
+
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..5 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= MASK;
+        }
+        g[4] = g[4].wrapping_add(carry << 26).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !keep_g) | (g[i] & keep_g);
+        }
+
+        // h mod 2^128, plus the pad.
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; 16];
+        let mut sum = 0u64;
+        for ((bytes, word), pad) in tag.chunks_exact_mut(4).zip(words).zip(self.pad) {
+            sum = (sum >> 32) + word as u64 + pad as u64;
+            bytes.copy_from_slice(&(sum as u32).to_le_bytes());
+        }
+        tag
+    }
+}
+
+/// Streaming ChaCha20-Poly1305 AEAD for one message.
+struct Aead {
+    cipher: ChaCha20,
+    mac: Poly1305,
+    aad_len: u64,
+    text_len: u64,
+}
+
+impl Aead {
+    fn new(key: &[u8; 32], nonce: &[u8; NONCE_SIZE], aad: &[u8]) -> Self {
+        // Keystream block 0 keys Poly1305; the message starts at block 1.
+        let mut cipher = ChaCha20::new(key, nonce, 0);
+        let mut mac_key = [0u8; 64];
+        cipher.apply(&mut mac_key);
+        let mut mac = Poly1305::new(mac_key[..32].try_into().unwrap());
+        mac.update(aad);
+        mac.update(&[0; 16][..(16 - aad.len() % 16) % 16]);
+        Aead {
+            cipher,
+            mac,
+            aad_len: aad.len() as u64,
+            text_len: 0,
+        }
+    }
+
+    fn encrypt(&mut self, data: &mut [u8]) {
+        self.cipher.apply(data);
+        self.mac.update(data);
+        self.text_len += data.len() as u64;
+    }
+
+    fn decrypt(&mut self, data: &mut [u8]) {
+        self.mac.update(data);
+        self.cipher.apply(data);
+        self.text_len += data.len() as u64;
+    }
+
+    fn tag(mut self) -> [u8; 16] {
+        self.mac
+            .update(&[0; 16][..(16 - (self.text_len % 16) as usize) % 16]);
+        self.mac.update(&self.aad_len.to_le_bytes());
+        self.mac.update(&self.text_len.to_le_bytes());
+        self.mac.finalize()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{self, EncoderOptions};
+    use crate::test_util::compress_temp;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn counting(start: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| start.wrapping_add(i as u8)).collect()
+    }
+
+    #[test]
+    fn pbkdf2_known_values() {
+        let mut key = [0u8; 32];
+        pbkdf2_hmac_sha256(b"password", b"salt", 1, &mut key);
+        assert_eq!(
+            hex(&key),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        pbkdf2_hmac_sha256(b"password", b"salt", 2, &mut key);
+        assert_eq!(
+            hex(&key),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+    }
+
+    #[test]
+    fn chacha20_block_known_value() {
+        // RFC 8439, section 2.3.2.
+        let key: [u8; 32] = counting(0, 32).try_into().unwrap();
+        let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let block = ChaCha20::new(&key, &nonce, 1).block();
+        assert_eq!(hex(&block[..16]), "10f1e7e4d13b5915500fdd1fa32071c4");
+    }
+
+    #[test]
+    fn poly1305_known_value() {
+        // RFC 8439, section 2.5.2.
+        let key: [u8; 32] = [
+            0x85, 0xd6, 0xbe, 0x78, 0x57, 0x55, 0x6d, 0x33, 0x7f, 0x44, 0x52, 0xfe, 0x42, 0xd5,
+            0x06, 0xa8, 0x01, 0x03, 0x80, 0x8a, 0xfb, 0x0d, 0xb2, 0xfd, 0x4a, 0xbf, 0xf6, 0xaf,
+            0x41, 0x49, 0xf5, 0x1b,
+        ];
+        let mut mac = Poly1305::new(&key);
+        mac.update(b"Cryptographic Forum ");
+        mac.update(b"Research Group");
+        assert_eq!(hex(&mac.finalize()), "a8061dc1305136c6c22b8baf0c0127a9");
+    }
+
+    #[test]
+    fn aead_known_value() {
+        // RFC 8439, section 2.8.2.
+        let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+            only one tip for the future, sunscreen would be it.";
+        let key: [u8; 32] = counting(0x80, 32).try_into().unwrap();
+        let nonce = [
+            0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+        ];
+
+        let mut text = plaintext.to_vec();
+        let mut sealer = Aead::new(&key, &nonce, &aad);
+        let (head, tail) = text.split_at_mut(50);
+        sealer.encrypt(head);
+        sealer.encrypt(tail);
+        assert_eq!(hex(&text[..16]), "d31a8d34648e60db7b86afbc53ef7ec2");
+        assert_eq!(hex(&sealer.tag()), "1ae10b594f09e26a7e902ecbd0600691");
+
+        let mut opener = Aead::new(&key, &nonce, &aad);
+        opener.decrypt(&mut text);
+        assert_eq!(text, plaintext);
+        assert_eq!(hex(&opener.tag()), "1ae10b594f09e26a7e902ecbd0600691");
+    }
+
+    fn key(byte: u8) -> Secret {
+        Secret::KeyFile(vec![byte; 32])
+    }
+
+    fn decoder(secret: Secret) -> DecoderOptions {
+        DecoderOptions {
+            secret: Some(secret),
+            ..Default::default()
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        b"confidential export, row 1234: amount 56.78\n".repeat(500)
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let data = sample();
+        let options = EncoderOptions {
+            index_interval: Some(4096),
+            encrypt: Some(key(1)),
+            ..Default::default()
+        };
+        let (dir, cmpr) = compress_temp("crypto", &data, &options);
+        let bytes = std::fs::read(&cmpr).unwrap();
+        let header = format::read_header(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(header.method, format::METHOD_ENCRYPTED);
+        assert!(!bytes.windows(12).any(|w| w == b"confidential"));
+
+        let out = dir.join("out.bin");
+        decompress::decompress_with_options(&cmpr, &out, &decoder(key(1))).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+
+        let err = decompress::decompress(&cmpr, &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("encrypted"));
+    }
+
+    fn decode_error(cmpr: &Path, secret: Secret) -> CorruptionKind {
+        let err = decompress::verify(cmpr, &decoder(secret)).unwrap_err();
+        DecodeError::from_io(&err).unwrap().kind
+    }
+
+    #[test]
+    fn wrong_key_and_tampering_fail_authentication() {
+        let (_dir, cmpr) = compress_temp(
+            "crypto",
+            &sample(),
+            &EncoderOptions {
+                encrypt: Some(key(1)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            decode_error(&cmpr, key(2)),
+            CorruptionKind::AuthenticationFailed
+        );
+
+        let original = std::fs::read(&cmpr).unwrap();
+        let header = format::read_header(&mut Cursor::new(&original)).unwrap();
+        let header_len = header.header_len();
+
+        // A tampered header: more iterations, with the header CRC fixed up.
+        let mut bytes = original.clone();
+        bytes[format::FIXED_HEADER_SIZE_V3 as usize + 2] = 2;
+        format::update_header_crc(&mut Cursor::new(&mut bytes), header_len).unwrap();
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert_eq!(
+            decode_error(&cmpr, key(1)),
+            CorruptionKind::AuthenticationFailed
+        );
+
+        // Tampered ciphertext, with the CRC-32 fixed up.
+        let mut bytes = original;
+        let end = bytes.len() - 4;
+        bytes[header_len as usize + 10] ^= 1;
+        let crc = format::crc32(&bytes[header_len as usize..end]);
+        bytes[end..].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert_eq!(
+            decode_error(&cmpr, key(1)),
+            CorruptionKind::AuthenticationFailed
+        );
+    }
+
+    #[test]
+    fn damage_is_a_crc_mismatch() {
+        let (_dir, cmpr) = compress_temp(
+            "crypto",
+            &sample(),
+            &EncoderOptions {
+                encrypt: Some(key(1)),
+                ..Default::default()
+            },
+        );
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        bytes[100] ^= 1;
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert_eq!(decode_error(&cmpr, key(1)), CorruptionKind::CrcMismatch);
+    }
+
+    #[test]
+    fn parity_protects_the_container() {
+        let data = sample();
+        let (dir, cmpr) = compress_temp(
+            "crypto",
+            &data,
+            &EncoderOptions {
+                parity: true,
+                encrypt: Some(key(1)),
+                ..Default::default()
+            },
+        );
+        let mut file = File::open(&cmpr).unwrap();
+        let header = format::read_header(&mut file).unwrap();
+        assert_eq!(header.flags, format::FLAG_PARITY);
+        assert_eq!(
+            EncryptionParams::parse(&header.table).unwrap().iterations,
+            1
+        );
+        assert!(parity::check(&mut file).unwrap().unwrap().is_clean());
+
+        let out = dir.join("out.bin");
+        decompress::decompress_with_options(&cmpr, &out, &decoder(key(1))).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    #[test]
+    fn range_of_encrypted_file() {
+        let data = sample();
+        let (dir, cmpr) = compress_temp(
+            "crypto",
+            &data,
+            &EncoderOptions {
+                encrypt: Some(key(1)),
+                ..Default::default()
+            },
+        );
+        let out = dir.join("range.bin");
+        decompress::decompress_range_with_options(&cmpr, &out, 5000, 300, &decoder(key(1)))
+            .unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), &data[5000..5300]);
+
+        // A failed range leaves the output as it was.
+        std::fs::write(&out, b"kept").unwrap();
+        let err =
+            decompress::decompress_range_with_options(&cmpr, &out, 5000, 300, &decoder(key(2)))
+                .unwrap_err();
+        let kind = DecodeError::from_io(&err).unwrap().kind;
+        assert_eq!(kind, CorruptionKind::AuthenticationFailed);
+        assert_eq!(std::fs::read(&out).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn failed_encryption_keeps_the_output() {
+        let (dir, cmpr) = compress_temp("crypto", &sample(), &EncoderOptions::default());
+        let before = std::fs::read(&cmpr).unwrap();
+        let options = EncoderOptions {
+            encrypt: Some(key(1)),
+            ..Default::default()
+        };
+        let err =
+            compress::compress_with_options(&dir.join("missing"), &cmpr, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(std::fs::read(&cmpr).unwrap(), before);
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    fn mac_key(byte: u8) -> MacKey {
+        MacKey::new(vec![byte; 32]).unwrap()
+    }
+
+    fn mac_decoder(key: MacKey) -> DecoderOptions {
+        DecoderOptions {
+            mac_key: Some(key),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hmac_round_trip() {
+        let data = sample();
+        let options = EncoderOptions {
+            index_interval: Some(4096),
+            parity: true,
+            mac_key: Some(mac_key(1)),
+            ..Default::default()
+        };
+        let (dir, cmpr) = compress_temp("crypto", &data, &options);
+        let mut file = File::open(&cmpr).unwrap();
+        let header = format::read_header(&mut file).unwrap();
+        assert_eq!(
+            header.flags,
+            format::FLAG_INDEX | format::FLAG_PARITY | format::FLAG_HMAC
+        );
+        let tag_offset = decompress::container_len(&header, &file).unwrap();
+        let layout = parity::ParityLayout::read(&mut file).unwrap().unwrap();
+        assert_eq!(layout.protected_len, tag_offset + MAC_SIZE);
+        assert!(parity::check(&mut file).unwrap().unwrap().is_clean());
+
+        let out = dir.join("out.bin");
+        decompress::decompress_with_options(&cmpr, &out, &mac_decoder(mac_key(1))).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+        let mut range = Vec::new();
+        decompress::decompress_range_to_writer(&cmpr, 10_000, 100, &mut range).unwrap();
+        assert_eq!(range, &data[10_000..10_100]);
+        let mut range = Vec::new();
+        let options = mac_decoder(mac_key(1));
+        decompress::decompress_range_to_writer_with_options(
+            &cmpr, 10_000, 100, &mut range, &options,
+        )
+        .unwrap();
+        assert_eq!(range, &data[10_000..10_100]);
+        let options = mac_decoder(mac_key(2));
+        let err = decompress::decompress_range_to_writer_with_options(
+            &cmpr,
+            10_000,
+            100,
+            &mut Vec::new(),
+            &options,
+        )
+        .unwrap_err();
+        let kind = DecodeError::from_io(&err).unwrap().kind;
+        assert_eq!(kind, CorruptionKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn hmac_detects_tampering() {
+        let (_dir, cmpr) = compress_temp(
+            "crypto",
+            &sample(),
+            &EncoderOptions {
+                mac_key: Some(mac_key(1)),
+                ..Default::default()
+            },
+        );
+        let mac_error = |key: MacKey| {
+            let err = decompress::verify(&cmpr, &mac_decoder(key)).unwrap_err();
+            DecodeError::from_io(&err).unwrap().kind
+        };
+        assert_eq!(mac_error(mac_key(2)), CorruptionKind::AuthenticationFailed);
+
+        // Change a payload byte and fix up the CRC-32, as a forger would.
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        let header = format::read_header(&mut Cursor::new(&bytes)).unwrap();
+        let header_len = header.header_len() as usize;
+        let crc_end = bytes.len() - MAC_SIZE as usize;
+        bytes[header_len + 10] ^= 1;
+        let crc = format::crc32(&bytes[header_len..crc_end - 4]);
+        bytes[crc_end - 4..crc_end].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert_eq!(mac_error(mac_key(1)), CorruptionKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn hmac_key_and_tag_go_together() {
+        let data = sample();
+        let (dir, tagged) = compress_temp(
+            "crypto",
+            &data,
+            &EncoderOptions {
+                mac_key: Some(mac_key(1)),
+                ..Default::default()
+            },
+        );
+        let err = decompress::verify(&tagged, &DecoderOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("HMAC"));
+
+        // Stripping the tag and its flag leaves a valid file, which a
+        // decoder with a key must still refuse.
+        let mut bytes = std::fs::read(&tagged).unwrap();
+        bytes.truncate(bytes.len() - MAC_SIZE as usize);
+        let header_len = format::read_header(&mut Cursor::new(&bytes))
+            .unwrap()
+            .header_len();
+        bytes[format::FLAGS_OFFSET as usize] = 0;
+        format::update_header_crc(&mut Cursor::new(&mut bytes), header_len).unwrap();
+        let stripped = dir.join("stripped.cmpr");
+        std::fs::write(&stripped, &bytes).unwrap();
+        decompress::verify(&stripped, &DecoderOptions::default()).unwrap();
+        let err = decompress::verify(&stripped, &mac_decoder(mac_key(1))).unwrap_err();
+        assert_eq!(
+            DecodeError::from_io(&err).unwrap().kind,
+            CorruptionKind::AuthenticationFailed
+        );
+    }
+
+    #[test]
+    fn hmac_tags_the_sealed_file() {
+        let data = sample();
+        let (dir, cmpr) = compress_temp(
+            "crypto",
+            &data,
+            &EncoderOptions {
+                encrypt: Some(key(1)),
+                mac_key: Some(mac_key(2)),
+                ..Default::default()
+            },
+        );
+        let header = format::read_header(&mut File::open(&cmpr).unwrap()).unwrap();
+        assert_eq!(header.flags, 0);
+
+        let options = DecoderOptions {
+            mac_key: Some(mac_key(2)),
+            ..decoder(key(1))
+        };
+        let out = dir.join("out.bin");
+        decompress::decompress_with_options(&cmpr, &out, &options).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+        let err = decompress::verify(&cmpr, &decoder(key(1))).unwrap_err();
+        assert!(err.to_string().contains("HMAC"));
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let params = EncryptionParams {
+            iterations: 1234,
+            salt: [7; SALT_SIZE],
+            nonce: [9; NONCE_SIZE],
+        };
+        let bytes = params.to_bytes();
+        assert_eq!(bytes.len(), PARAMS_SIZE);
+        assert_eq!(EncryptionParams::parse(&bytes).unwrap(), params);
+
+        let mut bad = bytes.clone();
+        bad[0] = 2;
+        assert!(EncryptionParams::parse(&bad).is_err());
+        let mut bad = bytes;
+        bad[2..6].copy_from_slice(&0u32.to_le_bytes());
+        assert!(EncryptionParams::parse(&bad).is_err());
+    }
+}
@@ -1,257 +1,1014 @@
 use std::fs::File;
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::ans::AnsCoder;
+use crate::coder::{EntropyCoder, StoredCoder};
+use crate::crypto::{self, MacKey, Secret, TempFile};
+use crate::error::{self, CorruptionKind, DecodeError};
+use crate::format::{self, Header};
+use crate::huffman::{Huffman4Coder, HuffmanCoder, HuffmanDecoder};
+use crate::index::{self, SeekIndex, SidecarKey};
+use crate::parity;
+use crate::segment;
+use crate::speculative;
+use crate::volume;
+
+/// Options controlling how a file is decompressed.
+///
+/// Independently of these limits, a file is refused if its payload is too
+/// short to decode to the original size in its header (see
+/// [`EntropyCoder::min_payload_len`]).
+#[derive(Debug, Clone, Default)]
+pub struct DecoderOptions {
+    /// Refuse files whose original size exceeds this many bytes.
+    pub max_output_bytes: Option<u64>,
+    /// Refuse files whose original size exceeds this multiple of the
+    /// compressed file size.
+    pub max_ratio: Option<u64>,
+    /// Decode on up to this many threads (see [`decompress_parallel`]).
+    /// 0 and 1 both decode on the calling thread.
+    pub threads: usize,
+    /// Accept bytes between the end of the bitstream and the end of the
+    /// payload instead of refusing the file.
+    pub ignore_trailing: bool,
+    /// Check the CRC-32 of the compressed data before decoding, so that
+    /// nothing is written from a file whose payload is corrupt.  Meant for
+    /// stdout: output files are always verified before they replace the
+    /// target.  Version 0x01 files have no CRC-32 to check.
+    pub verify_first: bool,
+    /// Key for encrypted files (see [`crate::crypto`]).  They are decrypted
+    /// and authenticated into a temporary file before anything is decoded.
+    pub secret: Option<Secret>,
+    /// Key for HMAC-SHA-256 tags (see [`crypto::MAC_SIZE`]).  With a key,
+    /// only files whose tag matches are decoded; without one, files with a
+    /// tag are refused.  The tag is checked before anything is decoded.
+    pub mac_key: Option<MacKey>,
+}
 
-use crate::bitio::BitReader;
-use crate::format;
-use crate::huffman::MAX_CODE_LENGTH;
+/// Decompresses `input_path` (a `.cmpr` file) to `output_path`.
+///
+/// The output is written to a temporary file next to `output_path` and
+/// renamed over it only once decoding and verification succeed, so a
+/// corrupt file never leaves partial or wrong output behind.
+pub fn decompress(input_path: &Path, output_path: &Path) -> std::io::Result<()> {
+    decompress_with_options(input_path, output_path, &DecoderOptions::default())
+}
 
-/// Number of bits to use for the fast-prefix lookup table.
-const LUT_BITS: u8 = 11;
+/// Like [`decompress`], but with explicit [`DecoderOptions`].
+///
+/// Limits are checked against the header before anything is written.
+pub fn decompress_with_options(
+    input_path: &Path,
+    output_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let joined = volume::join_if_volume(input_path)?;
+    let input_path = joined.as_ref().map_or(input_path, TempFile::path);
+    if options.threads > 1 && !segment::is_segmented(input_path)? {
+        let (input_path, _temps) = open_container(input_path, options)?;
+        let input_path = input_path.as_path();
+        return write_atomically(output_path, |temp_path| {
+            decompress_threaded(input_path, temp_path, options)
+        });
+    }
+    write_atomically(output_path, |temp_path| {
+        let mut writer = BufWriter::new(File::create(temp_path)?);
+        decode_segments(input_path, &mut writer, options)?;
+        writer.flush()
+    })
+}
 
-/// Number of entries in the prefix lookup table.
-const LUT_SIZE: usize = 1 << LUT_BITS as usize;
+/// Decrypts `input_path` into a temporary file if it is encrypted, then
+/// checks the HMAC-SHA-256 tag.  Returns the `.cmpr` file to decode and the
+/// temporary files, which must be kept until decoding is done.
+fn open_container(
+    input_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<(PathBuf, Vec<TempFile>)> {
+    let mut path = input_path.to_path_buf();
+    let mut temps = Vec::new();
+    if let Some(sealed) = crypto::open_if_encrypted(&path, options)? {
+        path = sealed.path().to_path_buf();
+        temps.push(sealed);
+    }
+    crypto::verify_mac(&path, options)?;
+    Ok((path, temps))
+}
 
-/// Compressed data structure for efficient canonical decoding.
+/// Decodes `input_path`, which may have appended segments (see
+/// [`crate::segment`]), on the calling thread and writes the output to
+/// `writer`.  Each segment is decoded and verified as a file of its own;
+/// with [`verify_first`](DecoderOptions::verify_first), every segment is
+/// checked before anything is written.  Limits apply to the output as a
+/// whole.
+fn decode_segments<W: Write>(
+    input_path: &Path,
+    writer: &mut W,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let segments = match segment::read_segments(&mut File::open(input_path)?)? {
+        Some(segments) => segments,
+        None => {
+            let (input_path, _temps) = open_container(input_path, options)?;
+            return decode_to_writer(&input_path, writer, decode_builtin, options);
+        }
+    };
+    segment::check_mac(&mut File::open(input_path)?, options.mac_key.as_ref())?;
+    if options.verify_first {
+        for segment in &segments {
+            segment::with_segment(input_path, segment, |path| {
+                let (path, _temps) = open_container(path, options)?;
+                let mut input = File::open(&path)?;
+                let header = format::read_header(&mut input)?;
+                verify_crc(&header, &mut input)
+            })
+            .map_err(|e| error::in_segment(e, segment.offset, 0))?;
+        }
+    }
+    let mut written = 0u64;
+    for segment in &segments {
+        let remaining = DecoderOptions {
+            max_output_bytes: options
+                .max_output_bytes
+                .map(|max| max.saturating_sub(written)),
+            ..options.clone()
+        };
+        written += segment::with_segment(input_path, segment, |path| {
+            let (path, _temps) = open_container(path, options)?;
+            decode_to_writer(&path, writer, decode_builtin, &remaining)?;
+            Ok(format::read_header(&mut File::open(&path)?)?.original_size)
+        })
+        .map_err(|e| error::in_segment(e, segment.offset, written))?;
+    }
+    Ok(())
+}
+
+/// [`decompress_with_options`] on the calling thread, writing straight to
+/// `output_path`.
+fn decompress_sequential(
+    input_path: &Path,
+    output_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+    decode_to_writer(input_path, &mut writer, decode_builtin, options)
+}
+
+/// Decompresses `input_path` (a `.cmpr` file) to stdout.
+pub fn decompress_to_stdout(input_path: &Path) -> std::io::Result<()> {
+    decompress_to_stdout_with_options(input_path, &DecoderOptions::default())
+}
+
+/// Like [`decompress_to_stdout`], but with explicit [`DecoderOptions`].
+/// Output to stdout is always decoded on the calling thread.
 ///
-/// Decoding uses a two-level strategy:
-/// 1. Fast path: a prefix lookup table maps the first `LUT_BITS` bits to
-///    `(symbol, code_length)` for all codes of length ≤ `LUT_BITS`.
-/// 2. Fallback: for codes longer than `LUT_BITS`, a linear scan grouped by
-///    bit length is used (as before).
-struct HuffmanDecoder {
-    /// Fast-prefix lookup table: maps an `LUT_BITS`-bit prefix to
-    /// `Some((symbol, code_len))` if the prefix uniquely identifies a
-    /// code, or `None` if the prefix is ambiguous (code longer than
-    /// `LUT_BITS`).
-    lookup: [Option<(u8, u8)>; LUT_SIZE],
-    /// Fallback: codes grouped by length for codes longer than `LUT_BITS`.
-    codes_by_len: [Vec<(u64, u8)>; MAX_CODE_LENGTH as usize + 1],
-}
-
-impl HuffmanDecoder {
-    /// Builds a decoder from a symbol table.
-    ///
-    /// `symbol_table` is a list of `(byte_value, code_length)` pairs sorted by
-    /// byte value, as produced by the compressor.
-    ///
-    /// Returns an `InvalidData` error if any code length is 0 or exceeds
-    /// [`MAX_CODE_LENGTH`].
-    fn new(symbol_table: &[(u8, u8)]) -> std::io::Result<Self> {
-        // Validate code lengths.
-        for &(byte, len) in symbol_table {
-            if len == 0 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Byte 0x{byte:02X} has zero-length code"),
-                ));
-            }
-            if len > MAX_CODE_LENGTH {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!(
-                        "Byte 0x{byte:02X} code length {len} exceeds maximum of {MAX_CODE_LENGTH}"
-                    ),
-                ));
-            }
+/// Decoded bytes are written as they are produced, so a corrupt file may
+/// emit some output before the error; set
+/// [`verify_first`](DecoderOptions::verify_first) to check the CRC-32
+/// beforehand.
+pub fn decompress_to_stdout_with_options(
+    input_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let joined = volume::join_if_volume(input_path)?;
+    let input_path = joined.as_ref().map_or(input_path, TempFile::path);
+    let stdout = std::io::stdout();
+    let mut writer = stdout.lock();
+    decode_segments(input_path, &mut writer, options)
+}
+
+/// Like [`decompress`], but decodes the payload with an arbitrary
+/// [`EntropyCoder`], including ones defined outside this crate.
+///
+/// Fails with `InvalidData` if the file was written by a different method.
+pub fn decompress_with<C: EntropyCoder>(
+    input_path: &Path,
+    output_path: &Path,
+) -> std::io::Result<()> {
+    write_atomically(output_path, |temp_path| {
+        let output_file = File::create(temp_path)?;
+        let mut writer = BufWriter::new(output_file);
+        decode_to_writer(
+            input_path,
+            &mut writer,
+            decode_payload::<C, _>,
+            &DecoderOptions::default(),
+        )
+    })
+}
+
+/// Checks `input_path` without writing anything: decodes it with `options`
+/// and verifies it exactly as [`decompress_with_options`] would.  Always
+/// decodes on the calling thread.
+pub fn verify(input_path: &Path, options: &DecoderOptions) -> std::io::Result<()> {
+    let joined = volume::join_if_volume(input_path)?;
+    let input_path = joined.as_ref().map_or(input_path, TempFile::path);
+    decode_segments(input_path, &mut std::io::sink(), options)
+}
+
+// ---------------------------------------------------------------------------
+// Random access
+// ---------------------------------------------------------------------------
+
+/// A range of the original (uncompressed) data, written `START:LEN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// Offset of the first byte.
+    pub start: u64,
+    /// Number of bytes.
+    pub len: u64,
+}
+
+impl FromStr for ByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .split_once(':')
+            .and_then(|(start, len)| Some((start.parse().ok()?, len.parse().ok()?)));
+        match parsed {
+            Some((start, len)) => Ok(ByteRange { start, len }),
+            None => Err(format!("invalid range `{s}` (expected START:LEN)")),
         }
+    }
+}
 
-        // symbol_table entries are (byte, code_len) from the file header.
-        // Sort by (code_len, byte_value) for canonical ordering.
-        let mut symbols: Vec<(u8, u8)> = symbol_table.to_vec();
-        symbols.sort_by_key(|&(byte, len)| (len, byte));
-
-        // Count how many symbols have each code length.
-        let mut count_by_len = [0u16; MAX_CODE_LENGTH as usize + 1];
-        for &(_, len) in &symbols {
-            count_by_len[len as usize] += 1;
-        }
-
-        // Compute starting code for each length using the canonical formula.
-        let mut next_code = [0u64; MAX_CODE_LENGTH as usize + 1];
-        let mut code = 0u64;
-        for len in 1..=MAX_CODE_LENGTH as usize {
-            code = (code + count_by_len[len - 1] as u64) << 1;
-            next_code[len] = code;
-        }
-
-        // Assign canonical codes, group by length.
-        let mut codes_by_len: [Vec<(u64, u8)>; MAX_CODE_LENGTH as usize + 1] =
-            array_init();
-        for &(byte, len) in &symbols {
-            let len = len as usize;
-            let c = next_code[len];
-            codes_by_len[len].push((c, byte));
-            next_code[len] = c.wrapping_add(1);
-        }
-
-        // Build the prefix lookup table for codes up to LUT_BITS long.
-        let mut lookup: [Option<(u8, u8)>; LUT_SIZE] = [None; LUT_SIZE];
-        for len in 1..=LUT_BITS as usize {
-            for &(code, symbol) in &codes_by_len[len] {
-                // Shift the code to fill LUT_BITS bits, then fill all
-                // suffix combinations.
-                let shift = LUT_BITS as u64 - len as u64;
-                let base = code << shift;
-                let count = 1u64 << shift;
-                for i in 0..count {
-                    lookup[(base | i) as usize] = Some((symbol, len as u8));
-                }
-            }
+/// Decompresses `len` bytes of `input_path` starting at uncompressed
+/// `offset`.
+///
+/// Files written with a seek index are decoded from the nearest checkpoint
+/// at or before `offset`; stored payloads are read in place; anything else
+/// is decoded from the start and the range cut out.  The CRC-32 is not
+/// verified, as that would mean reading the whole payload.
+pub fn decompress_range(input_path: &Path, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decompress_range_to_writer(input_path, offset, len, &mut out)?;
+    Ok(out)
+}
+
+/// Decompresses `len` bytes of `input_path` starting at uncompressed
+/// `offset` to `output_path`, with explicit [`DecoderOptions`].
+///
+/// Volumes are joined, encrypted files decrypted and HMAC-SHA-256 tags
+/// checked as for [`decompress_with_options`]; the other fields of
+/// `options` do not apply, as the range bounds the output.  Like a whole
+/// file, the range is written to a temporary file that replaces
+/// `output_path` only once decoding succeeds.
+pub fn decompress_range_with_options(
+    input_path: &Path,
+    output_path: &Path,
+    offset: u64,
+    len: u64,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    write_atomically(output_path, |temp_path| {
+        let mut writer = BufWriter::new(File::create(temp_path)?);
+        decompress_range_to_writer_with_options(input_path, offset, len, &mut writer, options)
+    })
+}
+
+/// Like [`decompress_range_with_options`], but writes the range to
+/// `writer`.
+pub fn decompress_range_to_writer_with_options<W: Write>(
+    input_path: &Path,
+    offset: u64,
+    len: u64,
+    writer: &mut W,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let joined = volume::join_if_volume(input_path)?;
+    let input_path = joined.as_ref().map_or(input_path, TempFile::path);
+    refuse_segments(&mut File::open(input_path)?, "Random access")?;
+    let (input_path, _temps) = open_container(input_path, options)?;
+    decompress_range_to_writer(&input_path, offset, len, writer)
+}
+
+/// Like [`decompress_range`], but writes the range to `writer`.
+pub fn decompress_range_to_writer<W: Write>(
+    input_path: &Path,
+    offset: u64,
+    len: u64,
+    writer: &mut W,
+) -> std::io::Result<()> {
+    let mut input = File::open(input_path)?;
+    refuse_segments(&mut input, "Random access")?;
+    let header = format::read_header(&mut input)?;
+    if offset
+        .checked_add(len)
+        .is_none_or(|end| end > header.original_size)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Range {offset}:{len} is outside the {}-byte original",
+                header.original_size
+            ),
+        ));
+    }
+    let index = load_index(input_path, &mut input, &header)?;
+
+    let range = ByteRange { start: offset, len };
+    let index = index.as_ref();
+    match header.method {
+        format::METHOD_HUFFMAN => {
+            decode_range::<HuffmanCoder, W>(&header, index, &mut input, writer, range)
+        }
+        format::METHOD_ANS => {
+            decode_range::<AnsCoder, W>(&header, index, &mut input, writer, range)
+        }
+        format::METHOD_STORED => {
+            decode_range::<StoredCoder, W>(&header, index, &mut input, writer, range)
         }
+        format::METHOD_HUFFMAN4 => {
+            decode_range::<Huffman4Coder, W>(&header, index, &mut input, writer, range)
+        }
+        method => Err(unsupported_method(method)),
+    }
+}
 
-        Ok(HuffmanDecoder {
-            lookup,
-            codes_by_len,
-        })
+/// Decodes `range` with `C`, starting from the best available position.
+fn decode_range<C: EntropyCoder, W: Write>(
+    header: &Header,
+    index: Option<&SeekIndex>,
+    input: &mut File,
+    writer: &mut W,
+    range: ByteRange,
+) -> std::io::Result<()> {
+    let coder = read_coder::<C>(header, input)?;
+    let payload_start = header.header_len();
+    let end = range.start + range.len;
+
+    let checkpoint = match index {
+        Some(index) => Some(index.checkpoint_for(range.start)),
+        None if C::METHOD == format::METHOD_STORED => Some((range.start, range.start * 8)),
+        None => None,
+    };
+    match checkpoint {
+        Some((start, bit_offset)) => {
+            input.seek(SeekFrom::Start(payload_start + bit_offset / 8))?;
+            let mut out = RangeWriter {
+                inner: writer,
+                skip: range.start - start,
+                take: range.len,
+            };
+            coder
+                .decode_at(input, &mut out, (bit_offset % 8) as u8, end - start)
+                .map_err(|e| {
+                    error::in_file(error::shift(e, bit_offset & !7, start), payload_start)
+                })?;
+        }
+        None => {
+            input.seek(SeekFrom::Start(payload_start))?;
+            let mut out = RangeWriter {
+                inner: writer,
+                skip: range.start,
+                take: range.len,
+            };
+            coder
+                .decode(input, &mut out, header.original_size)
+                .map_err(|e| error::in_file(e, payload_start))?;
+        }
+    }
+    writer.flush()
+}
+
+/// Forwards only the `take` bytes after the first `skip` written to it.
+struct RangeWriter<'a, W: Write> {
+    inner: &'a mut W,
+    skip: u64,
+    take: u64,
+}
+
+impl<W: Write> Write for RangeWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let skipped = buf.len().min(self.skip.try_into().unwrap_or(usize::MAX));
+        self.skip -= skipped as u64;
+        let rest = &buf[skipped..];
+        let taken = rest.len().min(self.take.try_into().unwrap_or(usize::MAX));
+        self.inner.write_all(&rest[..taken])?;
+        self.take -= taken as u64;
+        Ok(buf.len())
     }
 
-    /// Reads one byte from the bit stream.
-    ///
-    /// Returns `None` if the reader reaches EOF before a complete code is read.
-    fn decode_byte<R: Read>(&self, reader: &mut BitReader<R>) -> std::io::Result<Option<u8>> {
-        // Fast path: try the prefix lookup table.
-        match reader.peek_bits(LUT_BITS)? {
-            Some(prefix) => {
-                if let Some((symbol, code_len)) = self.lookup[prefix as usize] {
-                    reader.consume_bits(code_len);
-                    return Ok(Some(symbol));
-                }
-            }
-            None => return Ok(None),
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Parallel decoding
+// ---------------------------------------------------------------------------
+
+/// Decompresses `input_path` to `output_path`, splitting the payload at
+/// seek index checkpoints and decoding the pieces on up to `threads`
+/// threads.
+///
+/// The index is read from the file itself or from its sidecar (see
+/// [`write_sidecar_index`]).  Plain Huffman files without an index are
+/// decoded speculatively instead (see [`speculative`]).  Other files, and
+/// any file with `threads` below 2, are decoded as by [`decompress`].  The
+/// CRC-32 is always verified.
+pub fn decompress_parallel(
+    input_path: &Path,
+    output_path: &Path,
+    threads: usize,
+) -> std::io::Result<()> {
+    let options = DecoderOptions {
+        threads,
+        ..Default::default()
+    };
+    decompress_with_options(input_path, output_path, &options)
+}
+
+/// [`decompress_with_options`] for more than one thread.
+fn decompress_threaded(
+    input_path: &Path,
+    output_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let threads = options.threads;
+    let mut input = File::open(input_path)?;
+    let header = format::read_header(&mut input)?;
+    check_limits(&header, &input, options)?;
+    let index = match load_index(input_path, &mut input, &header)? {
+        Some(index) if !index.checkpoints.is_empty() => index,
+        // The speculative decoder needs the bitstream to end exactly where
+        // the padding says.
+        None if header.method == format::METHOD_HUFFMAN && !options.ignore_trailing => {
+            return decode_speculative(input_path, &header, output_path, threads);
+        }
+        _ => {
+            return decompress_sequential(input_path, output_path, options);
         }
+    };
 
-        // Fallback: linear scan for codes longer than LUT_BITS.
-        // Read the first LUT_BITS bits into the accumulator, then continue
-        // bit-by-bit checking against codes_by_len for longer lengths.
-        let mut value = 0u64;
-        for _ in 0..LUT_BITS {
-            match reader.read_bit()? {
-                Some(bit) => value = (value << 1) | u64::from(bit),
-                None => return Ok(None),
-            }
+    let parts = split_index(&index, header.original_size, threads)?;
+    let end = match header.method {
+        format::METHOD_HUFFMAN => {
+            decode_parallel::<HuffmanCoder>(input_path, &header, output_path, &parts)
         }
-        for len in (LUT_BITS as usize + 1)..=MAX_CODE_LENGTH as usize {
-            match reader.read_bit()? {
-                Some(bit) => {
-                    value = (value << 1) | u64::from(bit);
-                    for &(code, symbol) in &self.codes_by_len[len] {
-                        if code == value {
-                            return Ok(Some(symbol));
-                        }
-                    }
-                }
-                None => return Ok(None),
-            }
+        format::METHOD_ANS => decode_parallel::<AnsCoder>(input_path, &header, output_path, &parts),
+        format::METHOD_STORED => {
+            decode_parallel::<StoredCoder>(input_path, &header, output_path, &parts)
+        }
+        format::METHOD_HUFFMAN4 => {
+            decode_parallel::<Huffman4Coder>(input_path, &header, output_path, &parts)
         }
+        method => Err(unsupported_method(method)),
+    }?;
+    check_payload_end(&header, &mut input, Some(end), options.ignore_trailing)
+}
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Code length exceeded maximum of 32 bits",
-        ))
+/// Cuts the payload at up to `parts - 1` checkpoints into roughly equal
+/// `(uncompressed_offset, bit_offset, len)` pieces.
+///
+/// Returns an `InvalidData` error if a checkpoint is past `original_size`,
+/// which [`load_index`] already refuses.
+fn split_index(
+    index: &SeekIndex,
+    original_size: u64,
+    parts: usize,
+) -> std::io::Result<Vec<(u64, u64, u64)>> {
+    let points: Vec<(u64, u64)> = std::iter::once((0, 0))
+        .chain(index.checkpoints.iter().copied())
+        .collect();
+    let parts = parts.min(points.len());
+    let starts: Vec<(u64, u64)> = (0..parts)
+        .map(|i| points[i * points.len() / parts])
+        .collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &(start, bits))| {
+            let end = starts.get(i + 1).map_or(original_size, |&(next, _)| next);
+            let len = end.checked_sub(start).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Seek index checkpoint is past the original data",
+                )
+            })?;
+            Ok((start, bits, len))
+        })
+        .collect()
+}
+
+/// Decodes each of `parts` on its own thread straight into its place in
+/// `output_path`, checking the CRC-32 on this thread meanwhile.  The output
+/// file is only created, at its full size, once the header has passed
+/// [`read_coder`]'s checks.
+///
+/// Returns the payload bit at which the last part ended.
+fn decode_parallel<C: EntropyCoder + Sync>(
+    input_path: &Path,
+    header: &Header,
+    output_path: &Path,
+    parts: &[(u64, u64, u64)],
+) -> std::io::Result<u64> {
+    let mut input = File::open(input_path)?;
+    let coder = read_coder::<C>(header, &input)?;
+    let payload_start = header.header_len();
+    File::create(output_path)?.set_len(header.original_size)?;
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = parts
+            .iter()
+            .map(|&(start, bit_offset, len)| {
+                let coder = &coder;
+                scope.spawn(move || -> std::io::Result<u64> {
+                    let mut input = File::open(input_path)?;
+                    input.seek(SeekFrom::Start(payload_start + bit_offset / 8))?;
+                    let mut output = std::fs::OpenOptions::new().write(true).open(output_path)?;
+                    output.seek(SeekFrom::Start(start))?;
+                    let mut writer = BufWriter::new(output);
+                    let consumed = coder
+                        .decode_at(&mut input, &mut writer, (bit_offset % 8) as u8, len)
+                        .map_err(|e| {
+                            error::in_file(error::shift(e, bit_offset & !7, start), payload_start)
+                        })?;
+                    writer.flush()?;
+                    Ok(bit_offset / 8 * 8 + consumed)
+                })
+            })
+            .collect();
+
+        let crc = verify_crc(header, &mut input);
+        let mut end = 0;
+        for worker in workers {
+            end = worker.join().map_err(|_| worker_panicked())??;
+        }
+        crc.map(|()| end)
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Sidecar indexes
+// ---------------------------------------------------------------------------
+
+/// Decodes `input_path` once and returns a seek index with a checkpoint
+/// every `interval` uncompressed bytes.
+///
+/// Works for any file whose coder supports seek indexes, including v0x02
+/// files, which cannot embed one.  The CRC-32 is verified as well.
+pub fn build_index(input_path: &Path, interval: u64) -> std::io::Result<SeekIndex> {
+    if interval == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Index interval must be at least 1 byte",
+        ));
     }
+    let mut input = File::open(input_path)?;
+    refuse_segments(&mut input, "Indexing")?;
+    let header = format::read_header(&mut input)?;
+    let bit_offsets = match header.method {
+        format::METHOD_HUFFMAN => scan_payload::<HuffmanCoder>(&header, &mut input, interval),
+        format::METHOD_ANS => scan_payload::<AnsCoder>(&header, &mut input, interval),
+        format::METHOD_STORED => scan_payload::<StoredCoder>(&header, &mut input, interval),
+        format::METHOD_HUFFMAN4 => scan_payload::<Huffman4Coder>(&header, &mut input, interval),
+        method => Err(unsupported_method(method)),
+    }?;
+    verify_crc(&header, &mut input)?;
+    SeekIndex::from_interval(interval, &bit_offsets).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Index interval is too large",
+        )
+    })
 }
 
-/// Helper to create an array of empty Vecs (since `[Vec; 33]` doesn't
-/// implement `Default` for arbitrary sizes).
-fn array_init<T, const N: usize>() -> [Vec<T>; N] {
-    [(); N].map(|_| Vec::new())
+/// Builds an index with [`build_index`] and writes it to the sidecar file
+/// next to `input_path` (`<input_path>.idx`), which
+/// [`decompress_range`] and [`decompress_parallel`] then pick up.
+///
+/// Returns the index that was written.
+pub fn write_sidecar_index(input_path: &Path, interval: u64) -> std::io::Result<SeekIndex> {
+    let index = build_index(input_path, interval)?;
+    let key = SidecarKey::of_file(&mut File::open(input_path)?)?;
+    let path = index::sidecar_path(input_path);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    index.write_sidecar(&mut writer, key)?;
+    Ok(index)
 }
 
-/// Decompresses `input_path` (a `.cmpr` file) to `output_path`.
-pub fn decompress(input_path: &Path, output_path: &Path) -> std::io::Result<()> {
-    let output_file = File::create(output_path)?;
-    let mut writer = BufWriter::new(output_file);
-    decode_to_writer(input_path, &mut writer)
+fn scan_payload<C: EntropyCoder>(
+    header: &Header,
+    input: &mut File,
+    interval: u64,
+) -> std::io::Result<Vec<u64>> {
+    let coder = read_coder::<C>(header, input)?;
+    coder.scan_index(input, header.original_size, interval)
 }
 
-/// Decompresses `input_path` (a `.cmpr` file) to stdout.
-pub fn decompress_to_stdout(input_path: &Path) -> std::io::Result<()> {
-    let stdout = std::io::stdout();
-    let mut writer = stdout.lock();
-    decode_to_writer(input_path, &mut writer)
+// ---------------------------------------------------------------------------
+// Shared internal helpers
+// ---------------------------------------------------------------------------
+
+/// Refuses files with appended segments in operations that only know about
+/// a single `.cmpr` file.
+pub(crate) fn refuse_segments(input: &mut File, operation: &str) -> std::io::Result<()> {
+    if segment::read_segments(input)?.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("{operation} is not supported for files with appended segments"),
+        ));
+    }
+    Ok(())
+}
+
+/// The error for a decode thread that panicked.
+pub(crate) fn worker_panicked() -> std::io::Error {
+    std::io::Error::other("Decode thread panicked")
+}
+
+/// Runs `write` on a temporary path next to `output_path` and renames the
+/// result over `output_path` if it succeeds.  On failure the temporary file
+/// is removed and `output_path` is left as it was.
+pub(crate) fn write_atomically<T, F>(output_path: &Path, write: F) -> std::io::Result<T>
+where
+    F: FnOnce(&Path) -> std::io::Result<T>,
+{
+    let name = output_path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Output path `{}` has no file name", output_path.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = output_path.with_file_name(temp_name);
+
+    let result = write(&temp_path)
+        .and_then(|value| std::fs::rename(&temp_path, output_path).map(|()| value));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Decodes the payload with the built-in coder selected by the header's
+/// method byte.  Returns the number of payload bits consumed, if the coder
+/// counts them.
+fn decode_builtin<W: Write>(
+    header: &Header,
+    input: &mut File,
+    writer: &mut W,
+) -> std::io::Result<Option<u64>> {
+    match header.method {
+        format::METHOD_HUFFMAN => decode_payload::<HuffmanCoder, W>(header, input, writer),
+        format::METHOD_ANS => decode_payload::<AnsCoder, W>(header, input, writer),
+        format::METHOD_STORED => decode_payload::<StoredCoder, W>(header, input, writer),
+        format::METHOD_HUFFMAN4 => decode_payload::<Huffman4Coder, W>(header, input, writer),
+        method => Err(unsupported_method(method)),
+    }
+}
+
+/// The error for a method the decoder cannot handle here.  Encrypted files
+/// can only be decoded with a key, through [`DecoderOptions::secret`].
+pub(crate) fn unsupported_method(method: u8) -> std::io::Error {
+    if method == format::METHOD_ENCRYPTED {
+        return std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "File is encrypted; a passphrase or key file is needed to decrypt it",
+        );
+    }
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Unsupported coding method {method}"),
+    )
+}
+
+/// Rebuilds `C`'s model from the header table and decodes the payload.
+fn decode_payload<C: EntropyCoder, W: Write>(
+    header: &Header,
+    input: &mut File,
+    writer: &mut W,
+) -> std::io::Result<Option<u64>> {
+    let coder = read_coder::<C>(header, input)?;
+    let payload_bits = payload_len(header, container_len(header, input)?)? * 8;
+    coder
+        .decode_counted(input, writer, header.original_size)
+        .map_err(|e| error::in_file(truncated_at_end(e, payload_bits), header.header_len()))
+}
+
+/// Reports an error the decoder found past the last payload bit, after
+/// running on into the trailer, as the truncation it is.
+fn truncated_at_end(err: std::io::Error, payload_bits: u64) -> std::io::Error {
+    match DecodeError::from_io(&err) {
+        Some(decode) if decode.bit_offset.is_some_and(|bit| bit > payload_bits) => {
+            let mut truncated = DecodeError::new(
+                CorruptionKind::Truncated,
+                "Unexpected end of compressed bitstream",
+            )
+            .at_bit(payload_bits);
+            truncated.output_bytes = decode.output_bytes;
+            truncated.into()
+        }
+        _ => err,
+    }
+}
+
+/// Checks that the file was written by `C`, rebuilds its model and checks
+/// that the payload of `input` is long enough for the original size.
+fn read_coder<C: EntropyCoder>(header: &Header, input: &File) -> std::io::Result<C> {
+    if header.method != C::METHOD {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "File uses coding method {}, expected {} ({})",
+                header.method,
+                C::METHOD,
+                C::NAME
+            ),
+        ));
+    }
+    let coder = C::read_table(&header.table)?;
+    let payload = payload_len(header, container_len(header, input)?)?;
+    if payload < coder.min_payload_len(header.original_size) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Original size of {} bytes cannot be decoded from a {payload}-byte payload",
+                header.original_size
+            ),
+        ));
+    }
+    Ok(coder)
+}
+
+/// Enforces the output size and ratio limits of `options`.
+pub(crate) fn check_limits(
+    header: &Header,
+    input: &File,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    let size = header.original_size;
+    let too_large = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    if let Some(max) = options.max_output_bytes.filter(|&max| size > max) {
+        return Err(too_large(format!(
+            "Original size of {size} bytes exceeds the {max}-byte output limit"
+        )));
+    }
+    let file_len = input.metadata()?.len();
+    if let Some(max) = options
+        .max_ratio
+        .filter(|&max| size > max.saturating_mul(file_len))
+    {
+        return Err(too_large(format!(
+            "Compression ratio of {}:1 exceeds the {max}:1 limit",
+            size / file_len.max(1)
+        )));
+    }
+    Ok(())
 }
 
 /// Shared decode implementation: reads a `.cmpr` file and writes the
-/// decompressed bytes to `writer`.  CRC-32 is verified for v0x02+ files.
-fn decode_to_writer<W: Write>(input_path: &Path, writer: &mut W) -> std::io::Result<()> {
+/// decompressed bytes to `writer`, using `decode` for the payload.  The end
+/// of the bitstream is checked, and CRC-32 is verified for v0x02+ files.
+fn decode_to_writer<W: Write>(
+    input_path: &Path,
+    writer: &mut W,
+    decode: fn(&Header, &mut File, &mut W) -> std::io::Result<Option<u64>>,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
     // ------------------------------------------------------------------
-    // 1. Read header
+    // 1. Read header and check it against the limits
     // ------------------------------------------------------------------
     let mut input_file = File::open(input_path)?;
     let header = format::read_header(&mut input_file)?;
+    check_limits(&header, &input_file, options)?;
+    if options.verify_first {
+        verify_crc(&header, &mut input_file)?;
+        input_file.seek(SeekFrom::Start(header.header_len()))?;
+    }
 
-    // Record compressed data boundaries (for CRC verification).
-    let header_size = format::FIXED_HEADER_SIZE as u64 + header.symbol_count as u64 * 2;
-    let file_len = input_file.metadata()?.len();
+    // ------------------------------------------------------------------
+    // 2. Rebuild the coder model and decode the payload
+    // ------------------------------------------------------------------
+    let consumed = decode(&header, &mut input_file, writer)?;
 
     // ------------------------------------------------------------------
-    // 2. Reconstruct canonical codes
+    // 3. Check where the bitstream ended
     // ------------------------------------------------------------------
-    let decoder = HuffmanDecoder::new(&header.symbol_table)?;
+    check_payload_end(&header, &mut input_file, consumed, options.ignore_trailing)?;
 
     // ------------------------------------------------------------------
-    // 3. Decode bitstream
+    // 4. Verify CRC-32 (v0x02+), unless already done
     // ------------------------------------------------------------------
-    {
-        let mut bit_reader = BitReader::new(&mut input_file);
-        let mut out_buf = [0u8; 4096];
-        let mut buf_pos = 0;
-        let mut decoded: u64 = 0;
-
-        while decoded < header.original_size {
-            let byte = decoder.decode_byte(&mut bit_reader)?;
-            match byte {
-                Some(b) => {
-                    out_buf[buf_pos] = b;
-                    buf_pos += 1;
-                    decoded += 1;
-
-                    if buf_pos == out_buf.len() {
-                        writer.write_all(&out_buf)?;
-                        buf_pos = 0;
-                    }
-                }
-                None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::UnexpectedEof,
-                        "Unexpected end of compressed bitstream",
-                    ));
-                }
+    if options.verify_first {
+        return Ok(());
+    }
+    verify_crc(&header, &mut input_file)
+}
+
+/// Decodes a plain Huffman payload on `threads` threads with
+/// [`speculative::decode_parallel`], then checks the CRC-32.
+fn decode_speculative(
+    input_path: &Path,
+    header: &Header,
+    output_path: &Path,
+    threads: usize,
+) -> std::io::Result<()> {
+    let mut input = File::open(input_path)?;
+    let coder = read_coder::<HuffmanCoder>(header, &input)?;
+    let decoder = HuffmanDecoder::new(&coder.code_table().symbol_table())?;
+    let payload_bits = (payload_len(header, container_len(header, &input)?)? * 8)
+        .saturating_sub(header.padding as u64);
+    let payload_start = header.header_len();
+    let open_at = |byte: u64| {
+        let mut file = File::open(input_path)?;
+        file.seek(SeekFrom::Start(payload_start + byte))?;
+        Ok(file)
+    };
+
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    speculative::decode_parallel(
+        &decoder,
+        open_at,
+        payload_bits,
+        header.original_size,
+        threads,
+        &mut writer,
+    )
+    .map_err(|e| error::in_file(e, payload_start))?;
+    writer.flush()?;
+    // The stitched decode ended exactly at `payload_bits`; the padding bits
+    // after it are left.
+    check_payload_end(header, &mut input, Some(payload_bits), false)?;
+    verify_crc(header, &mut input)
+}
+
+/// Reads the seek index of `input_path`: the embedded one if the header
+/// has [`FLAG_INDEX`](format::FLAG_INDEX), otherwise its sidecar if there
+/// is one.
+pub(crate) fn load_index(
+    input_path: &Path,
+    input: &mut File,
+    header: &Header,
+) -> std::io::Result<Option<SeekIndex>> {
+    let file_len = container_len(header, input)?;
+    if header.flags & format::FLAG_INDEX != 0 {
+        return SeekIndex::read_section(input, header.header_len(), file_len, header.original_size)
+            .map(Some);
+    }
+    let mut sidecar = match File::open(index::sidecar_path(input_path)) {
+        Ok(file) => std::io::BufReader::new(file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = SidecarKey::of_file(input)?;
+    let payload_bits = payload_len(header, file_len)? * 8;
+    SeekIndex::read_sidecar(&mut sidecar, key, payload_bits, header.original_size).map(Some)
+}
+
+/// Length of the container in `input`: the whole file, less the parity
+/// section if the header has [`FLAG_PARITY`](format::FLAG_PARITY) and the
+/// HMAC-SHA-256 tag if it has [`FLAG_HMAC`](format::FLAG_HMAC).
+pub(crate) fn container_len(header: &Header, input: &File) -> std::io::Result<u64> {
+    let len = if header.flags & format::FLAG_PARITY == 0 {
+        input.metadata()?.len()
+    } else {
+        let mut reader = input;
+        match parity::ParityLayout::read(&mut reader)? {
+            Some(layout) => layout.protected_len,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Header flags a parity section, but the file does not end in one",
+                ))
             }
         }
-
-        // Flush remaining buffered output.
-        if buf_pos > 0 {
-            writer.write_all(&out_buf[..buf_pos])?;
+    };
+    if header.flags & format::FLAG_HMAC == 0 {
+        return Ok(len);
+    }
+    match len.checked_sub(crypto::MAC_SIZE) {
+        Some(len) if len >= header.header_len() => Ok(len),
+        _ => {
+            let msg = "File is too short to hold its HMAC-SHA-256 tag";
+            Err(DecodeError::new(CorruptionKind::Truncated, msg).into())
         }
-        writer.flush()?;
-    } // bit_reader dropped here, input_file is no longer borrowed
+    }
+}
 
-    // ------------------------------------------------------------------
-    // 4. Verify CRC-32 (v0x02+)
-    // ------------------------------------------------------------------
-    if header.version >= 0x02 {
-        let compressed_len = (file_len - header_size - format::CRC_SIZE) as usize;
-        input_file.seek(SeekFrom::Start(header_size))?;
-        let mut compressed_data = vec![0u8; compressed_len];
-        input_file.read_exact(&mut compressed_data)?;
-        let expected_crc = format::crc32(&compressed_data);
+/// Number of payload bytes in a file of `file_len` bytes: everything after
+/// the header, less the CRC-32 trailer for v0x02+ files.
+fn payload_len(header: &Header, file_len: u64) -> std::io::Result<u64> {
+    let trailer = if header.version >= 0x02 {
+        format::CRC_SIZE
+    } else {
+        0
+    };
+    file_len
+        .checked_sub(header.header_len() + trailer)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "File too short for CRC-32 trailer",
+            )
+        })
+}
 
-        let mut crc_buf = [0u8; 4];
-        input_file.read_exact(&mut crc_buf)?;
-        let actual_crc = u32::from_le_bytes(crc_buf);
+/// Checks that a bitstream which ended after `consumed` payload bits fills
+/// the payload exactly: its padding is the header's and is all zero bits,
+/// and no bytes follow it unless `ignore_trailing` is set.
+///
+/// `None` means the coder does not count the bits it consumes, and skips
+/// the check.
+fn check_payload_end(
+    header: &Header,
+    input: &mut File,
+    consumed: Option<u64>,
+    ignore_trailing: bool,
+) -> std::io::Result<()> {
+    let Some(consumed) = consumed else {
+        return Ok(());
+    };
+    let payload_start = header.header_len();
+    let invalid = |kind, msg: String, bit| {
+        let err = DecodeError::new(kind, msg).after_output(header.original_size);
+        error::in_file(err.at_bit(bit).into(), payload_start)
+    };
+
+    let file_len = container_len(header, input)?;
+    let mut payload_bytes = payload_len(header, file_len)?;
+    if header.flags & format::FLAG_INDEX != 0 {
+        let index =
+            SeekIndex::read_section(input, header.header_len(), file_len, header.original_size)?;
+        payload_bytes -= index.section_len();
+    }
+    let end_bytes = consumed.div_ceil(8);
+    if end_bytes > payload_bytes {
+        let msg = format!(
+            "Bitstream ends {} bytes past the {payload_bytes}-byte payload",
+            end_bytes - payload_bytes
+        );
+        return Err(invalid(CorruptionKind::InvalidLength, msg, consumed));
+    }
 
-        if actual_crc != expected_crc {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!(
-                    "CRC-32 mismatch: expected {expected_crc:#010x}, got {actual_crc:#010x}"
-                ),
-            ));
+    let padding = end_bytes * 8 - consumed;
+    if padding != header.padding as u64 {
+        let msg = format!(
+            "Bitstream ends with {padding} padding bits, header says {}",
+            header.padding
+        );
+        return Err(invalid(CorruptionKind::BadPadding, msg, consumed));
+    }
+    if padding > 0 {
+        input.seek(SeekFrom::Start(payload_start + end_bytes - 1))?;
+        let mut last = [0u8; 1];
+        input.read_exact(&mut last)?;
+        let bits = last[0] & ((1 << padding) - 1);
+        if bits != 0 {
+            let msg = format!(
+                "Non-zero padding bits in last payload byte {:#04x}",
+                last[0]
+            );
+            let err = DecodeError::new(CorruptionKind::BadPadding, msg)
+                .at_bit(consumed)
+                .at_file_offset(payload_start + end_bytes - 1)
+                .after_output(header.original_size)
+                .with_pattern(bits as u64, padding as u8);
+            return Err(err.into());
         }
     }
 
+    if end_bytes < payload_bytes && !ignore_trailing {
+        let msg = format!(
+            "{} trailing bytes after the end of the bitstream",
+            payload_bytes - end_bytes
+        );
+        return Err(invalid(CorruptionKind::TrailingBytes, msg, end_bytes * 8));
+    }
+    Ok(())
+}
+
+/// Checks the CRC-32 trailer of a v0x02+ file against everything between
+/// the header and the trailer.  Earlier versions have no CRC.
+fn verify_crc(header: &Header, input_file: &mut File) -> std::io::Result<()> {
+    if header.version < 0x02 {
+        return Ok(());
+    }
+    let file_len = container_len(header, input_file)?;
+    let compressed_len = payload_len(header, file_len)? as usize;
+    input_file.seek(SeekFrom::Start(header.header_len()))?;
+    let mut compressed_data = vec![0u8; compressed_len];
+    input_file.read_exact(&mut compressed_data)?;
+    let expected_crc = format::crc32(&compressed_data);
+
+    let mut crc_buf = [0u8; 4];
+    input_file.read_exact(&mut crc_buf)?;
+    let actual_crc = u32::from_le_bytes(crc_buf);
+
+    if actual_crc != expected_crc {
+        let msg = format!("CRC-32 mismatch: expected {expected_crc:#010x}, got {actual_crc:#010x}");
+        let trailer = header.header_len() + compressed_len as u64;
+        return Err(DecodeError::new(CorruptionKind::CrcMismatch, msg)
+            .at_file_offset(trailer)
+            .into());
+    }
     Ok(())
 }
 
@@ -262,7 +1019,8 @@ fn decode_to_writer<W: Write>(input_path: &Path, writer: &mut W) -> std::io::Res
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::compress::compress;
+    use crate::compress::{compress, compress_with_options, Coder, EncoderOptions};
+    use crate::test_util::{compress_temp, TempDir};
     use std::io::Write;
     use std::sync::atomic::{AtomicU64, Ordering};
 
@@ -274,8 +1032,28 @@ mod tests {
         format!("{pid:x}_{n:x}")
     }
 
+    fn indexed(coder: Coder, interval: u64) -> EncoderOptions {
+        EncoderOptions {
+            coder,
+            index_interval: Some(interval),
+            ..Default::default()
+        }
+    }
+
+    /// Pseudo-random text that Huffman compresses but does not store.
+    fn range_test_data() -> Vec<u8> {
+        (0..50_000u32)
+            .map(|i| b"abcdefgh ijk\n"[(i.wrapping_mul(2654435761) >> 28) as usize % 13])
+            .collect()
+    }
+
     /// Helper: compress `data`, then decompress and return the result.
     fn round_trip(data: &[u8]) -> Vec<u8> {
+        round_trip_with(data, Coder::Huffman)
+    }
+
+    /// Helper: like [`round_trip`], but with an explicit coder.
+    fn round_trip_with(data: &[u8], coder: Coder) -> Vec<u8> {
         let dir = std::env::temp_dir().join("cmprsr_test").join("decompress");
         let _ = std::fs::create_dir_all(&dir);
 
@@ -292,7 +1070,11 @@ mod tests {
         f.flush().unwrap();
 
         // Compress.
-        compress(&input_path, &cmpr_path).unwrap();
+        let options = EncoderOptions {
+            coder,
+            ..Default::default()
+        };
+        compress_with_options(&input_path, &cmpr_path, &options).unwrap();
 
         // Decompress.
         decompress(&cmpr_path, &output_path).unwrap();
@@ -350,6 +1132,59 @@ mod tests {
         assert_eq!(result, data);
     }
 
+    #[test]
+    fn round_trip_ans_varied_text() {
+        let data = b"The quick brown fox jumps over the lazy dog.";
+        let result = round_trip_with(data, Coder::Ans);
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn round_trip_ans_empty() {
+        let result = round_trip_with(b"", Coder::Ans);
+        assert_eq!(result, b"");
+    }
+
+    #[test]
+    fn round_trip_ans_large_skewed() {
+        let data: Vec<u8> = (0..200_000u32)
+            .map(|i| if i % 97 == 0 { (i % 256) as u8 } else { b'x' })
+            .collect();
+        let result = round_trip_with(&data, Coder::Ans);
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn round_trip_huffman4_small_blocks() {
+        // Fewer than four bytes leaves some streams empty.
+        for data in [&b"ab"[..], b"abc", b"abcde", &b"abracadabra".repeat(40)] {
+            assert_eq!(round_trip_with(data, Coder::Huffman4), data);
+        }
+    }
+
+    #[test]
+    fn round_trip_huffman4_multi_block() {
+        use crate::huffman::X4_BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * X4_BLOCK_SIZE as u32 + 12_345)
+            .map(|i| b"etaoin shrdlu"[(i as usize * 7 + i as usize / 13) % 13])
+            .collect();
+        assert_eq!(round_trip_with(&data, Coder::Huffman4), data);
+    }
+
+    /// `verify` decodes and checks the CRC-32 without writing any output.
+    #[test]
+    fn verify_checks_without_output() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        verify(&cmpr, &DecoderOptions::default()).unwrap();
+        let mut file = std::fs::read(&cmpr).unwrap();
+        let n = file.len();
+        file[n - 10] ^= 0x01;
+        std::fs::write(&cmpr, file).unwrap();
+        let err = verify(&cmpr, &DecoderOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
     /// Helper: compress, then corrupt one bit in the compressed data.
     #[test]
     fn crc_rejects_corrupted_data() {
@@ -377,7 +1212,7 @@ mod tests {
             // Decode header to find the start of compressed data.
             let mut cursor = std::io::Cursor::new(&cmpr_data[..]);
             let header = format::read_header(&mut cursor).unwrap();
-            let header_size = format::FIXED_HEADER_SIZE as usize + header.symbol_count as usize * 2;
+            let header_size = header.header_len() as usize;
             // Flip a bit in the compressed data region.
             if header_size < file_len - format::CRC_SIZE as usize {
                 cmpr_data[header_size] ^= 1 << 3;
@@ -394,6 +1229,73 @@ mod tests {
         let _ = std::fs::remove_dir_all(&subdir);
     }
 
+    #[test]
+    fn corrupt_file_leaves_output_untouched() {
+        let data = range_test_data();
+        for options in [EncoderOptions::default(), indexed(Coder::Huffman, 7000)] {
+            let (_dir, cmpr) = compress_temp("decompress", &data, &options);
+            let mut file = std::fs::read(&cmpr).unwrap();
+            let n = file.len();
+            file[n - 5] ^= 0x10;
+            std::fs::write(&cmpr, file).unwrap();
+            let out = cmpr.with_extension("out");
+            for threads in [1, 4] {
+                std::fs::write(&out, b"previous contents").unwrap();
+                assert!(decompress_parallel(&cmpr, &out, threads).is_err());
+                assert_eq!(std::fs::read(&out).unwrap(), b"previous contents");
+                // Only the .cmpr file and the output, nothing temporary.
+                let entries = std::fs::read_dir(cmpr.parent().unwrap()).unwrap();
+                assert_eq!(entries.count(), 2, "threads = {threads}");
+            }
+        }
+    }
+
+    #[test]
+    fn output_replaced_only_on_success() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let out = cmpr.with_extension("out");
+        std::fs::write(&out, b"previous contents").unwrap();
+        decompress(&cmpr, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    #[test]
+    fn verify_first_emits_nothing_from_corrupt_file() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let mut file = std::fs::read(&cmpr).unwrap();
+        let n = file.len();
+        file[n - 100] ^= 0x10;
+        std::fs::write(&cmpr, file).unwrap();
+
+        let mut streamed = Vec::new();
+        decode_to_writer(&cmpr, &mut streamed, decode_builtin, &Default::default()).unwrap_err();
+        assert!(!streamed.is_empty());
+
+        let options = DecoderOptions {
+            verify_first: true,
+            ..Default::default()
+        };
+        let mut checked = Vec::new();
+        let err = decode_to_writer(&cmpr, &mut checked, decode_builtin, &options).unwrap_err();
+        assert!(err.to_string().contains("CRC-32 mismatch"));
+        assert!(checked.is_empty());
+    }
+
+    #[test]
+    fn verify_first_decodes_intact_file() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let options = DecoderOptions {
+            verify_first: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        decode_to_writer(&cmpr, &mut out, decode_builtin, &options).unwrap();
+        assert_eq!(out, data);
+    }
+
     /// Decompress a manually-constructed v0x01 file (no CRC trailer).
     #[test]
     fn backward_compat_v0x01() {
@@ -431,6 +1333,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrows_for_generic_args)]
     fn reject_all_zeros() {
         let dir = std::env::temp_dir().join("cmprsr_test").join("reject_zeros");
         let _ = std::fs::create_dir_all(&dir);
@@ -468,4 +1371,537 @@ mod tests {
 
         let _ = std::fs::remove_dir_all(&subdir);
     }
+
+    // ---- Random access ----
+
+    #[test]
+    fn range_with_index() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &indexed(Coder::Huffman, 1000));
+        let mut f = File::open(&cmpr).unwrap();
+        let header = format::read_header(&mut f).unwrap();
+        assert_eq!(header.method, format::METHOD_HUFFMAN);
+        assert_eq!(header.flags, format::FLAG_INDEX);
+
+        for (start, len) in [
+            (0, 10),
+            (999, 2),
+            (1000, 1000),
+            (12_345, 6789),
+            (49_990, 10),
+        ] {
+            let got = decompress_range(&cmpr, start, len).unwrap();
+            assert_eq!(
+                got,
+                &data[start as usize..(start + len) as usize],
+                "{start}:{len}"
+            );
+        }
+        // The index is covered by the CRC and ignored by a full decode.
+        let out = cmpr.with_extension("out");
+        decompress(&cmpr, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    #[test]
+    fn range_without_index() {
+        let data = range_test_data();
+        for coder in [Coder::Huffman, Coder::Huffman4, Coder::Ans] {
+            let (_dir, cmpr) = compress_temp(
+                "decompress",
+                &data,
+                &EncoderOptions {
+                    coder,
+                    ..Default::default()
+                },
+            );
+            let got = decompress_range(&cmpr, 20_000, 300).unwrap();
+            assert_eq!(got, &data[20_000..20_300]);
+        }
+    }
+
+    #[test]
+    fn range_of_stored_payload() {
+        let data: Vec<u8> = (0..=255).collect();
+        for options in [EncoderOptions::default(), indexed(Coder::Huffman, 16)] {
+            let (_dir, cmpr) = compress_temp("decompress", &data, &options);
+            let mut f = File::open(&cmpr).unwrap();
+            assert_eq!(
+                format::read_header(&mut f).unwrap().method,
+                format::METHOD_STORED
+            );
+            assert_eq!(decompress_range(&cmpr, 100, 50).unwrap(), &data[100..150]);
+        }
+    }
+
+    #[test]
+    fn range_outside_original() {
+        let (_dir, cmpr) =
+            compress_temp("decompress", &range_test_data(), &EncoderOptions::default());
+        let err = decompress_range(&cmpr, 49_999, 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = decompress_range(&cmpr, u64::MAX, 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn crafted_index_interval_is_refused() {
+        let (_dir, cmpr) = compress_temp(
+            "decompress",
+            &range_test_data(),
+            &indexed(Coder::Huffman, 10_000),
+        );
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        let n = bytes.len();
+        bytes[n - 16..n - 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(&cmpr, bytes).unwrap();
+
+        let options = DecoderOptions {
+            threads: 4,
+            ..DecoderOptions::default()
+        };
+        let err = decompress_range(&cmpr, 0, 10).unwrap_err();
+        assert!(err.to_string().contains("past the original data"));
+        let out = cmpr.with_extension("out");
+        assert!(decompress_with_options(&cmpr, &out, &options).is_err());
+        assert!(verify(&cmpr, &DecoderOptions::default()).is_err());
+    }
+
+    #[test]
+    fn index_unsupported_by_coder() {
+        let dir = TempDir::new("decompress");
+        let input_path = dir.join("input.bin");
+        std::fs::write(&input_path, range_test_data()).unwrap();
+        // The refusal comes before an existing output is touched.
+        let cmpr = dir.join("x.cmpr");
+        std::fs::write(&cmpr, b"kept").unwrap();
+        for coder in [Coder::Ans, Coder::Huffman4] {
+            let err = compress_with_options(&input_path, &cmpr, &indexed(coder, 100)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+            assert_eq!(std::fs::read(&cmpr).unwrap(), b"kept");
+        }
+    }
+
+    #[test]
+    fn byte_range_from_str() {
+        assert_eq!("10:20".parse(), Ok(ByteRange { start: 10, len: 20 }));
+        assert!("10".parse::<ByteRange>().is_err());
+        assert!("a:1".parse::<ByteRange>().is_err());
+    }
+
+    // ---- Sidecar indexes and parallel decoding ----
+
+    /// Writes `data` as a v0x02 file, the layout that has no room for an
+    /// embedded index, and returns its directory and path.
+    fn v2_file(data: &[u8]) -> (TempDir, std::path::PathBuf) {
+        let mut freqs = [0u64; 256];
+        for &b in data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        let padding = coder.encode(&mut &data[..], &mut payload).unwrap();
+        let symbols = coder.code_table().symbol_table();
+
+        let mut file = format::MAGIC.to_vec();
+        file.push(0x02);
+        file.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        file.push(padding);
+        file.extend_from_slice(&(symbols.len() as u16).to_le_bytes());
+        for (symbol, len) in symbols {
+            file.extend_from_slice(&[symbol, len]);
+        }
+        file.extend_from_slice(&payload);
+        file.extend_from_slice(&format::crc32(&payload).to_le_bytes());
+
+        let dir = TempDir::new("decompress");
+        let path = dir.join("legacy.cmpr");
+        std::fs::write(&path, file).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn sidecar_index_for_v2_file() {
+        let data = range_test_data();
+        let (_dir, cmpr) = v2_file(&data);
+        let before = std::fs::read(&cmpr).unwrap();
+
+        let index = write_sidecar_index(&cmpr, 4096).unwrap();
+        assert!(index::sidecar_path(&cmpr).exists());
+        assert_eq!(std::fs::read(&cmpr).unwrap(), before);
+        assert_eq!(index, build_index(&cmpr, 4096).unwrap());
+        assert_eq!(index.checkpoints.len(), 12);
+
+        for (start, len) in [(0, 1), (4095, 3), (30_000, 12_000)] {
+            let got = decompress_range(&cmpr, start, len).unwrap();
+            assert_eq!(got, &data[start as usize..(start + len) as usize]);
+        }
+    }
+
+    #[test]
+    fn stale_sidecar_is_rejected() {
+        let data = range_test_data();
+        let (_dir, cmpr) = v2_file(&data);
+        write_sidecar_index(&cmpr, 4096).unwrap();
+        std::fs::write(&cmpr, std::fs::read(v2_file(&data[1..]).1).unwrap()).unwrap();
+        let err = decompress_range(&cmpr, 0, 10).unwrap_err();
+        assert!(err.to_string().contains("different file"));
+    }
+
+    #[test]
+    fn parity_section_is_skipped_and_repairs_damage() {
+        let data = range_test_data();
+        let options = EncoderOptions {
+            parity: true,
+            ..indexed(Coder::Huffman, 1000)
+        };
+        let (_dir, cmpr) = compress_temp("decompress", &data, &options);
+        let mut f = File::open(&cmpr).unwrap();
+        let header = format::read_header(&mut f).unwrap();
+        assert_eq!(header.flags, format::FLAG_INDEX | format::FLAG_PARITY);
+        let layout = parity::ParityLayout::read(&mut f).unwrap().unwrap();
+        assert_eq!(container_len(&header, &f).unwrap(), layout.protected_len);
+
+        let out = cmpr.with_extension("out");
+        decompress(&cmpr, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+        assert_eq!(
+            decompress_range(&cmpr, 12_345, 10).unwrap(),
+            &data[12_345..12_355]
+        );
+
+        // Damage the header and a run of payload bytes.
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        bytes[format::FLAGS_OFFSET as usize] ^= 0x40;
+        let start = header.header_len() as usize + 500;
+        for byte in &mut bytes[start..start + 300] {
+            *byte = !*byte;
+        }
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert!(verify(&cmpr, &DecoderOptions::default()).is_err());
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&cmpr)
+            .unwrap();
+        let report = parity::repair(&mut file).unwrap().unwrap();
+        assert_eq!(report.corrected_bytes, 301);
+        assert_eq!(report.uncorrectable_codewords, 0);
+        decompress(&cmpr, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    #[test]
+    fn parallel_decode_matches() {
+        let data = range_test_data();
+        let (_dir, v2) = v2_file(&data);
+        write_sidecar_index(&v2, 3000).unwrap();
+        let (_dir, embedded) = compress_temp("decompress", &data, &indexed(Coder::Huffman, 7000));
+        let (_dir, plain) = compress_temp("decompress", &data, &EncoderOptions::default());
+
+        for cmpr in [v2, embedded, plain] {
+            for threads in [1, 2, 3, 64] {
+                let out = cmpr.with_extension(format!("out{threads}"));
+                decompress_parallel(&cmpr, &out, threads).unwrap();
+                assert_eq!(std::fs::read(&out).unwrap(), data, "{threads} threads");
+            }
+        }
+    }
+
+    #[test]
+    fn speculative_decode_without_index() {
+        let data: Vec<u8> = (0..600_000u32)
+            .map(|i| b"abcdefgh ijk\n"[(i.wrapping_mul(2654435761) >> 28) as usize % 13])
+            .collect();
+        let (_dir, v2) = v2_file(&data);
+        let (_dir, plain) = compress_temp("decompress", &data, &EncoderOptions::default());
+        for cmpr in [v2, plain] {
+            let out = cmpr.with_extension("out");
+            decompress_parallel(&cmpr, &out, 4).unwrap();
+            assert_eq!(std::fs::read(&out).unwrap(), data);
+
+            let mut file = std::fs::read(&cmpr).unwrap();
+            let n = file.len();
+            file[n - 5] ^= 0x10;
+            std::fs::write(&cmpr, file).unwrap();
+            assert!(decompress_parallel(&cmpr, &out, 4).is_err());
+        }
+    }
+
+    #[test]
+    fn parallel_decode_checks_crc() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &indexed(Coder::Huffman, 7000));
+        let mut file = std::fs::read(&cmpr).unwrap();
+        let n = file.len();
+        file[n - 1] ^= 0xFF;
+        std::fs::write(&cmpr, file).unwrap();
+        let err = decompress_parallel(&cmpr, &cmpr.with_extension("out"), 4).unwrap_err();
+        assert!(err.to_string().contains("CRC-32 mismatch"));
+    }
+
+    #[test]
+    fn split_index_covers_input() {
+        let index = SeekIndex::from_interval(10, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).unwrap();
+        let parts = split_index(&index, 95, 4).unwrap();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], (0, 0, 20));
+        assert_eq!(parts.iter().map(|p| p.2).sum::<u64>(), 95);
+        assert!(parts.windows(2).all(|w| w[0].0 + w[0].2 == w[1].0));
+    }
+
+    // ---- Decoder limits ----
+
+    #[test]
+    fn forged_original_size_is_refused() {
+        let coder = HuffmanCoder::from_frequencies(&{
+            let mut freqs = [0u64; 256];
+            freqs[b'A' as usize] = 1;
+            freqs[b'B' as usize] = 1;
+            freqs
+        })
+        .unwrap();
+        let mut table = Vec::new();
+        coder.write_table(&mut table);
+        let mut file = Vec::new();
+        format::write_method_header(&mut file, 1 << 60, format::METHOD_HUFFMAN, &table, 0).unwrap();
+        let payload = [0u8; 64];
+        file.extend_from_slice(&payload);
+        file.extend_from_slice(&format::crc32(&payload).to_le_bytes());
+
+        let dir = TempDir::new("decompress");
+        let cmpr = dir.join("bomb.cmpr");
+        std::fs::write(&cmpr, file).unwrap();
+        let out = dir.join("bomb.out");
+        for threads in [1, 4] {
+            let err = decompress_parallel(&cmpr, &out, threads).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("cannot be decoded"), "{err}");
+            assert_eq!(std::fs::metadata(&out).map_or(0, |m| m.len()), 0);
+        }
+
+        // With a seek index the parallel decoder is refused before it
+        // creates the output at the forged size.
+        let mut file = Vec::new();
+        format::write_method_header(&mut file, 1 << 60, format::METHOD_HUFFMAN, &table, 0).unwrap();
+        let header_len = file.len() as u64;
+        file[format::FLAGS_OFFSET as usize] = format::FLAG_INDEX;
+        format::update_header_crc(&mut std::io::Cursor::new(&mut file), header_len).unwrap();
+        let mut body = payload.to_vec();
+        body.extend_from_slice(&0u64.to_le_bytes());
+        body.extend_from_slice(&(1u64 << 50).to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&body);
+        file.extend_from_slice(&format::crc32(&body).to_le_bytes());
+        std::fs::write(&cmpr, file).unwrap();
+        let _ = std::fs::remove_file(&out);
+        let options = DecoderOptions {
+            threads: 4,
+            ..Default::default()
+        };
+        let err = decompress_threaded(&cmpr, &out, &options).unwrap_err();
+        assert!(err.to_string().contains("cannot be decoded"), "{err}");
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn split_index_refuses_checkpoint_past_end() {
+        let index = SeekIndex {
+            checkpoints: vec![(50, 1), (200, 2)],
+        };
+        assert!(split_index(&index, 100, 3).is_err());
+    }
+
+    #[test]
+    fn output_limits() {
+        let data = vec![0u8; 100_000];
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let out = cmpr.with_extension("out");
+        let file_len = std::fs::metadata(&cmpr).unwrap().len();
+        let ratio = 100_000 / file_len;
+
+        let limited = |max_output_bytes, max_ratio| DecoderOptions {
+            max_output_bytes,
+            max_ratio,
+            ..Default::default()
+        };
+        let err = decompress_with_options(&cmpr, &out, &limited(Some(99_999), None)).unwrap_err();
+        assert!(err.to_string().contains("output limit"), "{err}");
+        let err = decompress_with_options(&cmpr, &out, &limited(None, Some(ratio))).unwrap_err();
+        assert!(err.to_string().contains("limit"), "{err}");
+
+        decompress_with_options(&cmpr, &out, &limited(Some(100_000), Some(ratio + 1))).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    // ---- Stream boundaries ----
+
+    /// Rewrites the payload of `cmpr` with `patch` and recomputes its CRC.
+    fn patch_payload(cmpr: &Path, patch: impl FnOnce(&mut Vec<u8>)) {
+        let file = std::fs::read(cmpr).unwrap();
+        let header = format::read_header(&mut &file[..]).unwrap();
+        let start = header.header_len() as usize;
+        let mut payload = file[start..file.len() - format::CRC_SIZE as usize].to_vec();
+        patch(&mut payload);
+        let mut patched = file[..start].to_vec();
+        patched.extend_from_slice(&payload);
+        patched.extend_from_slice(&format::crc32(&payload).to_le_bytes());
+        std::fs::write(cmpr, patched).unwrap();
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let text = range_test_data();
+        // Uniform bytes do not compress and are stored.
+        let noise: Vec<u8> = (0..20_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let cases = [
+            (Coder::Huffman, &text),
+            (Coder::Huffman4, &text),
+            (Coder::Ans, &text),
+            (Coder::Huffman, &noise),
+        ];
+        for (coder, data) in cases {
+            let options = EncoderOptions {
+                coder,
+                ..Default::default()
+            };
+            let (_dir, cmpr) = compress_temp("decompress", data, &options);
+            let out = cmpr.with_extension("out");
+            patch_payload(&cmpr, |payload| payload.extend_from_slice(&[0, 0, 0]));
+
+            let err = decompress(&cmpr, &out).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(
+                err.to_string().contains("3 trailing bytes"),
+                "{coder:?}: {err}"
+            );
+
+            let lenient = DecoderOptions {
+                ignore_trailing: true,
+                ..Default::default()
+            };
+            decompress_with_options(&cmpr, &out, &lenient).unwrap();
+            assert_eq!(&std::fs::read(&out).unwrap(), data, "{coder:?}");
+        }
+    }
+
+    #[test]
+    fn nonzero_padding_bits_are_refused() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let header = format::read_header(&mut File::open(&cmpr).unwrap()).unwrap();
+        assert!(header.padding > 0);
+        patch_payload(&cmpr, |payload| *payload.last_mut().unwrap() |= 1);
+        for threads in [1, 4] {
+            let err = decompress_parallel(&cmpr, &cmpr.with_extension("out"), threads).unwrap_err();
+            assert!(err.to_string().contains("Non-zero padding bits"), "{err}");
+        }
+    }
+
+    #[test]
+    fn padding_must_match_header() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&cmpr)
+            .unwrap();
+        let header = format::read_header(&mut file).unwrap();
+        file.seek(SeekFrom::Start(format::PADDING_OFFSET)).unwrap();
+        file.write_all(&[(header.padding + 1) % 8]).unwrap();
+        format::update_header_crc(&mut file, header.header_len()).unwrap();
+        drop(file);
+        let err = decompress(&cmpr, &cmpr.with_extension("out")).unwrap_err();
+        assert!(
+            err.to_string().contains("padding bits, header says"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn unused_interleaved_stream_bytes_are_refused() {
+        let data = range_test_data();
+        let options = EncoderOptions {
+            coder: Coder::Huffman4,
+            ..Default::default()
+        };
+        let (_dir, cmpr) = compress_temp("decompress", &data, &options);
+        // Grow the first stream of the first block by one byte.
+        patch_payload(&cmpr, |payload| {
+            let len = u32::from_le_bytes(payload[..4].try_into().unwrap());
+            payload[..4].copy_from_slice(&(len + 1).to_le_bytes());
+            payload.insert(16 + len as usize, 0);
+        });
+        let err = decompress(&cmpr, &cmpr.with_extension("out")).unwrap_err();
+        assert!(err.to_string().contains("1 unused bytes"), "{err}");
+    }
+
+    // ---- Corruption diagnostics ----
+
+    #[test]
+    fn invalid_code_is_located() {
+        // A = 0, B = 10; the code 11 is unused.  The payload decodes
+        // A A B A and then hits 11 at bit 5.
+        let mut file = Vec::new();
+        format::write_header(&mut file, 5, &[(b'A', 1), (b'B', 2)], 1).unwrap();
+        let header_len = file.len() as u64;
+        let payload = [0b0010_0110];
+        file.extend_from_slice(&payload);
+        file.extend_from_slice(&format::crc32(&payload).to_le_bytes());
+        let dir = TempDir::new("decompress");
+        let cmpr = dir.join("invalid.cmpr");
+        std::fs::write(&cmpr, file).unwrap();
+
+        let err = decompress(&cmpr, &dir.join("invalid.out")).unwrap_err();
+        let decode = DecodeError::from_io(&err).expect("structured error");
+        assert_eq!(decode.kind, CorruptionKind::InvalidCode);
+        assert_eq!(decode.bit_offset, Some(5));
+        assert_eq!(decode.file_offset, Some(header_len));
+        assert_eq!(decode.output_bytes, Some(4));
+        assert_eq!(decode.bit_pattern.unwrap().to_string(), "11");
+        assert!(err.to_string().contains("bits 11"), "{err}");
+    }
+
+    #[test]
+    fn truncation_is_located() {
+        let data = range_test_data();
+        for coder in [Coder::Huffman, Coder::Huffman4, Coder::Ans] {
+            let options = EncoderOptions {
+                coder,
+                ..Default::default()
+            };
+            let (_dir, cmpr) = compress_temp("decompress", &data, &options);
+            patch_payload(&cmpr, |payload| payload.truncate(payload.len() - 100));
+            let header = format::read_header(&mut File::open(&cmpr).unwrap()).unwrap();
+            let payload_bits =
+                payload_len(&header, std::fs::metadata(&cmpr).unwrap().len()).unwrap() * 8;
+
+            let err = decompress(&cmpr, &cmpr.with_extension("out")).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            let decode = DecodeError::from_io(&err).expect("structured error");
+            assert_eq!(decode.kind, CorruptionKind::Truncated, "{coder:?}");
+            let bit = decode.bit_offset.unwrap();
+            assert!(bit <= payload_bits, "{coder:?}: {bit} > {payload_bits}");
+            assert_eq!(decode.file_offset, Some(header.header_len() + bit / 8));
+            assert!(decode.output_bytes.unwrap() < data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn crc_mismatch_points_at_trailer() {
+        let data = range_test_data();
+        let (_dir, cmpr) = compress_temp("decompress", &data, &EncoderOptions::default());
+        let mut file = std::fs::read(&cmpr).unwrap();
+        let n = file.len();
+        file[n - 1] ^= 0xFF;
+        std::fs::write(&cmpr, file).unwrap();
+        let err = decompress(&cmpr, &cmpr.with_extension("out")).unwrap_err();
+        let decode = DecodeError::from_io(&err).expect("structured error");
+        assert_eq!(decode.kind, CorruptionKind::CrcMismatch);
+        assert_eq!(decode.file_offset, Some(n as u64 - format::CRC_SIZE));
+    }
 }
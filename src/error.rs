@@ -0,0 +1,277 @@
+use std::fmt;
+
+// ---------------------------------------------------------------------------
+// Structured decode errors
+// ---------------------------------------------------------------------------
+//
+// Decoders still return `std::io::Error`, but errors caused by corrupt input
+// wrap a `DecodeError` that records where the corruption was found.  Each
+// layer fills in what it knows: the bit decoders record offsets relative to
+// the stream they read, the coders shift them to the payload, and the
+// container adds the file offset.  The `Display` form carries the location
+// too, so the CLI prints it without looking inside.
+
+/// What kind of corruption a [`DecodeError`] reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The payload ended before the original size was decoded.
+    Truncated,
+    /// A bit pattern that is no code of the Huffman table.
+    InvalidCode,
+    /// An ANS chunk state out of range, or not back at its initial value.
+    InvalidState,
+    /// A bitstream, interleaved stream or block whose length does not fit
+    /// the data.
+    InvalidLength,
+    /// The bitstream does not end where the header's padding says, or its
+    /// padding bits are not zero.
+    BadPadding,
+    /// Bytes follow the end of the bitstream.
+    TrailingBytes,
+    /// The payload CRC-32 does not match.
+    CrcMismatch,
+    /// The header CRC-32 (v0x05+) does not match.
+    HeaderCrcMismatch,
+    /// The authentication tag of an encrypted file, or the HMAC-SHA-256 tag,
+    /// does not match or is missing: the key is wrong, or the file was
+    /// altered on purpose.
+    AuthenticationFailed,
+}
+
+/// A bit pattern read from the compressed data, most significant bit first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitPattern {
+    /// The bits, right-aligned.
+    pub bits: u64,
+    /// Number of bits in the pattern.
+    pub len: u8,
+}
+
+impl fmt::Display for BitPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0width$b}", self.bits, width = self.len as usize)
+    }
+}
+
+/// Corrupt compressed data, with as much of its location as is known.
+///
+/// Carried inside the `std::io::Error` the decoders return; use
+/// [`DecodeError::from_io`] to get at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    /// What went wrong.
+    pub kind: CorruptionKind,
+    /// Human-readable description, without the location.
+    pub message: String,
+    /// Payload bit at which the corruption was detected, counting from the
+    /// first payload byte's most significant bit.
+    pub bit_offset: Option<u64>,
+    /// Byte of the `.cmpr` file holding [`bit_offset`](Self::bit_offset),
+    /// or the checksum that failed.
+    pub file_offset: Option<u64>,
+    /// Decompressed bytes produced before the corruption.
+    pub output_bytes: Option<u64>,
+    /// For [`CorruptionKind::InvalidCode`], the bits that matched no code.
+    pub bit_pattern: Option<BitPattern>,
+}
+
+impl DecodeError {
+    /// An error of `kind` with no location yet.
+    pub fn new(kind: CorruptionKind, message: impl Into<String>) -> Self {
+        DecodeError {
+            kind,
+            message: message.into(),
+            bit_offset: None,
+            file_offset: None,
+            output_bytes: None,
+            bit_pattern: None,
+        }
+    }
+
+    /// Sets the payload bit offset.
+    pub fn at_bit(mut self, bit_offset: u64) -> Self {
+        self.bit_offset = Some(bit_offset);
+        self
+    }
+
+    /// Sets the file offset.
+    pub fn at_file_offset(mut self, file_offset: u64) -> Self {
+        self.file_offset = Some(file_offset);
+        self
+    }
+
+    /// Sets the number of decompressed bytes produced.
+    pub fn after_output(mut self, output_bytes: u64) -> Self {
+        self.output_bytes = Some(output_bytes);
+        self
+    }
+
+    /// Sets the offending bit pattern.
+    pub fn with_pattern(mut self, bits: u64, len: u8) -> Self {
+        self.bit_pattern = Some(BitPattern { bits, len });
+        self
+    }
+
+    /// The `DecodeError` inside `err`, if there is one.
+    pub fn from_io(err: &std::io::Error) -> Option<&DecodeError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    fn from_io_mut(err: &mut std::io::Error) -> Option<&mut DecodeError> {
+        err.get_mut()?.downcast_mut()
+    }
+}
+
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        let kind = match err.kind {
+            CorruptionKind::Truncated => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        let mut location = Vec::new();
+        match (self.file_offset, self.bit_offset) {
+            (Some(byte), Some(bit)) => location.push(format!("at byte {byte} bit {}", bit % 8)),
+            (Some(byte), None) => location.push(format!("at byte {byte}")),
+            (None, Some(bit)) => location.push(format!("at payload bit {bit}")),
+            (None, None) => {}
+        }
+        if let Some(n) = self.output_bytes {
+            location.push(format!("after {n} output bytes"));
+        }
+        if let Some(pattern) = self.bit_pattern {
+            location.push(format!("bits {pattern}"));
+        }
+        if !location.is_empty() {
+            write!(f, " ({})", location.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Fills in the bit offset and output count of a [`DecodeError`] inside
+/// `err` where they are not known yet.
+pub(crate) fn locate(
+    mut err: std::io::Error,
+    bit_offset: u64,
+    output_bytes: u64,
+) -> std::io::Error {
+    if let Some(decode) = DecodeError::from_io_mut(&mut err) {
+        decode.bit_offset.get_or_insert(bit_offset);
+        decode.output_bytes.get_or_insert(output_bytes);
+    }
+    err
+}
+
+/// Moves the location of a [`DecodeError`] inside `err` by `bits` and
+/// `output_bytes`, for errors found in a stream that starts there.
+pub(crate) fn shift(mut err: std::io::Error, bits: u64, output_bytes: u64) -> std::io::Error {
+    if let Some(decode) = DecodeError::from_io_mut(&mut err) {
+        if let Some(bit) = &mut decode.bit_offset {
+            *bit += bits;
+        }
+        if let Some(n) = &mut decode.output_bytes {
+            *n += output_bytes;
+        }
+    }
+    err
+}
+
+/// Moves the file offset and output bytes of a [`DecodeError`] inside `err`
+/// by `file_offset` and `output_bytes`, for errors found in an appended
+/// segment (see [`crate::segment`]) that starts there.
+pub(crate) fn in_segment(
+    mut err: std::io::Error,
+    file_offset: u64,
+    output_bytes: u64,
+) -> std::io::Error {
+    if let Some(decode) = DecodeError::from_io_mut(&mut err) {
+        if let Some(byte) = &mut decode.file_offset {
+            *byte += file_offset;
+        }
+        if let Some(n) = &mut decode.output_bytes {
+            *n += output_bytes;
+        }
+    }
+    err
+}
+
+/// Sets the file offset of a [`DecodeError`] inside `err` from its bit
+/// offset, given the file offset of the first payload byte.
+pub(crate) fn in_file(mut err: std::io::Error, payload_start: u64) -> std::io::Error {
+    if let Some(decode) = DecodeError::from_io_mut(&mut err) {
+        if let (None, Some(bit)) = (decode.file_offset, decode.bit_offset) {
+            decode.file_offset = Some(payload_start + bit / 8);
+        }
+    }
+    err
+}
+
+/// Prefixes the message of `err` with the archive member `name` (see
+/// [`crate::archive`]), keeping a [`DecodeError`] inside intact.
+pub(crate) fn in_member(mut err: std::io::Error, name: &str) -> std::io::Error {
+    match DecodeError::from_io_mut(&mut err) {
+        Some(decode) => {
+            decode.message = format!("{name}: {}", decode.message);
+            err
+        }
+        None => std::io::Error::new(err.kind(), format!("{name}: {err}")),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_includes_location() {
+        let err = DecodeError::new(
+            CorruptionKind::InvalidCode,
+            "Invalid Huffman code in bitstream",
+        )
+        .at_bit(8 * 100 + 3)
+        .with_pattern(0b0011, 6);
+        let err = in_file(locate(err.into(), 0, 512), 40);
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Invalid Huffman code in bitstream (at byte 140 bit 3, after 512 output bytes, \
+             bits 000011)"
+        );
+        let decode = DecodeError::from_io(&err).unwrap();
+        assert_eq!(decode.file_offset, Some(140));
+        assert_eq!(decode.output_bytes, Some(512));
+    }
+
+    #[test]
+    fn shift_moves_known_fields_only() {
+        let err: std::io::Error = DecodeError::new(CorruptionKind::Truncated, "Truncated")
+            .at_bit(5)
+            .into();
+        let err = shift(err, 16, 7);
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let decode = DecodeError::from_io(&err).unwrap();
+        assert_eq!(decode.bit_offset, Some(21));
+        assert_eq!(decode.output_bytes, None);
+    }
+
+    #[test]
+    fn other_errors_pass_through() {
+        let err = std::io::Error::new(std::io::ErrorKind::InvalidData, "plain");
+        let err = in_file(shift(locate(err, 1, 2), 3, 4), 5);
+        assert!(DecodeError::from_io(&err).is_none());
+        assert_eq!(err.to_string(), "plain");
+    }
+}
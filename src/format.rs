@@ -1,4 +1,8 @@
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::codelen;
+use crate::error::{CorruptionKind, DecodeError};
+use crate::huffman::MAX_CODE_LENGTH;
 
 // ---------------------------------------------------------------------------
 // Format constants
@@ -8,19 +12,120 @@ use std::io::{Read, Write};
 pub const MAGIC: [u8; 4] = [0x43, 0x4D, 0x50, 0x52];
 
 /// Current file format version.
-pub const VERSION: u8 = 0x02;
+pub const VERSION: u8 = 0x08;
+
+/// Last format version with a fixed Huffman symbol table and no method byte.
+pub const VERSION_HUFFMAN_ONLY: u8 = 0x02;
+
+/// First format version whose Huffman tables start with an encoding tag and
+/// may use the compact run-length form.
+pub const VERSION_COMPACT_TABLE: u8 = 0x04;
+
+/// First format version whose header ends with a CRC-32 of the header
+/// itself (magic through coder table).
+pub const VERSION_HEADER_CRC: u8 = 0x05;
+
+/// First format version that may end in a Reed–Solomon parity section
+/// (see [`crate::parity`]).
+pub const VERSION_PARITY: u8 = 0x06;
 
-/// Size of the CRC-32 trailer in bytes.
+/// First format version with the [`METHOD_ENCRYPTED`] method.
+pub const VERSION_ENCRYPTED: u8 = 0x07;
+
+/// First format version that may carry an HMAC-SHA-256 tag
+/// (see [`FLAG_HMAC`]).
+pub const VERSION_HMAC: u8 = 0x08;
+
+/// Size of the CRC-32 trailer, and of the v0x05+ header CRC, in bytes.
 pub const CRC_SIZE: u64 = 4;
 
 /// Offset of the padding byte in the header.
 #[allow(dead_code)]
 pub const PADDING_OFFSET: u64 = 13;
 
+/// Offset of the flags byte in a v0x03+ header.
+pub const FLAGS_OFFSET: u64 = 15;
+
 /// Size of the fixed portion of the header (before the symbol table).
 #[allow(dead_code)]
 pub const FIXED_HEADER_SIZE: u64 = 16;
 
+/// Size of the fixed portion of a v0x03 header (before the coder table).
+pub const FIXED_HEADER_SIZE_V3: u64 = 18;
+
+// ---------------------------------------------------------------------------
+// Entropy coding methods (v0x03+)
+// ---------------------------------------------------------------------------
+
+/// Canonical Huffman coding; the table is a list of `(symbol, code_len)`.
+pub const METHOD_HUFFMAN: u8 = 0x00;
+
+/// rANS coding; the table is a normalised frequency table
+/// (see [`crate::ans::FrequencyTable`]).
+pub const METHOD_ANS: u8 = 0x01;
+
+/// Stored: the payload is the original bytes, used when entropy coding
+/// would expand the input.  The table is empty.
+pub const METHOD_STORED: u8 = 0x02;
+
+/// Canonical Huffman coding split into four interleaved streams per block
+/// (see [`crate::huffman::Huffman4Coder`]).  The table is the same as for
+/// [`METHOD_HUFFMAN`].
+pub const METHOD_HUFFMAN4: u8 = 0x03;
+
+/// Encrypted (v0x07+): the payload is a complete `.cmpr` file encrypted with
+/// ChaCha20-Poly1305, and the table holds the encryption parameters (see
+/// [`crate::crypto`]).
+pub const METHOD_ENCRYPTED: u8 = 0x04;
+
+/// Header flag (v0x04+): a seek index section precedes the CRC-32 trailer
+/// (see [`crate::index`]).
+pub const FLAG_INDEX: u8 = 0x01;
+
+/// Header flag (v0x06+): a parity section follows the CRC-32 trailer
+/// (see [`crate::parity`]).
+pub const FLAG_PARITY: u8 = 0x02;
+
+/// Header flag (v0x08+): an HMAC-SHA-256 tag over everything before it
+/// follows the CRC-32 trailer (see [`crate::crypto::MAC_SIZE`]).
+pub const FLAG_HMAC: u8 = 0x04;
+
+/// Header flag (v0x03+): more segments were appended after this file, and
+/// the file ends in a segment table (see [`crate::segment`]).  Only the
+/// first segment carries it, so it may appear in a header of any version
+/// with flags; decoders that predate it refuse the file instead of
+/// decoding just the first segment.
+pub const FLAG_SEGMENTED: u8 = 0x08;
+
+/// All header flags this version understands.
+pub const KNOWN_FLAGS: u8 = FLAG_INDEX | FLAG_PARITY | FLAG_HMAC | FLAG_SEGMENTED;
+
+/// Huffman table tag (v0x04+): plain `(symbol, code_len)` pair list.
+pub const HUFFMAN_TABLE_PAIRS: u8 = 0x00;
+
+/// Huffman table tag (v0x04+): compact run-length/Huffman-coded length
+/// vector (see [`crate::codelen`]).
+pub const HUFFMAN_TABLE_COMPACT: u8 = 0x01;
+
+/// Returns a human-readable name for a built-in method byte.
+pub fn method_name(method: u8) -> &'static str {
+    match method {
+        METHOD_HUFFMAN => "huffman",
+        METHOD_ANS => "ans",
+        METHOD_STORED => "stored",
+        METHOD_HUFFMAN4 => "huffman4",
+        METHOD_ENCRYPTED => "encrypted",
+        _ => "custom",
+    }
+}
+
+/// Whether `method` stores a Huffman `(symbol, code_len)` table, which is
+/// written in the tagged pair/compact form and parsed into
+/// [`Header::symbol_table`].
+pub fn uses_huffman_table(method: u8) -> bool {
+    matches!(method, METHOD_HUFFMAN | METHOD_HUFFMAN4)
+}
+
 // ---------------------------------------------------------------------------
 // CRC-32 (Ethernet / ISO-HDLC, polynomial 0xEDB88320)
 // ---------------------------------------------------------------------------
@@ -48,13 +153,193 @@ const CRC32_TABLE: [u32; 256] = {
 
 /// Computes the CRC-32 checksum over `data`.
 pub fn crc32(data: &[u8]) -> u32 {
-    let mut crc = !0u32;
+    crc32_update(0, data)
+}
+
+/// Extends the CRC-32 `crc` of some bytes to cover `data` after them, so
+/// that `crc32_update(crc32(a), b) == crc32(a ++ b)`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
     for &byte in data {
         crc = CRC32_TABLE[((crc as u8) ^ byte) as usize] ^ (crc >> 8);
     }
     !crc
 }
 
+// ---------------------------------------------------------------------------
+// SHA-256 (FIPS 180-4) and HMAC-SHA-256 (RFC 2104)
+// ---------------------------------------------------------------------------
+
+/// SHA-256 round constants.
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    /// A hash of no bytes yet.
+    pub fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Hashes `data` after the bytes seen so far.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.block_len > 0 {
+            let take = data.len().min(64 - self.block_len);
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.block[..rest.len()].copy_from_slice(rest);
+        self.block_len = rest.len();
+    }
+
+    /// The digest of everything hashed.
+    pub fn finalize(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        let mut tail = vec![0x80u8];
+        tail.resize((119 - self.block_len) % 64 + 1, 0);
+        tail.extend_from_slice(&bit_len.to_be_bytes());
+        self.update(&tail);
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/// Computes the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finalize()
+}
+
+/// Incremental HMAC-SHA-256.
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    /// A MAC keyed with `key`, of no bytes yet.
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if key.len() > 64 {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|byte| byte ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|byte| byte ^ 0x5c));
+        HmacSha256 { inner, outer }
+    }
+
+    /// Authenticates `data` after the bytes seen so far.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// The tag of everything authenticated.
+    pub fn finalize(self) -> [u8; 32] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finalize());
+        outer.finalize()
+    }
+}
+
+/// Computes the HMAC-SHA-256 tag of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finalize()
+}
+
 // ---------------------------------------------------------------------------
 // Header type
 // ---------------------------------------------------------------------------
@@ -62,24 +347,57 @@ pub fn crc32(data: &[u8]) -> u32 {
 /// Parsed `.cmpr` file header.
 #[derive(Debug, PartialEq)]
 pub struct Header {
-    /// Format version (0x01 or 0x02).
+    /// Format version (0x01 through [`VERSION`]).
     pub version: u8,
     /// Original uncompressed file size in bytes.
     pub original_size: u64,
     /// Number of padding bits (0..7) in the final byte of the bitstream.
     pub padding: u8,
-    /// Number of distinct symbols in the symbol table.
+    /// Entropy coding method.  Always [`METHOD_HUFFMAN`] before v0x03.
+    pub method: u8,
+    /// Header flags such as [`FLAG_INDEX`].  Always 0 before v0x04.
+    pub flags: u8,
+    /// Number of distinct symbols in the symbol table.  Only meaningful for
+    /// Huffman methods (see [`uses_huffman_table`]).
     pub symbol_count: u16,
     /// Symbol table entries: (byte_value, code_length_in_bits).
-    /// Sorted by byte value.
+    /// Sorted by byte value.  Empty for methods other than Huffman.
     pub symbol_table: Vec<(u8, u8)>,
+    /// Coder table bytes, ready for
+    /// [`EntropyCoder::read_table`](crate::coder::EntropyCoder::read_table).
+    /// For Huffman this is always the `u16` count plus `(symbol, code_len)`
+    /// pair layout, whatever encoding the file used on disk.
+    pub table: Vec<u8>,
+    /// Size of the coder table as stored on disk (v0x03+), or 0.
+    pub stored_table_len: u16,
+    /// Whether the Huffman table was stored in the compact form.
+    pub compact_table: bool,
+    /// Whether the Huffman code lengths leave part of the code space unused
+    /// (see [`check_kraft`]).  Always `false` for other methods.
+    pub incomplete_table: bool,
+    /// CRC-32 of the header (v0x05+), already checked by [`read_header`].
+    pub header_crc: Option<u32>,
+}
+
+impl Header {
+    /// Total size of the encoded header in bytes, i.e. the offset of the
+    /// first compressed payload byte.
+    pub fn header_len(&self) -> u64 {
+        if self.version >= VERSION_HEADER_CRC {
+            FIXED_HEADER_SIZE_V3 + self.stored_table_len as u64 + CRC_SIZE
+        } else if self.version >= 0x03 {
+            FIXED_HEADER_SIZE_V3 + self.stored_table_len as u64
+        } else {
+            FIXED_HEADER_SIZE + self.symbol_count as u64 * 2
+        }
+    }
 }
 
 // ---------------------------------------------------------------------------
 // Write helpers
 // ---------------------------------------------------------------------------
 
-/// Writes a complete `.cmpr` header to `writer`.
+/// Writes a complete Huffman `.cmpr` header to `writer`.
 ///
 /// `symbol_table` must be sorted by byte value.  The caller is responsible
 /// for seeking back to [`PADDING_OFFSET`] and writing the correct padding
@@ -89,18 +407,146 @@ pub fn write_header<W: Write>(
     original_size: u64,
     symbol_table: &[(u8, u8)],
     padding: u8,
+) -> std::io::Result<()> {
+    write_method_header(
+        writer,
+        original_size,
+        METHOD_HUFFMAN,
+        &encode_symbol_table(symbol_table),
+        padding,
+    )
+}
+
+/// Writes a complete `.cmpr` header for an arbitrary coding `method`.
+///
+/// `table` is the coder's serialised table and is stored behind a `u16`
+/// length prefix.  Huffman tables are re-encoded in whichever of the pair
+/// list and the compact form is smaller; other tables are stored verbatim.
+/// As with [`write_header`], the padding byte may be patched afterwards at
+/// [`PADDING_OFFSET`], followed by [`update_header_crc`].
+pub fn write_method_header<W: Write>(
+    writer: &mut W,
+    original_size: u64,
+    method: u8,
+    table: &[u8],
+    padding: u8,
 ) -> std::io::Result<()> {
     debug_assert!(padding <= 7, "padding must be 0..7");
+    let stored;
+    let table = if uses_huffman_table(method) {
+        stored = encode_huffman_table(table)?;
+        &stored[..]
+    } else {
+        table
+    };
+    let table_len = u16::try_from(table.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Coder table of {} bytes is too large", table.len()),
+        )
+    })?;
+
+    let mut header = Vec::with_capacity(FIXED_HEADER_SIZE_V3 as usize + table.len() + 4);
+    header.extend_from_slice(&MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&original_size.to_le_bytes());
+    header.push(padding);
+    header.extend_from_slice(&[method, 0]); // method, reserved flags
+    header.extend_from_slice(&table_len.to_le_bytes());
+    header.extend_from_slice(table);
+    header.extend_from_slice(&crc32(&header).to_le_bytes());
+    writer.write_all(&header)
+}
+
+/// Recomputes the header CRC of the current-version header occupying the
+/// first `header_len` bytes of `file`, after its padding or flags byte was
+/// patched.  Leaves the position at the end of the header.
+pub fn update_header_crc<F: Read + Write + Seek>(
+    file: &mut F,
+    header_len: u64,
+) -> std::io::Result<()> {
+    let mut header = vec![0u8; (header_len - CRC_SIZE) as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    file.write_all(&crc32(&header).to_le_bytes())
+}
+
+/// Picks the smaller on-disk form of a Huffman pair table and prefixes it
+/// with its encoding tag.
+fn encode_huffman_table(table: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut cursor = table;
+    let (_, symbol_table) = read_symbol_table(&mut cursor)?;
+
+    let mut lengths = [0u8; 256];
+    for &(symbol, code_len) in &symbol_table {
+        lengths[symbol as usize] = code_len;
+    }
+    let compact = codelen::encode(&lengths);
+
+    let mut out = Vec::with_capacity(1 + table.len().min(compact.len()));
+    if compact.len() < table.len() {
+        out.push(HUFFMAN_TABLE_COMPACT);
+        out.extend_from_slice(&compact);
+    } else {
+        out.push(HUFFMAN_TABLE_PAIRS);
+        out.extend_from_slice(table);
+    }
+    Ok(out)
+}
+
+/// Parses an on-disk Huffman table of the given header `version`.  Returns
+/// the symbol table and whether it was stored in the compact form.
+fn decode_huffman_table(version: u8, stored: &[u8]) -> std::io::Result<(Vec<(u8, u8)>, bool)> {
+    let (tag, body) = if version >= VERSION_COMPACT_TABLE {
+        match stored.split_first() {
+            Some((&tag, body)) => (tag, body),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Huffman table is empty",
+                ))
+            }
+        }
+    } else {
+        (HUFFMAN_TABLE_PAIRS, stored)
+    };
+
+    match tag {
+        HUFFMAN_TABLE_PAIRS => {
+            let mut cursor = body;
+            let (_, symbol_table) = read_symbol_pairs(&mut cursor)?;
+            if !cursor.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Trailing bytes after Huffman symbol table",
+                ));
+            }
+            Ok((symbol_table, false))
+        }
+        HUFFMAN_TABLE_COMPACT => {
+            let lengths = codelen::decode(body)?;
+            let symbol_table: Vec<(u8, u8)> = (0..=255u8)
+                .zip(lengths)
+                .filter(|&(_, len)| len > 0)
+                .collect();
+            Ok((symbol_table, true))
+        }
+        tag => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown Huffman table encoding {tag:#04x}"),
+        )),
+    }
+}
 
-    writer.write_all(&MAGIC)?;
-    writer.write_all(&[VERSION])?;
-    writer.write_all(&original_size.to_le_bytes())?;
-    writer.write_all(&[padding])?;
-    writer.write_all(&(symbol_table.len() as u16).to_le_bytes())?;
+/// Serialises a Huffman symbol table: `u16` count, then `(symbol, code_len)`
+/// pairs.
+fn encode_symbol_table(symbol_table: &[(u8, u8)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 + symbol_table.len() * 2);
+    out.extend_from_slice(&(symbol_table.len() as u16).to_le_bytes());
     for &(symbol, code_len) in symbol_table {
-        writer.write_all(&[symbol, code_len])?;
+        out.extend_from_slice(&[symbol, code_len]);
     }
-    Ok(())
+    out
 }
 
 // ---------------------------------------------------------------------------
@@ -109,8 +555,11 @@ pub fn write_header<W: Write>(
 
 /// Reads and validates a `.cmpr` header from `reader`.
 ///
-/// Returns an `InvalidData` error if the magic, version, padding, or symbol
-/// count are out of range.
+/// Returns an `InvalidData` error if the magic, version, padding, flags, or
+/// symbol count are out of range, or if the header CRC (v0x05+) does not
+/// match.  The CRC is checked before any field after the version is
+/// interpreted.  Method bytes are not checked here, so that headers written
+/// by custom coders can still be inspected.
 pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
     // --- Magic ---
     let mut magic = [0u8; 4];
@@ -118,9 +567,7 @@ pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
     if magic != MAGIC {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
-            format!(
-                "Invalid magic bytes: expected {MAGIC:02X?}, got {magic:02X?}"
-            ),
+            format!("Invalid magic bytes: expected {MAGIC:02X?}, got {magic:02X?}"),
         ));
     }
 
@@ -128,13 +575,53 @@ pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
     let mut version = [0u8; 1];
     reader.read_exact(&mut version)?;
     let version = version[0];
-    if version != 0x01 && version != 0x02 {
+    if !(0x01..=VERSION).contains(&version) {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             format!("Unsupported format version {}", version),
         ));
     }
 
+    if version >= VERSION_HEADER_CRC {
+        let (body, crc) = read_checked_body(reader, version)?;
+        let mut header = read_header_body(version, &mut &body[..])?;
+        header.header_crc = Some(crc);
+        return Ok(header);
+    }
+    read_header_body(version, reader)
+}
+
+/// Reads the rest of a v0x05+ header after the version byte and checks it
+/// against the header CRC that follows it.  Returns the bytes between the
+/// version and the CRC, and the CRC.
+fn read_checked_body<R: Read>(reader: &mut R, version: u8) -> std::io::Result<(Vec<u8>, u32)> {
+    let fixed = (FIXED_HEADER_SIZE_V3 - 5) as usize;
+    let mut body = vec![0u8; fixed];
+    reader.read_exact(&mut body)?;
+    let table_len = u16::from_le_bytes([body[fixed - 2], body[fixed - 1]]);
+    body.resize(fixed + table_len as usize, 0);
+    reader.read_exact(&mut body[fixed..])?;
+
+    let mut crc_buf = [0u8; 4];
+    reader.read_exact(&mut crc_buf)?;
+    let stored_crc = u32::from_le_bytes(crc_buf);
+    let mut covered = MAGIC.to_vec();
+    covered.push(version);
+    covered.extend_from_slice(&body);
+    let expected_crc = crc32(&covered);
+    if stored_crc != expected_crc {
+        let msg = format!(
+            "Header CRC-32 mismatch: expected {expected_crc:#010x}, got {stored_crc:#010x}"
+        );
+        return Err(DecodeError::new(CorruptionKind::HeaderCrcMismatch, msg)
+            .at_file_offset(covered.len() as u64)
+            .into());
+    }
+    Ok((body, stored_crc))
+}
+
+/// Parses the header fields after the version byte.
+fn read_header_body<R: Read>(version: u8, reader: &mut R) -> std::io::Result<Header> {
     // --- Original size ---
     let mut size_buf = [0u8; 8];
     reader.read_exact(&mut size_buf)?;
@@ -151,6 +638,138 @@ pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
         ));
     }
 
+    if version <= VERSION_HUFFMAN_ONLY {
+        let (symbol_count, symbol_table) = read_symbol_pairs(reader)?;
+        let table = encode_symbol_table(&symbol_table);
+        let incomplete_table = !check_kraft(&symbol_table)?;
+        return Ok(Header {
+            version,
+            original_size,
+            padding,
+            method: METHOD_HUFFMAN,
+            flags: 0,
+            symbol_count,
+            symbol_table,
+            table,
+            stored_table_len: 0,
+            compact_table: false,
+            incomplete_table,
+            header_crc: None,
+        });
+    }
+
+    // --- Method and flags (v0x03+) ---
+    let mut method_buf = [0u8; 2];
+    reader.read_exact(&mut method_buf)?;
+    let [method, flags] = method_buf;
+    let known = if version >= VERSION_HMAC {
+        KNOWN_FLAGS
+    } else if version >= VERSION_PARITY {
+        FLAG_INDEX | FLAG_PARITY
+    } else if version >= VERSION_COMPACT_TABLE {
+        FLAG_INDEX
+    } else {
+        0
+    };
+    if flags & !(known | FLAG_SEGMENTED) != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported header flags {flags:#04x}"),
+        ));
+    }
+
+    // --- Coder table ---
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf)?;
+    let stored_table_len = u16::from_le_bytes(len_buf);
+    let mut stored = vec![0u8; stored_table_len as usize];
+    reader.read_exact(&mut stored)?;
+
+    let (symbol_count, symbol_table, compact_table, table) = if uses_huffman_table(method) {
+        let (symbol_table, compact) = decode_huffman_table(version, &stored)?;
+        let table = encode_symbol_table(&symbol_table);
+        (symbol_table.len() as u16, symbol_table, compact, table)
+    } else {
+        (0, Vec::new(), false, stored)
+    };
+    let incomplete_table = !check_kraft(&symbol_table)?;
+
+    Ok(Header {
+        version,
+        original_size,
+        padding,
+        method,
+        flags,
+        symbol_count,
+        symbol_table,
+        table,
+        stored_table_len,
+        compact_table,
+        incomplete_table,
+        header_crc: None,
+    })
+}
+
+/// Kraft sum of a code with these lengths, scaled by 2^32 so that a
+/// complete code sums to exactly [`KRAFT_COMPLETE`].
+pub fn kraft_sum(symbol_table: &[(u8, u8)]) -> u64 {
+    symbol_table
+        .iter()
+        .map(|&(_, len)| 1u64 << (MAX_CODE_LENGTH - len.min(MAX_CODE_LENGTH)))
+        .sum()
+}
+
+/// [`kraft_sum`] of a complete code.
+pub const KRAFT_COMPLETE: u64 = 1 << MAX_CODE_LENGTH;
+
+/// Checks Huffman code lengths against the Kraft inequality and returns
+/// whether the code is complete.
+///
+/// Oversubscribed lengths (more codes than fit, e.g. three 1-bit codes)
+/// cannot be assigned prefix-free codes and are rejected with
+/// `InvalidData`.  An incomplete code is accepted but leaves bit patterns
+/// that decode to nothing, which no encoder in this crate produces.  Tables
+/// with fewer than two symbols count as complete.
+///
+/// A lone symbol must have a 1-bit code, the one every encoder gives it, so
+/// that the decoder and the payload size checks read it the same way.
+pub fn check_kraft(symbol_table: &[(u8, u8)]) -> std::io::Result<bool> {
+    if let [(byte, len)] = symbol_table {
+        if *len != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Lone byte 0x{byte:02X} has code length {len}, not 1"),
+            ));
+        }
+    }
+    let sum = kraft_sum(symbol_table);
+    if sum > KRAFT_COMPLETE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Huffman code lengths are oversubscribed (Kraft sum {:.4} > 1)",
+                sum as f64 / KRAFT_COMPLETE as f64
+            ),
+        ));
+    }
+    Ok(sum == KRAFT_COMPLETE || symbol_table.len() < 2)
+}
+
+/// Reads a `u16` symbol count followed by that many `(symbol, code_len)`
+/// pairs.
+///
+/// Returns an `InvalidData` error if a code length is out of range, the
+/// symbols are not strictly increasing (duplicated or unsorted), or the
+/// lengths are oversubscribed (see [`check_kraft`]).
+pub fn read_symbol_table<R: Read>(reader: &mut R) -> std::io::Result<(u16, Vec<(u8, u8)>)> {
+    let (symbol_count, symbol_table) = read_symbol_pairs(reader)?;
+    check_kraft(&symbol_table)?;
+    Ok((symbol_count, symbol_table))
+}
+
+/// [`read_symbol_table`] without the Kraft check, for header parsing, which
+/// runs [`check_kraft`] once on whichever table form it read.
+fn read_symbol_pairs<R: Read>(reader: &mut R) -> std::io::Result<(u16, Vec<(u8, u8)>)> {
     // --- Symbol count ---
     let mut count_buf = [0u8; 2];
     reader.read_exact(&mut count_buf)?;
@@ -174,24 +793,32 @@ pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
                 format!("Byte 0x{byte:02X} has zero-length code"),
             ));
         }
-        if code_len > 32 {
+        if code_len > MAX_CODE_LENGTH {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!(
-                    "Byte 0x{byte:02X} code length {code_len} exceeds maximum of 32"
+                    "Byte 0x{byte:02X} code length {code_len} exceeds maximum of {MAX_CODE_LENGTH}"
                 ),
             ));
         }
+        if let Some(&(prev, _)) = symbol_table.last() {
+            if byte == prev {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Byte 0x{byte:02X} appears twice in the symbol table"),
+                ));
+            }
+            if byte < prev {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Symbol table is not sorted: byte 0x{byte:02X} follows 0x{prev:02X}"),
+                ));
+            }
+        }
         symbol_table.push((byte, code_len));
     }
 
-    Ok(Header {
-        version,
-        original_size,
-        padding,
-        symbol_count,
-        symbol_table,
-    })
+    Ok((symbol_count, symbol_table))
 }
 
 // ---------------------------------------------------------------------------
@@ -201,6 +828,8 @@ pub fn read_header<R: Read>(reader: &mut R) -> std::io::Result<Header> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::coder::EntropyCoder;
+    use crate::huffman::HuffmanCoder;
     use std::io::Cursor;
 
     /// Helper: write then immediately read back a header.
@@ -215,7 +844,7 @@ mod tests {
     fn round_trip_small_table() {
         let table = vec![(0x41, 3), (0x42, 3), (0x43, 2)];
         let h = round_trip(table.clone(), 1000, 3);
-        assert_eq!(h.version, 0x02);
+        assert_eq!(h.version, VERSION);
         assert_eq!(h.original_size, 1000);
         assert_eq!(h.padding, 3);
         assert_eq!(h.symbol_count, 3);
@@ -226,7 +855,7 @@ mod tests {
     fn round_trip_all_256() {
         let table: Vec<(u8, u8)> = (0..=255).map(|b| (b, 8)).collect();
         let h = round_trip(table.clone(), 1_000_000, 0);
-        assert_eq!(h.version, 0x02);
+        assert_eq!(h.version, VERSION);
         assert_eq!(h.original_size, 1_000_000);
         assert_eq!(h.padding, 0);
         assert_eq!(h.symbol_count, 256);
@@ -252,12 +881,20 @@ mod tests {
         assert!(err.to_string().contains("version"));
     }
 
+    /// Helper: recompute the CRC at the end of a patched current-version
+    /// header.
+    fn reseal(buf: &mut [u8]) {
+        let end = buf.len() - CRC_SIZE as usize;
+        let crc = crc32(&buf[..end]);
+        buf[end..].copy_from_slice(&crc.to_le_bytes());
+    }
+
     #[test]
     fn reject_bad_padding() {
-        let mut buf = MAGIC.to_vec();
-        buf.push(VERSION);
-        buf.extend(&0u64.to_le_bytes()); // size
-        buf.push(8); // padding > 7
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 0, METHOD_ANS, &[], 0).unwrap();
+        buf[PADDING_OFFSET as usize] = 8; // padding > 7
+        reseal(&mut buf);
         let mut cursor = Cursor::new(buf);
         let err = read_header(&mut cursor).unwrap_err();
         assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
@@ -267,7 +904,7 @@ mod tests {
     #[test]
     fn reject_bad_symbol_count() {
         let mut buf = MAGIC.to_vec();
-        buf.push(VERSION);
+        buf.push(VERSION_HUFFMAN_ONLY);
         buf.extend(&0u64.to_le_bytes()); // size
         buf.push(0); // padding
         buf.extend(&257u16.to_le_bytes()); // symbol_count = 257 (invalid)
@@ -290,16 +927,195 @@ mod tests {
         let table = vec![(b'A', 3), (b'B', 4)];
         let mut buf = Vec::new();
         write_header(&mut buf, 100, &table, 1).unwrap();
-        // Fixed header: 4 + 1 + 8 + 1 + 2 = 16 bytes
-        // Symbol table: 2 entries * 2 bytes = 4 bytes
-        // Total: 20 bytes
-        assert_eq!(buf.len(), 20);
+        // Fixed header: 4 + 1 + 8 + 1 + 1 + 1 + 2 = 18 bytes
+        // Symbol table: tag + 2-byte count + 2 entries * 2 bytes = 7 bytes
+        // (the pair list beats the compact form for tiny tables)
+        // Header CRC: 4 bytes
+        // Total: 29 bytes
+        assert_eq!(buf.len(), 29);
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.header_len(), 29);
+        assert_eq!(h.header_crc, Some(crc32(&buf[..25])));
+        assert!(!h.compact_table);
+    }
+
+    #[test]
+    fn v2_header_len() {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION_HUFFMAN_ONLY);
+        buf.extend(&100u64.to_le_bytes()); // size
+        buf.push(1); // padding
+        buf.extend(&2u16.to_le_bytes()); // symbol_count = 2
+        buf.extend(&[b'A', 1, b'B', 1]);
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.method, METHOD_HUFFMAN);
+        assert_eq!(h.symbol_table, vec![(b'A', 1), (b'B', 1)]);
+        assert_eq!(h.table, vec![2, 0, b'A', 1, b'B', 1]);
+        assert_eq!(h.header_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn all_256_uses_compact_table() {
+        let table: Vec<(u8, u8)> = (0..=255).map(|b| (b, 8 + (b % 3))).collect();
+        let mut buf = Vec::new();
+        write_header(&mut buf, 1, &table, 0).unwrap();
+        // The v0x03 pair layout would need 18 + 2 + 256 * 2 = 532 bytes.
+        assert!(buf.len() < 100, "header is {} bytes", buf.len());
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert!(h.compact_table);
+        assert_eq!(h.symbol_count, 256);
+        assert_eq!(h.symbol_table, table);
+        assert_eq!(h.header_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn v3_pair_table_still_readable() {
+        let mut buf = MAGIC.to_vec();
+        buf.push(0x03);
+        buf.extend(&7u64.to_le_bytes()); // size
+        buf.push(2); // padding
+        buf.extend(&[METHOD_HUFFMAN, 0]); // method, flags
+        buf.extend(&6u16.to_le_bytes()); // table length
+        buf.extend(&2u16.to_le_bytes()); // symbol_count = 2
+        buf.extend(&[b'x', 1, b'y', 1]);
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.version, 0x03);
+        assert_eq!(h.symbol_table, vec![(b'x', 1), (b'y', 1)]);
+        assert_eq!(h.header_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn v4_header_without_crc_still_readable() {
+        let mut buf = MAGIC.to_vec();
+        buf.push(0x04);
+        buf.extend(&7u64.to_le_bytes()); // size
+        buf.extend(&[0, METHOD_ANS, 0]); // padding, method, flags
+        buf.extend(&2u16.to_le_bytes()); // table length
+        buf.extend(&[0xAB, 0xCD]);
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.version, 0x04);
+        assert_eq!(h.header_crc, None);
+        assert_eq!(h.table, vec![0xAB, 0xCD]);
+        assert_eq!(h.header_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn header_crc_catches_corruption() {
+        let table: Vec<(u8, u8)> = (0..=255).map(|b| (b, 8)).collect();
+        let mut buf = Vec::new();
+        write_header(&mut buf, 1000, &table, 3).unwrap();
+        // Original size, padding, and a byte of the symbol table.
+        for pos in [
+            6,
+            PADDING_OFFSET as usize,
+            FIXED_HEADER_SIZE_V3 as usize + 2,
+        ] {
+            let mut corrupt = buf.clone();
+            corrupt[pos] ^= 0x04;
+            let err = read_header(&mut Cursor::new(&corrupt)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("Header CRC-32 mismatch"), "{err}");
+        }
+    }
+
+    #[test]
+    fn update_header_crc_after_patch() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, 1000, &[(b'A', 1), (b'B', 1)], 0).unwrap();
+        let header_len = buf.len() as u64;
+        buf.extend(&[0xFF; 8]); // payload
+        buf[PADDING_OFFSET as usize] = 5;
+        let mut cursor = Cursor::new(buf);
+        update_header_crc(&mut cursor, header_len).unwrap();
+        assert_eq!(cursor.position(), header_len);
+        cursor.set_position(0);
+        assert_eq!(read_header(&mut cursor).unwrap().padding, 5);
+    }
+
+    #[test]
+    fn reject_unknown_table_encoding() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, 0, &[(b'A', 1)], 0).unwrap();
+        buf[FIXED_HEADER_SIZE_V3 as usize] = 0x7F;
+        reseal(&mut buf);
+        let err = read_header(&mut Cursor::new(&buf)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("table encoding"));
+    }
+
+    #[test]
+    fn round_trip_method_header() {
+        let table = vec![0xDE, 0xAD, 0xBE, 0xEF];
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 42, METHOD_ANS, &table, 0).unwrap();
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.version, VERSION);
+        assert_eq!(h.method, METHOD_ANS);
+        assert_eq!(h.original_size, 42);
+        assert_eq!(h.table, table);
+        assert!(h.symbol_table.is_empty());
+        assert_eq!(h.header_len(), buf.len() as u64);
+    }
+
+    #[test]
+    fn accept_custom_method() {
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 0, 0x80, &[1, 2, 3], 0).unwrap();
+        let h = read_header(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(h.method, 0x80);
+        assert_eq!(h.table, vec![1, 2, 3]);
+        assert_eq!(method_name(h.method), "custom");
+    }
+
+    #[test]
+    fn reject_unknown_flags() {
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 0, METHOD_ANS, &[], 0).unwrap();
+        buf[15] = 0x80;
+        reseal(&mut buf);
+        let err = read_header(&mut Cursor::new(&buf)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("flags"));
+    }
+
+    #[test]
+    fn parity_flag_needs_v6() {
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 0, METHOD_ANS, &[], 0).unwrap();
+        buf[15] = FLAG_PARITY;
+        reseal(&mut buf);
+        assert_eq!(
+            read_header(&mut Cursor::new(&buf)).unwrap().flags,
+            FLAG_PARITY
+        );
+
+        buf[4] = VERSION_HEADER_CRC;
+        reseal(&mut buf);
+        let err = read_header(&mut Cursor::new(&buf)).unwrap_err();
+        assert!(err.to_string().contains("flags"));
+    }
+
+    #[test]
+    fn hmac_flag_needs_v8() {
+        let mut buf = Vec::new();
+        write_method_header(&mut buf, 0, METHOD_ANS, &[], 0).unwrap();
+        buf[15] = FLAG_HMAC;
+        reseal(&mut buf);
+        assert_eq!(
+            read_header(&mut Cursor::new(&buf)).unwrap().flags,
+            FLAG_HMAC
+        );
+
+        buf[4] = VERSION_ENCRYPTED;
+        reseal(&mut buf);
+        let err = read_header(&mut Cursor::new(&buf)).unwrap_err();
+        assert!(err.to_string().contains("flags"));
     }
 
     #[test]
     fn reject_zero_length_code() {
         let mut buf = MAGIC.to_vec();
-        buf.push(VERSION);
+        buf.push(VERSION_HUFFMAN_ONLY);
         buf.extend(&0u64.to_le_bytes()); // size
         buf.push(0); // padding
         buf.extend(&1u16.to_le_bytes()); // symbol_count = 1
@@ -313,7 +1129,7 @@ mod tests {
     #[test]
     fn reject_code_len_too_long() {
         let mut buf = MAGIC.to_vec();
-        buf.push(VERSION);
+        buf.push(VERSION_HUFFMAN_ONLY);
         buf.extend(&0u64.to_le_bytes()); // size
         buf.push(0); // padding
         buf.extend(&1u16.to_le_bytes()); // symbol_count = 1
@@ -324,6 +1140,95 @@ mod tests {
         assert!(err.to_string().contains("exceeds maximum"));
     }
 
+    /// Helper: read a v0x02 header with the given raw symbol table.
+    fn read_v2_pairs(pairs: &[(u8, u8)]) -> std::io::Result<Header> {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION_HUFFMAN_ONLY);
+        buf.extend(&0u64.to_le_bytes()); // size
+        buf.push(0); // padding
+        buf.extend(&(pairs.len() as u16).to_le_bytes());
+        for &(symbol, len) in pairs {
+            buf.extend(&[symbol, len]);
+        }
+        read_header(&mut Cursor::new(buf))
+    }
+
+    #[test]
+    fn reject_oversubscribed_table() {
+        let err = read_v2_pairs(&[(b'A', 1), (b'B', 1), (b'C', 1)]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("oversubscribed"));
+        assert!(err.to_string().contains("1.5000"));
+    }
+
+    #[test]
+    fn reject_oversubscribed_compact_table() {
+        let mut lengths = [0u8; 256];
+        lengths[..3].fill(1);
+        let mut stored = vec![HUFFMAN_TABLE_COMPACT];
+        stored.extend(codelen::encode(&lengths));
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION);
+        buf.extend(&0u64.to_le_bytes()); // size
+        buf.extend(&[0, METHOD_HUFFMAN, 0]); // padding, method, flags
+        buf.extend(&(stored.len() as u16).to_le_bytes());
+        buf.extend(&stored);
+        buf.extend(&crc32(&buf).to_le_bytes());
+        let err = read_header(&mut Cursor::new(buf)).unwrap_err();
+        assert!(err.to_string().contains("oversubscribed"));
+    }
+
+    #[test]
+    fn reject_duplicate_symbol() {
+        let err = read_v2_pairs(&[(b'A', 1), (b'A', 1)]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("appears twice"));
+    }
+
+    #[test]
+    fn reject_unsorted_table() {
+        let err = read_v2_pairs(&[(b'B', 1), (b'A', 1)]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("not sorted"));
+    }
+
+    #[test]
+    fn flag_incomplete_table() {
+        let header = read_v2_pairs(&[(b'A', 1), (b'B', 2)]).unwrap();
+        assert!(header.incomplete_table);
+        let header = read_v2_pairs(&[(b'A', 1), (b'B', 2), (b'C', 2)]).unwrap();
+        assert!(!header.incomplete_table);
+        let header = read_v2_pairs(&[(b'A', 1)]).unwrap();
+        assert!(!header.incomplete_table);
+    }
+
+    #[test]
+    fn lone_symbol_needs_a_one_bit_code() {
+        // Decoded, a lone 1-bit code takes one bit per symbol.
+        let header = read_v2_pairs(&[(b'A', 1)]).unwrap();
+        let coder = HuffmanCoder::read_table(&header.table).unwrap();
+        assert_eq!(coder.min_payload_len(16), 2);
+        let mut out = Vec::new();
+        coder
+            .decode(&mut &[0u8, 0, 0xFF][..], &mut out, 16)
+            .unwrap();
+        assert_eq!(out, [b'A'; 16]);
+
+        // Any other length would be read differently by the decoder and by
+        // the size checks, so it is refused.
+        let err = read_v2_pairs(&[(b'A', 5)]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Lone byte 0x41"));
+        assert!(read_symbol_table(&mut &[1, 0, b'A', 5][..]).is_err());
+    }
+
+    #[test]
+    fn kraft_sum_of_full_length_range() {
+        let table: Vec<(u8, u8)> = (1..=32).map(|len| (len, len)).collect();
+        assert_eq!(kraft_sum(&table), KRAFT_COMPLETE - 1);
+        assert!(!check_kraft(&table).unwrap());
+    }
+
     #[test]
     fn crc32_known_values() {
         // Known CRC-32 (ISO-HDLC) values from https://crccalc.com
@@ -338,4 +1243,60 @@ mod tests {
         let b = crc32(b"the quick brown foy");
         assert_ne!(a, b);
     }
+
+    #[test]
+    fn crc32_update_extends() {
+        let crc = crc32_update(crc32(b"1234"), b"56789");
+        assert_eq!(crc, crc32(b"123456789"));
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn sha256_known_values() {
+        // FIPS 180-4 examples.
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_incremental_matches() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for split in [0, 1, 63, 64, 65, 500, 1000] {
+            let mut hash = Sha256::new();
+            hash.update(&data[..split]);
+            hash.update(&data[split..]);
+            assert_eq!(hash.finalize(), sha256(&data), "{split}");
+        }
+    }
+
+    #[test]
+    fn hmac_sha256_known_values() {
+        // RFC 4231 test cases 2 and 6.
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
 }
@@ -34,6 +34,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn single_byte_repeated() {
         let data = vec![0xAB; 100];
         let freqs = count_frequencies(&mut &data[..]).unwrap();
@@ -47,6 +48,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn all_bytes_once() {
         let data: Vec<u8> = (0..=255).collect();
         let freqs = count_frequencies(&mut &data[..]).unwrap();
@@ -56,6 +58,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::manual_repeat_n, clippy::slow_vector_initialization)]
     fn varying_counts() {
         let mut data = Vec::new();
         // 200 times 0x00, 150 times 0x01, 100 times 0x02
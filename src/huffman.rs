@@ -1,5 +1,11 @@
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
+use std::io::{BufRead, Read, Write};
+
+use crate::bitio::{BitReader, BitWriter};
+use crate::coder::EntropyCoder;
+use crate::error::{self, CorruptionKind, DecodeError};
+use crate::format;
 
 /// Maximum allowed Huffman code length in bits.
 pub const MAX_CODE_LENGTH: u8 = 32;
@@ -132,6 +138,7 @@ fn compute_lengths(tree: &HuffTree) -> [u8; 256] {
 ///            next_code[len] = (next_code[len-1] + count[len-1]) << 1
 ///
 /// Returns (code_len_array, code_array, symbol_count).
+#[allow(clippy::doc_overindented_list_items, clippy::needless_range_loop)]
 fn canonicalize(code_len: &[u8; 256]) -> Result<([u8; 256], [u64; 256], u16), String> {
     let mut canonical_len = [0u8; 256];
     let mut canonical_code = [0u64; 256];
@@ -164,6 +171,14 @@ fn canonicalize(code_len: &[u8; 256]) -> Result<([u8; 256], [u64; 256], u16), St
         return Ok((canonical_len, canonical_code, symbol_count));
     }
 
+    // --- Reject lengths that cannot be assigned prefix-free codes ---
+    let kraft: u64 = (1..=MAX_CODE_LENGTH as usize)
+        .map(|len| (count_by_len[len] as u64) << (MAX_CODE_LENGTH as usize - len))
+        .sum();
+    if kraft > 1 << MAX_CODE_LENGTH {
+        return Err("Huffman code lengths are oversubscribed".to_string());
+    }
+
     // --- Compute starting code for each length ---
     let mut next_code = [0u64; MAX_CODE_LENGTH as usize + 2];
     let mut code = 0u64;
@@ -198,6 +213,31 @@ fn canonicalize(code_len: &[u8; 256]) -> Result<([u8; 256], [u64; 256], u16), St
 // Public API
 // ---------------------------------------------------------------------------
 
+impl CodeTable {
+    /// Rebuilds canonical codes from per-byte code lengths (0 = absent), as
+    /// stored in a file header.
+    pub fn from_lengths(code_len: &[u8; 256]) -> Result<CodeTable, String> {
+        let (code_len, code, symbol_count) = canonicalize(code_len)?;
+        Ok(CodeTable {
+            code,
+            code_len,
+            symbol_count,
+        })
+    }
+
+    /// Returns the `(byte_value, code_length)` entries for every present
+    /// byte, sorted by byte value.
+    pub fn symbol_table(&self) -> Vec<(u8, u8)> {
+        let mut symbol_table = Vec::with_capacity(self.symbol_count as usize);
+        for (b, &len) in self.code_len.iter().enumerate() {
+            if len > 0 {
+                symbol_table.push((b as u8, len));
+            }
+        }
+        symbol_table
+    }
+}
+
 /// Build canonical Huffman codes from a full frequency table.
 pub fn build_codes(freqs: &[u64; 256]) -> Result<CodeTable, String> {
     let tree = build_tree(freqs);
@@ -224,6 +264,874 @@ pub fn build_codes(freqs: &[u64; 256]) -> Result<CodeTable, String> {
     })
 }
 
+// ---------------------------------------------------------------------------
+// Encoding
+// ---------------------------------------------------------------------------
+
+/// Writes the Huffman bitstream for everything in `input` to `output` and
+/// returns the number of padding bits in the final byte.
+pub fn encode<R: Read + ?Sized, W: Write>(
+    table: &CodeTable,
+    input: &mut R,
+    output: W,
+) -> std::io::Result<u8> {
+    encode_indexed(table, input, output, u64::MAX).map(|(padding, _)| padding)
+}
+
+/// Like [`encode`], but also returns the bit offset of every `interval`-th
+/// input byte (excluding the end of the input).
+pub fn encode_indexed<R: Read + ?Sized, W: Write>(
+    table: &CodeTable,
+    input: &mut R,
+    output: W,
+    interval: u64,
+) -> std::io::Result<(u8, Vec<u64>)> {
+    // One (code, len) lookup per byte; bytes without a code emit nothing.
+    let entries: [(u64, u8); 256] = std::array::from_fn(|b| (table.code[b], table.code_len[b]));
+    let mut buf = [0u8; 8192];
+    let mut bit_writer = BitWriter::new(output);
+    let mut checkpoints = Vec::new();
+    let mut until_checkpoint = interval;
+
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let mut rest = &buf[..n];
+        while !rest.is_empty() {
+            if until_checkpoint == 0 {
+                checkpoints.push(bit_writer.bit_position());
+                until_checkpoint = interval;
+            }
+            let take = rest
+                .len()
+                .min(until_checkpoint.try_into().unwrap_or(usize::MAX));
+            for &byte in &rest[..take] {
+                let (code, len) = entries[byte as usize];
+                bit_writer.write_bits(code, len)?;
+            }
+            rest = &rest[take..];
+            until_checkpoint -= take as u64;
+        }
+    }
+
+    Ok((bit_writer.flush()?, checkpoints))
+}
+
+// ---------------------------------------------------------------------------
+// Decoding
+// ---------------------------------------------------------------------------
+
+/// Default width in bits of the primary lookup table.
+pub const DEFAULT_LUT_BITS: u8 = 11;
+
+/// Widest primary lookup table a decoder may be configured with.
+pub const MAX_LUT_BITS: u8 = 16;
+
+/// Table entry flag marking a link to a second-level subtable.
+const ENTRY_LINK: u32 = 1 << 31;
+
+/// Table entry for a prefix that no code starts with.
+const ENTRY_INVALID: u32 = 0;
+
+/// Most symbols a single multi-symbol table entry can emit.
+const MULTI_MAX: usize = 4;
+
+/// Size of the output chunk [`HuffmanDecoder::decode`] decodes into.
+const OUT_CHUNK: usize = 64 * 1024;
+
+/// Multi-symbol table entry: every complete code that fits, back to back,
+/// in one primary-table window.
+#[derive(Clone, Copy, Default)]
+struct MultiEntry {
+    /// Decoded symbols; only the first `count` are meaningful.
+    symbols: [u8; MULTI_MAX],
+    /// Number of symbols, 0 if the window does not hold a complete code.
+    count: u8,
+    /// Total code length of those symbols.
+    bits: u8,
+}
+
+/// Packs a symbol entry: the byte in bits 0..8, its code length in bits 8..14.
+fn symbol_entry(symbol: u8, len: u8) -> u32 {
+    u32::from(symbol) | u32::from(len) << 8
+}
+
+/// Packs a link entry: the subtable width in bits 0..5, its offset in the
+/// table from bit 5.
+fn link_entry(offset: usize, bits: u8) -> u32 {
+    ENTRY_LINK | (offset as u32) << 5 | u32::from(bits)
+}
+
+/// Table-driven canonical Huffman decoder.
+///
+/// Decoding uses zlib-style two-level tables:
+/// 1. A primary table indexed by the next `lut_bits` bits maps every code of
+///    length ≤ `lut_bits` straight to `(symbol, code_length)`.
+/// 2. For longer codes the primary entry links to a subtable indexed by the
+///    following bits, sized for the longest code sharing that prefix.
+///
+/// Every code therefore resolves in at most two lookups, whatever the shape
+/// of the code-length table.
+///
+/// Bulk decoding ([`decode_into`](Self::decode_into)) goes through a third,
+/// multi-symbol table indexed like the primary one, whose entries emit up to
+/// four short codes per lookup.
+pub struct HuffmanDecoder {
+    /// Width of the primary table in bits.
+    root_bits: u8,
+    /// Primary table followed by all subtables, as packed entries (see
+    /// [`symbol_entry`] and [`link_entry`]).
+    table: Vec<u32>,
+    /// Multi-symbol entries, one per primary-table index.
+    multi: Vec<MultiEntry>,
+}
+
+impl HuffmanDecoder {
+    /// Builds a decoder from a symbol table with a [`DEFAULT_LUT_BITS`]-wide
+    /// primary table.
+    ///
+    /// `symbol_table` is a list of `(byte_value, code_length)` pairs sorted by
+    /// byte value, as produced by the compressor.
+    ///
+    /// Returns an `InvalidData` error if any code length is 0 or exceeds
+    /// [`MAX_CODE_LENGTH`], or if the lengths do not form a prefix code.
+    pub fn new(symbol_table: &[(u8, u8)]) -> std::io::Result<Self> {
+        Self::with_lut_bits(symbol_table, DEFAULT_LUT_BITS)
+    }
+
+    /// Like [`new`](Self::new), but with a `lut_bits`-wide primary table.
+    ///
+    /// Wider tables resolve more codes in a single lookup at the cost of a
+    /// larger, slower-to-build table.  Each subtable holds
+    /// `2^(longest code below its prefix - lut_bits)` entries, so narrow
+    /// primary tables over very skewed codes cost memory.  The width is
+    /// capped at the longest code length in use.  Returns an `InvalidInput` error unless
+    /// `1 <= lut_bits <=` [`MAX_LUT_BITS`].
+    pub fn with_lut_bits(symbol_table: &[(u8, u8)], lut_bits: u8) -> std::io::Result<Self> {
+        if lut_bits == 0 || lut_bits > MAX_LUT_BITS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Lookup table width {lut_bits} is not in 1..={MAX_LUT_BITS}"),
+            ));
+        }
+
+        // Validate code lengths.
+        for &(byte, len) in symbol_table {
+            if len == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Byte 0x{byte:02X} has zero-length code"),
+                ));
+            }
+            if len > MAX_CODE_LENGTH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Byte 0x{byte:02X} code length {len} exceeds maximum of {MAX_CODE_LENGTH}"
+                    ),
+                ));
+            }
+        }
+
+        // symbol_table entries are (byte, code_len) from the file header.
+        // Sort by (code_len, byte_value) for canonical ordering.
+        let mut symbols: Vec<(u8, u8)> = symbol_table.to_vec();
+        symbols.sort_by_key(|&(byte, len)| (len, byte));
+
+        // Count how many symbols have each code length.
+        let mut count_by_len = [0u16; MAX_CODE_LENGTH as usize + 1];
+        for &(_, len) in &symbols {
+            count_by_len[len as usize] += 1;
+        }
+
+        // Compute starting code for each length using the canonical formula.
+        let mut next_code = [0u64; MAX_CODE_LENGTH as usize + 1];
+        let mut code = 0u64;
+        for len in 1..=MAX_CODE_LENGTH as usize {
+            code = (code + count_by_len[len - 1] as u64) << 1;
+            next_code[len] = code;
+        }
+
+        // Assign canonical codes as (code, len, byte).
+        let mut codes = Vec::with_capacity(symbols.len());
+        for &(byte, len) in &symbols {
+            let c = next_code[len as usize];
+            if c >> len != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Huffman code lengths are oversubscribed",
+                ));
+            }
+            codes.push((c, len, byte));
+            next_code[len as usize] = c + 1;
+        }
+
+        let max_len = symbols.last().map_or(1, |&(_, len)| len);
+        let root_bits = lut_bits.min(max_len);
+        let mut table = vec![ENTRY_INVALID; 1 << root_bits];
+
+        // Primary entries: shift each short code to fill root_bits bits, then
+        // fill all suffix combinations.
+        for &(code, len, byte) in codes.iter().filter(|&&(_, len, _)| len <= root_bits) {
+            let shift = root_bits - len;
+            let base = (code << shift) as usize;
+            table[base..base + (1 << shift)].fill(symbol_entry(byte, len));
+        }
+
+        // Size one subtable per root prefix for the longest code below it.
+        let mut sub_bits = vec![0u8; 1 << root_bits];
+        for &(code, len, _) in codes.iter().filter(|&&(_, len, _)| len > root_bits) {
+            let prefix = (code >> (len - root_bits)) as usize;
+            sub_bits[prefix] = sub_bits[prefix].max(len - root_bits);
+        }
+        for (prefix, &bits) in sub_bits.iter().enumerate().filter(|&(_, &b)| b > 0) {
+            table[prefix] = link_entry(table.len(), bits);
+            table.resize(table.len() + (1 << bits), ENTRY_INVALID);
+        }
+
+        // Subtable entries, indexed by the bits after the root prefix.
+        for &(code, len, byte) in codes.iter().filter(|&&(_, len, _)| len > root_bits) {
+            let extra = len - root_bits;
+            let prefix = (code >> extra) as usize;
+            let bits = sub_bits[prefix];
+            let offset = ((table[prefix] & !ENTRY_LINK) >> 5) as usize;
+            let shift = bits - extra;
+            let base = offset + (((code & ((1 << extra) - 1)) << shift) as usize);
+            table[base..base + (1 << shift)].fill(symbol_entry(byte, len));
+        }
+
+        // Multi-symbol entries: decode greedily from each window for as long
+        // as the next code is short and entirely inside it.
+        let window_mask = (1usize << root_bits) - 1;
+        let multi = (0..1usize << root_bits)
+            .map(|window| {
+                let mut entry = MultiEntry::default();
+                while (entry.count as usize) < MULTI_MAX {
+                    let e = table[(window << entry.bits) & window_mask];
+                    let len = (e >> 8) as u8;
+                    if e & ENTRY_LINK != 0 || len == 0 || len > root_bits - entry.bits {
+                        break;
+                    }
+                    entry.symbols[entry.count as usize] = e as u8;
+                    entry.count += 1;
+                    entry.bits += len;
+                }
+                entry
+            })
+            .collect();
+
+        Ok(HuffmanDecoder {
+            root_bits,
+            table,
+            multi,
+        })
+    }
+
+    /// Width of the primary lookup table in bits.
+    pub fn lut_bits(&self) -> u8 {
+        self.root_bits
+    }
+
+    /// Reads one byte from the bit stream.
+    ///
+    /// Returns `None` if the reader reaches EOF before a complete code is
+    /// read, and an `InvalidData` error for a bit pattern that is not a code.
+    pub fn decode_byte<R: BufRead>(
+        &self,
+        reader: &mut BitReader<R>,
+    ) -> std::io::Result<Option<u8>> {
+        let Some(prefix) = reader.peek_bits(self.root_bits)? else {
+            return Ok(None);
+        };
+        let mut entry = self.table[prefix as usize];
+        let mut pattern = (prefix, self.root_bits);
+
+        if entry & ENTRY_LINK != 0 {
+            let bits = (entry & 0x1F) as u8;
+            let Some(window) = reader.peek_bits(self.root_bits + bits)? else {
+                return Ok(None);
+            };
+            let offset = ((entry & !ENTRY_LINK) >> 5) as usize;
+            entry = self.table[offset + (window & ((1 << bits) - 1)) as usize];
+            pattern = (window, self.root_bits + bits);
+        }
+
+        let len = (entry >> 8) as u8;
+        if len == 0 {
+            let err = DecodeError::new(
+                CorruptionKind::InvalidCode,
+                "Invalid Huffman code in bitstream",
+            );
+            return Err(err.with_pattern(pattern.0, pattern.1).into());
+        }
+        reader.consume_bits(len);
+        Ok(Some(entry as u8))
+    }
+
+    /// Fills `out` with symbols decoded from `reader`.
+    ///
+    /// Returns an `UnexpectedEof` error if the bitstream ends before `out` is
+    /// full.  Errors are located (see [`DecodeError`]) relative to the
+    /// reader's first bit and the start of `out`.
+    pub fn decode_into<R: BufRead>(
+        &self,
+        reader: &mut BitReader<R>,
+        out: &mut [u8],
+    ) -> std::io::Result<()> {
+        let mut pos = 0;
+        while out.len() - pos >= MULTI_MAX {
+            self.decode_step(reader, out, &mut pos)
+                .map_err(|e| error::locate(e, reader.bit_position(), pos as u64))?;
+        }
+        while pos < out.len() {
+            out[pos] = self
+                .decode_byte(reader)
+                .and_then(|byte| byte.ok_or_else(unexpected_eof))
+                .map_err(|e| error::locate(e, reader.bit_position(), pos as u64))?;
+            pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Fills four outputs from four independent bitstreams, advancing them
+    /// in lockstep so their table lookups overlap.
+    ///
+    /// `origins` holds each stream's payload bit offset and output offset,
+    /// which only serve to locate errors.
+    pub fn decode_into_x4<R: BufRead>(
+        &self,
+        readers: &mut [BitReader<R>; 4],
+        outs: [&mut [u8]; 4],
+        origins: [(u64, u64); 4],
+    ) -> std::io::Result<()> {
+        let [r0, r1, r2, r3] = readers;
+        let [o0, o1, o2, o3] = outs;
+        let mut pos = [0usize; 4];
+        let located = |e, (bits, out): (u64, u64), reader: &BitReader<R>, pos: usize| {
+            error::locate(e, bits + reader.bit_position(), out + pos as u64)
+        };
+        loop {
+            // Each step writes at most MULTI_MAX bytes, so this many steps
+            // fit in every output without further checks.
+            let steps = [&o0, &o1, &o2, &o3]
+                .iter()
+                .zip(pos)
+                .map(|(out, p)| (out.len() - p) / MULTI_MAX)
+                .min()
+                .unwrap();
+            if steps == 0 {
+                break;
+            }
+            for _ in 0..steps {
+                self.decode_step(r0, o0, &mut pos[0])
+                    .map_err(|e| located(e, origins[0], r0, pos[0]))?;
+                self.decode_step(r1, o1, &mut pos[1])
+                    .map_err(|e| located(e, origins[1], r1, pos[1]))?;
+                self.decode_step(r2, o2, &mut pos[2])
+                    .map_err(|e| located(e, origins[2], r2, pos[2]))?;
+                self.decode_step(r3, o3, &mut pos[3])
+                    .map_err(|e| located(e, origins[3], r3, pos[3]))?;
+            }
+        }
+        let lanes = [r0, r1, r2, r3].into_iter().zip([o0, o1, o2, o3]);
+        for (((reader, out), p), (bits, out_base)) in lanes.zip(pos).zip(origins) {
+            self.decode_into(reader, &mut out[p..])
+                .map_err(|e| error::shift(e, bits, out_base + p as u64))?;
+        }
+        Ok(())
+    }
+
+    /// Decodes one multi-symbol entry (or one symbol) into `out[*pos..]`.
+    ///
+    /// Copies a whole entry, so at least [`MULTI_MAX`] bytes of `out` must
+    /// remain; `*pos` only advances past the symbols the entry holds.
+    #[inline(always)]
+    fn decode_step<R: BufRead>(
+        &self,
+        reader: &mut BitReader<R>,
+        out: &mut [u8],
+        pos: &mut usize,
+    ) -> std::io::Result<()> {
+        let Some(window) = reader.peek_bits(self.root_bits)? else {
+            return Err(unexpected_eof());
+        };
+        let entry = &self.multi[window as usize];
+        if entry.count > 0 {
+            out[*pos..*pos + MULTI_MAX].copy_from_slice(&entry.symbols);
+            *pos += entry.count as usize;
+            reader.consume_bits(entry.bits);
+        } else {
+            out[*pos] = self.decode_byte(reader)?.ok_or_else(unexpected_eof)?;
+            *pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Decodes exactly `len` bytes from the bitstream in `input` and writes
+    /// them to `writer`.
+    pub fn decode<R: Read, W: Write + ?Sized>(
+        &self,
+        input: R,
+        writer: &mut W,
+        len: u64,
+    ) -> std::io::Result<()> {
+        self.decode_at(input, writer, 0, len).map(|_| ())
+    }
+
+    /// Like [`decode`](Self::decode), but skips the first `skip_bits` bits
+    /// of `input`.  Returns the number of bits consumed, including the
+    /// skipped ones.
+    pub fn decode_at<R: Read, W: Write + ?Sized>(
+        &self,
+        input: R,
+        writer: &mut W,
+        skip_bits: u8,
+        len: u64,
+    ) -> std::io::Result<u64> {
+        let mut bit_reader = BitReader::new(input);
+        if bit_reader.read_bits(skip_bits)?.is_none() {
+            return Err(error::locate(unexpected_eof(), 0, 0));
+        }
+        let mut out_buf = vec![0u8; OUT_CHUNK];
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let n = remaining.min(OUT_CHUNK as u64) as usize;
+            self.decode_into(&mut bit_reader, &mut out_buf[..n])
+                .map_err(|e| error::shift(e, 0, len - remaining))?;
+            writer.write_all(&out_buf[..n])?;
+            remaining -= n as u64;
+        }
+        writer.flush()?;
+        Ok(bit_reader.bit_position())
+    }
+
+    /// Decodes `len` bytes from `input` without keeping them and returns
+    /// the bit offset of every `interval`-th byte (excluding the end), the
+    /// same checkpoints [`encode_indexed`] records.
+    pub fn scan_checkpoints<R: Read>(
+        &self,
+        input: R,
+        len: u64,
+        interval: u64,
+    ) -> std::io::Result<Vec<u64>> {
+        let mut bit_reader = BitReader::new(input);
+        let mut out_buf = vec![0u8; OUT_CHUNK];
+        let mut checkpoints = Vec::new();
+        let mut pos = 0u64;
+
+        while pos < len {
+            let boundary = (pos / interval + 1).saturating_mul(interval).min(len);
+            let n = (boundary - pos).min(OUT_CHUNK as u64) as usize;
+            self.decode_into(&mut bit_reader, &mut out_buf[..n])?;
+            pos += n as u64;
+            if pos.is_multiple_of(interval) && pos < len {
+                checkpoints.push(bit_reader.bit_position());
+            }
+        }
+        Ok(checkpoints)
+    }
+}
+
+fn unexpected_eof() -> std::io::Error {
+    DecodeError::new(
+        CorruptionKind::Truncated,
+        "Unexpected end of compressed bitstream",
+    )
+    .into()
+}
+
+// ---------------------------------------------------------------------------
+// EntropyCoder implementation
+// ---------------------------------------------------------------------------
+
+/// Canonical Huffman coding as an [`EntropyCoder`].
+///
+/// The header table is a `u16` symbol count followed by `(symbol, code_len)`
+/// pairs sorted by byte value.
+pub struct HuffmanCoder {
+    table: CodeTable,
+    /// `(symbol, code_len)` entries exactly as stored in the header.
+    symbol_table: Vec<(u8, u8)>,
+}
+
+impl HuffmanCoder {
+    /// The underlying canonical code table.
+    pub fn code_table(&self) -> &CodeTable {
+        &self.table
+    }
+}
+
+impl EntropyCoder for HuffmanCoder {
+    const METHOD: u8 = format::METHOD_HUFFMAN;
+    const NAME: &'static str = "huffman";
+    const SUPPORTS_INDEX: bool = true;
+
+    fn from_frequencies(freqs: &[u64; 256]) -> std::io::Result<Self> {
+        let table = build_codes(freqs)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let symbol_table = table.symbol_table();
+        Ok(HuffmanCoder {
+            table,
+            symbol_table,
+        })
+    }
+
+    fn write_table(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.symbol_table.len() as u16).to_le_bytes());
+        for &(symbol, code_len) in &self.symbol_table {
+            out.extend_from_slice(&[symbol, code_len]);
+        }
+    }
+
+    fn read_table(table: &[u8]) -> std::io::Result<Self> {
+        let mut cursor = table;
+        let (_, symbol_table) = format::read_symbol_table(&mut cursor)?;
+        if !cursor.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Trailing bytes after Huffman symbol table",
+            ));
+        }
+
+        let mut code_len = [0u8; 256];
+        for &(byte, len) in &symbol_table {
+            code_len[byte as usize] = len;
+        }
+        let table = CodeTable::from_lengths(&code_len)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(HuffmanCoder {
+            table,
+            symbol_table,
+        })
+    }
+
+    fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8> {
+        encode(&self.table, input, output)
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<()> {
+        HuffmanDecoder::new(&self.symbol_table)?.decode(input, output, len)
+    }
+
+    fn decode_counted<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        let decoder = HuffmanDecoder::new(&self.symbol_table)?;
+        decoder.decode_at(input, output, 0, len).map(Some)
+    }
+
+    fn estimated_payload_len(&self, freqs: &[u64; 256]) -> Option<u64> {
+        let bits: u64 = freqs
+            .iter()
+            .zip(self.table.code_len.iter())
+            .map(|(&f, &len)| f * len as u64)
+            .sum();
+        Some(bits.div_ceil(8))
+    }
+
+    fn min_payload_len(&self, len: u64) -> u64 {
+        // No symbol is coded in fewer bits than the shortest code.
+        match self
+            .symbol_table
+            .iter()
+            .map(|&(_, code_len)| code_len)
+            .min()
+        {
+            Some(shortest) => len.saturating_mul(shortest as u64).div_ceil(8),
+            None if len == 0 => 0,
+            None => u64::MAX,
+        }
+    }
+
+    fn encode_indexed<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        interval: u64,
+    ) -> std::io::Result<(u8, Vec<u64>)> {
+        encode_indexed(&self.table, input, output, interval)
+    }
+
+    fn decode_at<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        skip_bits: u8,
+        len: u64,
+    ) -> std::io::Result<u64> {
+        HuffmanDecoder::new(&self.symbol_table)?.decode_at(input, output, skip_bits, len)
+    }
+
+    fn scan_index<R: Read>(
+        &self,
+        input: &mut R,
+        len: u64,
+        interval: u64,
+    ) -> std::io::Result<Vec<u64>> {
+        HuffmanDecoder::new(&self.symbol_table)?.scan_checkpoints(input, len, interval)
+    }
+}
+
+/// Input bytes per [`Huffman4Coder`] block.
+pub const X4_BLOCK_SIZE: usize = 256 * 1024;
+
+/// Size of the per-block jump table: four little-endian `u32` stream lengths.
+pub(crate) const X4_JUMP_TABLE_SIZE: usize = 16;
+
+/// Canonical Huffman coding with four interleaved streams, as in zstd's
+/// huff0, so the decoder can run four independent bit readers in one loop.
+///
+/// The input is cut into [`X4_BLOCK_SIZE`] blocks and each block into four
+/// equal segments (the last may be shorter), each encoded as its own
+/// byte-aligned bitstream.  A block is stored as a jump table of the four
+/// stream lengths followed by the streams.  The header table and code are
+/// the same as for [`HuffmanCoder`].
+pub struct Huffman4Coder(HuffmanCoder);
+
+impl Huffman4Coder {
+    /// The underlying canonical code table.
+    pub fn code_table(&self) -> &CodeTable {
+        self.0.code_table()
+    }
+
+    /// Decodes the `n`-byte block at the start of `data` stream by stream,
+    /// for salvage.  Returns `None` if the jump table does not describe a
+    /// block of `n` bytes that fits in `data`.
+    pub(crate) fn decode_block(decoder: &HuffmanDecoder, data: &[u8], n: usize) -> Option<X4Block> {
+        let seg = x4_segment_len(n);
+        let jump_table = data.get(..X4_JUMP_TABLE_SIZE)?;
+        let mut offset = X4_JUMP_TABLE_SIZE;
+        let mut streams: [(usize, Option<Vec<u8>>); 4] = Default::default();
+        let mut slices = [&data[..0]; 4];
+        for (i, ((out_start, _), slice)) in streams.iter_mut().zip(&mut slices).enumerate() {
+            let len = u32::from_le_bytes(jump_table[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+            let symbols = ((i + 1) * seg).min(n) - (i * seg).min(n);
+            // Every symbol takes between 1 and MAX_CODE_LENGTH bits.
+            if len < symbols.div_ceil(8) || len > seg * MAX_CODE_LENGTH as usize / 8 + 1 {
+                return None;
+            }
+            *slice = data.get(offset..offset + len)?;
+            *out_start = (i * seg).min(n);
+            offset += len;
+        }
+
+        for ((out_start, out), slice) in streams.iter_mut().zip(slices) {
+            let mut symbols = vec![0u8; (*out_start + seg).min(n) - *out_start];
+            let mut reader = BitReader::from_slice(slice);
+            let clean = decoder
+                .decode_into(&mut reader, &mut symbols)
+                .and_then(|()| check_stream_end(&mut reader, slice.len(), 0));
+            *out = clean.ok().map(|()| symbols);
+        }
+        Some(X4Block {
+            len: offset,
+            streams,
+        })
+    }
+}
+
+/// A [`Huffman4Coder`] block decoded stream by stream, so that a damaged
+/// stream does not take the rest of the block with it.
+pub(crate) struct X4Block {
+    /// Payload bytes the block takes, jump table included.
+    pub len: usize,
+    /// Each stream's offset in the block's output, and its symbols if it
+    /// decoded cleanly.
+    pub streams: [(usize, Option<Vec<u8>>); 4],
+}
+
+/// Segment length for a block of `n` bytes split four ways.
+fn x4_segment_len(n: usize) -> usize {
+    n.div_ceil(4)
+}
+
+/// Splits `buf` into four consecutive segments of at most `seg` bytes.
+fn split4(buf: &mut [u8], seg: usize) -> [&mut [u8]; 4] {
+    let (a, rest) = buf.split_at_mut(seg.min(buf.len()));
+    let (b, rest) = rest.split_at_mut(seg.min(rest.len()));
+    let (c, d) = rest.split_at_mut(seg.min(rest.len()));
+    [a, b, c, d]
+}
+
+/// Checks that a fully decoded interleaved stream of `len` bytes, starting
+/// at payload bit `origin`, has only its zero padding bits left.
+fn check_stream_end(reader: &mut BitReader<&[u8]>, len: usize, origin: u64) -> std::io::Result<()> {
+    let end = reader.bit_position();
+    let unused = len as u64 * 8 - end;
+    if unused >= 8 {
+        let msg = format!("Interleaved stream has {} unused bytes", unused / 8);
+        return Err(DecodeError::new(CorruptionKind::TrailingBytes, msg)
+            .at_bit(origin + end)
+            .into());
+    }
+    if let Some(bits @ 1..) = reader.read_bits(unused as u8)? {
+        let msg = "Non-zero padding bits in interleaved stream";
+        return Err(DecodeError::new(CorruptionKind::BadPadding, msg)
+            .at_bit(origin + end)
+            .with_pattern(bits, unused as u8)
+            .into());
+    }
+    Ok(())
+}
+
+impl EntropyCoder for Huffman4Coder {
+    const METHOD: u8 = format::METHOD_HUFFMAN4;
+    const NAME: &'static str = "huffman4";
+
+    fn from_frequencies(freqs: &[u64; 256]) -> std::io::Result<Self> {
+        HuffmanCoder::from_frequencies(freqs).map(Huffman4Coder)
+    }
+
+    fn write_table(&self, out: &mut Vec<u8>) {
+        self.0.write_table(out)
+    }
+
+    fn read_table(table: &[u8]) -> std::io::Result<Self> {
+        HuffmanCoder::read_table(table).map(Huffman4Coder)
+    }
+
+    fn encode<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> std::io::Result<u8> {
+        let mut block = vec![0u8; X4_BLOCK_SIZE];
+        let mut streams: [Vec<u8>; 4] = Default::default();
+        loop {
+            let n = read_full(input, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            let seg = x4_segment_len(n);
+            let mut jump_table = [0u8; X4_JUMP_TABLE_SIZE];
+            for (i, stream) in streams.iter_mut().enumerate() {
+                stream.clear();
+                let part = &block[(i * seg).min(n)..((i + 1) * seg).min(n)];
+                encode(&self.0.table, &mut &part[..], &mut *stream)?;
+                jump_table[i * 4..i * 4 + 4].copy_from_slice(&(stream.len() as u32).to_le_bytes());
+            }
+            output.write_all(&jump_table)?;
+            for stream in &streams {
+                output.write_all(stream)?;
+            }
+            if n < block.len() {
+                break;
+            }
+        }
+        output.flush()?;
+        // Every stream is byte-aligned and the decoder knows each stream's
+        // symbol count, so no padding needs recording.
+        Ok(0)
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<()> {
+        self.decode_counted(input, output, len).map(|_| ())
+    }
+
+    fn decode_counted<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        let decoder = HuffmanDecoder::new(&self.0.symbol_table)?;
+        let mut input = std::io::BufReader::new(input);
+        let mut consumed = 0u64;
+        let mut block = vec![0u8; X4_BLOCK_SIZE];
+        let mut streams = Vec::new();
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let n = remaining.min(X4_BLOCK_SIZE as u64) as usize;
+            let seg = x4_segment_len(n);
+
+            let produced = len - remaining;
+            let truncated = |_| error::locate(unexpected_eof(), consumed, produced);
+            let mut jump_table = [0u8; X4_JUMP_TABLE_SIZE];
+            input.read_exact(&mut jump_table).map_err(truncated)?;
+            let mut lens = [0usize; 4];
+            for (i, len) in lens.iter_mut().enumerate() {
+                *len =
+                    u32::from_le_bytes(jump_table[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+                // A stream never needs more than MAX_CODE_LENGTH bits per symbol.
+                if *len > seg * MAX_CODE_LENGTH as usize / 8 + 1 {
+                    let msg = format!("Interleaved stream length {len} is too large for its block");
+                    return Err(DecodeError::new(CorruptionKind::InvalidLength, msg)
+                        .at_bit(consumed + i as u64 * 32)
+                        .after_output(produced)
+                        .into());
+                }
+            }
+
+            streams.resize(lens.iter().sum(), 0);
+            input.read_exact(&mut streams).map_err(truncated)?;
+            let (s0, rest) = streams.split_at(lens[0]);
+            let (s1, rest) = rest.split_at(lens[1]);
+            let (s2, s3) = rest.split_at(lens[2]);
+            let mut readers = [s0, s1, s2, s3].map(BitReader::from_slice);
+
+            let mut origins = [(0u64, 0u64); 4];
+            let mut stream_start = consumed + X4_JUMP_TABLE_SIZE as u64 * 8;
+            for (i, origin) in origins.iter_mut().enumerate() {
+                *origin = (stream_start, produced + (i * seg).min(n) as u64);
+                stream_start += lens[i] as u64 * 8;
+            }
+
+            let out = &mut block[..n];
+            decoder.decode_into_x4(&mut readers, split4(out, seg), origins)?;
+            for ((reader, stream), (origin, _)) in readers.iter_mut().zip(lens).zip(origins) {
+                check_stream_end(reader, stream, origin)?;
+            }
+            output.write_all(out)?;
+            consumed += (X4_JUMP_TABLE_SIZE + streams.len()) as u64 * 8;
+            remaining -= n as u64;
+        }
+        output.flush()?;
+        Ok(Some(consumed))
+    }
+
+    fn estimated_payload_len(&self, freqs: &[u64; 256]) -> Option<u64> {
+        // Jump table plus up to one padding byte per stream for every block.
+        let total: u64 = freqs.iter().sum();
+        let blocks = total.div_ceil(X4_BLOCK_SIZE as u64);
+        let payload = self.0.estimated_payload_len(freqs)?;
+        Some(payload + blocks * (X4_JUMP_TABLE_SIZE as u64 + 4))
+    }
+
+    fn min_payload_len(&self, len: u64) -> u64 {
+        let blocks = len.div_ceil(X4_BLOCK_SIZE as u64);
+        self.0
+            .min_payload_len(len)
+            .saturating_add(blocks * X4_JUMP_TABLE_SIZE as u64)
+    }
+}
+
+/// Reads until `buf` is full or the input ends, returning the byte count.
+fn read_full<R: Read + ?Sized>(input: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 // ---------------------------------------------------------------------------
 // Tests
 // ---------------------------------------------------------------------------
@@ -327,6 +1235,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn all_256_bytes() {
         let mut freqs = [0u64; 256];
         for i in 0..=255usize {
@@ -340,6 +1249,239 @@ mod tests {
         }
     }
 
+    #[test]
+    fn estimated_payload_len_is_exact() {
+        let data = b"abracadabra";
+        let mut freqs = [0u64; 256];
+        for &b in data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        coder.encode(&mut &data[..], &mut payload).unwrap();
+        assert_eq!(
+            coder.estimated_payload_len(&freqs),
+            Some(payload.len() as u64)
+        );
+    }
+
+    #[test]
+    fn min_payload_len_is_a_lower_bound() {
+        let data = b"aaaaaaaabbbbccd".repeat(30_000);
+        let mut freqs = [0u64; 256];
+        for &b in &data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        coder.encode(&mut &data[..], &mut payload).unwrap();
+        let min = coder.min_payload_len(data.len() as u64);
+        assert_eq!(min, (data.len() as u64).div_ceil(8));
+        assert!(min <= payload.len() as u64);
+
+        let (x4, payload) = x4_payload(&data);
+        assert!(x4.min_payload_len(data.len() as u64) <= payload.len() as u64);
+        assert_eq!(coder.min_payload_len(u64::MAX), u64::MAX.div_ceil(8));
+    }
+
+    /// Fibonacci frequencies give a maximally skewed code, one symbol per
+    /// length up to the maximum.
+    fn skewed_round_trip(lut_bits: u8) {
+        let mut freqs = [0u64; 256];
+        let (mut a, mut b) = (1u64, 1u64);
+        for f in freqs.iter_mut().take(24) {
+            *f = a;
+            (a, b) = (b, a + b);
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        assert!(coder
+            .code_table()
+            .code_len
+            .iter()
+            .any(|&l| l > MAX_LUT_BITS));
+
+        let data: Vec<u8> = (0..24u8).chain((0..24u8).rev()).collect();
+        let mut payload = Vec::new();
+        coder.encode(&mut &data[..], &mut payload).unwrap();
+
+        let decoder = HuffmanDecoder::with_lut_bits(&coder.symbol_table, lut_bits).unwrap();
+        assert_eq!(decoder.lut_bits(), lut_bits);
+        let mut out = Vec::new();
+        decoder
+            .decode(&payload[..], &mut out, data.len() as u64)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn two_level_decode_any_lut_width() {
+        for lut_bits in [1, 4, DEFAULT_LUT_BITS, MAX_LUT_BITS] {
+            skewed_round_trip(lut_bits);
+        }
+    }
+
+    #[test]
+    fn lut_width_capped_at_longest_code() {
+        let decoder =
+            HuffmanDecoder::with_lut_bits(&[(b'a', 1), (b'b', 2), (b'c', 2)], 11).unwrap();
+        assert_eq!(decoder.lut_bits(), 2);
+    }
+
+    #[test]
+    fn reject_bad_lut_width() {
+        for lut_bits in [0, MAX_LUT_BITS + 1] {
+            let err = HuffmanDecoder::with_lut_bits(&[(b'a', 1)], lut_bits)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn reject_oversubscribed_lengths() {
+        let err = HuffmanDecoder::new(&[(b'a', 1), (b'b', 1), (b'c', 1)])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let mut lengths = [0u8; 256];
+        lengths[..3].fill(1);
+        let err = CodeTable::from_lengths(&lengths).err().unwrap();
+        assert!(err.contains("oversubscribed"));
+    }
+
+    #[test]
+    fn invalid_code_is_an_error() {
+        // A single 1-bit code leaves the prefix `1` unassigned.
+        let decoder = HuffmanDecoder::new(&[(b'a', 1)]).unwrap();
+        let mut out = Vec::new();
+        let err = decoder.decode(&[0b0100_0000][..], &mut out, 3).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn multi_symbol_entries_pack_short_codes() {
+        // Codes a=0, b=10, c=11: the window 0010 holds "a", "a", "b".
+        let decoder = HuffmanDecoder::with_lut_bits(&[(b'a', 1), (b'b', 2), (b'c', 2)], 2).unwrap();
+        assert_eq!(decoder.multi[0b00].count, 2);
+        assert_eq!(decoder.multi[0b10].count, 1);
+        let decoder =
+            HuffmanDecoder::with_lut_bits(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)], 3)
+                .unwrap();
+        let entry = decoder.multi[0b001];
+        assert_eq!(&entry.symbols[..entry.count as usize], b"aa");
+        assert_eq!(entry.bits, 2);
+    }
+
+    #[test]
+    fn decode_into_matches_per_symbol_decode() {
+        let data = b"the log line repeats, the log line repeats; level=INFO\n".repeat(50);
+        let mut freqs = [0u64; 256];
+        for &b in &data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        coder.encode(&mut &data[..], &mut payload).unwrap();
+        let decoder = HuffmanDecoder::new(&coder.symbol_table).unwrap();
+
+        let mut reader = BitReader::from_slice(&payload);
+        let slow: Vec<u8> = (0..data.len())
+            .map(|_| decoder.decode_byte(&mut reader).unwrap().unwrap())
+            .collect();
+        assert_eq!(slow, data);
+
+        // Odd-sized slices exercise the per-symbol tail.
+        let mut reader = BitReader::from_slice(&payload);
+        let mut out = vec![0u8; data.len()];
+        for chunk in out.chunks_mut(7) {
+            decoder.decode_into(&mut reader, chunk).unwrap();
+        }
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn scan_matches_encode_checkpoints() {
+        let data = b"the log line repeats, the log line repeats; level=INFO\n".repeat(50);
+        let mut freqs = [0u64; 256];
+        for &b in &data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        for interval in [1, 100, 2750, 2751, 10_000] {
+            let mut payload = Vec::new();
+            let (_, encoded) = coder
+                .encode_indexed(&mut &data[..], &mut payload, interval)
+                .unwrap();
+            let scanned = coder
+                .scan_index(&mut &payload[..], data.len() as u64, interval)
+                .unwrap();
+            assert_eq!(scanned, encoded, "interval {interval}");
+        }
+    }
+
+    #[test]
+    fn decode_into_truncated() {
+        let decoder = HuffmanDecoder::new(&[(b'a', 1), (b'b', 1)]).unwrap();
+        let mut out = [0u8; 16];
+        let err = decoder
+            .decode_into(&mut BitReader::from_slice(&[0xAA]), &mut out)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    fn x4_payload(data: &[u8]) -> (Huffman4Coder, Vec<u8>) {
+        let mut freqs = [0u64; 256];
+        for &b in data {
+            freqs[b as usize] += 1;
+        }
+        let coder = Huffman4Coder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        assert_eq!(coder.encode(&mut &data[..], &mut payload).unwrap(), 0);
+        (coder, payload)
+    }
+
+    #[test]
+    fn x4_jump_table_matches_streams() {
+        let data = b"aaaabbbbccccdddd".repeat(4);
+        let (coder, payload) = x4_payload(&data);
+        let lens: Vec<usize> = payload[..16]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect();
+        assert_eq!(16 + lens.iter().sum::<usize>(), payload.len());
+
+        let mut out = Vec::new();
+        coder
+            .decode(&mut &payload[..], &mut out, data.len() as u64)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn x4_reject_oversized_stream() {
+        let data = b"abcdabcd".repeat(8);
+        let (coder, mut payload) = x4_payload(&data);
+        payload[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = coder
+            .decode(&mut &payload[..], &mut Vec::new(), data.len() as u64)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn x4_truncated_payload() {
+        let data = b"abcdabcd".repeat(8);
+        let (coder, payload) = x4_payload(&data);
+        let err = coder
+            .decode(
+                &mut &payload[..payload.len() - 1],
+                &mut Vec::new(),
+                data.len() as u64,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     #[test]
     fn zero_freq_byte_has_no_code() {
         let mut freqs = [0u64; 256];
@@ -0,0 +1,425 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::format;
+use crate::parity::ParityLayout;
+
+// ---------------------------------------------------------------------------
+// Seek index
+// ---------------------------------------------------------------------------
+//
+// A seek index maps uncompressed offsets to payload bit offsets so a range
+// can be decoded from the nearest checkpoint instead of from the start.
+//
+// When the header has `FLAG_INDEX` set, the index section sits between the
+// payload and the CRC-32 trailer (which covers it):
+//
+//   N x 8    bit offset of uncompressed byte k * interval, for k = 1..=N
+//   8        interval in uncompressed bytes, little-endian u64
+//   4        N, little-endian u32
+//
+// Files that cannot be rewritten (v0x02 files have no flags byte) can carry
+// the same checkpoints in a sidecar file, `<file>.idx`:
+//
+//   4        magic "CIDX"
+//   1        sidecar version 0x01
+//   8        length of the indexed file, little-endian u64
+//   4        last four bytes of the indexed file (its CRC-32)
+//   4        N, little-endian u32
+//   N x 16   (uncompressed offset, bit offset) pairs, little-endian u64s
+//   4        CRC-32 of everything above
+
+/// Size of the fixed trailer at the end of an embedded index section.
+pub const INDEX_TRAILER_SIZE: u64 = 12;
+
+/// Magic bytes at the start of a sidecar index.
+pub const SIDECAR_MAGIC: [u8; 4] = *b"CIDX";
+
+/// Current sidecar index version.
+pub const SIDECAR_VERSION: u8 = 0x01;
+
+/// Size of a sidecar index before its checkpoints.
+const SIDECAR_HEADER_SIZE: usize = 21;
+
+/// The sidecar index path for `path`: the same name with `.idx` appended.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// Identifies the compressed file a sidecar index was built for, so a
+/// sidecar left behind after the file changed is not trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SidecarKey {
+    /// Length of the compressed file.
+    pub file_len: u64,
+    /// The last four bytes before any parity section, the CRC-32 trailer
+    /// for v0x02+ files.
+    pub trailer: u32,
+}
+
+impl SidecarKey {
+    /// Computes the key of a compressed file.
+    pub fn of_file<R: Read + Seek>(reader: &mut R) -> std::io::Result<Self> {
+        let container_len = ParityLayout::read(reader)?.map(|layout| layout.protected_len);
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let end = container_len.unwrap_or(file_len);
+        let mut trailer = [0u8; 4];
+        if end >= 4 {
+            reader.seek(SeekFrom::Start(end - 4))?;
+            reader.read_exact(&mut trailer)?;
+        }
+        Ok(SidecarKey {
+            file_len,
+            trailer: u32::from_le_bytes(trailer),
+        })
+    }
+}
+
+/// Checkpoints into a payload, sorted by uncompressed offset.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SeekIndex {
+    /// `(uncompressed_offset, bit_offset)` pairs.  The implicit checkpoint
+    /// `(0, 0)` is not stored.
+    pub checkpoints: Vec<(u64, u64)>,
+}
+
+impl SeekIndex {
+    /// Builds an index from bit offsets recorded every `interval` bytes.
+    ///
+    /// Returns `None` if an uncompressed offset does not fit in a `u64`.
+    pub fn from_interval(interval: u64, bit_offsets: &[u64]) -> Option<Self> {
+        let checkpoints = bit_offsets
+            .iter()
+            .zip(1u64..)
+            .map(|(&bits, k)| Some((k.checked_mul(interval)?, bits)))
+            .collect::<Option<_>>()?;
+        Some(SeekIndex { checkpoints })
+    }
+
+    /// The last checkpoint at or before uncompressed `offset`, as
+    /// `(uncompressed_offset, bit_offset)`.
+    pub fn checkpoint_for(&self, offset: u64) -> (u64, u64) {
+        let i = self
+            .checkpoints
+            .partition_point(|&(start, _)| start <= offset);
+        if i == 0 {
+            (0, 0)
+        } else {
+            self.checkpoints[i - 1]
+        }
+    }
+
+    /// Size of the embedded index section in bytes.
+    pub fn section_len(&self) -> u64 {
+        self.checkpoints.len() as u64 * 8 + INDEX_TRAILER_SIZE
+    }
+
+    /// Writes the embedded index section for checkpoints every `interval`
+    /// bytes.
+    pub fn write_section<W: Write>(&self, writer: &mut W, interval: u64) -> std::io::Result<()> {
+        debug_assert!(self
+            .checkpoints
+            .iter()
+            .zip(1u64..)
+            .all(|(&(start, _), k)| Some(start) == k.checked_mul(interval)));
+        let count = u32::try_from(self.checkpoints.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Too many index checkpoints",
+            )
+        })?;
+        for &(_, bits) in &self.checkpoints {
+            writer.write_all(&bits.to_le_bytes())?;
+        }
+        writer.write_all(&interval.to_le_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Reads the embedded index section of a file whose payload starts at
+    /// `payload_start`, which is `file_len` bytes long and decompresses to
+    /// `original_size` bytes.
+    ///
+    /// Returns an `InvalidData` error if the section does not fit between
+    /// the payload start and the CRC-32 trailer, or if its checkpoints are
+    /// not increasing or point past the payload or the original data.
+    pub fn read_section<R: Read + Seek>(
+        reader: &mut R,
+        payload_start: u64,
+        file_len: u64,
+        original_size: u64,
+    ) -> std::io::Result<Self> {
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+
+        let trailer_pos = file_len
+            .checked_sub(format::CRC_SIZE + INDEX_TRAILER_SIZE)
+            .filter(|&pos| pos >= payload_start)
+            .ok_or_else(|| invalid("File too short for seek index"))?;
+        reader.seek(SeekFrom::Start(trailer_pos))?;
+        let mut trailer = [0u8; INDEX_TRAILER_SIZE as usize];
+        reader.read_exact(&mut trailer)?;
+        let interval = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let count = u32::from_le_bytes(trailer[8..].try_into().unwrap()) as u64;
+        if interval == 0 {
+            return Err(invalid("Seek index interval is zero"));
+        }
+
+        let section_start = (trailer_pos - payload_start)
+            .checked_sub(count * 8)
+            .map(|payload_len| payload_start + payload_len)
+            .ok_or_else(|| invalid("Seek index is larger than the file"))?;
+        let payload_bits = (section_start - payload_start) * 8;
+        if interval
+            .checked_mul(count)
+            .is_none_or(|last| last > original_size)
+        {
+            return Err(invalid("Seek index checkpoint is past the original data"));
+        }
+
+        reader.seek(SeekFrom::Start(section_start))?;
+        let mut raw = vec![0u8; count as usize * 8];
+        reader.read_exact(&mut raw)?;
+        let bit_offsets: Vec<u64> = raw
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        if bit_offsets.windows(2).any(|w| w[0] > w[1]) {
+            return Err(invalid("Seek index checkpoints are not in order"));
+        }
+        if bit_offsets.last().is_some_and(|&bits| bits > payload_bits) {
+            return Err(invalid("Seek index checkpoint points past the payload"));
+        }
+        Self::from_interval(interval, &bit_offsets)
+            .ok_or_else(|| invalid("Seek index checkpoint is past the original data"))
+    }
+
+    /// Writes a sidecar index for the file identified by `key`.
+    pub fn write_sidecar<W: Write>(&self, writer: &mut W, key: SidecarKey) -> std::io::Result<()> {
+        let count = u32::try_from(self.checkpoints.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Too many index checkpoints",
+            )
+        })?;
+        let mut buf = Vec::with_capacity(SIDECAR_HEADER_SIZE + self.checkpoints.len() * 16 + 4);
+        buf.extend_from_slice(&SIDECAR_MAGIC);
+        buf.push(SIDECAR_VERSION);
+        buf.extend_from_slice(&key.file_len.to_le_bytes());
+        buf.extend_from_slice(&key.trailer.to_le_bytes());
+        buf.extend_from_slice(&count.to_le_bytes());
+        for &(start, bits) in &self.checkpoints {
+            buf.extend_from_slice(&start.to_le_bytes());
+            buf.extend_from_slice(&bits.to_le_bytes());
+        }
+        let crc = format::crc32(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Reads a sidecar index and checks that it was built for the file
+    /// identified by `key`, whose payload is `payload_bits` bits long and
+    /// decompresses to `original_size` bytes.
+    ///
+    /// Returns an `InvalidData` error if the sidecar is corrupt, belongs to
+    /// a different file, or has checkpoints out of order or past the
+    /// payload or the original data.
+    pub fn read_sidecar<R: Read>(
+        reader: &mut R,
+        key: SidecarKey,
+        payload_bits: u64,
+        original_size: u64,
+    ) -> std::io::Result<Self> {
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        if buf.len() < SIDECAR_HEADER_SIZE + 4 || buf[..4] != SIDECAR_MAGIC {
+            return Err(invalid("Not a sidecar index"));
+        }
+        if buf[4] != SIDECAR_VERSION {
+            return Err(invalid(&format!(
+                "Unsupported sidecar index version {:#04x}",
+                buf[4]
+            )));
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        if format::crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(invalid("Sidecar index CRC-32 mismatch"));
+        }
+
+        let file_len = u64::from_le_bytes(body[5..13].try_into().unwrap());
+        let trailer = u32::from_le_bytes(body[13..17].try_into().unwrap());
+        if (SidecarKey { file_len, trailer }) != key {
+            return Err(invalid("Sidecar index was built for a different file"));
+        }
+        let count = u32::from_le_bytes(body[17..21].try_into().unwrap()) as usize;
+        let pairs = &body[SIDECAR_HEADER_SIZE..];
+        if pairs.len() != count * 16 {
+            return Err(invalid("Sidecar index length does not match its count"));
+        }
+
+        let checkpoints: Vec<(u64, u64)> = pairs
+            .chunks_exact(16)
+            .map(|c| {
+                (
+                    u64::from_le_bytes(c[..8].try_into().unwrap()),
+                    u64::from_le_bytes(c[8..].try_into().unwrap()),
+                )
+            })
+            .collect();
+        if checkpoints
+            .windows(2)
+            .any(|w| w[0].0 >= w[1].0 || w[0].1 > w[1].1)
+            || checkpoints.first().is_some_and(|&(start, _)| start == 0)
+        {
+            return Err(invalid("Seek index checkpoints are not in order"));
+        }
+        if checkpoints
+            .last()
+            .is_some_and(|&(_, bits)| bits > payload_bits)
+        {
+            return Err(invalid("Seek index checkpoint points past the payload"));
+        }
+        if checkpoints
+            .last()
+            .is_some_and(|&(start, _)| start > original_size)
+        {
+            return Err(invalid("Seek index checkpoint is past the original data"));
+        }
+        Ok(SeekIndex { checkpoints })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A fake file: 10 header bytes, `payload_len` payload bytes, the
+    /// index section and a CRC placeholder.
+    fn file_with_index(payload_len: usize, interval: u64, bits: &[u64]) -> Vec<u8> {
+        let mut file = vec![0u8; 10 + payload_len];
+        SeekIndex::from_interval(interval, bits)
+            .unwrap()
+            .write_section(&mut file, interval)
+            .unwrap();
+        file.extend_from_slice(&[0u8; 4]);
+        file
+    }
+
+    #[test]
+    fn section_round_trip() {
+        let file = file_with_index(100, 1000, &[10, 200, 799]);
+        let index =
+            SeekIndex::read_section(&mut Cursor::new(&file), 10, file.len() as u64, 3000).unwrap();
+        assert_eq!(
+            index.checkpoints,
+            vec![(1000, 10), (2000, 200), (3000, 799)]
+        );
+        assert_eq!(index.section_len(), 3 * 8 + 12);
+    }
+
+    #[test]
+    fn checkpoint_lookup() {
+        let index = SeekIndex::from_interval(100, &[5, 9]).unwrap();
+        assert_eq!(index.checkpoint_for(0), (0, 0));
+        assert_eq!(index.checkpoint_for(99), (0, 0));
+        assert_eq!(index.checkpoint_for(100), (100, 5));
+        assert_eq!(index.checkpoint_for(250), (200, 9));
+    }
+
+    #[test]
+    fn reject_checkpoint_past_payload() {
+        let file = file_with_index(4, 10, &[33]);
+        let err = SeekIndex::read_section(&mut Cursor::new(&file), 10, file.len() as u64, 100)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_checkpoint_past_original() {
+        let file = file_with_index(4, 10, &[1, 2]);
+        let len = file.len() as u64;
+        let err = SeekIndex::read_section(&mut Cursor::new(&file), 10, len, 19).unwrap_err();
+        assert!(err.to_string().contains("past the original data"));
+
+        // An interval whose checkpoints overflow u64 is refused, not a panic.
+        let mut file = file;
+        let n = file.len();
+        file[n - 16..n - 8].copy_from_slice(&(u64::MAX / 2 + 1).to_le_bytes());
+        let err = SeekIndex::read_section(&mut Cursor::new(&file), 10, len, u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("past the original data"));
+        assert!(SeekIndex::from_interval(u64::MAX / 2 + 1, &[1, 2]).is_none());
+    }
+
+    #[test]
+    fn reject_oversized_count() {
+        let mut file = file_with_index(4, 10, &[1]);
+        let n = file.len();
+        file[n - 8..n - 4].copy_from_slice(&1000u32.to_le_bytes());
+        let err = SeekIndex::read_section(&mut Cursor::new(&file), 10, n as u64, 100).unwrap_err();
+        assert!(err.to_string().contains("larger than the file"));
+    }
+
+    fn key() -> SidecarKey {
+        SidecarKey {
+            file_len: 500,
+            trailer: 0xDEAD_BEEF,
+        }
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let index = SeekIndex {
+            checkpoints: vec![(100, 7), (250, 90), (900, 400)],
+        };
+        let mut buf = Vec::new();
+        index.write_sidecar(&mut buf, key()).unwrap();
+        assert_eq!(buf.len(), SIDECAR_HEADER_SIZE + 3 * 16 + 4);
+        assert_eq!(
+            SeekIndex::read_sidecar(&mut &buf[..], key(), 400, 900).unwrap(),
+            index
+        );
+        let err = SeekIndex::read_sidecar(&mut &buf[..], key(), 400, 899).unwrap_err();
+        assert!(err.to_string().contains("past the original data"));
+    }
+
+    #[test]
+    fn sidecar_for_other_file_is_rejected() {
+        let mut buf = Vec::new();
+        SeekIndex::default().write_sidecar(&mut buf, key()).unwrap();
+        let other = SidecarKey {
+            file_len: 501,
+            ..key()
+        };
+        let err = SeekIndex::read_sidecar(&mut &buf[..], other, 0, 0).unwrap_err();
+        assert!(err.to_string().contains("different file"));
+    }
+
+    #[test]
+    fn sidecar_corruption_is_detected() {
+        let index = SeekIndex::from_interval(100, &[7, 90]).unwrap();
+        let mut buf = Vec::new();
+        index.write_sidecar(&mut buf, key()).unwrap();
+        buf[SIDECAR_HEADER_SIZE + 9] ^= 0x01;
+        let err = SeekIndex::read_sidecar(&mut &buf[..], key(), 400, 200).unwrap_err();
+        assert!(err.to_string().contains("CRC-32"));
+    }
+
+    #[test]
+    fn sidecar_path_appends_extension() {
+        assert_eq!(
+            sidecar_path(Path::new("logs/a.cmpr")),
+            PathBuf::from("logs/a.cmpr.idx")
+        );
+    }
+}
@@ -1,6 +1,21 @@
+pub mod ans;
+pub mod archive;
 pub mod bitio;
+pub mod codelen;
+pub mod coder;
 pub mod compress;
+pub mod crypto;
 pub mod decompress;
+pub mod error;
 pub mod format;
 pub mod frequency;
 pub mod huffman;
+pub mod index;
+pub mod parity;
+pub mod salvage;
+pub mod segment;
+pub mod speculative;
+pub mod volume;
+
+#[cfg(test)]
+mod test_util;
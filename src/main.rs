@@ -1,11 +1,20 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
 use std::path::Path;
 
-use argh::FromArgs;
+use argh::{EarlyExit, FromArgs};
 
-use cmprsr_rs::compress;
-use cmprsr_rs::decompress;
+use cmprsr_rs::archive;
+use cmprsr_rs::compress::{self, Coder, EncoderOptions};
+use cmprsr_rs::crypto::{self, EncryptionParams, MacKey, Secret};
+use cmprsr_rs::decompress::{self, ByteRange, DecoderOptions};
+use cmprsr_rs::error::{CorruptionKind, DecodeError};
 use cmprsr_rs::format;
+use cmprsr_rs::index::{self, SeekIndex, SidecarKey};
+use cmprsr_rs::parity::{self, ParityLayout, ParityReport};
+use cmprsr_rs::salvage;
+use cmprsr_rs::segment::{self, Segment};
+use cmprsr_rs::volume::{self, VolumeHeader, VolumeSize};
 
 #[derive(FromArgs)]
 /// A fast canonical Huffman compressor.
@@ -13,6 +22,12 @@ use cmprsr_rs::format;
 /// Compress:    cmprsr <file>
 /// Decompress:  cmprsr -d <input.cmpr> <output>
 /// List info:   cmprsr -l <file.cmpr>
+/// Test:        cmprsr -t <file.cmpr>
+/// Repair:      cmprsr --repair <file.cmpr>
+/// Archive:     cmprsr -a <out.cmpra> <file-or-dir>
+/// Extract:     cmprsr --extract <in.cmpra> [dir]
+/// Append:      cmprsr --append <log.cmpr> <newdata>
+/// Index:       cmprsr index <file.cmpr>
 #[derive(PartialEq, Debug)]
 struct Args {
     /// decompress a .cmpr file into the specified output
@@ -23,6 +38,34 @@ struct Args {
     #[argh(switch, short = 'l')]
     list: bool,
 
+    /// test the integrity of a .cmpr file: check the header and decode the
+    /// data without writing it
+    #[argh(switch, short = 't')]
+    test: bool,
+
+    /// correct damaged bytes of a .cmpr file in place using its parity
+    /// section (see --parity)
+    #[argh(switch)]
+    repair: bool,
+
+    /// create a .cmpra archive at PATH from the input file or directory,
+    /// compressing each file on its own
+    #[argh(option, short = 'a')]
+    archive: Option<String>,
+
+    /// extract a .cmpra archive into the output directory (default: the
+    /// current directory)
+    #[argh(switch)]
+    extract: bool,
+
+    /// with --extract, extract only this member (may be repeated)
+    #[argh(option)]
+    member: Vec<String>,
+
+    /// with -l, also print header details
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
     /// write to stdout (compressed data or decompressed output)
     #[argh(switch, short = 'c')]
     stdout: bool,
@@ -35,15 +78,107 @@ struct Args {
     #[argh(switch, short = 'k')]
     keep: bool,
 
+    /// entropy coder to compress with: `huffman` (default), `huffman4`
+    /// (four interleaved streams, faster to decode) or `ans`
+    #[argh(option, default = "Coder::Huffman")]
+    coder: Coder,
+
+    /// record a seek index checkpoint every N uncompressed bytes so that
+    /// --range can start near the requested bytes
+    #[argh(option)]
+    index_interval: Option<u64>,
+
+    /// append a Reed–Solomon parity section so that -t can detect and
+    /// --repair can correct damage to the file
+    #[argh(switch)]
+    parity: bool,
+
+    /// encrypt the compressed file with ChaCha20-Poly1305, keyed from
+    /// --key-file or the CMPRSR_PASSPHRASE environment variable
+    #[argh(switch)]
+    encrypt: bool,
+
+    /// key file to encrypt or decrypt with, instead of a passphrase
+    #[argh(option)]
+    key_file: Option<String>,
+
+    /// append an HMAC-SHA-256 tag so that -d, -t and -l can detect
+    /// tampering, keyed from --hmac-key-file or the CMPRSR_HMAC_KEY
+    /// environment variable
+    #[argh(switch)]
+    hmac: bool,
+
+    /// key file for HMAC-SHA-256 tags, instead of CMPRSR_HMAC_KEY
+    #[argh(option)]
+    hmac_key_file: Option<String>,
+
+    /// split the compressed output into volumes of at most SIZE bytes
+    /// (e.g. 100M), named <input>.cmpr.001, .002, ...
+    #[argh(option)]
+    volume_size: Option<VolumeSize>,
+
+    /// compress the input file into a new segment at the end of the
+    /// existing .cmpr file PATH, without re-encoding what is already there
+    #[argh(option)]
+    append: Option<String>,
+
+    /// with -d, decompress only LEN bytes starting at START (START:LEN)
+    #[argh(option)]
+    range: Option<ByteRange>,
+
+    /// with -d, decode on up to N threads, split at the file's seek index
+    /// or its sidecar index, or speculatively for plain Huffman files
+    /// (default 1)
+    #[argh(option, default = "1")]
+    threads: usize,
+
+    /// with -d, refuse files that claim to decompress to more than N bytes
+    #[argh(option)]
+    max_output: Option<u64>,
+
+    /// with -d, refuse files that claim to decompress to more than N times
+    /// their compressed size
+    #[argh(option)]
+    max_ratio: Option<u64>,
+
+    /// with -d, accept bytes after the end of the bitstream instead of
+    /// refusing the file
+    #[argh(switch)]
+    ignore_trailing: bool,
+
+    /// with -d -c, check the CRC-32 of the compressed data before writing
+    /// anything to stdout
+    #[argh(switch)]
+    verify_first: bool,
+
+    /// with -d, recover what can be decoded from a damaged file, fill the
+    /// rest with zero bytes and report the damaged ranges
+    #[argh(switch)]
+    salvage: bool,
+
     /// input file path
     #[argh(positional)]
     input: String,
 
-    /// output file path (required when decompressing unless --stdout)
+    /// output file path (required when decompressing unless --stdout), or
+    /// directory for --extract
     #[argh(positional)]
     output: Option<String>,
 }
 
+#[derive(FromArgs)]
+/// Decode a .cmpr file once and write a sidecar seek index (<file>.idx)
+/// that --range and --threads use, without modifying the file.
+struct IndexArgs {
+    /// uncompressed bytes between checkpoints (default 1048576)
+    #[argh(option, default = "1 << 20")]
+    interval: u64,
+
+    /// the .cmpr file to index
+    #[argh(positional)]
+    input: String,
+}
+
 fn main() {
     // Handle --version before argh so it works without any arguments.
     if std::env::args().any(|a| a == "--version") {
@@ -51,11 +186,25 @@ fn main() {
         return;
     }
 
+    let raw_args: Vec<String> = std::env::args().collect();
+    if raw_args.get(1).map(String::as_str) == Some("index") {
+        index_cmd(&raw_args[2..]);
+        return;
+    }
+
     let args: Args = argh::from_env();
 
     // --list mode: inspect a .cmpr file without decompressing
     if args.list {
-        if let Err(e) = list_file(Path::new(&args.input)) {
+        let input_path = Path::new(&args.input);
+        let result = if archive::is_archive(input_path).unwrap_or(false) {
+            list_archive(input_path)
+        } else if volume::is_volume(input_path).unwrap_or(false) {
+            list_volume(input_path)
+        } else {
+            list_file(input_path, args.verbose, mac_key(&args).as_ref())
+        };
+        if let Err(e) = result {
             eprintln!("error: {e}");
             std::process::exit(1);
         }
@@ -64,6 +213,60 @@ fn main() {
 
     let input_path = Path::new(&args.input).to_path_buf();
 
+    // --test mode: decode and verify without writing output
+    if args.test {
+        let passed = if archive::is_archive(&input_path).unwrap_or(false) {
+            test_archive(&input_path, &decoder_options(&args))
+        } else if volume::is_volume(&input_path).unwrap_or(false) {
+            test_volumes(&input_path, &decoder_options(&args))
+        } else {
+            test_file(&input_path, &decoder_options(&args))
+        };
+        if !passed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // --repair mode: correct damage in place using the parity section
+    if args.repair {
+        if !repair_file(&input_path) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.volume_size.is_some() && (args.archive.is_some() || args.stdout) {
+        eprintln!("error: --volume-size cannot be combined with -a or -c");
+        std::process::exit(2);
+    }
+
+    if let Some(cmpr_path) = &args.append {
+        if args.parity
+            || args.volume_size.is_some()
+            || args.archive.is_some()
+            || args.stdout
+            || args.decompress
+        {
+            eprintln!(
+                "error: --append cannot be combined with --parity, --volume-size, -a, -c or -d"
+            );
+            std::process::exit(2);
+        }
+        append_cmd(&input_path, Path::new(cmpr_path), &args);
+        return;
+    }
+
+    if let Some(archive_path) = &args.archive {
+        archive_cmd(&input_path, Path::new(archive_path), &args);
+        return;
+    }
+
+    if args.extract {
+        extract_cmd(&input_path, &args);
+        return;
+    }
+
     if args.decompress {
         decompress_cmd(&input_path, &args);
     } else {
@@ -76,6 +279,11 @@ fn main() {
 // ---------------------------------------------------------------------------
 
 fn compress_cmd(input_path: &Path, args: &Args) {
+    if let Some(VolumeSize(volume_size)) = args.volume_size {
+        volumes_cmd(input_path, volume_size, args);
+        return;
+    }
+
     let output_path = if args.stdout {
         None
     } else {
@@ -86,33 +294,28 @@ fn compress_cmd(input_path: &Path, args: &Args) {
 
     if let Some(ref out) = output_path {
         if out.exists() && !args.force {
-            eprintln!(
-                "Warning: overwriting existing file `{}`",
-                out.display()
-            );
+            eprintln!("Warning: overwriting existing file `{}`", out.display());
         }
     }
 
+    let options = encoder_options(args);
+
     let result = if let Some(ref out) = output_path {
-        compress::compress(input_path, out)
+        compress::compress_with_options(input_path, out, &options)
     } else {
-        compress::compress_to_stdout(input_path)
+        compress::compress_to_stdout_with_options(input_path, &options)
     };
 
     match result {
         Ok((original_size, compressed_size)) => {
             // Print compression statistics to stderr
-            let ratio = if original_size > 0 {
-                (compressed_size as f64 / original_size as f64) * 100.0
-            } else {
-                0.0
-            };
             let display_name = output_path
                 .as_ref()
                 .map(|p| p.display().to_string())
                 .unwrap_or_else(|| "<stdout>".to_string());
             eprintln!(
-                "original: {original_size:>12}   compressed: {compressed_size:>12}   ratio: {ratio:.1}%   {}",
+                "original: {original_size:>12}   compressed: {compressed_size:>12}   ratio: {}   {}",
+                ratio(compressed_size, original_size),
                 display_name
             );
         }
@@ -123,11 +326,108 @@ fn compress_cmd(input_path: &Path, args: &Args) {
     }
 }
 
+/// Compresses `input_path` into volumes named `<input>.cmpr.001`, ...
+fn volumes_cmd(input_path: &Path, volume_size: u64, args: &Args) {
+    let mut base = input_path.as_os_str().to_owned();
+    base.push(".cmpr");
+    let base = Path::new(&base);
+    let options = encoder_options(args);
+    match volume::compress_to_volumes(input_path, base, volume_size, &options) {
+        Ok((original_size, paths)) => {
+            let compressed_size: u64 = paths
+                .iter()
+                .map(|path| path.metadata().map_or(0, |m| m.len()))
+                .sum();
+            let plural = if paths.len() == 1 { "" } else { "s" };
+            eprintln!(
+                "original: {original_size:>12}   compressed: {compressed_size:>12}   ratio: {}   {} volume{plural}   {}",
+                ratio(compressed_size, original_size),
+                paths.len(),
+                paths[0].display()
+            );
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Compresses `input_path` into a new segment at the end of `cmpr_path`.
+fn append_cmd(input_path: &Path, cmpr_path: &Path, args: &Args) {
+    let options = encoder_options(args);
+    match segment::append(cmpr_path, input_path, &options) {
+        Ok((original_size, segment_size, count)) => {
+            eprintln!(
+                "original: {original_size:>12}   compressed: {segment_size:>12}   ratio: {}   segment {count}   {}",
+                ratio(segment_size, original_size),
+                cmpr_path.display()
+            );
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Encoder options from the command line.  Exits with code 2 if
+/// `--encrypt` or `--hmac` has no key to use.
+fn encoder_options(args: &Args) -> EncoderOptions {
+    let encrypt = if args.encrypt {
+        let secret = secret(args);
+        if secret.is_none() {
+            eprintln!("error: --encrypt needs --key-file or the {PASSPHRASE_VAR} variable");
+            std::process::exit(2);
+        }
+        secret
+    } else {
+        None
+    };
+
+    let mac_key = if args.hmac {
+        let key = mac_key(args);
+        if key.is_none() {
+            eprintln!("error: --hmac needs --hmac-key-file or the {MAC_KEY_VAR} variable");
+            std::process::exit(2);
+        }
+        key
+    } else {
+        None
+    };
+
+    EncoderOptions {
+        coder: args.coder,
+        index_interval: args.index_interval,
+        parity: args.parity,
+        encrypt,
+        mac_key,
+    }
+}
+
+/// `compressed` as a percentage of `original`, formatted like `49.1%`.
+fn ratio(compressed: u64, original: u64) -> String {
+    let ratio = if original > 0 {
+        (compressed as f64 / original as f64) * 100.0
+    } else {
+        0.0
+    };
+    format!("{ratio:.1}%")
+}
+
 // ---------------------------------------------------------------------------
 // Decompression command
 // ---------------------------------------------------------------------------
 
 fn decompress_cmd(input_path: &Path, args: &Args) {
+    if archive::is_archive(input_path).unwrap_or(false) {
+        eprintln!(
+            "error: `{}` is an archive; use --extract",
+            input_path.display()
+        );
+        std::process::exit(2);
+    }
+
     let output_path = if args.stdout {
         None
     } else {
@@ -142,61 +442,670 @@ fn decompress_cmd(input_path: &Path, args: &Args) {
 
     if let Some(ref out) = output_path {
         if out.exists() && !args.force {
-            eprintln!(
-                "Warning: overwriting existing file `{}`",
-                out.display()
-            );
+            eprintln!("Warning: overwriting existing file `{}`", out.display());
         }
     }
 
-    let result = if let Some(ref out) = output_path {
-        decompress::decompress(input_path, out)
-    } else {
-        decompress::decompress_to_stdout(input_path)
+    let options = decoder_options(args);
+
+    if args.salvage {
+        if args.range.is_some() {
+            eprintln!("error: --salvage cannot be combined with --range");
+            std::process::exit(2);
+        }
+        salvage_cmd(input_path, output_path.as_deref(), &options);
+        return;
+    }
+
+    let result = match (args.range, &output_path) {
+        (Some(range), Some(out)) => decompress::decompress_range_with_options(
+            input_path,
+            out,
+            range.start,
+            range.len,
+            &options,
+        ),
+        (Some(range), None) => decompress::decompress_range_to_writer_with_options(
+            input_path,
+            range.start,
+            range.len,
+            &mut std::io::stdout().lock(),
+            &options,
+        ),
+        (None, Some(out)) => decompress::decompress_with_options(input_path, out, &options),
+        (None, None) => decompress::decompress_to_stdout_with_options(input_path, &options),
     };
 
     if let Err(e) = result {
         eprintln!("error: {e}");
+        std::process::exit(failure_code(&e));
+    }
+}
+
+/// Salvages `input_path` and reports what was recovered on stderr.  Exits
+/// with code 1 unless the file turned out to be intact.
+fn salvage_cmd(input_path: &Path, output_path: Option<&Path>, options: &DecoderOptions) {
+    let result = match output_path {
+        Some(out) => salvage::salvage(input_path, out, options),
+        None => salvage::salvage_to_writer(input_path, &mut std::io::stdout().lock(), options),
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    eprintln!(
+        "salvage: recovered {} of {} bytes",
+        report.recovered_bytes(),
+        report.original_size
+    );
+    let ranges = [
+        ("damaged", &report.damaged),
+        ("misplaced", &report.misplaced),
+    ];
+    for (label, ranges) in ranges {
+        for range in ranges {
+            eprintln!(
+                "  {label:<10} bytes {}..{} ({} bytes)",
+                range.start,
+                range.start + range.len,
+                range.len
+            );
+        }
+    }
+    if report.crc_ok == Some(false) {
+        eprintln!("  CRC-32 mismatch");
+    }
+    if !report.is_intact() {
         std::process::exit(1);
     }
 }
 
+fn decoder_options(args: &Args) -> DecoderOptions {
+    DecoderOptions {
+        max_output_bytes: args.max_output,
+        max_ratio: args.max_ratio,
+        threads: args.threads,
+        ignore_trailing: args.ignore_trailing,
+        verify_first: args.verify_first,
+        secret: secret(args),
+        mac_key: mac_key(args),
+    }
+}
+
+/// Environment variable holding the passphrase of encrypted files.
+const PASSPHRASE_VAR: &str = "CMPRSR_PASSPHRASE";
+
+/// The key from `--key-file`, or else the passphrase from the environment.
+/// Exits with code 1 if the key file cannot be read.
+fn secret(args: &Args) -> Option<Secret> {
+    if let Some(path) = &args.key_file {
+        return match Secret::from_key_file(Path::new(path)) {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        };
+    }
+    std::env::var_os(PASSPHRASE_VAR)
+        .map(|passphrase| Secret::Passphrase(passphrase.into_encoded_bytes()))
+}
+
+/// Environment variable holding the key of HMAC-SHA-256 tags.
+const MAC_KEY_VAR: &str = "CMPRSR_HMAC_KEY";
+
+/// The key from `--hmac-key-file`, or else from the environment.  Exits
+/// with code 1 if the key file cannot be read or the key is empty.
+fn mac_key(args: &Args) -> Option<MacKey> {
+    let key = match &args.hmac_key_file {
+        Some(path) => MacKey::from_key_file(Path::new(path)),
+        None => match std::env::var_os(MAC_KEY_VAR) {
+            Some(key) => MacKey::new(key.into_encoded_bytes()),
+            None => return None,
+        },
+    };
+    match key {
+        Ok(key) => Some(key),
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit code for a failed decompression: 3 if the file failed
+/// authentication (an encrypted file or an HMAC-SHA-256 tag), 1 otherwise.
+fn failure_code(err: &std::io::Error) -> i32 {
+    match DecodeError::from_io(err) {
+        Some(decode) if decode.kind == CorruptionKind::AuthenticationFailed => 3,
+        _ => 1,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Test command
+// ---------------------------------------------------------------------------
+
+/// Checks the header and then the data of `input_path`, with its
+/// HMAC-SHA-256 tag and parity section if it has them, reporting each on one
+/// line.  Returns whether all passed.
+fn test_file(input_path: &Path, options: &DecoderOptions) -> bool {
+    let name = input_path.display();
+    match File::open(input_path).and_then(|mut file| segment::read_segments(&mut file)) {
+        Ok(None) => {}
+        Ok(Some(segments)) => return test_segments(input_path, segments.len(), options),
+        Err(e) => {
+            println!("{name}: segment table FAILED: {e}");
+            return false;
+        }
+    }
+    let (parity_status, parity_ok) =
+        match File::open(input_path).and_then(|mut file| parity::check(&mut file)) {
+            Ok(None) => (String::new(), true),
+            Ok(Some(report)) if report.is_clean() => (", parity ok".to_string(), true),
+            Ok(Some(report)) if report.uncorrectable_codewords == 0 => (
+                format!(
+                    ", parity: {} damaged bytes correctable, run --repair",
+                    report.corrected_bytes
+                ),
+                false,
+            ),
+            Ok(Some(report)) => (
+                format!(
+                    ", parity FAILED: {} of {} codewords uncorrectable",
+                    report.uncorrectable_codewords, report.codewords
+                ),
+                false,
+            ),
+            Err(e) => (format!(", parity FAILED: {e}"), false),
+        };
+    let header = match File::open(input_path).and_then(|mut file| format::read_header(&mut file)) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("{name}: header FAILED: {e}{parity_status}");
+            return false;
+        }
+    };
+    let header_status = match header.header_crc {
+        Some(_) => "ok",
+        None => "ok (no checksum)",
+    };
+    let mac_status = if header.flags & format::FLAG_HMAC != 0 {
+        ", hmac ok"
+    } else {
+        ""
+    };
+    match decompress::verify(input_path, options) {
+        Ok(()) => {
+            println!("{name}: header {header_status}, data ok{mac_status}{parity_status}");
+            parity_ok
+        }
+        Err(e) => {
+            println!("{name}: header {header_status}, data FAILED: {e}{parity_status}");
+            false
+        }
+    }
+}
+
+/// Joins the volume set of `input_path` and checks the data, reporting on
+/// one line.  Returns whether it passed.
+fn test_volumes(input_path: &Path, options: &DecoderOptions) -> bool {
+    let name = input_path.display();
+    match decompress::verify(input_path, options) {
+        Ok(()) => {
+            println!("{name}: volumes ok, data ok");
+            true
+        }
+        Err(e) => {
+            println!("{name}: FAILED: {e}");
+            false
+        }
+    }
+}
+
+/// Checks every segment of a file with appended segments, reporting on one
+/// line.  Returns whether all passed.
+fn test_segments(input_path: &Path, count: usize, options: &DecoderOptions) -> bool {
+    let name = input_path.display();
+    match decompress::verify(input_path, options) {
+        Ok(()) => {
+            println!("{name}: {count} segments, data ok");
+            true
+        }
+        Err(e) => {
+            println!("{name}: {count} segments, data FAILED: {e}");
+            false
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Repair command
+// ---------------------------------------------------------------------------
+
+/// Corrects `input_path` in place from its parity section and reports what
+/// was done on one line.  Returns whether the file is now intact.
+fn repair_file(input_path: &Path) -> bool {
+    let name = input_path.display();
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(input_path)
+        .and_then(|mut file| parity::repair(&mut file));
+    let report = match result {
+        Ok(Some(report)) => report,
+        Ok(None) => {
+            println!("{name}: no parity section, cannot repair");
+            return false;
+        }
+        Err(e) => {
+            println!("{name}: repair FAILED: {e}");
+            return false;
+        }
+    };
+    let ParityReport {
+        codewords,
+        correctable_codewords,
+        corrected_bytes,
+        uncorrectable_codewords,
+    } = report;
+    if report.is_clean() {
+        println!("{name}: no damage found");
+    } else if uncorrectable_codewords == 0 {
+        println!("{name}: repaired {corrected_bytes} bytes in {correctable_codewords} codewords");
+    } else {
+        println!(
+            "{name}: repaired {corrected_bytes} bytes in {correctable_codewords} codewords, \
+             {uncorrectable_codewords} of {codewords} codewords uncorrectable"
+        );
+    }
+    uncorrectable_codewords == 0
+}
+
+// ---------------------------------------------------------------------------
+// Archive commands
+// ---------------------------------------------------------------------------
+
+fn archive_cmd(input_path: &Path, archive_path: &Path, args: &Args) {
+    if archive_path.exists() && !args.force {
+        eprintln!(
+            "Warning: overwriting existing file `{}`",
+            archive_path.display()
+        );
+    }
+    match archive::create(input_path, archive_path, &encoder_options(args)) {
+        Ok(entries) => {
+            let original_size: u64 = entries.iter().map(|entry| entry.size).sum();
+            let archive_size = archive_path.metadata().map_or(0, |m| m.len());
+            eprintln!(
+                "files: {:>8}   original: {original_size:>12}   compressed: {archive_size:>12}   ratio: {}   {}",
+                entries.len(),
+                ratio(archive_size, original_size),
+                archive_path.display()
+            );
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Extracts the members named by `--member`, or all of them, into the
+/// output directory or to stdout.
+fn extract_cmd(archive_path: &Path, args: &Args) {
+    let options = decoder_options(args);
+    let dest = Path::new(args.output.as_deref().unwrap_or("."));
+    let result = if args.stdout {
+        archive::extract_to_stdout(archive_path, &args.member, &options)
+    } else {
+        archive::extract(archive_path, dest, &args.member, &options, |path| {
+            if !args.force {
+                eprintln!("Warning: overwriting existing file `{}`", path.display());
+            }
+        })
+    };
+    match result {
+        Ok(entries) if !args.stdout => {
+            let bytes: u64 = entries.iter().map(|entry| entry.size).sum();
+            eprintln!(
+                "files: {:>8}   extracted: {bytes:>12}   {}",
+                entries.len(),
+                dest.display()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(failure_code(&e));
+        }
+    }
+}
+
+/// Tests every member of an archive, reporting each on one line.  Returns
+/// whether all passed.
+fn test_archive(archive_path: &Path, options: &DecoderOptions) -> bool {
+    let name = archive_path.display();
+    let entries = match File::open(archive_path).and_then(|mut f| archive::read_directory(&mut f)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("{name}: directory FAILED: {e}");
+            return false;
+        }
+    };
+    let mut passed = true;
+    for entry in &entries {
+        match archive::verify_member(archive_path, entry, options) {
+            Ok(()) => println!("{name}: {}: data ok", entry.name),
+            Err(e) => {
+                println!("{name}: {}: data FAILED: {e}", entry.name);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+/// Lists the central directory of an archive, one member per line and a
+/// total.
+fn list_archive(archive_path: &Path) -> std::io::Result<()> {
+    let entries = archive::read_directory(&mut File::open(archive_path)?)?;
+    for entry in &entries {
+        println!(
+            "{:>12} {:>12} {:>7}  {:04o}  {}  {}",
+            entry.len,
+            entry.size,
+            ratio(entry.len, entry.size),
+            entry.mode,
+            format_mtime(entry.mtime),
+            entry.name
+        );
+    }
+    let compressed: u64 = entries.iter().map(|entry| entry.len).sum();
+    let original: u64 = entries.iter().map(|entry| entry.size).sum();
+    println!(
+        "{compressed:>12} {original:>12} {:>7}  {} files",
+        ratio(compressed, original),
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Formats seconds since the Unix epoch as a UTC `YYYY-MM-DD HH:MM`.
+fn format_mtime(mtime: i64) -> String {
+    let (days, seconds) = (mtime.div_euclid(86_400), mtime.rem_euclid(86_400));
+    // Civil date from a day count (H. Hinnant, "chrono-Compatible Low-Level
+    // Date Algorithms").
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+// ---------------------------------------------------------------------------
+// Index command
+// ---------------------------------------------------------------------------
+
+fn index_cmd(rest: &[String]) {
+    let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+    let args = match IndexArgs::from_args(&["cmprsr index"], &rest) {
+        Ok(args) => args,
+        Err(EarlyExit { output, status }) => match status {
+            Ok(()) => {
+                println!("{output}");
+                return;
+            }
+            Err(()) => {
+                eprintln!("{output}");
+                std::process::exit(2);
+            }
+        },
+    };
+
+    let input_path = Path::new(&args.input);
+    match decompress::write_sidecar_index(input_path, args.interval) {
+        Ok(index) => eprintln!(
+            "checkpoints: {:>8}   interval: {:>10}   {}",
+            index.checkpoints.len(),
+            args.interval,
+            index::sidecar_path(input_path).display()
+        ),
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // List command
 // ---------------------------------------------------------------------------
 
-fn list_file(input_path: &Path) -> std::io::Result<()> {
+fn list_file(input_path: &Path, verbose: bool, mac_key: Option<&MacKey>) -> std::io::Result<()> {
     let mut file = File::open(input_path)?;
+    if let Some(segments) = segment::read_segments(&mut file)? {
+        return list_segments(input_path, &mut file, &segments, verbose);
+    }
     let header = format::read_header(&mut file)?;
 
-    // Get the total file size
-    let file_len = file.metadata()?.len();
+    // Get the container size, without any parity section or HMAC tag
+    let parity = if header.flags & format::FLAG_PARITY != 0 {
+        ParityLayout::read(&mut file)?
+    } else {
+        None
+    };
+    let mut file_len = match parity {
+        Some(layout) => layout.protected_len,
+        None => file.metadata()?.len(),
+    };
+    let has_mac = header.flags & format::FLAG_HMAC != 0;
+    if has_mac {
+        file_len = file_len.saturating_sub(crypto::MAC_SIZE);
+    }
 
     // Compressed data size (excludes header and CRC)
-    let header_size = format::FIXED_HEADER_SIZE as u64 + header.symbol_count as u64 * 2;
+    let header_size = header.header_len();
     let compressed_data_size = if header.version >= 0x02 {
         file_len - header_size - format::CRC_SIZE
     } else {
         file_len - header_size
     };
 
-    let ratio = if header.original_size > 0 {
-        (compressed_data_size as f64 / header.original_size as f64) * 100.0
-    } else {
-        0.0
-    };
-
     // Use tab-aligned output like gzip -l
     println!(
-        "{:>12} {:>12} {:>7}  {}",
+        "{:>12} {:>12} {:>7}  {:<8} {}",
         compressed_data_size,
         header.original_size,
-        format!("{:.1}%", ratio),
+        ratio(compressed_data_size, header.original_size),
+        format::method_name(header.method),
+        input_path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default()
+    );
+
+    if verbose {
+        let table = if header.version < 0x03 {
+            "pairs (legacy)"
+        } else if !format::uses_huffman_table(header.method) {
+            "coder-defined"
+        } else if header.compact_table {
+            "compact"
+        } else {
+            "pairs"
+        };
+        let completeness = if header.incomplete_table {
+            " (incomplete code)"
+        } else {
+            ""
+        };
+        println!("  version:  {:#04x}", header.version);
+        println!("  method:   {}", format::method_name(header.method));
+        if format::uses_huffman_table(header.method) {
+            println!("  symbols:  {}", header.symbol_count);
+        }
+        let crc_size = header.header_crc.map_or(0, |_| format::CRC_SIZE);
+        println!(
+            "  table:    {table}, {} bytes{completeness}",
+            header_size - header_fixed_size(&header) - crc_size
+        );
+        match header.header_crc {
+            Some(crc) => println!("  header:   {header_size} bytes, CRC-32 {crc:#010x} ok"),
+            None => println!("  header:   {header_size} bytes, no checksum"),
+        }
+        println!("  padding:  {} bits", header.padding);
+        if crypto::is_encrypted(&header) {
+            let params = EncryptionParams::parse(&header.table)?;
+            let plural = if params.iterations == 1 { "" } else { "s" };
+            println!(
+                "  cipher:   ChaCha20-Poly1305, key from PBKDF2-HMAC-SHA-256 with {} iteration{plural}",
+                params.iterations
+            );
+        }
+        if has_mac {
+            let status = match mac_key {
+                Some(key) => match crypto::check_mac(&mut file, &header, Some(key)) {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("FAILED: {e}"),
+                },
+                None => "not checked (no key)".to_string(),
+            };
+            println!("  hmac:     HMAC-SHA-256, {status}");
+        }
+        let (index, source) = if header.flags & format::FLAG_INDEX != 0 {
+            let index =
+                SeekIndex::read_section(&mut file, header_size, file_len, header.original_size)?;
+            (Some(index), "")
+        } else if let Ok(sidecar) = File::open(index::sidecar_path(input_path)) {
+            let key = SidecarKey::of_file(&mut file)?;
+            let index = SeekIndex::read_sidecar(
+                &mut std::io::BufReader::new(sidecar),
+                key,
+                compressed_data_size * 8,
+                header.original_size,
+            )?;
+            (Some(index), " (sidecar)")
+        } else {
+            (None, "")
+        };
+        match index.as_ref().map(|index| &index.checkpoints[..]) {
+            Some([]) => println!("  index:    no checkpoints{source}"),
+            Some(checkpoints @ [(first, _), ..]) => println!(
+                "  index:    {} checkpoints, every {first} bytes{source}",
+                checkpoints.len()
+            ),
+            None => {}
+        }
+        if let Some(layout) = parity {
+            println!(
+                "  parity:   {} bytes, {} codewords of {} parity bytes",
+                layout.section_len(),
+                layout.groups() * layout.stripe as u64,
+                layout.parity_symbols
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists a file with appended segments: the totals on one line as for a
+/// single `.cmpr` file and the number of segments, and with `verbose` one
+/// line per segment.
+fn list_segments(
+    input_path: &Path,
+    file: &mut File,
+    segments: &[Segment],
+    verbose: bool,
+) -> std::io::Result<()> {
+    let mut rows = Vec::with_capacity(segments.len());
+    for segment in segments {
+        file.seek(SeekFrom::Start(segment.offset))?;
+        let header = format::read_header(file)?;
+        let mut trailer_size = 0;
+        if header.version >= 0x02 {
+            trailer_size += format::CRC_SIZE;
+        }
+        if header.flags & format::FLAG_HMAC != 0 {
+            trailer_size += crypto::MAC_SIZE;
+        }
+        let compressed_data_size = segment
+            .len
+            .saturating_sub(header.header_len() + trailer_size);
+        rows.push((compressed_data_size, header.original_size, header.method));
+    }
+    let compressed_total: u64 = rows.iter().map(|row| row.0).sum();
+    let original_total: u64 = rows.iter().map(|row| row.1).sum();
+    let method = if rows.iter().all(|row| row.2 == rows[0].2) {
+        format::method_name(rows[0].2)
+    } else {
+        "mixed"
+    };
+
+    println!(
+        "{:>12} {:>12} {:>7}  {:<8} {}",
+        compressed_total,
+        original_total,
+        ratio(compressed_total, original_total),
+        method,
         input_path
             .file_name()
             .map(|n| n.to_string_lossy())
             .unwrap_or_default()
     );
+    println!("  segments: {}", segments.len());
+    if verbose {
+        for (i, (segment, (compressed, original, method))) in segments.iter().zip(&rows).enumerate()
+        {
+            println!(
+                "  {:>8} {:>12} {:>12} {:>7}  {:<8} at {}",
+                i + 1,
+                compressed,
+                original,
+                ratio(*compressed, *original),
+                format::method_name(*method),
+                segment.offset
+            );
+        }
+    }
+    Ok(())
+}
 
+/// Prints the header of one volume.  Decompress the set to see more.
+fn list_volume(input_path: &Path) -> std::io::Result<()> {
+    let header = VolumeHeader::read(&mut File::open(input_path)?)?;
+    let set_id: String = header.set_id.iter().map(|b| format!("{b:02x}")).collect();
+    println!(
+        "volume {} of {}   set {set_id}   {}",
+        header.sequence,
+        header.total,
+        input_path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default()
+    );
     Ok(())
 }
+
+/// Size of the part of the header that precedes the coder/symbol table.
+fn header_fixed_size(header: &format::Header) -> u64 {
+    if header.version >= 0x03 {
+        format::FIXED_HEADER_SIZE_V3
+    } else {
+        format::FIXED_HEADER_SIZE - 2
+    }
+}
@@ -0,0 +1,700 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// Reed–Solomon parity section
+// ---------------------------------------------------------------------------
+//
+// Files compressed with `--parity` end in a parity section that protects
+// every byte before it -- header, payload, seek index and CRC-32 trailer --
+// against damage on the storage medium:
+//
+//   G x S x P   parity bytes: for group 0..G, codeword 0..S, P bytes each
+//   8           protected length L, little-endian u64
+//   4           stripe S, little-endian u32
+//   1           parity bytes per codeword P
+//   3           reserved, zero
+//   4           CRC-32 of the 16 bytes above
+//   4           magic "CPAR"
+//
+// The protected bytes are cut into groups of `(255 - P) * S` bytes.  Within
+// a group, byte j belongs to codeword j % S, so the S codewords interleave
+// byte by byte and a burst of damage is spread over all of them.  Each
+// codeword is Reed–Solomon coded over GF(256) with P parity bytes and can
+// correct up to P / 2 damaged bytes, including damaged parity bytes; a
+// group therefore survives a burst of up to `P / 2 * S` bytes.
+//
+// The trailer is found from the end of the file, so the section can be
+// used even when the header is damaged.  The header's `FLAG_PARITY` tells
+// readers that the container ends before the section.
+
+/// Magic bytes ending a parity section.
+pub const PARITY_MAGIC: [u8; 4] = *b"CPAR";
+
+/// Size of the fixed trailer at the end of a parity section.
+pub const PARITY_TRAILER_SIZE: u64 = 24;
+
+/// Parity bytes per codeword written by [`write_section`].  Each codeword
+/// corrects up to half as many damaged bytes.
+pub const PARITY_SYMBOLS: u8 = 32;
+
+/// Most codewords interleaved in one group.
+pub const MAX_STRIPE: u32 = 256;
+
+/// Length of a Reed–Solomon codeword over GF(256).
+const CODEWORD_LEN: usize = 255;
+
+/// Shape of a parity section, as recorded in its trailer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParityLayout {
+    /// Number of bytes the section protects, from the start of the file.
+    pub protected_len: u64,
+    /// Codewords interleaved per group.
+    pub stripe: u32,
+    /// Parity bytes per codeword.
+    pub parity_symbols: u8,
+}
+
+impl ParityLayout {
+    /// The layout [`write_section`] uses for `protected_len` bytes: full
+    /// stripes, narrowed for small files so they are not padded out.
+    pub fn new(protected_len: u64) -> Self {
+        let data_symbols = (CODEWORD_LEN - PARITY_SYMBOLS as usize) as u64;
+        let stripe = protected_len
+            .div_ceil(data_symbols)
+            .clamp(1, MAX_STRIPE as u64);
+        ParityLayout {
+            protected_len,
+            stripe: stripe as u32,
+            parity_symbols: PARITY_SYMBOLS,
+        }
+    }
+
+    /// Data bytes per codeword.
+    fn data_symbols(&self) -> usize {
+        CODEWORD_LEN - self.parity_symbols as usize
+    }
+
+    /// Protected bytes per group.
+    fn group_len(&self) -> u64 {
+        self.data_symbols() as u64 * self.stripe as u64
+    }
+
+    /// Number of groups.
+    pub fn groups(&self) -> u64 {
+        self.protected_len.div_ceil(self.group_len())
+    }
+
+    /// Parity bytes per group.
+    fn group_parity_len(&self) -> usize {
+        self.stripe as usize * self.parity_symbols as usize
+    }
+
+    /// Size of the whole section, trailer included.
+    pub fn section_len(&self) -> u64 {
+        self.groups() * self.group_parity_len() as u64 + PARITY_TRAILER_SIZE
+    }
+
+    fn trailer(&self) -> [u8; PARITY_TRAILER_SIZE as usize] {
+        let mut trailer = [0u8; PARITY_TRAILER_SIZE as usize];
+        trailer[..8].copy_from_slice(&self.protected_len.to_le_bytes());
+        trailer[8..12].copy_from_slice(&self.stripe.to_le_bytes());
+        trailer[12] = self.parity_symbols;
+        let crc = format::crc32(&trailer[..16]);
+        trailer[16..20].copy_from_slice(&crc.to_le_bytes());
+        trailer[20..].copy_from_slice(&PARITY_MAGIC);
+        trailer
+    }
+
+    /// Reads the layout from the trailer at the end of `reader`, and
+    /// restores the reader's position.
+    ///
+    /// Returns `None` if the file does not end in a parity trailer, and an
+    /// `InvalidData` error if the trailer is damaged or does not match the
+    /// file length.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Self>> {
+        let position = reader.stream_position()?;
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let layout = match file_len.checked_sub(PARITY_TRAILER_SIZE) {
+            Some(trailer_pos) => {
+                reader.seek(SeekFrom::Start(trailer_pos))?;
+                let mut trailer = [0u8; PARITY_TRAILER_SIZE as usize];
+                reader.read_exact(&mut trailer)?;
+                Self::parse(&trailer, file_len)
+            }
+            None => Ok(None),
+        };
+        reader.seek(SeekFrom::Start(position))?;
+        layout
+    }
+
+    fn parse(
+        trailer: &[u8; PARITY_TRAILER_SIZE as usize],
+        file_len: u64,
+    ) -> std::io::Result<Option<Self>> {
+        if trailer[20..] != PARITY_MAGIC {
+            return Ok(None);
+        }
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+        let stored_crc = u32::from_le_bytes(trailer[16..20].try_into().unwrap());
+        if format::crc32(&trailer[..16]) != stored_crc || trailer[13..16] != [0; 3] {
+            return Err(invalid("Parity section trailer is damaged"));
+        }
+        let layout = ParityLayout {
+            protected_len: u64::from_le_bytes(trailer[..8].try_into().unwrap()),
+            stripe: u32::from_le_bytes(trailer[8..12].try_into().unwrap()),
+            parity_symbols: trailer[12],
+        };
+        if layout.protected_len == 0
+            || layout.stripe == 0
+            || layout.stripe > MAX_STRIPE
+            || layout.parity_symbols == 0
+            || layout.data_symbols() == 0
+        {
+            return Err(invalid("Invalid parity section layout"));
+        }
+        let expected = layout
+            .groups()
+            .checked_mul(layout.group_parity_len() as u64)
+            .and_then(|len| len.checked_add(layout.protected_len))
+            .and_then(|len| len.checked_add(PARITY_TRAILER_SIZE));
+        if expected != Some(file_len) {
+            return Err(invalid("Parity section does not match the file length"));
+        }
+        Ok(Some(layout))
+    }
+}
+
+/// Appends a parity section protecting the first `protected_len` bytes of
+/// `file` at its end, and returns the section's size.
+///
+/// Returns an `InvalidInput` error if `protected_len` is 0, a section that
+/// [`ParityLayout::read`] would refuse.
+pub fn write_section<F: Read + Write + Seek>(
+    file: &mut F,
+    protected_len: u64,
+) -> std::io::Result<u64> {
+    if protected_len == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Nothing to protect with a parity section",
+        ));
+    }
+    let layout = ParityLayout::new(protected_len);
+    let code = ReedSolomon::new(layout.parity_symbols);
+    let mut parity = Vec::with_capacity(layout.group_parity_len());
+    let mut codeword = Vec::with_capacity(CODEWORD_LEN);
+    for group in 0..layout.groups() {
+        let data = read_group(file, &layout, group)?;
+        parity.clear();
+        for c in 0..layout.stripe as usize {
+            codeword.clear();
+            codeword.extend(data.iter().skip(c).step_by(layout.stripe as usize));
+            parity.extend_from_slice(&code.parity(&codeword));
+        }
+        file.seek(SeekFrom::Start(parity_offset(&layout, group)))?;
+        file.write_all(&parity)?;
+    }
+    file.seek(SeekFrom::Start(parity_offset(&layout, layout.groups())))?;
+    file.write_all(&layout.trailer())?;
+    file.flush()?;
+    Ok(layout.section_len())
+}
+
+/// Reads the protected bytes of `group`.
+fn read_group<R: Read + Seek>(
+    reader: &mut R,
+    layout: &ParityLayout,
+    group: u64,
+) -> std::io::Result<Vec<u8>> {
+    let start = group * layout.group_len();
+    let len = layout.group_len().min(layout.protected_len - start);
+    let mut data = vec![0u8; len as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// File offset of the parity bytes of `group`.
+fn parity_offset(layout: &ParityLayout, group: u64) -> u64 {
+    layout.protected_len + group * layout.group_parity_len() as u64
+}
+
+/// What [`check`] or [`repair`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParityReport {
+    /// Codewords checked.
+    pub codewords: u64,
+    /// Codewords with damage that parity can correct.
+    pub correctable_codewords: u64,
+    /// Damaged bytes in those codewords, parity bytes included.
+    pub corrected_bytes: u64,
+    /// Codewords with more damage than parity can correct.
+    pub uncorrectable_codewords: u64,
+}
+
+impl ParityReport {
+    /// Whether no damage was found.
+    pub fn is_clean(&self) -> bool {
+        self.correctable_codewords == 0 && self.uncorrectable_codewords == 0
+    }
+}
+
+/// Checks `reader` against its parity section without changing it.
+/// Returns `None` if it has none.
+pub fn check<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<ParityReport>> {
+    let Some(layout) = ParityLayout::read(reader)? else {
+        return Ok(None);
+    };
+    scan(reader, &layout, |_, _| Ok(())).map(Some)
+}
+
+/// Corrects the damaged bytes of `file` that its parity section can
+/// correct, in place.  Codewords with too much damage are left as they are
+/// and counted in the report.  Returns `None` if the file has no parity
+/// section.
+pub fn repair<F: Read + Write + Seek>(file: &mut F) -> std::io::Result<Option<ParityReport>> {
+    let Some(layout) = ParityLayout::read(file)? else {
+        return Ok(None);
+    };
+    let mut fixes = Vec::new();
+    let report = scan(file, &layout, |offset, byte| {
+        fixes.push((offset, byte));
+        Ok(())
+    })?;
+    for (offset, byte) in fixes {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&[byte])?;
+    }
+    file.flush()?;
+    Ok(Some(report))
+}
+
+/// Decodes every codeword of `reader` and passes each correction to `fix`
+/// as `(file offset, corrected byte)`.
+fn scan<R, F>(reader: &mut R, layout: &ParityLayout, mut fix: F) -> std::io::Result<ParityReport>
+where
+    R: Read + Seek,
+    F: FnMut(u64, u8) -> std::io::Result<()>,
+{
+    let code = ReedSolomon::new(layout.parity_symbols);
+    let stripe = layout.stripe as usize;
+    let nsym = layout.parity_symbols as usize;
+    let mut report = ParityReport::default();
+    let mut parity = vec![0u8; layout.group_parity_len()];
+    let mut codeword = Vec::with_capacity(CODEWORD_LEN);
+    for group in 0..layout.groups() {
+        let data = read_group(reader, layout, group)?;
+        reader.seek(SeekFrom::Start(parity_offset(layout, group)))?;
+        reader.read_exact(&mut parity)?;
+
+        for c in 0..stripe {
+            codeword.clear();
+            codeword.extend(data.iter().skip(c).step_by(stripe));
+            let message_len = codeword.len();
+            codeword.extend_from_slice(&parity[c * nsym..(c + 1) * nsym]);
+            report.codewords += 1;
+            match code.correct(&mut codeword) {
+                Some(0) => {}
+                Some(errors) => {
+                    report.correctable_codewords += 1;
+                    report.corrected_bytes += errors as u64;
+                    let group_start = group * layout.group_len();
+                    let parity_start = parity_offset(layout, group) + (c * nsym) as u64;
+                    for (i, &byte) in codeword.iter().enumerate() {
+                        let (offset, old) = if i < message_len {
+                            let j = c + i * stripe;
+                            (group_start + j as u64, data[j])
+                        } else {
+                            let k = i - message_len;
+                            (parity_start + k as u64, parity[c * nsym + k])
+                        };
+                        if byte != old {
+                            fix(offset, byte)?;
+                        }
+                    }
+                }
+                None => report.uncorrectable_codewords += 1,
+            }
+        }
+    }
+    Ok(report)
+}
+
+// ---------------------------------------------------------------------------
+// GF(256) arithmetic and the Reed–Solomon code
+// ---------------------------------------------------------------------------
+
+/// Powers of the generator α = 2 modulo x^8 + x^4 + x^3 + x^2 + 1, twice
+/// over so that products need no reduction of the exponent sum.
+const GF_EXP: [u8; 512] = {
+    let mut table = [0u8; 512];
+    let mut x = 1u16;
+    let mut i = 0;
+    while i < 255 {
+        table[i] = x as u8;
+        table[i + 255] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11D;
+        }
+        i += 1;
+    }
+    table
+};
+
+/// Discrete logarithms base α; entry 0 is unused.
+const GF_LOG: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[GF_EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+    }
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    debug_assert!(b != 0);
+    if a == 0 {
+        0
+    } else {
+        GF_EXP[GF_LOG[a as usize] as usize + 255 - GF_LOG[b as usize] as usize]
+    }
+}
+
+/// α^`power`.
+fn gf_pow(power: usize) -> u8 {
+    GF_EXP[power % 255]
+}
+
+/// Evaluates a polynomial with coefficients lowest degree first at `x`.
+fn poly_eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter()
+        .rev()
+        .fold(0, |acc, &coef| gf_mul(acc, x) ^ coef)
+}
+
+/// Systematic Reed–Solomon code over GF(256) with `nsym` parity bytes,
+/// whose generator has the roots α^0 .. α^(nsym - 1).
+///
+/// A codeword is the message followed by its parity, read as polynomial
+/// coefficients from the highest degree down; messages may be shorter than
+/// `255 - nsym` bytes (a shortened code).
+struct ReedSolomon {
+    nsym: usize,
+    /// Generator coefficients after the leading 1, highest degree first.
+    generator: Vec<u8>,
+}
+
+impl ReedSolomon {
+    fn new(nsym: u8) -> Self {
+        let mut generator = vec![1u8];
+        for j in 0..nsym as usize {
+            // Multiply by (x + α^j).
+            let root = gf_pow(j);
+            generator.push(0);
+            for i in (1..generator.len()).rev() {
+                generator[i] ^= gf_mul(generator[i - 1], root);
+            }
+        }
+        generator.remove(0);
+        ReedSolomon {
+            nsym: nsym as usize,
+            generator,
+        }
+    }
+
+    /// The parity bytes for `message`.
+    fn parity(&self, message: &[u8]) -> Vec<u8> {
+        let mut parity = vec![0u8; self.nsym];
+        for &byte in message {
+            let coef = byte ^ parity[0];
+            parity.rotate_left(1);
+            parity[self.nsym - 1] = 0;
+            if coef != 0 {
+                for (p, &g) in parity.iter_mut().zip(&self.generator) {
+                    *p ^= gf_mul(g, coef);
+                }
+            }
+        }
+        parity
+    }
+
+    /// Syndromes of `codeword`, lowest first; all zero for a valid one.
+    fn syndromes(&self, codeword: &[u8]) -> Vec<u8> {
+        (0..self.nsym)
+            .map(|j| {
+                let x = gf_pow(j);
+                codeword.iter().fold(0, |acc, &byte| gf_mul(acc, x) ^ byte)
+            })
+            .collect()
+    }
+
+    /// Corrects `codeword` in place.  Returns the number of corrected
+    /// bytes, or `None` if it has more errors than the code can correct
+    /// (in which case it is left unchanged).
+    fn correct(&self, codeword: &mut [u8]) -> Option<usize> {
+        // Re-encoding is cheaper than the syndromes for intact codewords.
+        let (message, parity) = codeword.split_at(codeword.len() - self.nsym);
+        if self.parity(message) == parity {
+            return Some(0);
+        }
+        let syndromes = self.syndromes(codeword);
+
+        // Berlekamp–Massey: the error locator Λ(x) = Π (1 - X_k x), lowest
+        // degree first, where X_k = α^(degree of error k).
+        let mut locator = vec![1u8];
+        let mut previous = vec![1u8];
+        let mut errors = 0;
+        let mut shift = 1;
+        let mut previous_discrepancy = 1u8;
+        for n in 0..self.nsym {
+            let discrepancy = (1..=errors).fold(syndromes[n], |d, i| {
+                d ^ gf_mul(*locator.get(i).unwrap_or(&0), syndromes[n - i])
+            });
+            if discrepancy == 0 {
+                shift += 1;
+                continue;
+            }
+            let scale = gf_div(discrepancy, previous_discrepancy);
+            let mut next = locator.clone();
+            next.resize(next.len().max(previous.len() + shift), 0);
+            for (i, &coef) in previous.iter().enumerate() {
+                next[i + shift] ^= gf_mul(coef, scale);
+            }
+            if 2 * errors <= n {
+                errors = n + 1 - errors;
+                previous = std::mem::replace(&mut locator, next);
+                previous_discrepancy = discrepancy;
+                shift = 1;
+            } else {
+                locator = next;
+                shift += 1;
+            }
+        }
+        if 2 * errors > self.nsym {
+            return None;
+        }
+
+        // Chien search: position p holds degree n - 1 - p, and is in error
+        // if Λ vanishes at α^-(n - 1 - p).
+        let n = codeword.len();
+        let positions: Vec<usize> = (0..n)
+            .filter(|&p| poly_eval(&locator, gf_pow(255 - (n - 1 - p) % 255)) == 0)
+            .collect();
+        if positions.len() != errors {
+            return None;
+        }
+
+        // Forney: e_k = X_k Ω(X_k^-1) / Λ'(X_k^-1), with
+        // Ω(x) = S(x) Λ(x) mod x^nsym.
+        let mut evaluator = vec![0u8; self.nsym];
+        for (i, &s) in syndromes.iter().enumerate() {
+            for (j, &l) in locator.iter().enumerate().take(self.nsym - i) {
+                evaluator[i + j] ^= gf_mul(s, l);
+            }
+        }
+        let derivative: Vec<u8> = locator
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(i, &coef)| if i % 2 == 1 { coef } else { 0 })
+            .collect();
+        let mut corrected = codeword.to_vec();
+        for &p in &positions {
+            let x = gf_pow(n - 1 - p);
+            let x_inv = gf_pow(255 - (n - 1 - p) % 255);
+            let denominator = poly_eval(&derivative, x_inv);
+            if denominator == 0 {
+                return None;
+            }
+            corrected[p] ^= gf_mul(x, gf_div(poly_eval(&evaluator, x_inv), denominator));
+        }
+        if self.syndromes(&corrected).iter().any(|&s| s != 0) {
+            return None;
+        }
+        codeword.copy_from_slice(&corrected);
+        Some(errors)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A deterministic byte pattern.
+    fn bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gf_tables_are_consistent() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_div(1, a)), 1);
+            assert_eq!(GF_EXP[GF_LOG[a as usize] as usize], a);
+        }
+    }
+
+    #[test]
+    fn corrects_up_to_half_the_parity() {
+        let code = ReedSolomon::new(PARITY_SYMBOLS);
+        for len in [1, 10, 223] {
+            let message = bytes(len, len as u64);
+            let mut codeword = message.clone();
+            codeword.extend(code.parity(&message));
+            assert_eq!(code.correct(&mut codeword.clone()), Some(0));
+
+            let n = codeword.len();
+            let mut damaged = codeword.clone();
+            let errors = (PARITY_SYMBOLS as usize / 2).min(n);
+            for (k, byte) in bytes(errors, 7).into_iter().enumerate() {
+                damaged[k * n / errors] ^= byte | 1;
+            }
+            assert_eq!(code.correct(&mut damaged), Some(errors));
+            assert_eq!(damaged, codeword);
+        }
+    }
+
+    #[test]
+    fn too_many_errors_are_refused() {
+        let code = ReedSolomon::new(PARITY_SYMBOLS);
+        let message = bytes(223, 3);
+        let mut codeword = message.clone();
+        codeword.extend(code.parity(&message));
+        for (k, byte) in bytes(40, 11).into_iter().enumerate() {
+            codeword[k * 6] ^= byte | 1;
+        }
+        let before = codeword.clone();
+        assert_eq!(code.correct(&mut codeword), None);
+        assert_eq!(codeword, before);
+    }
+
+    fn protected(len: usize) -> (Vec<u8>, Cursor<Vec<u8>>) {
+        let data = bytes(len, 42);
+        let mut file = Cursor::new(data.clone());
+        file.seek(SeekFrom::End(0)).unwrap();
+        let section = write_section(&mut file, len as u64).unwrap();
+        assert_eq!(file.get_ref().len() as u64, len as u64 + section);
+        (data, file)
+    }
+
+    #[test]
+    fn layout_round_trips_through_the_trailer() {
+        for len in [1, 500, 200_000] {
+            let (_, mut file) = protected(len);
+            let layout = ParityLayout::read(&mut file).unwrap().unwrap();
+            assert_eq!(layout, ParityLayout::new(len as u64));
+            assert!(check(&mut file).unwrap().unwrap().is_clean());
+        }
+        let mut plain = Cursor::new(bytes(100, 1));
+        assert_eq!(ParityLayout::read(&mut plain).unwrap(), None);
+        let err = write_section(&mut Cursor::new(Vec::new()), 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn repairs_a_burst() {
+        let (data, mut file) = protected(200_000);
+        // Half of the largest correctable burst, crossing into a second
+        // group, plus damage to the parity itself.
+        let burst = PARITY_SYMBOLS as usize / 2 * MAX_STRIPE as usize;
+        let group = 223 * MAX_STRIPE as usize;
+        for byte in &mut file.get_mut()[group - burst / 2..group + burst / 2] {
+            *byte = !*byte;
+        }
+        file.get_mut()[200_010] ^= 0xff;
+
+        let report = check(&mut file).unwrap().unwrap();
+        assert_eq!(report.uncorrectable_codewords, 0);
+        assert_eq!(report.corrected_bytes, burst as u64 + 1);
+        assert_ne!(file.get_ref()[..200_000], data[..]);
+
+        assert_eq!(repair(&mut file).unwrap().unwrap(), report);
+        assert_eq!(file.get_ref()[..200_000], data[..]);
+        assert!(check(&mut file).unwrap().unwrap().is_clean());
+    }
+
+    #[test]
+    fn uncorrectable_damage_is_reported() {
+        let (_, mut file) = protected(10_000);
+        for byte in &mut file.get_mut()[..2_000] {
+            *byte = !*byte;
+        }
+        let report = check(&mut file).unwrap().unwrap();
+        assert!(report.uncorrectable_codewords > 0);
+    }
+
+    #[test]
+    fn damaged_trailer_is_an_error() {
+        let (_, mut file) = protected(1_000);
+        let len = file.get_ref().len();
+        file.get_mut()[len - 20] ^= 1;
+        let err = ParityLayout::read(&mut file).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_protected_len_is_an_error() {
+        let layout = ParityLayout {
+            protected_len: u64::MAX - 5,
+            stripe: 1,
+            parity_symbols: PARITY_SYMBOLS,
+        };
+        let mut file = bytes(100, 1);
+        file.extend_from_slice(&layout.trailer());
+        let err = ParityLayout::read(&mut Cursor::new(file)).unwrap_err();
+        assert!(err.to_string().contains("does not match the file length"));
+    }
+
+    #[test]
+    fn unbounded_stripe_is_an_error() {
+        // An empty protected range makes the section length check pass
+        // whatever the stripe, which `scan` would then allocate.
+        let layout = ParityLayout {
+            protected_len: 0,
+            stripe: u32::MAX,
+            parity_symbols: 200,
+        };
+        let mut file = Cursor::new(layout.trailer().to_vec());
+        let err = ParityLayout::read(&mut file).unwrap_err();
+        assert!(err.to_string().contains("Invalid parity section layout"));
+        assert!(check(&mut file).is_err());
+
+        let layout = ParityLayout {
+            stripe: MAX_STRIPE + 1,
+            ..ParityLayout::new(1_000)
+        };
+        let mut file = bytes(1_000, 1);
+        file.resize(
+            1_000 + layout.section_len() as usize - PARITY_TRAILER_SIZE as usize,
+            0,
+        );
+        file.extend_from_slice(&layout.trailer());
+        let err = ParityLayout::read(&mut Cursor::new(file)).unwrap_err();
+        assert!(err.to_string().contains("Invalid parity section layout"));
+    }
+}
@@ -0,0 +1,1149 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
+use std::path::Path;
+
+use crate::ans::{self, AnsCoder, AnsDecoder};
+use crate::bitio::BitReader;
+use crate::coder::{EntropyCoder, StoredCoder};
+use crate::crypto::TempFile;
+use crate::decompress::{self, ByteRange, DecoderOptions};
+use crate::error::{CorruptionKind, DecodeError};
+use crate::format;
+use crate::huffman::{self, Huffman4Coder, HuffmanCoder, HuffmanDecoder, MAX_CODE_LENGTH};
+use crate::index::SeekIndex;
+use crate::speculative::SYNC_WINDOW_BITS;
+use crate::volume;
+
+// ---------------------------------------------------------------------------
+// Salvaging damaged files
+// ---------------------------------------------------------------------------
+//
+// Salvage decodes whatever it can from a damaged payload and fills the rest
+// of the output with zero bytes, so recovered data keeps its offsets.  How
+// far damage spreads depends on what the payload offers to start again
+// from:
+//
+// - Stored payloads are copied; only missing bytes are lost.
+// - Huffman4 blocks record the length of each of their four streams, so a
+//   damaged stream costs a quarter of a block.  After a damaged jump table
+//   the next block is found by scanning for a jump table whose streams
+//   decode cleanly.
+// - ANS chunks have no lengths, but a chunk decodes cleanly only if it
+//   ends back in its initial state.  After a damaged chunk the next one is
+//   searched for around where the damaged one ended.
+// - Huffman payloads are cut at their seek index checkpoints, if any, and
+//   each stretch must end exactly at the next checkpoint.  After an invalid
+//   code, decoding restarts once decodes started one bit apart agree on a
+//   symbol boundary, as canonical codes self-synchronize.  Damage that
+//   yields no invalid code only shows as a stretch not ending where it
+//   should, and the whole stretch is reported damaged.
+//
+// Data found after resynchronizing is placed by counting back from the end
+// of the stretch (or payload) when it decodes cleanly up to there.
+// Otherwise how much data the damage swallowed is unknown, and its position
+// is estimated from the compressed distance skipped.
+//
+// The payload is read as it is decoded and the output written in order, so
+// memory use does not grow with the file.  Data after damage whose place is
+// only known once the data after it is decoded is decoded twice: once to
+// place it and once to write it.
+
+/// Most candidate positions tried when searching for the next ANS chunk
+/// after a damaged one.
+const ANS_RESYNC_TRIES: usize = 4096;
+
+/// Below this many symbols a Huffman stretch is decoded symbol by symbol.
+const BULK_MIN: u64 = 64;
+
+/// Decoded bytes collected before they are written out.
+const WRITE_CHUNK: usize = 1 << 16;
+
+/// What [`salvage`] recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Bytes written: the original size from the header.
+    pub original_size: u64,
+    /// Output ranges that were not recovered and hold zero bytes, or hold
+    /// decoded data that failed verification.
+    pub damaged: Vec<ByteRange>,
+    /// Recovered output ranges whose offset is an estimate, because they
+    /// follow damage that swallowed an unknown amount of data.
+    pub misplaced: Vec<ByteRange>,
+    /// Whether the payload CRC-32 matched.  `None` for version 0x01 files,
+    /// which have none.
+    pub crc_ok: Option<bool>,
+}
+
+impl SalvageReport {
+    /// Number of output bytes outside the damaged ranges.
+    pub fn recovered_bytes(&self) -> u64 {
+        let damaged: u64 = self.damaged.iter().map(|range| range.len).sum();
+        self.original_size - damaged
+    }
+
+    /// Whether the whole file was recovered and verified.
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty() && self.misplaced.is_empty() && self.crc_ok != Some(false)
+    }
+}
+
+/// Recovers what can be decoded from a damaged `input_path` into
+/// `output_path`, which always receives `original_size` bytes.
+///
+/// Only the header must be intact.  A volume (see [`crate::volume`]) is
+/// joined with the rest of its set first, which must be complete.  The
+/// limits in `options` apply as for
+/// [`decompress_with_options`](decompress::decompress_with_options); its
+/// other fields are ignored.  An original size the payload is too short
+/// to decode to is refused unless `options` limits the output size, as it
+/// would be written out mostly as zero bytes.
+pub fn salvage(
+    input_path: &Path,
+    output_path: &Path,
+    options: &DecoderOptions,
+) -> std::io::Result<SalvageReport> {
+    decompress::write_atomically(output_path, |temp_path| {
+        let mut writer = BufWriter::new(File::create(temp_path)?);
+        let report = salvage_to_writer(input_path, &mut writer, options)?;
+        writer.flush()?;
+        Ok(report)
+    })
+}
+
+/// Like [`salvage`], but writes the recovered data to `writer`.
+pub fn salvage_to_writer<W: Write>(
+    input_path: &Path,
+    writer: &mut W,
+    options: &DecoderOptions,
+) -> std::io::Result<SalvageReport> {
+    let joined = volume::join_if_volume(input_path)?;
+    let input_path = joined.as_ref().map_or(input_path, TempFile::path);
+    let mut input = File::open(input_path)?;
+    decompress::refuse_segments(&mut input, "Salvage")?;
+    let header = format::read_header(&mut input)?;
+    decompress::check_limits(&header, &input, options)?;
+    // A damaged parity trailer is no reason to give up: the parity bytes are
+    // then salvaged as payload, and show up as trailing damage.
+    let data_len = match decompress::container_len(&header, &input) {
+        Ok(container_len) => container_len,
+        Err(_) => input.metadata()?.len(),
+    }
+    .saturating_sub(header.header_len());
+
+    let crc_ok = (header.version >= 0x02)
+        .then(|| crc_matches(&input, header.header_len(), data_len))
+        .transpose()?;
+    let mut payload_end = data_len;
+    if header.version >= 0x02 {
+        payload_end = payload_end.saturating_sub(format::CRC_SIZE);
+    }
+    // A damaged index is no reason to give up: the payload is then salvaged
+    // as a single stretch.
+    let index = decompress::load_index(input_path, &mut input, &header)
+        .ok()
+        .flatten();
+    if let (Some(index), true) = (&index, header.flags & format::FLAG_INDEX != 0) {
+        payload_end = payload_end.saturating_sub(index.section_len());
+    }
+
+    let size = header.original_size;
+    let payload = Payload {
+        file: input,
+        start: header.header_len(),
+        len: payload_end,
+    };
+    let mut sink = RunWriter {
+        writer,
+        size,
+        pos: 0,
+        damaged: Vec::new(),
+        misplaced: Vec::new(),
+    };
+    match header.method {
+        format::METHOD_STORED => {
+            check_plausible(&StoredCoder, size, payload_end, options)?;
+            let len = payload_end.min(size);
+            let mut pos = 0;
+            while pos < len {
+                let chunk = payload.read(pos, (len - pos).min(WRITE_CHUNK as u64) as usize)?;
+                sink.write(pos, &chunk, Trust::Exact)?;
+                pos += chunk.len() as u64;
+            }
+        }
+        format::METHOD_HUFFMAN => {
+            let coder = HuffmanCoder::read_table(&header.table)?;
+            check_plausible(&coder, size, payload_end, options)?;
+            let decoder = HuffmanDecoder::new(&coder.code_table().symbol_table())?;
+            let payload_bits = (payload_end * 8).saturating_sub(header.padding as u64);
+            let stretches = stretches(index.as_ref(), size, payload_bits);
+            for (from, to) in stretches.iter().zip(&stretches[1..]) {
+                salvage_stretch(&decoder, &payload, *from, *to, &mut sink)?;
+            }
+        }
+        format::METHOD_HUFFMAN4 => {
+            let coder = Huffman4Coder::read_table(&header.table)?;
+            check_plausible(&coder, size, payload_end, options)?;
+            let decoder = HuffmanDecoder::new(&coder.code_table().symbol_table())?;
+            let decode = |frame: &[u8], n| {
+                let block = Huffman4Coder::decode_block(&decoder, frame, n).ok_or(None)?;
+                let parts = block
+                    .streams
+                    .into_iter()
+                    .filter_map(|(offset, symbols)| Some((offset as u64, symbols?)))
+                    .collect();
+                Ok(Frame {
+                    len: block.len,
+                    parts,
+                })
+            };
+            let frames = FrameLayout {
+                frame_size: huffman::X4_BLOCK_SIZE,
+                max_frame_len: huffman::X4_BLOCK_SIZE * MAX_CODE_LENGTH as usize / 8
+                    + huffman::X4_JUMP_TABLE_SIZE,
+                resync_tries: usize::MAX,
+            };
+            frames.salvage(&payload, size, decode, &mut sink)?;
+        }
+        format::METHOD_ANS => {
+            let coder = AnsCoder::read_table(&header.table)?;
+            check_plausible(&coder, size, payload_end, options)?;
+            let decoder = AnsDecoder::new(coder.frequency_table());
+            let decode = |mut frame: &[u8], n| {
+                let mut out = Vec::new();
+                match decoder.decode(&mut frame, &mut out, n as u64) {
+                    Ok(consumed) => Ok(Frame {
+                        len: consumed as usize,
+                        parts: vec![(0, out)],
+                    }),
+                    // A chunk that decoded to the wrong state still
+                    // consumed about as many bytes as the real one.
+                    Err(e) => Err(DecodeError::from_io(&e)
+                        .filter(|e| e.kind == CorruptionKind::InvalidState)
+                        .and_then(|e| e.bit_offset)
+                        .map(|bit| (bit / 8) as usize)),
+                }
+            };
+            // No symbol takes more than `PROB_BITS` bits, plus the 32-bit
+            // state and the bytes left over from renormalizing.
+            let frames = FrameLayout {
+                frame_size: ans::CHUNK_SIZE,
+                max_frame_len: ans::CHUNK_SIZE * ans::PROB_BITS as usize / 8 + 8,
+                resync_tries: ANS_RESYNC_TRIES,
+            };
+            frames.salvage(&payload, size, decode, &mut sink)?;
+        }
+        method => return Err(decompress::unsupported_method(method)),
+    }
+
+    let (damaged, misplaced) = sink.finish()?;
+    writer.flush()?;
+    Ok(SalvageReport {
+        original_size: size,
+        damaged,
+        misplaced,
+        crc_ok,
+    })
+}
+
+/// Refuses an original size that a `payload_len`-byte payload cannot
+/// decode to, unless `options` limits the output size.
+fn check_plausible<C: EntropyCoder>(
+    coder: &C,
+    size: u64,
+    payload_len: u64,
+    options: &DecoderOptions,
+) -> std::io::Result<()> {
+    if options.max_output_bytes.is_none() && payload_len < coder.min_payload_len(size) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Original size of {size} bytes cannot be decoded from a \
+                 {payload_len}-byte payload; set an output limit to salvage it anyway"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the CRC-32 in the last four of the `len` bytes at `start` of
+/// `file` matches the bytes before it.
+fn crc_matches(file: &File, start: u64, len: u64) -> std::io::Result<bool> {
+    let Some(body) = len.checked_sub(format::CRC_SIZE) else {
+        return Ok(false);
+    };
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(start))?;
+    let mut crc = 0;
+    let mut left = body;
+    let mut buf = vec![0u8; WRITE_CHUNK];
+    while left > 0 {
+        let chunk = &mut buf[..left.min(WRITE_CHUNK as u64) as usize];
+        reader.read_exact(chunk)?;
+        crc = format::crc32_update(crc, chunk);
+        left -= chunk.len() as u64;
+    }
+    let mut stored = [0u8; 4];
+    reader.read_exact(&mut stored)?;
+    Ok(crc == u32::from_le_bytes(stored))
+}
+
+/// The payload of the file being salvaged, read as it is needed.
+struct Payload {
+    file: File,
+    /// File offset of the first payload byte.
+    start: u64,
+    len: u64,
+}
+
+impl Payload {
+    /// Reads up to `len` payload bytes from byte `pos` on.
+    fn read(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let len = self.len.saturating_sub(pos).min(len as u64) as usize;
+        let mut buf = vec![0u8; len];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.start + pos))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// A bit reader positioned at payload bit `bit`, or `None` past the
+    /// end; its own bit count starts at `bit & !7`.  Readers share the file
+    /// position, so only the one made last may be read from.
+    fn reader_at(&self, bit: u64) -> std::io::Result<Option<BitReader<BufReader<Take<&File>>>>> {
+        let byte = bit / 8;
+        if byte >= self.len {
+            return Ok(None);
+        }
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.start + byte))?;
+        let mut reader = BitReader::new(file.take(self.len - byte));
+        Ok(reader
+            .read_bits((bit % 8) as u8)
+            .ok()
+            .flatten()
+            .map(|_| reader))
+    }
+}
+
+/// How far the offset of recovered data can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trust {
+    /// Decoded and placed exactly.
+    Exact,
+    /// Decoded cleanly, but placed by estimate.
+    Estimated,
+}
+
+/// Writes recovered data at its offsets, zero-filling the gaps up to
+/// `size`, and collects the damaged and misplaced ranges.  Data must come
+/// in order of offset: where it overlaps what was already written, the
+/// data written first wins.
+struct RunWriter<'a, W: Write> {
+    writer: &'a mut W,
+    size: u64,
+    /// Output bytes written so far.
+    pos: u64,
+    damaged: Vec<ByteRange>,
+    misplaced: Vec<ByteRange>,
+}
+
+impl<W: Write> RunWriter<'_, W> {
+    /// Writes `data` at output offset `start`.
+    fn write(&mut self, start: u64, data: &[u8], trust: Trust) -> std::io::Result<()> {
+        let begin = start.max(self.pos);
+        let skip = (begin - start).min(data.len() as u64) as usize;
+        let take = (data.len() - skip).min(self.size.saturating_sub(begin) as usize);
+        if take == 0 {
+            return Ok(());
+        }
+        if begin > self.pos {
+            write_zeros(self.writer, begin - self.pos)?;
+            add_range(&mut self.damaged, self.pos, begin - self.pos);
+        }
+        self.writer.write_all(&data[skip..skip + take])?;
+        match trust {
+            Trust::Exact => {}
+            Trust::Estimated => add_range(&mut self.misplaced, begin, take as u64),
+        }
+        self.pos = begin + take as u64;
+        Ok(())
+    }
+
+    /// Reports what was written from output offset `start` on as damaged,
+    /// for data that failed verification only once it was written.
+    fn distrust(&mut self, start: u64) {
+        if self.pos > start {
+            add_range(&mut self.damaged, start, self.pos - start);
+        }
+    }
+
+    /// Zero-fills the rest of the output and returns the damaged and
+    /// misplaced ranges.
+    fn finish(mut self) -> std::io::Result<(Vec<ByteRange>, Vec<ByteRange>)> {
+        if self.pos < self.size {
+            write_zeros(self.writer, self.size - self.pos)?;
+            add_range(&mut self.damaged, self.pos, self.size - self.pos);
+        }
+        Ok((self.damaged, self.misplaced))
+    }
+}
+
+fn write_zeros<W: Write>(writer: &mut W, len: u64) -> std::io::Result<()> {
+    std::io::copy(&mut std::io::repeat(0).take(len), writer).map(|_| ())
+}
+
+/// Appends `start..start + len` to `ranges`, merging it into the last range
+/// if they touch.
+fn add_range(ranges: &mut Vec<ByteRange>, start: u64, len: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.start + last.len == start => last.len += len,
+        _ => ranges.push(ByteRange { start, len }),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Framed payloads (Huffman4 blocks, ANS chunks)
+// ---------------------------------------------------------------------------
+
+/// What decoding one frame recovered.
+struct Frame {
+    /// Payload bytes the frame takes.
+    len: usize,
+    /// Clean parts of the frame's output, by offset in the frame.
+    parts: Vec<(u64, Vec<u8>)>,
+}
+
+/// A payload of independently decodable frames of `frame_size` output
+/// bytes each (the last may be shorter).
+struct FrameLayout {
+    frame_size: usize,
+    /// Most payload bytes a frame can take.
+    max_frame_len: usize,
+    /// Most candidate positions tried when resynchronizing.
+    resync_tries: usize,
+}
+
+/// Where a walk over the frames of a payload is.
+#[derive(Clone, Copy)]
+struct Walk {
+    /// Payload position of the next frame.
+    pos: u64,
+    /// Output offset of the next frame.
+    out: u64,
+    trust: Trust,
+    /// Payload position the walk resynchronized at after the last damage.
+    chain: u64,
+    /// Frame length in bytes to estimate skipped frames by: that of the
+    /// frames decoded before the first damage, else of the whole payload.
+    avg_frame_len: u128,
+    /// Whether the last frame decoded was the last of the output.
+    ended_on_last: bool,
+}
+
+/// Receives each frame a [`Walk`] decodes.
+type Emit<'a> = dyn FnMut(&Walk, Frame) -> std::io::Result<()> + 'a;
+
+/// The payload around the frames being decoded.
+struct Window<'a> {
+    payload: &'a Payload,
+    /// Payload position of the first byte of `buf`.
+    start: u64,
+    buf: Vec<u8>,
+    /// Bytes that must follow a position handed out.
+    span: usize,
+}
+
+impl Window<'_> {
+    /// The payload from `pos` on: at least `span` bytes of it, unless the
+    /// payload ends sooner.  Reads in some of the payload before `pos`
+    /// too, for positions searched on both sides of it.
+    fn at(&mut self, pos: u64) -> std::io::Result<&[u8]> {
+        let end = pos.saturating_add(self.span as u64).min(self.payload.len);
+        if pos < self.start || end > self.start + self.buf.len() as u64 {
+            self.start = pos.saturating_sub(self.span as u64);
+            self.buf = self.payload.read(self.start, 4 * self.span)?;
+        }
+        Ok(&self.buf[(pos - self.start) as usize..])
+    }
+}
+
+impl FrameLayout {
+    /// Salvages `payload` into `sink`, decoding an `n`-byte frame at the
+    /// start of a slice with `decode`.  A frame whose end is unknown is
+    /// `Err`, with a guess at its length in bytes if there is one.
+    fn salvage<F, W>(
+        &self,
+        payload: &Payload,
+        size: u64,
+        decode: F,
+        sink: &mut RunWriter<W>,
+    ) -> std::io::Result<()>
+    where
+        F: Fn(&[u8], usize) -> Result<Frame, Option<usize>>,
+        W: Write,
+    {
+        if size == 0 {
+            return Ok(());
+        }
+        let mut window = Window {
+            payload,
+            start: 0,
+            buf: Vec::new(),
+            span: self.max_frame_len,
+        };
+        let mut at = Walk {
+            pos: 0,
+            out: 0,
+            trust: Trust::Exact,
+            chain: 0,
+            avg_frame_len: (payload.len as u128 * self.frame_size as u128 / size as u128).max(1),
+            ended_on_last: false,
+        };
+        let mut write = |at: &Walk, frame: Frame, placed: Option<(u64, u64)>| {
+            for (offset, data) in frame.parts {
+                let start = at.out + offset;
+                // A chain that decoded cleanly up to the end of the payload
+                // ends where the output ends.
+                match placed {
+                    Some((chain, out)) if at.chain == chain => {
+                        sink.write((start + size).saturating_sub(out), &data, Trust::Exact)?
+                    }
+                    _ => sink.write(start, &data, at.trust)?,
+                }
+            }
+            Ok(())
+        };
+
+        // Frames up to the first damage are written as they are decoded.
+        // The walk after it is made twice, and the searches for where to
+        // resume are kept for the second time.
+        let mut resyncs = Vec::new();
+        let mut walk = |at: &mut Walk, stop_at_damage, emit: &mut Emit| {
+            self.walk(
+                &mut window,
+                size,
+                &decode,
+                &mut resyncs,
+                at,
+                stop_at_damage,
+                emit,
+            )
+        };
+        if !walk(&mut at, true, &mut |at, frame| write(at, frame, None))? {
+            return Ok(());
+        }
+        let resumed = at;
+        walk(&mut at, false, &mut |_, _| Ok(()))?;
+        let placed = (at.trust == Trust::Estimated && at.pos == payload.len && at.ended_on_last)
+            .then_some((at.chain, at.out));
+        at = resumed;
+        walk(&mut at, false, &mut |at, frame| write(at, frame, placed))?;
+        Ok(())
+    }
+
+    /// Decodes frames from `at` on, passing each to `emit` along with the
+    /// walk as it was before the frame.  With `stop_at_damage`, returns
+    /// `true` at the first damage, leaving `at` before it.  `resyncs` keeps
+    /// where the walk resumed after damage at each payload position.
+    #[allow(clippy::too_many_arguments)]
+    fn walk<F>(
+        &self,
+        window: &mut Window,
+        size: u64,
+        decode: &F,
+        resyncs: &mut Vec<(u64, Option<u64>)>,
+        at: &mut Walk,
+        stop_at_damage: bool,
+        emit: &mut Emit,
+    ) -> std::io::Result<bool>
+    where
+        F: Fn(&[u8], usize) -> Result<Frame, Option<usize>>,
+    {
+        let frame_size = self.frame_size as u64;
+        let last_start = (size - 1) / frame_size * frame_size;
+        let len = window.payload.len;
+        while at.pos < len && (at.trust != Trust::Exact || at.out < size) {
+            let frame = if at.trust == Trust::Exact {
+                let n = (size - at.out).min(frame_size) as usize;
+                decode(window.at(at.pos)?, n).map(|frame| (frame, n))
+            } else {
+                self.find_frame(window, at.pos, size, decode)?.ok_or(None)
+            };
+            match frame {
+                Ok((frame, n)) => {
+                    let frame_len = frame.len as u64;
+                    emit(at, frame)?;
+                    at.pos += frame_len;
+                    at.out += n as u64;
+                    at.ended_on_last = n as u64 != frame_size || size.is_multiple_of(frame_size);
+                }
+                Err(_) if stop_at_damage && at.trust == Trust::Exact => return Ok(true),
+                Err(hint) => {
+                    if at.trust == Trust::Exact && at.out > 0 {
+                        at.avg_frame_len =
+                            (at.pos as u128 * frame_size as u128 / at.out as u128).max(1);
+                    }
+                    let avg_frame_len = at.avg_frame_len;
+                    let expected = at.pos + hint.map_or(avg_frame_len as u64, |hint| hint as u64);
+                    let next = match resyncs.iter().find(|&&(pos, _)| pos == at.pos) {
+                        Some(&(_, next)) => next,
+                        None => {
+                            let next = self.resync(window, at.pos + 1, expected, size, decode)?;
+                            resyncs.push((at.pos, next));
+                            next
+                        }
+                    };
+                    let Some(next) = next else {
+                        return Ok(false);
+                    };
+                    // The damaged frame is lost, and with it whatever
+                    // frames the skipped bytes held.
+                    let skipped = ((next - at.pos) as u128 + avg_frame_len / 2) / avg_frame_len;
+                    at.out = (at.out + skipped.max(1) as u64 * frame_size).min(last_start);
+                    at.pos = next;
+                    at.trust = Trust::Estimated;
+                    at.chain = next;
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Decodes a frame at payload position `pos` whose position in the
+    /// output is unknown: a full frame, or else the shorter last frame if
+    /// it ends the payload.  Returns the frame and its output size.
+    fn find_frame<F>(
+        &self,
+        window: &mut Window,
+        pos: u64,
+        size: u64,
+        decode: &F,
+    ) -> std::io::Result<Option<(Frame, usize)>>
+    where
+        F: Fn(&[u8], usize) -> Result<Frame, Option<usize>>,
+    {
+        let rest = window.payload.len - pos;
+        let data = window.at(pos)?;
+        let full = decode(data, self.frame_size).ok();
+        if full.as_ref().is_some_and(|frame| !frame.parts.is_empty()) {
+            return Ok(full.map(|frame| (frame, self.frame_size)));
+        }
+        let last = (size % self.frame_size as u64) as usize;
+        if last != 0 {
+            if let Ok(frame) = decode(data, last) {
+                if frame.len as u64 == rest && !frame.parts.is_empty() {
+                    return Ok(Some((frame, last)));
+                }
+            }
+        }
+        Ok(full.map(|frame| (frame, self.frame_size)))
+    }
+
+    /// The first position in `from..` that, trying those nearest
+    /// `expected` first, starts a frame with some clean output.  Beyond a
+    /// frame's length of `expected`, positions are tried in order of
+    /// distance on one side and then on the other, to read the payload in
+    /// order.
+    fn resync<F>(
+        &self,
+        window: &mut Window,
+        from: u64,
+        expected: u64,
+        size: u64,
+        decode: &F,
+    ) -> std::io::Result<Option<u64>>
+    where
+        F: Fn(&[u8], usize) -> Result<Frame, Option<usize>>,
+    {
+        let end = window.payload.len;
+        if from >= end {
+            return Ok(None);
+        }
+        let center = expected.clamp(from, end - 1);
+        let radius = self.max_frame_len as u64;
+        let near = (0..radius)
+            .flat_map(|d| [center.checked_add(d), center.checked_sub(d + 1)])
+            .flatten();
+        let after = center.saturating_add(radius)..end;
+        let before = (from..center.saturating_sub(radius).max(from)).rev();
+        let candidates = near
+            .chain(after)
+            .chain(before)
+            .filter(|candidate| (from..end).contains(candidate))
+            .take(self.resync_tries);
+        for candidate in candidates {
+            let found = self.find_frame(window, candidate, size, decode)?;
+            if found.is_some_and(|(frame, _)| !frame.parts.is_empty()) {
+                return Ok(Some(candidate));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Huffman payloads
+// ---------------------------------------------------------------------------
+
+/// `(output offset, payload bit)` at the start of each stretch between
+/// seek index checkpoints, and at the end of the payload.
+fn stretches(index: Option<&SeekIndex>, size: u64, payload_bits: u64) -> Vec<(u64, u64)> {
+    let mut marks = vec![(0, 0)];
+    for &(offset, bit) in index.map_or(&[][..], |index| &index.checkpoints) {
+        let &(last_offset, last_bit) = marks.last().unwrap();
+        if offset > last_offset && offset < size && bit >= last_bit && bit <= payload_bits {
+            marks.push((offset, bit));
+        }
+    }
+    marks.push((size, payload_bits));
+    marks
+}
+
+/// Salvages the Huffman stretch from `from` to `to`, both `(output offset,
+/// payload bit)`, into `sink`.
+fn salvage_stretch<W: Write>(
+    decoder: &HuffmanDecoder,
+    payload: &Payload,
+    from: (u64, u64),
+    to: (u64, u64),
+    sink: &mut RunWriter<W>,
+) -> std::io::Result<()> {
+    let len = to.0 - from.0;
+    // The stretch is written as it is decoded, and found damaged only once
+    // it fails to end where it should.
+    let first_written = sink.pos.max(from.0);
+    let first = decode_run(
+        decoder,
+        payload,
+        from.1,
+        to.1,
+        len,
+        &mut |offset, symbols| sink.write(from.0 + offset, symbols, Trust::Exact),
+    )?;
+    if !first.failed {
+        if first.count != len || first.end != to.1 {
+            sink.distrust(first_written);
+        }
+        return Ok(());
+    }
+
+    let mut prev = (from.0 + first.count, first.end);
+    while let Some(sync) = sync_point(decoder, payload, prev.1 + 1, to.1)? {
+        // Where the run belongs is only known once it is decoded, so it is
+        // decoded once to place it and again to write it.
+        let run = decode_run(decoder, payload, sync, to.1, len, &mut |_, _| Ok(()))?;
+        if !run.failed && run.end == to.1 {
+            let start = to.0.saturating_sub(run.count).max(from.0);
+            decode_run(decoder, payload, sync, to.1, len, &mut |offset, symbols| {
+                sink.write(start + offset, symbols, Trust::Exact)
+            })?;
+            return Ok(());
+        }
+        // Decoding stopped short of the end again, so where this run
+        // belongs can only be estimated from the bits skipped.
+        let skipped = (sync - prev.1) as u128 * len as u128 / (to.1 - from.1).max(1) as u128;
+        let start = prev.0.saturating_add(skipped as u64);
+        decode_run(decoder, payload, sync, to.1, len, &mut |offset, symbols| {
+            sink.write(start + offset, symbols, Trust::Estimated)
+        })?;
+        if !run.failed {
+            return Ok(());
+        }
+        prev = (start + run.count, run.end);
+    }
+    Ok(())
+}
+
+/// How far decoding one stretch of a Huffman payload got.
+struct Decoded {
+    /// Symbols decoded.
+    count: u64,
+    /// The bit after the last symbol.
+    end: u64,
+    /// Whether decoding stopped at data that is no valid code.
+    failed: bool,
+}
+
+/// Decodes from payload bit `start` until `max` symbols are decoded, bit
+/// `stop` is reached, or a symbol is invalid or crosses `stop`.  The
+/// symbols are passed to `emit` in pieces, along with their offset from the
+/// first symbol.
+fn decode_run(
+    decoder: &HuffmanDecoder,
+    payload: &Payload,
+    start: u64,
+    stop: u64,
+    max: u64,
+    emit: &mut dyn FnMut(u64, &[u8]) -> std::io::Result<()>,
+) -> std::io::Result<Decoded> {
+    let Some(mut reader) = payload.reader_at(start)? else {
+        return Ok(Decoded {
+            count: 0,
+            end: start,
+            failed: true,
+        });
+    };
+    let mut base = start & !7;
+    let mut pos = start;
+    let mut symbols = Vec::new();
+    let mut count = 0u64;
+
+    // Bulk decode while far from `stop`, then symbol by symbol.  A bulk
+    // decode that fails is redone symbol by symbol from where it began.
+    let mut bulk_ok = true;
+    let failed = loop {
+        let done = count + symbols.len() as u64;
+        if pos >= stop || done >= max {
+            break false;
+        }
+        if symbols.len() >= WRITE_CHUNK {
+            emit(count, &symbols)?;
+            count += symbols.len() as u64;
+            symbols.clear();
+        }
+        let bulk = ((stop - pos) / MAX_CODE_LENGTH as u64).min(max - done);
+        if bulk_ok && bulk >= BULK_MIN {
+            let at = symbols.len();
+            let bulk = bulk.min(1 << 16) as usize;
+            symbols.resize(at + bulk, 0);
+            if decoder.decode_into(&mut reader, &mut symbols[at..]).is_ok() {
+                pos = base + reader.bit_position();
+                continue;
+            }
+            symbols.truncate(at);
+            bulk_ok = false;
+            match payload.reader_at(pos)? {
+                Some(fresh) => (reader, base) = (fresh, pos & !7),
+                None => break true,
+            }
+        }
+        match decoder.decode_byte(&mut reader) {
+            Ok(Some(symbol)) => {
+                let next = base + reader.bit_position();
+                if next > stop {
+                    break true;
+                }
+                symbols.push(symbol);
+                pos = next;
+            }
+            _ => break true,
+        }
+    };
+    emit(count, &symbols)?;
+    Ok(Decoded {
+        count: count + symbols.len() as u64,
+        end: pos,
+        failed,
+    })
+}
+
+/// The first symbol boundary at or after `from` (and before `stop`) that
+/// decodes started at `from` and one bit later agree on, which is taken
+/// to be a true boundary.
+fn sync_point(
+    decoder: &HuffmanDecoder,
+    payload: &Payload,
+    mut from: u64,
+    stop: u64,
+) -> std::io::Result<Option<u64>> {
+    while from < stop {
+        let a = boundaries(decoder, payload, from, stop)?;
+        let b = boundaries(decoder, payload, from + 1, stop)?;
+        if let Some(&bit) = a.iter().find(|bit| b.binary_search(bit).is_ok()) {
+            return Ok(Some(bit));
+        }
+        from = a.last().map_or(from, |&last| last.max(from)) + 1;
+    }
+    Ok(None)
+}
+
+/// Symbol boundaries passed decoding from `start` (included) for up to
+/// [`SYNC_WINDOW_BITS`] bits, stopping at an invalid code or at `stop`.
+fn boundaries(
+    decoder: &HuffmanDecoder,
+    payload: &Payload,
+    start: u64,
+    stop: u64,
+) -> std::io::Result<Vec<u64>> {
+    let mut bits = Vec::new();
+    let Some(mut reader) = payload.reader_at(start)? else {
+        return Ok(bits);
+    };
+    let mut pos = start;
+    while pos < stop.min(start + SYNC_WINDOW_BITS) {
+        bits.push(pos);
+        match decoder.decode_byte(&mut reader) {
+            Ok(Some(_)) => pos = (start & !7) + reader.bit_position(),
+            _ => return Ok(bits),
+        }
+    }
+    if pos <= stop {
+        bits.push(pos);
+    }
+    Ok(bits)
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{Coder, EncoderOptions};
+    use crate::test_util::{compress_temp, TempDir};
+
+    /// Pseudo-random words, compressible but without long repeats.
+    fn sample(len: usize) -> Vec<u8> {
+        let words = [
+            &b"salvage "[..],
+            b"huffman ",
+            b"stream ",
+            b"block ",
+            b"chunk\n",
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut data = Vec::with_capacity(len + 8);
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.extend_from_slice(words[(state % words.len() as u64) as usize]);
+        }
+        data.truncate(len);
+        data
+    }
+
+    /// Compresses `data` and returns the compressed bytes and header size.
+    fn compressed(data: &[u8], options: &EncoderOptions) -> (Vec<u8>, u64) {
+        let (_dir, cmpr) = compress_temp("salvage", data, options);
+        let bytes = std::fs::read(&cmpr).unwrap();
+        let header = format::read_header(&mut &bytes[..]).unwrap();
+        (bytes, header.header_len())
+    }
+
+    /// Salvages `bytes` and returns the output and report.
+    fn run_salvage(bytes: &[u8]) -> (Vec<u8>, SalvageReport) {
+        try_salvage(bytes, &DecoderOptions::default()).unwrap()
+    }
+
+    fn try_salvage(
+        bytes: &[u8],
+        options: &DecoderOptions,
+    ) -> std::io::Result<(Vec<u8>, SalvageReport)> {
+        let dir = TempDir::new("salvage");
+        let path = dir.join("data.cmpr");
+        std::fs::write(&path, bytes).unwrap();
+        let mut out = Vec::new();
+        let report = salvage_to_writer(&path, &mut out, options)?;
+        assert_eq!(out.len() as u64, report.original_size);
+        Ok((out, report))
+    }
+
+    /// Asserts that `out` matches `data` outside the damaged ranges and
+    /// that some damage was found and placed exactly.
+    fn check_recovered(data: &[u8], out: &[u8], report: &SalvageReport) {
+        assert!(!report.damaged.is_empty());
+        assert!(report.misplaced.is_empty(), "{report:?}");
+        let end = ByteRange {
+            start: data.len() as u64,
+            len: 0,
+        };
+        let mut pos = 0;
+        for range in report.damaged.iter().chain([&end]) {
+            let start = range.start as usize;
+            assert_eq!(out[pos..start], data[pos..start], "{report:?}");
+            pos = start + range.len as usize;
+        }
+    }
+
+    /// Overwrites eight bytes at `at` with their complement.
+    fn smash(bytes: &mut [u8], at: usize) {
+        for byte in &mut bytes[at..at + 8] {
+            *byte = !*byte;
+        }
+    }
+
+    #[test]
+    fn intact_file_is_recovered_whole() {
+        let data = sample(100_000);
+        for coder in [Coder::Huffman, Coder::Huffman4, Coder::Ans] {
+            let options = EncoderOptions {
+                coder,
+                ..Default::default()
+            };
+            let (bytes, _) = compressed(&data, &options);
+            let (out, report) = run_salvage(&bytes);
+            assert_eq!(out, data);
+            assert!(report.is_intact(), "{report:?}");
+            assert_eq!(report.recovered_bytes(), data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn damage_is_confined_to_its_checkpoint_stretch() {
+        let data = sample(200_000);
+        let options = EncoderOptions {
+            coder: Coder::Huffman,
+            index_interval: Some(20_000),
+            ..Default::default()
+        };
+        let (mut bytes, header_len) = compressed(&data, &options);
+        let payload_len = bytes.len() - header_len as usize - 4;
+        smash(&mut bytes, header_len as usize + payload_len / 2);
+        let (out, report) = run_salvage(&bytes);
+        check_recovered(&data, &out, &report);
+        let [damaged] = report.damaged[..] else {
+            panic!("{report:?}");
+        };
+        assert_eq!(damaged.start % 20_000, 0);
+        assert_eq!(damaged.len, 20_000);
+        assert_eq!(report.crc_ok, Some(false));
+    }
+
+    #[test]
+    fn damaged_x4_stream_costs_part_of_a_block() {
+        let block = huffman::X4_BLOCK_SIZE as u64;
+        let data = sample(block as usize * 3);
+        let options = EncoderOptions {
+            coder: Coder::Huffman4,
+            ..Default::default()
+        };
+        let (mut bytes, header_len) = compressed(&data, &options);
+        let payload_len = bytes.len() - header_len as usize - 4;
+        smash(&mut bytes, header_len as usize + payload_len / 2);
+        let (out, report) = run_salvage(&bytes);
+        check_recovered(&data, &out, &report);
+        for range in &report.damaged {
+            assert!(range.start >= block && range.start + range.len <= 2 * block);
+        }
+        assert!(report.recovered_bytes() >= 3 * block - block / 2);
+    }
+
+    #[test]
+    fn ans_resynchronizes_after_a_damaged_chunk() {
+        let chunk = ans::CHUNK_SIZE as u64;
+        let data = sample(chunk as usize * 4 + 1000);
+        let options = EncoderOptions {
+            coder: Coder::Ans,
+            ..Default::default()
+        };
+        let (mut bytes, header_len) = compressed(&data, &options);
+        // A little way into the second chunk.
+        let payload_len = bytes.len() - header_len as usize - 4;
+        bytes[header_len as usize + payload_len * 3 / 10] ^= 0x01;
+        let (out, report) = run_salvage(&bytes);
+        check_recovered(&data, &out, &report);
+        assert_eq!(
+            report.damaged,
+            [ByteRange {
+                start: chunk,
+                len: chunk
+            }]
+        );
+    }
+
+    #[test]
+    fn truncated_file_keeps_its_prefix() {
+        // Several Huffman4 blocks, as a partial block is lost whole.
+        let data = sample(huffman::X4_BLOCK_SIZE * 3);
+        for coder in [Coder::Huffman, Coder::Huffman4, Coder::Ans] {
+            let options = EncoderOptions {
+                coder,
+                ..Default::default()
+            };
+            let (bytes, _) = compressed(&data, &options);
+            // Half a payload may be too short for the original size, which
+            // is then only believed up to an output limit.
+            let limited = DecoderOptions {
+                max_output_bytes: Some(data.len() as u64),
+                ..Default::default()
+            };
+            let (out, report) = try_salvage(&bytes[..bytes.len() / 2], &limited).unwrap();
+            let [damaged] = report.damaged[..] else {
+                panic!("{report:?}");
+            };
+            assert_eq!(damaged.start + damaged.len, data.len() as u64);
+            assert!(damaged.start > 0);
+            let kept = damaged.start as usize;
+            assert_eq!(out[..kept], data[..kept]);
+        }
+    }
+
+    #[test]
+    fn volumes_are_joined_first() {
+        let data = sample(20_000);
+        let dir = TempDir::new("salvage");
+        let input = dir.join("data.bin");
+        std::fs::write(&input, &data).unwrap();
+        let (_, paths) = volume::compress_to_volumes(
+            &input,
+            &dir.join("data.bin.cmpr"),
+            2000,
+            &EncoderOptions::default(),
+        )
+        .unwrap();
+        assert!(paths.len() > 2);
+        let mut out = Vec::new();
+        let report = salvage_to_writer(&paths[1], &mut out, &DecoderOptions::default()).unwrap();
+        assert_eq!(out, data);
+        assert!(report.damaged.is_empty());
+
+        std::fs::remove_file(&paths[2]).unwrap();
+        let err =
+            salvage_to_writer(&paths[0], &mut Vec::new(), &DecoderOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("is missing"), "{err}");
+    }
+
+    #[test]
+    fn implausible_size_needs_an_output_limit() {
+        let mut freqs = [0u64; 256];
+        freqs[b'A' as usize] = 1;
+        freqs[b'B' as usize] = 1;
+        let mut table = Vec::new();
+        HuffmanCoder::from_frequencies(&freqs)
+            .unwrap()
+            .write_table(&mut table);
+        let mut bytes = Vec::new();
+        format::write_method_header(&mut bytes, 1 << 40, format::METHOD_HUFFMAN, &table, 0)
+            .unwrap();
+        let payload = [0u8; 64];
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&format::crc32(&payload).to_le_bytes());
+
+        let err = try_salvage(&bytes, &DecoderOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("cannot be decoded"), "{err}");
+
+        let limited = DecoderOptions {
+            max_output_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let err = try_salvage(&bytes, &limited).unwrap_err();
+        assert!(err.to_string().contains("output limit"), "{err}");
+    }
+
+    #[test]
+    fn arbitrary_damage_never_panics() {
+        let data = sample(70_000);
+        for coder in [Coder::Huffman, Coder::Huffman4, Coder::Ans] {
+            let options = EncoderOptions {
+                coder,
+                index_interval: (coder == Coder::Huffman).then_some(10_000),
+                ..Default::default()
+            };
+            let (bytes, header_len) = compressed(&data, &options);
+            let mut state = 0x9e37_79b9_7f4a_7c15u64;
+            for _ in 0..8 {
+                let mut damaged = bytes.clone();
+                for _ in 0..8 {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    let span = damaged.len() - header_len as usize;
+                    let at = header_len as usize + (state >> 33) as usize % span;
+                    damaged[at] = (state >> 8) as u8;
+                }
+                run_salvage(&damaged);
+            }
+        }
+    }
+}
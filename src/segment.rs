@@ -0,0 +1,640 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::compress::{self, EncoderOptions};
+use crate::crypto::{self, MacKey, TempFile, MAC_SIZE};
+use crate::error::{CorruptionKind, DecodeError};
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// Appended segments
+// ---------------------------------------------------------------------------
+//
+// `--append` adds data to an existing `.cmpr` file without re-encoding what
+// is already there.  The new data is compressed into a complete `.cmpr` file
+// of its own, a segment, and written after the existing segments, followed
+// by a segment table that records where each one starts:
+//
+//   N x 8    offset of each segment, little-endian u64; the first is 0
+//   32       HMAC-SHA-256 tag of the table, if the segments have tags
+//   4        N, little-endian u32
+//   4        CRC-32 of the offsets, tag and N
+//   4        magic "CSEG"
+//
+// The first append leaves the original file at offset 0 and only sets
+// `FLAG_SEGMENTED` in its header, which tells decoders to look for the
+// table; the flag is cleared again before that segment is decoded, so its
+// header CRC, tag and encryption check as written.  Each append replaces
+// the table.  Decoders decode the segments in order, each exactly as a file
+// of its own, and write the concatenation.
+//
+// Tags are all or nothing: segments are appended to a file with tags only
+// with the key, and get tags too.  The table tag covers the offsets and the
+// tag ending each segment, so that segments cannot be dropped, reordered or
+// swapped for other tagged ones without the key.
+
+/// Magic bytes at the end of a segmented file.
+pub const SEGMENT_MAGIC: [u8; 4] = *b"CSEG";
+
+/// Size of the fixed part of the segment table after the offsets and tag.
+pub const SEGMENT_TRAILER_SIZE: u64 = 12;
+
+/// One segment of a segmented file: a complete `.cmpr` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Offset of the segment's header in the file.
+    pub offset: u64,
+    /// Length of the segment in bytes.
+    pub len: u64,
+}
+
+/// A segment table as read from a file.
+struct Table {
+    segments: Vec<Segment>,
+    tag: Option<[u8; MAC_SIZE as usize]>,
+}
+
+/// Reads the segment table of `reader`, and restores the reader's
+/// position.
+///
+/// Returns `None` unless the header flags appended segments, and an
+/// `InvalidData` error if the table is missing, damaged or does not match
+/// the file.
+pub fn read_segments<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Vec<Segment>>> {
+    let position = reader.stream_position()?;
+    let table = read_table(reader);
+    reader.seek(SeekFrom::Start(position))?;
+    Ok(table?.map(|table| table.segments))
+}
+
+/// Parses the segment table of `reader`, if its header flags one.
+fn read_table<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Table>> {
+    reader.seek(SeekFrom::Start(0))?;
+    // A file whose header cannot be read is left for the caller to report.
+    let Ok(header) = format::read_header(reader) else {
+        return Ok(None);
+    };
+    if header.flags & format::FLAG_SEGMENTED == 0 {
+        return Ok(None);
+    }
+    let tag_len = if header.flags & format::FLAG_HMAC != 0 {
+        MAC_SIZE
+    } else {
+        0
+    };
+
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut trailer = [0u8; SEGMENT_TRAILER_SIZE as usize];
+    let trailer_pos = match file_len.checked_sub(SEGMENT_TRAILER_SIZE) {
+        Some(trailer_pos) => {
+            reader.seek(SeekFrom::Start(trailer_pos))?;
+            reader.read_exact(&mut trailer)?;
+            trailer_pos
+        }
+        None => 0,
+    };
+    if trailer[8..] != SEGMENT_MAGIC {
+        return Err(invalid(
+            "Header flags appended segments, but the segment table is missing",
+        ));
+    }
+    let count = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+    let table_start = match trailer_pos.checked_sub(count * 8 + tag_len) {
+        Some(start) if count >= 2 => start,
+        _ => return Err(invalid("Segment table is damaged")),
+    };
+    reader.seek(SeekFrom::Start(table_start))?;
+    let mut table = vec![0u8; (count * 8 + tag_len) as usize];
+    reader.read_exact(&mut table)?;
+    table.extend_from_slice(&trailer[..4]);
+    let stored_crc = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+    if format::crc32(&table) != stored_crc {
+        return Err(invalid("Segment table is damaged"));
+    }
+
+    let (offsets, tag) = table[..table.len() - 4].split_at((count * 8) as usize);
+    let offsets: Vec<u64> = offsets
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    let ends = offsets[1..].iter().chain([&table_start]);
+    if offsets[0] != 0 || offsets.iter().zip(ends).any(|(start, end)| start >= end) {
+        return Err(invalid("Segment table does not match the file"));
+    }
+    Ok(Some(Table {
+        segments: segments_of(&offsets, table_start),
+        tag: tag.try_into().ok(),
+    }))
+}
+
+/// The segments starting at `offsets`, the last of which ends at `end`.
+fn segments_of(offsets: &[u64], end: u64) -> Vec<Segment> {
+    offsets
+        .iter()
+        .zip(offsets[1..].iter().chain([&end]))
+        .map(|(&offset, &next)| Segment {
+            offset,
+            len: next - offset,
+        })
+        .collect()
+}
+
+/// Writes a segment table for segments starting at `offsets`, with `tag`
+/// if the segments have tags.
+fn write_table<W: Write>(
+    writer: &mut W,
+    offsets: &[u64],
+    tag: Option<&[u8]>,
+) -> std::io::Result<()> {
+    let mut table: Vec<u8> = offsets.iter().flat_map(|o| o.to_le_bytes()).collect();
+    table.extend_from_slice(tag.unwrap_or_default());
+    table.extend_from_slice(&(offsets.len() as u32).to_le_bytes());
+    let crc = format::crc32(&table);
+    writer.write_all(&table)?;
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.write_all(&SEGMENT_MAGIC)
+}
+
+/// HMAC-SHA-256 under `key` of the table for `segments` of `reader`: of
+/// the offsets and count, and of the tag ending each segment.
+fn table_mac<R: Read + Seek>(
+    reader: &mut R,
+    segments: &[Segment],
+    key: &MacKey,
+) -> std::io::Result<[u8; MAC_SIZE as usize]> {
+    let mut mac = key.hmac();
+    mac.update(&SEGMENT_MAGIC);
+    for segment in segments {
+        mac.update(&segment.offset.to_le_bytes());
+    }
+    mac.update(&(segments.len() as u32).to_le_bytes());
+    let mut tag = [0u8; MAC_SIZE as usize];
+    for segment in segments {
+        // Too short to have a tag; its own check refuses it.
+        let Some(len) = segment.len.checked_sub(MAC_SIZE) else {
+            continue;
+        };
+        reader.seek(SeekFrom::Start(segment.offset + len))?;
+        reader.read_exact(&mut tag)?;
+        mac.update(&tag);
+    }
+    Ok(mac.finalize())
+}
+
+/// Checks the segment table of `reader` against `key`: with a key, a
+/// segmented file needs a table tag that matches.  Without one nothing is
+/// checked here, as segments with tags then refuse to decode themselves.
+pub fn check_mac<R: Read + Seek>(reader: &mut R, key: Option<&MacKey>) -> std::io::Result<()> {
+    let (Some(key), Some(table)) = (key, read_table(reader)?) else {
+        return Ok(());
+    };
+    let Some(stored) = table.tag else {
+        let msg = "Segment table has no HMAC-SHA-256 tag to verify";
+        return Err(DecodeError::new(CorruptionKind::AuthenticationFailed, msg).into());
+    };
+    let tag = table_mac(reader, &table.segments, key)?;
+    if !crypto::tags_equal(&tag, &stored) {
+        let msg = "Segment table HMAC-SHA-256 mismatch: wrong key, or segments were dropped, \
+                   reordered or replaced";
+        return Err(DecodeError::new(CorruptionKind::AuthenticationFailed, msg).into());
+    }
+    Ok(())
+}
+
+/// Whether `path` has appended segments.
+pub fn is_segmented(path: &Path) -> std::io::Result<bool> {
+    Ok(read_segments(&mut File::open(path)?)?.is_some())
+}
+
+/// Compresses `input_path` with `options` into a new segment at the end of
+/// `cmpr_path`, which must be a `.cmpr` file.  The existing segments are not
+/// re-encoded.  Returns the original size of the new data, the size of the
+/// new segment and the number of segments in the file.
+///
+/// Parity sections cannot be used: they would protect one segment in a file
+/// that `--repair` sees as a whole.  A file with HMAC-SHA-256 tags needs
+/// the key in `options`, and one without cannot be given one; the same goes
+/// for encryption.  If writing fails, the file is put back as it was.
+pub fn append(
+    cmpr_path: &Path,
+    input_path: &Path,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, u64, usize)> {
+    let mut file = OpenOptions::new().read(true).write(true).open(cmpr_path)?;
+    let header = format::read_header(&mut file)?;
+    let refuse = |msg: String| Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+    if options.parity || header.flags & format::FLAG_PARITY != 0 {
+        return refuse("Cannot append to a file with a parity section".to_string());
+    }
+    if header.version <= format::VERSION_HUFFMAN_ONLY {
+        return refuse(format!(
+            "Cannot append to a version {:#04x} file, which has no header flags",
+            header.version
+        ));
+    }
+    match (header.flags & format::FLAG_HMAC != 0, &options.mac_key) {
+        (true, None) => {
+            return refuse("File has HMAC-SHA-256 tags; a key is needed to append to it".into())
+        }
+        (false, Some(_)) => {
+            return refuse(
+                "File has no HMAC-SHA-256 tags, so appended segments cannot either".into(),
+            )
+        }
+        _ => {}
+    }
+    match (header.method == format::METHOD_ENCRYPTED, &options.encrypt) {
+        (true, None) => return refuse("File is encrypted; a key is needed to append to it".into()),
+        (false, Some(_)) => {
+            return refuse("File is not encrypted, so appended segments cannot be either".into())
+        }
+        _ => {}
+    }
+    let existing = read_segments(&mut file)?;
+    let (mut offsets, end) = match &existing {
+        Some(segments) => {
+            // A table that does not check would be vouched for by its new tag.
+            check_mac(&mut file, options.mac_key.as_ref())?;
+            let last = segments[segments.len() - 1];
+            (
+                segments.iter().map(|s| s.offset).collect(),
+                last.offset + last.len,
+            )
+        }
+        None => (vec![0], file.metadata()?.len()),
+    };
+    let mut old_table = Vec::new();
+    file.seek(SeekFrom::Start(end))?;
+    file.read_to_end(&mut old_table)?;
+
+    let (segment, _) = TempFile::create()?;
+    let (original_size, segment_len) =
+        compress::compress_with_options(input_path, segment.path(), options)?;
+    offsets.push(end);
+
+    let first = existing.is_none();
+    let result = write_segment(&mut file, end, segment.path(), &offsets, options, first);
+    if let Err(e) = result {
+        // Put the old table back over whatever part of the segment was
+        // written, and the header as it was.
+        let _ = file
+            .set_len(end)
+            .and_then(|()| file.seek(SeekFrom::Start(end)))
+            .and_then(|_| file.write_all(&old_table))
+            .and_then(|()| {
+                if first {
+                    mark_segmented(&mut file, false)
+                } else {
+                    Ok(())
+                }
+            });
+        return Err(e);
+    }
+    Ok((original_size, segment_len, offsets.len()))
+}
+
+/// Writes the segment at `segment_path` to `file` at `end`, followed by the
+/// table for `offsets`, tagged with the key in `options` if any.  For the
+/// `first` append, flags the header.  Syncs the file.
+fn write_segment(
+    file: &mut File,
+    end: u64,
+    segment_path: &Path,
+    offsets: &[u64],
+    options: &EncoderOptions,
+    first: bool,
+) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(end))?;
+    let mut writer = BufWriter::new(&mut *file);
+    std::io::copy(&mut File::open(segment_path)?, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    let table_start = file.stream_position()?;
+    let tag = match &options.mac_key {
+        Some(key) => Some(table_mac(file, &segments_of(offsets, table_start), key)?),
+        None => None,
+    };
+    file.seek(SeekFrom::Start(table_start))?;
+    let mut writer = BufWriter::new(&mut *file);
+    write_table(&mut writer, offsets, tag.as_ref().map(|tag| &tag[..]))?;
+    writer.flush()?;
+    drop(writer);
+    let len = file.stream_position()?;
+    file.set_len(len)?;
+    if first {
+        mark_segmented(file, true)?;
+    }
+    file.sync_all()
+}
+
+/// Sets or clears [`FLAG_SEGMENTED`](format::FLAG_SEGMENTED) in the header
+/// at the start of `file`, updating its header CRC.  The header is
+/// rewritten in one write.
+fn mark_segmented(file: &mut File, segmented: bool) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let header = format::read_header(file)?;
+    let mut bytes = vec![0u8; header.header_len() as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut bytes)?;
+    let flags = &mut bytes[format::FLAGS_OFFSET as usize];
+    if segmented {
+        *flags |= format::FLAG_SEGMENTED;
+    } else {
+        *flags &= !format::FLAG_SEGMENTED;
+    }
+    if header.version >= format::VERSION_HEADER_CRC {
+        let body = bytes.len() - format::CRC_SIZE as usize;
+        let crc = format::crc32(&bytes[..body]);
+        bytes[body..].copy_from_slice(&crc.to_le_bytes());
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&bytes)
+}
+
+/// Copies `segment` of `path` to a temporary file and runs `f` on it.  The
+/// first segment's header is put back as it was before the first append.
+pub(crate) fn with_segment<T>(
+    path: &Path,
+    segment: &Segment,
+    f: impl FnOnce(&Path) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let copy = TempFile::copy_of(path, segment.offset, segment.len)?;
+    if segment.offset == 0 {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(copy.path())?;
+        mark_segmented(&mut file, false)?;
+    }
+    f(copy.path())
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Secret;
+    use crate::decompress::{self, DecoderOptions};
+    use crate::test_util::TempDir;
+    use std::path::PathBuf;
+
+    fn log_lines(from: u32, to: u32) -> Vec<u8> {
+        (from..to)
+            .flat_map(|i| format!("{i:08} GET /index.html 200\n").into_bytes())
+            .collect()
+    }
+
+    /// Compresses `log_lines(0, 500)` and appends two more batches of lines
+    /// to it, the second with a different coder, all tagged with `mac_key`
+    /// if given.
+    fn grown_log(dir: &Path, mac_key: Option<MacKey>) -> PathBuf {
+        let cmpr = dir.join("log.cmpr");
+        let first = dir.join("first.log");
+        std::fs::write(&first, log_lines(0, 500)).unwrap();
+        let options = EncoderOptions {
+            mac_key,
+            ..EncoderOptions::default()
+        };
+        compress::compress_with_options(&first, &cmpr, &options).unwrap();
+        let plain = std::fs::read(&cmpr).unwrap();
+
+        let more = dir.join("more.log");
+        std::fs::write(&more, log_lines(500, 800)).unwrap();
+        let (original_size, _, count) = append(&cmpr, &more, &options).unwrap();
+        assert_eq!((original_size, count), (300 * 29, 2));
+        // The original file is left as it was but for a header flag, which
+        // is cleared again to decode it.
+        let grown = std::fs::read(&cmpr).unwrap();
+        let flags = format::FLAGS_OFFSET as usize;
+        assert_eq!(grown[flags], plain[flags] | format::FLAG_SEGMENTED);
+        let header_len = format::read_header(&mut &plain[..]).unwrap().header_len() as usize;
+        assert_eq!(grown[header_len..plain.len()], plain[header_len..]);
+        let first_segment = Segment {
+            offset: 0,
+            len: plain.len() as u64,
+        };
+        let restored = with_segment(&cmpr, &first_segment, |path| std::fs::read(path)).unwrap();
+        assert_eq!(restored, plain);
+
+        std::fs::write(&more, log_lines(800, 1000)).unwrap();
+        let options = EncoderOptions {
+            coder: compress::Coder::Ans,
+            ..options
+        };
+        let (_, segment_len, count) = append(&cmpr, &more, &options).unwrap();
+        assert_eq!(count, 3);
+        let segments = read_segments(&mut File::open(&cmpr).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].offset, 0);
+        assert_eq!(segments[2].len, segment_len);
+        cmpr
+    }
+
+    #[test]
+    fn appended_segments_decode_to_the_concatenation() {
+        let dir = TempDir::new("segment");
+        let cmpr = grown_log(&dir, None);
+        let options = DecoderOptions::default();
+        let out = dir.join("out.log");
+        decompress::decompress_with_options(&cmpr, &out, &options).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), log_lines(0, 1000));
+        decompress::verify(&cmpr, &options).unwrap();
+
+        // Caps on the output apply to the whole file.
+        let capped = DecoderOptions {
+            max_output_bytes: Some(900 * 29),
+            ..DecoderOptions::default()
+        };
+        assert!(decompress::verify(&cmpr, &capped).is_err());
+    }
+
+    #[test]
+    fn damaged_tables_and_segments_are_refused() {
+        let dir = TempDir::new("segment");
+        let cmpr = grown_log(&dir, None);
+        let good = std::fs::read(&cmpr).unwrap();
+        let options = DecoderOptions::default();
+
+        let mut damaged = good.clone();
+        let at = damaged.len() - SEGMENT_TRAILER_SIZE as usize - 4;
+        damaged[at] ^= 1;
+        std::fs::write(&cmpr, &damaged).unwrap();
+        let err = decompress::verify(&cmpr, &options).unwrap_err();
+        assert!(err.to_string().contains("Segment table is damaged"));
+
+        // A flipped bit in the last segment's payload fails its CRC-32.
+        let segments = read_segments(&mut std::io::Cursor::new(&good))
+            .unwrap()
+            .unwrap();
+        let mut damaged = good.clone();
+        damaged[(segments[2].offset + segments[2].len / 2) as usize] ^= 0x10;
+        std::fs::write(&cmpr, &damaged).unwrap();
+        let err = decompress::verify(&cmpr, &options).unwrap_err();
+        // Its location is reported in the whole file.
+        let decode = DecodeError::from_io(&err).unwrap();
+        assert!(decode.file_offset.unwrap() > segments[2].offset);
+
+        // Random access and sidecar indexes do not know about segments.
+        std::fs::write(&cmpr, &good).unwrap();
+        let err = decompress::decompress_range(&cmpr, 0, 10).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn table_is_only_read_when_the_header_flags_it() {
+        let dir = TempDir::new("segment");
+        let input = dir.join("data.log");
+        std::fs::write(&input, log_lines(0, 100)).unwrap();
+        let cmpr = dir.join("data.cmpr");
+        compress::compress(&input, &cmpr).unwrap();
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        let len = bytes.len() as u64;
+        write_table(&mut bytes, &[0, len / 2], None).unwrap();
+        std::fs::write(&cmpr, &bytes).unwrap();
+        assert!(!is_segmented(&cmpr).unwrap());
+
+        // A flagged file must end in a table.
+        let cmpr = grown_log(&dir, None);
+        let mut bytes = std::fs::read(&cmpr).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        std::fs::write(&cmpr, &bytes).unwrap();
+        let err = is_segmented(&cmpr).unwrap_err();
+        assert!(
+            err.to_string().contains("segment table is missing"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn table_tag_covers_the_segments_and_their_order() {
+        let dir = TempDir::new("segment");
+        let key = MacKey::new(vec![7; 32]).unwrap();
+        let cmpr = grown_log(&dir, Some(key.clone()));
+        let good = std::fs::read(&cmpr).unwrap();
+        let keyed = DecoderOptions {
+            mac_key: Some(key),
+            ..DecoderOptions::default()
+        };
+        decompress::verify(&cmpr, &keyed).unwrap();
+        assert!(decompress::verify(&cmpr, &DecoderOptions::default()).is_err());
+
+        let segments = read_segments(&mut std::io::Cursor::new(&good))
+            .unwrap()
+            .unwrap();
+        let bytes_of = |i: usize| {
+            let Segment { offset, len } = segments[i];
+            &good[offset as usize..(offset + len) as usize]
+        };
+        let table_start = (segments[2].offset + segments[2].len) as usize;
+        let tag = &good[table_start + 3 * 8..table_start + 3 * 8 + MAC_SIZE as usize];
+        // Rebuilds the file from segments `order`, with the original tag.
+        let forge = |order: &[usize]| {
+            let mut bytes = Vec::new();
+            let mut offsets = Vec::new();
+            for &i in order {
+                offsets.push(bytes.len() as u64);
+                bytes.extend_from_slice(bytes_of(i));
+            }
+            write_table(&mut bytes, &offsets, Some(tag)).unwrap();
+            std::fs::write(&cmpr, &bytes).unwrap();
+            let err = decompress::verify(&cmpr, &keyed).unwrap_err();
+            DecodeError::from_io(&err).unwrap().kind
+        };
+        assert_eq!(forge(&[0, 1]), CorruptionKind::AuthenticationFailed);
+        assert_eq!(forge(&[0, 2, 1]), CorruptionKind::AuthenticationFailed);
+        assert_eq!(forge(&[0, 1, 1]), CorruptionKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn tags_are_all_or_nothing() {
+        let dir = TempDir::new("segment");
+        let input = dir.join("data.log");
+        std::fs::write(&input, log_lines(0, 10)).unwrap();
+        let key = MacKey::new(vec![7; 32]).unwrap();
+        let keyed = EncoderOptions {
+            mac_key: Some(key),
+            ..EncoderOptions::default()
+        };
+        for (first, then) in [
+            (&EncoderOptions::default(), &keyed),
+            (&keyed, &EncoderOptions::default()),
+        ] {
+            let cmpr = dir.join("data.cmpr");
+            compress::compress_with_options(&input, &cmpr, first).unwrap();
+            let before = std::fs::read(&cmpr).unwrap();
+            let err = append(&cmpr, &input, then).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(std::fs::read(&cmpr).unwrap(), before);
+        }
+    }
+
+    #[test]
+    fn encryption_is_all_or_nothing() {
+        let dir = TempDir::new("segment");
+        let input = dir.join("data.log");
+        std::fs::write(&input, log_lines(0, 10)).unwrap();
+        let secret = Secret::KeyFile(vec![7; 32]);
+        let sealed = EncoderOptions {
+            encrypt: Some(secret.clone()),
+            ..EncoderOptions::default()
+        };
+        for (first, then) in [
+            (&EncoderOptions::default(), &sealed),
+            (&sealed, &EncoderOptions::default()),
+        ] {
+            let cmpr = dir.join("data.cmpr");
+            compress::compress_with_options(&input, &cmpr, first).unwrap();
+            let before = std::fs::read(&cmpr).unwrap();
+            let err = append(&cmpr, &input, then).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(std::fs::read(&cmpr).unwrap(), before);
+        }
+
+        // With the key, the new segment is sealed too.
+        let cmpr = dir.join("data.cmpr");
+        append(&cmpr, &input, &sealed).unwrap();
+        let segments = read_segments(&mut File::open(&cmpr).unwrap())
+            .unwrap()
+            .unwrap();
+        let mut file = File::open(&cmpr).unwrap();
+        file.seek(SeekFrom::Start(segments[1].offset)).unwrap();
+        let header = format::read_header(&mut file).unwrap();
+        assert_eq!(header.method, format::METHOD_ENCRYPTED);
+        let out = dir.join("out.log");
+        let options = DecoderOptions {
+            secret: Some(secret),
+            ..DecoderOptions::default()
+        };
+        decompress::decompress_with_options(&cmpr, &out, &options).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), log_lines(0, 10).repeat(2));
+    }
+
+    #[test]
+    fn append_refuses_parity_and_non_cmpr_files() {
+        let dir = TempDir::new("segment");
+        let input = dir.join("data.log");
+        std::fs::write(&input, log_lines(0, 10)).unwrap();
+        let not_cmpr = dir.join("data.txt");
+        std::fs::write(&not_cmpr, log_lines(0, 10)).unwrap();
+        assert!(append(&not_cmpr, &input, &EncoderOptions::default()).is_err());
+        assert_eq!(std::fs::read(&not_cmpr).unwrap(), log_lines(0, 10));
+
+        let cmpr = dir.join("data.cmpr");
+        compress::compress(&input, &cmpr).unwrap();
+        let before = std::fs::read(&cmpr).unwrap();
+        let options = EncoderOptions {
+            parity: true,
+            ..EncoderOptions::default()
+        };
+        let err = append(&cmpr, &input, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(std::fs::read(&cmpr).unwrap(), before);
+    }
+}
@@ -0,0 +1,447 @@
+use std::io::{BufReader, Read, Write};
+
+use crate::bitio::BitReader;
+use crate::decompress;
+use crate::error::{self, CorruptionKind, DecodeError};
+use crate::huffman::{HuffmanDecoder, MAX_CODE_LENGTH};
+
+// ---------------------------------------------------------------------------
+// Speculative parallel decoding of a single Huffman bitstream
+// ---------------------------------------------------------------------------
+//
+// A plain Huffman payload has no block boundaries or checkpoints, but
+// canonical codes usually self-synchronize: a decoder started at an
+// arbitrary bit lands on the true symbol boundaries again within a few dozen
+// bits.  The payload is therefore cut into byte-aligned segments and every
+// segment is decoded on its own thread from its first bit, recording the
+// symbol boundaries it passes in its first `window` bits.
+//
+// Segments are decoded `threads` at a time and stitched in order as soon as
+// their round is done, so only a round's output is held in memory, however
+// long the payload.  The decode of segment i - 1
+// ends on a true boundary at or just past the start of segment i; from
+// there the true decode is walked symbol by symbol until it reaches a
+// boundary segment i also passed.  Everything segment i decoded from that
+// boundary on is correct.  If the two never meet inside the window, the
+// rest of the segment is decoded again sequentially, so the result is
+// always exact -- only the speed-up is speculative.
+
+/// Bits at the start of each segment within which its decode is expected
+/// to synchronize with the true symbol boundaries.
+pub const SYNC_WINDOW_BITS: u64 = 4096;
+
+/// Smallest payload segment worth a thread of its own.
+pub const MIN_SEGMENT_BYTES: u64 = 64 * 1024;
+
+/// Largest payload segment, which bounds the output a thread holds before
+/// it is stitched.
+pub const MAX_SEGMENT_BYTES: u64 = 1024 * 1024;
+
+/// Below this many symbols the span decoder goes symbol by symbol.
+const BULK_MIN: usize = 64;
+
+/// How a speculative decode went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncStats {
+    /// Number of segments the payload was cut into.
+    pub segments: usize,
+    /// Segments after the first whose speculative decode synchronized and
+    /// was used; the others were decoded again.
+    pub synced: usize,
+}
+
+/// Decodes a `payload_bits`-bit Huffman payload holding `len` symbols on up
+/// to `threads` threads and writes the symbols to `writer`.
+///
+/// `open_at(n)` must return a reader positioned at payload byte `n`; every
+/// thread opens its own.  The payload is cut into segments of at most
+/// [`MAX_SEGMENT_BYTES`], decoded `threads` at a time, and each round is
+/// written out before the next starts, so memory use grows with `threads`
+/// and not with `len`.
+///
+/// Returns an `InvalidData` error if the bitstream does not decode to
+/// exactly `len` symbols.
+pub fn decode_parallel<R, F, W>(
+    decoder: &HuffmanDecoder,
+    open_at: F,
+    payload_bits: u64,
+    len: u64,
+    threads: usize,
+    writer: &mut W,
+) -> std::io::Result<SyncStats>
+where
+    R: Read,
+    F: Fn(u64) -> std::io::Result<R> + Sync,
+    W: Write,
+{
+    let threads = threads.max(1);
+    let payload_bytes = payload_bits / 8;
+    let segments = (payload_bytes / MIN_SEGMENT_BYTES)
+        .clamp(1, threads as u64)
+        .max(payload_bytes.div_ceil(MAX_SEGMENT_BYTES));
+    let starts: Vec<u64> = (0..segments)
+        .map(|i| i * payload_bytes / segments * 8)
+        .collect();
+    decode_segments(
+        decoder,
+        &open_at,
+        &starts,
+        payload_bits,
+        len,
+        SYNC_WINDOW_BITS,
+        threads,
+        writer,
+    )
+}
+
+/// Symbols decoded from one segment.
+struct Span {
+    out: Vec<u8>,
+    /// `(bit position, symbols before it)` for every symbol boundary in the
+    /// sync window.
+    boundaries: Vec<(u64, usize)>,
+    /// The first symbol boundary at or past the end of the segment.
+    end: u64,
+}
+
+/// Decodes speculatively from each of `starts` (the first must be 0),
+/// `threads` segments at a time, and stitches each round of segments.
+#[allow(clippy::too_many_arguments)]
+fn decode_segments<R, F, W>(
+    decoder: &HuffmanDecoder,
+    open_at: &F,
+    starts: &[u64],
+    payload_bits: u64,
+    len: u64,
+    window: u64,
+    threads: usize,
+    writer: &mut W,
+) -> std::io::Result<SyncStats>
+where
+    R: Read,
+    F: Fn(u64) -> std::io::Result<R> + Sync,
+    W: Write,
+{
+    debug_assert_eq!(starts.first(), Some(&0));
+    let stops: Vec<u64> = starts[1..].iter().copied().chain([payload_bits]).collect();
+
+    let mut stats = SyncStats {
+        segments: starts.len(),
+        synced: 0,
+    };
+    let mut out = CheckedWriter {
+        inner: writer,
+        left: len,
+    };
+    let mut pos = 0;
+    for (round, round_stops) in starts.chunks(threads).zip(stops.chunks(threads)) {
+        let spans = decode_round(decoder, open_at, round, round_stops, window);
+        for ((spec, &start), &stop) in spans.into_iter().zip(round).zip(round_stops) {
+            // The first segment starts on a true boundary, so it is never
+            // wrong.
+            if start == 0 {
+                let first = spec?;
+                out.write(&first.out)?;
+                pos = first.end;
+                continue;
+            }
+            let spec = spec.ok();
+            let (mut reader, base) = reader_at(open_at, pos)?;
+            let mut patch = Vec::new();
+            let synced = loop {
+                if pos >= stop {
+                    break None;
+                }
+                let hit = spec.as_ref().and_then(|spec| {
+                    let i = spec.boundaries.binary_search_by_key(&pos, |&(bit, _)| bit);
+                    i.ok().map(|i| spec.boundaries[i].1)
+                });
+                if hit.is_some() || pos >= start + window {
+                    break hit;
+                }
+                let symbol = decoder
+                    .decode_byte(&mut reader)
+                    .and_then(|symbol| symbol.ok_or_else(unexpected_eof))
+                    .map_err(|e| error::locate(e, pos, len - out.left + patch.len() as u64))?;
+                patch.push(symbol);
+                pos = base + reader.bit_position();
+            };
+            out.write(&patch)?;
+
+            match (synced, spec) {
+                (Some(skip), Some(spec)) => {
+                    out.write(&spec.out[skip..])?;
+                    pos = spec.end;
+                    stats.synced += 1;
+                }
+                _ if pos < stop => {
+                    let span = decode_span(decoder, &mut reader, base, stop, 0)
+                        .map_err(|e| error::shift(e, 0, len - out.left))?;
+                    out.write(&span.out)?;
+                    pos = span.end;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if out.left != 0 || pos != payload_bits {
+        let msg = "Huffman bitstream does not match the original size";
+        return Err(DecodeError::new(CorruptionKind::InvalidLength, msg)
+            .at_bit(pos)
+            .after_output(len - out.left)
+            .into());
+    }
+    Ok(stats)
+}
+
+/// Decodes the segments from each of `starts` to the matching `stops` on a
+/// thread each.  A thread that panicked counts as a failed decode.
+fn decode_round<R, F>(
+    decoder: &HuffmanDecoder,
+    open_at: &F,
+    starts: &[u64],
+    stops: &[u64],
+    window: u64,
+) -> Vec<std::io::Result<Span>>
+where
+    R: Read,
+    F: Fn(u64) -> std::io::Result<R> + Sync,
+{
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = starts
+            .iter()
+            .zip(stops)
+            .map(|(&start, &stop)| {
+                scope.spawn(move || {
+                    let (mut reader, base) = reader_at(open_at, start)?;
+                    decode_span(decoder, &mut reader, base, stop, start + window)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|_| Err(decompress::worker_panicked()))
+            })
+            .collect()
+    })
+}
+
+/// Decodes from the reader's position (`base` plus the bits it has
+/// consumed) up to the first symbol boundary at or past `stop`, recording
+/// boundaries before `window_end`.  Errors are located relative to the
+/// start of the span's output.
+fn decode_span<R: Read>(
+    decoder: &HuffmanDecoder,
+    reader: &mut BitReader<BufReader<R>>,
+    base: u64,
+    stop: u64,
+    window_end: u64,
+) -> std::io::Result<Span> {
+    let mut out = Vec::new();
+    let mut boundaries = Vec::new();
+    let mut pos = base + reader.bit_position();
+
+    while pos < stop {
+        // Each symbol takes at most MAX_CODE_LENGTH bits, so a bulk decode
+        // of this many symbols cannot run past `stop`.
+        let bulk = if pos < window_end {
+            boundaries.push((pos, out.len()));
+            0
+        } else {
+            ((stop - pos) / MAX_CODE_LENGTH as u64) as usize
+        };
+        if bulk >= BULK_MIN {
+            let at = out.len();
+            out.resize(at + bulk, 0);
+            decoder
+                .decode_into(reader, &mut out[at..])
+                .map_err(|e| error::shift(e, base, at as u64))?;
+        } else {
+            let symbol = decoder
+                .decode_byte(reader)
+                .and_then(|symbol| symbol.ok_or_else(unexpected_eof))
+                .map_err(|e| error::locate(e, pos, out.len() as u64))?;
+            out.push(symbol);
+        }
+        pos = base + reader.bit_position();
+    }
+    Ok(Span {
+        out,
+        boundaries,
+        end: pos,
+    })
+}
+
+/// A bit reader positioned at payload bit `bit`, and the bit position its
+/// own count is relative to.
+fn reader_at<R: Read, F: Fn(u64) -> std::io::Result<R>>(
+    open_at: &F,
+    bit: u64,
+) -> std::io::Result<(BitReader<BufReader<R>>, u64)> {
+    let mut reader = BitReader::new(open_at(bit / 8)?);
+    if reader.read_bits((bit % 8) as u8)?.is_none() {
+        return Err(unexpected_eof());
+    }
+    Ok((reader, bit & !7))
+}
+
+/// Writes stitched output, refusing to go past the original size.
+struct CheckedWriter<'a, W: Write> {
+    inner: &'a mut W,
+    left: u64,
+}
+
+impl<W: Write> CheckedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.left = self.left.checked_sub(buf.len() as u64).ok_or_else(|| {
+            DecodeError::new(
+                CorruptionKind::InvalidLength,
+                "Huffman bitstream decodes to more than the original size",
+            )
+        })?;
+        self.inner.write_all(buf)
+    }
+}
+
+fn unexpected_eof() -> std::io::Error {
+    DecodeError::new(
+        CorruptionKind::Truncated,
+        "Unexpected end of compressed bitstream",
+    )
+    .into()
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coder::EntropyCoder;
+    use crate::huffman::HuffmanCoder;
+
+    /// Pseudo-random text with a skewed alphabet, its decoder, payload and
+    /// payload length in bits.
+    fn sample(len: usize) -> (Vec<u8>, HuffmanDecoder, Vec<u8>, u64) {
+        let data: Vec<u8> = (0..len as u32)
+            .map(|i| b"eeeetttaaoinshrd lu\n"[(i.wrapping_mul(2654435761) >> 27) as usize % 20])
+            .collect();
+        let mut freqs = [0u64; 256];
+        for &b in &data {
+            freqs[b as usize] += 1;
+        }
+        let coder = HuffmanCoder::from_frequencies(&freqs).unwrap();
+        let mut payload = Vec::new();
+        let padding = coder.encode(&mut &data[..], &mut payload).unwrap();
+        let decoder = HuffmanDecoder::new(&coder.code_table().symbol_table()).unwrap();
+        let bits = payload.len() as u64 * 8 - padding as u64;
+        (data, decoder, payload, bits)
+    }
+
+    fn run(starts: &[u64], window: u64, threads: usize) -> SyncStats {
+        let (data, decoder, payload, bits) = sample(40_000);
+        let open_at = |byte: u64| Ok(&payload[byte as usize..]);
+        let mut out = Vec::new();
+        let stats = decode_segments(
+            &decoder,
+            &open_at,
+            starts,
+            bits,
+            data.len() as u64,
+            window,
+            threads,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, data);
+        stats
+    }
+
+    #[test]
+    fn segments_synchronize() {
+        // Starts need not be byte-aligned, and segments may be tiny.
+        let stats = run(&[0, 8000, 80_001, 80_040, 120_000], SYNC_WINDOW_BITS, 5);
+        assert_eq!(stats.segments, 5);
+        assert_eq!(stats.synced, 4);
+    }
+
+    #[test]
+    fn segments_are_stitched_round_by_round() {
+        for threads in [1, 2, 3] {
+            let stats = run(
+                &[0, 8000, 80_001, 80_040, 120_000],
+                SYNC_WINDOW_BITS,
+                threads,
+            );
+            assert_eq!(stats.synced, 4);
+        }
+    }
+
+    #[test]
+    fn unsynchronized_segments_are_decoded_again() {
+        let stats = run(&[0, 8000, 64_000, 120_000], 0, 4);
+        assert_eq!(stats.synced, 0);
+    }
+
+    #[test]
+    fn public_entry_point() {
+        let (data, decoder, payload, bits) = sample(700_000);
+        let max_segments = payload.len() / MIN_SEGMENT_BYTES as usize;
+        assert!(max_segments >= 4);
+        let open_at = |byte: u64| Ok(&payload[byte as usize..]);
+        for threads in [1, 2, 4] {
+            let mut out = Vec::new();
+            let stats = decode_parallel(
+                &decoder,
+                open_at,
+                bits,
+                data.len() as u64,
+                threads,
+                &mut out,
+            )
+            .unwrap();
+            assert_eq!(out, data);
+            assert_eq!(stats.segments, threads);
+            assert_eq!(stats.synced, threads - 1);
+        }
+    }
+
+    #[test]
+    fn long_payloads_are_cut_into_bounded_segments() {
+        let (data, decoder, payload, bits) = sample(5_000_000);
+        let segments = payload.len().div_ceil(MAX_SEGMENT_BYTES as usize);
+        assert!(segments > 2);
+        let open_at = |byte: u64| Ok(&payload[byte as usize..]);
+        let mut out = Vec::new();
+        let stats =
+            decode_parallel(&decoder, open_at, bits, data.len() as u64, 2, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(stats.segments, segments);
+        assert_eq!(stats.synced, segments - 1);
+    }
+
+    #[test]
+    fn wrong_length_is_an_error() {
+        let (data, decoder, payload, bits) = sample(40_000);
+        let open_at = |byte: u64| Ok(&payload[byte as usize..]);
+        for len in [data.len() as u64 - 1, data.len() as u64 + 1] {
+            let err = decode_segments(
+                &decoder,
+                &open_at,
+                &[0, 50_000],
+                bits,
+                len,
+                64,
+                2,
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Fixtures shared by the unit tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::compress::{compress_with_options, EncoderOptions};
+
+/// A fresh, empty directory under `cmprsr_test/<name>` in the system temp
+/// directory.  It is removed with its contents when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> TempDir {
+        static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir()
+            .join("cmprsr_test")
+            .join(name)
+            .join(format!(
+                "{:x}_{:x}",
+                std::process::id(),
+                TEST_COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Compresses `data` with `options` into a fresh [`TempDir`] under `name`
+/// and returns the directory and the `.cmpr` path.
+pub(crate) fn compress_temp(
+    name: &str,
+    data: &[u8],
+    options: &EncoderOptions,
+) -> (TempDir, PathBuf) {
+    let dir = TempDir::new(name);
+    let input_path = dir.join("input.bin");
+    let cmpr_path = dir.join("data.cmpr");
+    std::fs::write(&input_path, data).unwrap();
+    compress_with_options(&input_path, &cmpr_path, options).unwrap();
+    let _ = std::fs::remove_file(&input_path);
+    (dir, cmpr_path)
+}
@@ -0,0 +1,360 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::compress::{self, EncoderOptions};
+use crate::crypto::{self, TempFile};
+use crate::decompress;
+use crate::format;
+
+// ---------------------------------------------------------------------------
+// Split volumes
+// ---------------------------------------------------------------------------
+//
+// `--volume-size` splits a compressed file into volumes no larger than the
+// given size, named `<input>.cmpr.001`, `.002`, ...  Each volume is a small
+// header followed by the next slice of the `.cmpr` file:
+//
+//   4    magic "CMPV"
+//   1    volume version 0x01
+//   3    zero
+//   16   set ID, random, the same in every volume of a set
+//   4    sequence number, 1-based, little-endian u32
+//   4    number of volumes in the set, little-endian u32
+//   4    CRC-32 of the 32 bytes above
+//
+// The decoder joins the volumes in order before decoding, so the CRC-32 of
+// the `.cmpr` file covers the data; the headers only have to prove that
+// every volume is there, in its place and from the same set.
+
+/// Magic bytes at the start of a volume.
+pub const VOLUME_MAGIC: [u8; 4] = *b"CMPV";
+
+/// Current volume version.
+pub const VOLUME_VERSION: u8 = 0x01;
+
+/// Size of the volume header.
+pub const VOLUME_HEADER_SIZE: u64 = 36;
+
+/// Size of the volume set ID.
+pub const SET_ID_SIZE: usize = 16;
+
+/// A maximum volume size, written as bytes or with a `K`, `M`, `G` or `T`
+/// suffix (powers of 1024).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeSize(pub u64);
+
+impl FromStr for VolumeSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 30),
+            Some((i, 'T' | 't')) => (&s[..i], 40),
+            _ => (s, 0),
+        };
+        digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .map(VolumeSize)
+            .ok_or_else(|| format!("invalid size `{s}` (expected e.g. 4096, 512K or 100M)"))
+    }
+}
+
+/// The header of one volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeHeader {
+    /// Random ID shared by the volumes of a set.
+    pub set_id: [u8; SET_ID_SIZE],
+    /// Position of this volume in the set, from 1.
+    pub sequence: u32,
+    /// Number of volumes in the set.
+    pub total: u32,
+}
+
+impl VolumeHeader {
+    /// Reads a volume header from the start of `reader`.
+    ///
+    /// Returns an `InvalidData` error if it is not a volume, or if the
+    /// header is damaged.
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+        let mut buf = [0u8; VOLUME_HEADER_SIZE as usize];
+        reader.read_exact(&mut buf)?;
+        if buf[..4] != VOLUME_MAGIC {
+            return Err(invalid("Not a volume"));
+        }
+        if buf[4] != VOLUME_VERSION {
+            return Err(invalid(&format!(
+                "Unsupported volume version {:#04x}",
+                buf[4]
+            )));
+        }
+        let stored_crc = u32::from_le_bytes(buf[32..].try_into().unwrap());
+        if format::crc32(&buf[..32]) != stored_crc || buf[5..8] != [0; 3] {
+            return Err(invalid("Volume header is damaged"));
+        }
+        let header = VolumeHeader {
+            set_id: buf[8..24].try_into().unwrap(),
+            sequence: u32::from_le_bytes(buf[24..28].try_into().unwrap()),
+            total: u32::from_le_bytes(buf[28..32].try_into().unwrap()),
+        };
+        if header.sequence == 0 || header.sequence > header.total {
+            return Err(invalid("Volume header is damaged"));
+        }
+        Ok(header)
+    }
+
+    fn to_bytes(self) -> [u8; VOLUME_HEADER_SIZE as usize] {
+        let mut buf = [0u8; VOLUME_HEADER_SIZE as usize];
+        buf[..4].copy_from_slice(&VOLUME_MAGIC);
+        buf[4] = VOLUME_VERSION;
+        buf[8..24].copy_from_slice(&self.set_id);
+        buf[24..28].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.total.to_le_bytes());
+        let crc = format::crc32(&buf[..32]);
+        buf[32..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+}
+
+/// Whether `path` starts with the volume magic.
+pub fn is_volume(path: &Path) -> std::io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == VOLUME_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The path of volume `sequence` of a set of `total` volumes named after
+/// `base`: `base` with `.001` appended, or more digits for large sets.
+pub fn volume_path(base: &Path, sequence: u32, total: u32) -> PathBuf {
+    let width = total.to_string().len().max(3);
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{sequence:0width$}"));
+    PathBuf::from(name)
+}
+
+/// Compresses `input_path` with `options` and splits the result into
+/// volumes of at most `volume_size` bytes named after `output_base` (see
+/// [`volume_path`]).  Returns the original size and the volumes written.
+pub fn compress_to_volumes(
+    input_path: &Path,
+    output_base: &Path,
+    volume_size: u64,
+    options: &EncoderOptions,
+) -> std::io::Result<(u64, Vec<PathBuf>)> {
+    if volume_size <= VOLUME_HEADER_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Volume size must be larger than the {VOLUME_HEADER_SIZE}-byte volume header"),
+        ));
+    }
+    let (compressed, _) = TempFile::create()?;
+    let (original_size, compressed_len) =
+        compress::compress_with_options(input_path, compressed.path(), options)?;
+
+    let data_size = volume_size - VOLUME_HEADER_SIZE;
+    let total = u32::try_from(compressed_len.div_ceil(data_size)).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Volume size is too small: more than 2^32 volumes needed",
+        )
+    })?;
+    let mut set_id = [0u8; SET_ID_SIZE];
+    crypto::random_bytes(&mut set_id)?;
+
+    let mut reader = BufReader::new(File::open(compressed.path())?);
+    let mut paths = Vec::with_capacity(total as usize);
+    for sequence in 1..=total {
+        let path = volume_path(output_base, sequence, total);
+        let header = VolumeHeader {
+            set_id,
+            sequence,
+            total,
+        };
+        decompress::write_atomically(&path, |temp_path| {
+            let mut writer = BufWriter::new(File::create(temp_path)?);
+            writer.write_all(&header.to_bytes())?;
+            std::io::copy(&mut (&mut reader).take(data_size), &mut writer)?;
+            writer.flush()
+        })?;
+        paths.push(path);
+    }
+    Ok((original_size, paths))
+}
+
+/// The base name of a volume path, without its sequence number suffix.
+fn volume_base(path: &Path) -> Option<PathBuf> {
+    let (base, suffix) = path.to_str()?.rsplit_once('.')?;
+    if suffix.len() < 3 || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(PathBuf::from(base))
+}
+
+/// Joins the set of volumes that `path` belongs to, in order, writing the
+/// `.cmpr` file they hold to `writer`.  Any volume of the set may be given.
+///
+/// Fails with `NotFound` if a volume is missing, and with `InvalidData` if
+/// one is damaged, out of place or from another set.
+pub fn join<W: Write>(path: &Path, writer: &mut W) -> std::io::Result<()> {
+    let first = VolumeHeader::read(&mut File::open(path)?)?;
+    let base = match volume_base(path) {
+        Some(base) => base,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Volume `{}` is not named like `<file>.001`", path.display()),
+            ))
+        }
+    };
+    for sequence in 1..=first.total {
+        let volume = volume_path(&base, sequence, first.total);
+        let mut file = File::open(&volume).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "Volume {sequence} of {}, `{}`, is missing: {e}",
+                    first.total,
+                    volume.display()
+                ),
+            )
+        })?;
+        let header = VolumeHeader::read(&mut file)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("`{}`: {e}", volume.display())))?;
+        let mismatch = if header.set_id != first.set_id {
+            Some("belongs to a different set".to_string())
+        } else if header.total != first.total {
+            Some(format!("says the set has {} volumes", header.total))
+        } else if header.sequence != sequence {
+            Some(format!("is volume {}", header.sequence))
+        } else {
+            None
+        };
+        if let Some(mismatch) = mismatch {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Volume {sequence} of {}, `{}`, {mismatch}",
+                    first.total,
+                    volume.display()
+                ),
+            ));
+        }
+        std::io::copy(&mut file, writer)?;
+    }
+    writer.flush()
+}
+
+/// If `input_path` is a volume, joins its set into a temporary `.cmpr`
+/// file.
+pub(crate) fn join_if_volume(input_path: &Path) -> std::io::Result<Option<TempFile>> {
+    if !is_volume(input_path)? {
+        return Ok(None);
+    }
+    let (temp, file) = TempFile::create()?;
+    join(input_path, &mut BufWriter::new(file))?;
+    Ok(Some(temp))
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompress::DecoderOptions;
+    use crate::test_util::TempDir;
+
+    fn sample() -> Vec<u8> {
+        (0..20_000u32)
+            .map(|i| b"volumes of text "[(i % 16) as usize] ^ (i / 997) as u8)
+            .collect()
+    }
+
+    /// Splits `sample()` into 1000-byte volumes under a fresh directory.
+    fn split(dir: &Path) -> Vec<PathBuf> {
+        let input = dir.join("data.bin");
+        std::fs::write(&input, sample()).unwrap();
+        let (original_size, paths) = compress_to_volumes(
+            &input,
+            &dir.join("data.bin.cmpr"),
+            1000,
+            &EncoderOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(original_size, 20_000);
+        paths
+    }
+
+    #[test]
+    fn volume_size_parses() {
+        assert_eq!("4096".parse(), Ok(VolumeSize(4096)));
+        assert_eq!("512K".parse(), Ok(VolumeSize(512 << 10)));
+        assert_eq!("100M".parse(), Ok(VolumeSize(100 << 20)));
+        assert_eq!("2g".parse(), Ok(VolumeSize(2 << 30)));
+        for bad in ["", "M", "1.5M", "-1", "100X", "99999999999T"] {
+            assert!(bad.parse::<VolumeSize>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn volumes_round_trip() {
+        let dir = TempDir::new("volume");
+        let paths = split(&dir);
+        assert!(paths.len() > 3);
+        assert_eq!(paths[0], dir.join("data.bin.cmpr.001"));
+        for (path, sequence) in paths.iter().zip(1..) {
+            assert!(path.metadata().unwrap().len() <= 1000);
+            let header = VolumeHeader::read(&mut File::open(path).unwrap()).unwrap();
+            assert_eq!(header.sequence, sequence);
+            assert_eq!(header.total as usize, paths.len());
+        }
+
+        // Any volume of the set will do.
+        let out = dir.join("out.bin");
+        decompress::decompress_with_options(&paths[1], &out, &DecoderOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), sample());
+    }
+
+    #[test]
+    fn missing_and_mismatched_volumes_are_refused() {
+        let dir = TempDir::new("volume");
+        let paths = split(&dir);
+        let options = DecoderOptions::default();
+
+        let saved = std::fs::read(&paths[2]).unwrap();
+        std::fs::remove_file(&paths[2]).unwrap();
+        let err = decompress::verify(&paths[0], &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("Volume 3 of"));
+
+        std::fs::copy(&paths[3], &paths[2]).unwrap();
+        let err = decompress::verify(&paths[0], &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("is volume 4"));
+
+        // A volume from an earlier run of the same input.
+        let other = TempDir::new("volume");
+        let other_paths = split(&other);
+        std::fs::write(&paths[2], saved).unwrap();
+        std::fs::copy(&other_paths[1], &paths[1]).unwrap();
+        let err = decompress::verify(&paths[0], &options).unwrap_err();
+        assert!(err.to_string().contains("different set"));
+
+        let mut damaged = std::fs::read(&other_paths[1]).unwrap();
+        damaged[25] ^= 1;
+        std::fs::write(&paths[1], damaged).unwrap();
+        let err = decompress::verify(&paths[0], &options).unwrap_err();
+        assert!(err.to_string().contains("damaged"));
+    }
+}